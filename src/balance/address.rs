@@ -0,0 +1,241 @@
+// Signet address encoding: bech32/bech32m for segwit programs (BIP173/BIP350)
+// and base58check for legacy p2pkh/p2sh.
+use crate::balance::balance::BalanceError;
+use sha2::{Digest, Sha256};
+
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+// signet shares testnet's address prefixes
+pub const SIGNET_HRP: &str = "tb";
+pub const P2PKH_VERSION: u8 = 0x6f;
+pub const P2SH_VERSION: u8 = 0xc4;
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    let generator = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for value in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x1ffffff) << 5) ^ (*value as u32);
+        for (i, g) in generator.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|b| b & 31));
+    expanded
+}
+
+// regroup bits, e.g. 8-bit bytes into 5-bit bech32 groups
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let mut result = Vec::new();
+    let max = (1 << to) - 1;
+    for value in data {
+        if (*value as u32) >> from != 0 {
+            return None;
+        }
+        acc = (acc << from) | *value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            result.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & max) != 0 {
+        return None;
+    }
+    Some(result)
+}
+
+// Encode a witness program as a segwit address, bech32 for v0 and bech32m for v1+
+pub fn encode_segwit_address(hrp: &str, version: u8, program: &[u8]) -> String {
+    let mut data = vec![version];
+    data.extend(convert_bits(program, 8, 5, true).expect("bytes always convert"));
+    let constant = if version == 0 { BECH32_CONST } else { BECH32M_CONST };
+
+    let mut values = hrp_expand(hrp);
+    values.extend(&data);
+    values.extend([0u8; 6]);
+    let polymod = bech32_polymod(&values) ^ constant;
+
+    let mut address = format!("{}1", hrp);
+    for d in &data {
+        address.push(BECH32_CHARSET[*d as usize] as char);
+    }
+    for i in 0..6 {
+        address.push(BECH32_CHARSET[((polymod >> (5 * (5 - i))) & 31) as usize] as char);
+    }
+    address
+}
+
+// Decode a segwit address into (version, program)
+pub fn decode_segwit_address(hrp: &str, address: &str) -> Result<(u8, Vec<u8>), BalanceError> {
+    let invalid = |msg: &str| BalanceError::ParseError(format!("{}: {}", msg, address));
+    if address.to_lowercase() != address && address.to_uppercase() != address {
+        return Err(invalid("mixed case address"));
+    }
+    let address = address.to_lowercase();
+    let (addr_hrp, rest) = address.rsplit_once('1').ok_or_else(|| invalid("missing separator"))?;
+    if addr_hrp != hrp {
+        return Err(invalid("wrong network prefix"));
+    }
+    let data: Vec<u8> = rest
+        .bytes()
+        .map(|c| BECH32_CHARSET.iter().position(|x| *x == c).map(|p| p as u8))
+        .collect::<Option<_>>()
+        .ok_or_else(|| invalid("invalid bech32 character"))?;
+    if data.len() < 7 {
+        return Err(invalid("address too short"));
+    }
+
+    let mut values = hrp_expand(addr_hrp);
+    values.extend(&data);
+    let version = data[0];
+    let expected = if version == 0 { BECH32_CONST } else { BECH32M_CONST };
+    if bech32_polymod(&values) != expected {
+        return Err(invalid("bad checksum"));
+    }
+
+    let program = convert_bits(&data[1..data.len() - 6], 5, 8, false)
+        .ok_or_else(|| invalid("invalid padding"))?;
+    if version > 16 || program.len() < 2 || program.len() > 40 {
+        return Err(invalid("invalid witness program"));
+    }
+    if version == 0 && program.len() != 20 && program.len() != 32 {
+        return Err(invalid("invalid v0 witness program length"));
+    }
+    Ok((version, program))
+}
+
+fn checksum(data: &[u8]) -> [u8; 4] {
+    let hash = Sha256::digest(Sha256::digest(data));
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+pub fn base58check_encode(payload: &[u8]) -> String {
+    let mut data = payload.to_vec();
+    data.extend(checksum(payload));
+
+    // repeated division by 58 of the big-endian number
+    let mut digits: Vec<u8> = Vec::new();
+    for byte in &data {
+        let mut carry = *byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let leading_zeros = data.iter().take_while(|b| **b == 0).count();
+    let mut encoded = "1".repeat(leading_zeros);
+    encoded.extend(digits.iter().rev().map(|d| BASE58_ALPHABET[*d as usize] as char));
+    encoded
+}
+
+pub fn base58check_decode(encoded: &str) -> Result<Vec<u8>, BalanceError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in encoded.bytes() {
+        let mut carry = BASE58_ALPHABET
+            .iter()
+            .position(|x| *x == c)
+            .ok_or(BalanceError::InvalidBase58Character)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+    let leading_ones = encoded.bytes().take_while(|c| *c == b'1').count();
+    let mut data = vec![0u8; leading_ones];
+    data.extend(bytes.iter().rev());
+
+    if data.len() < 4 {
+        return Err(BalanceError::ParseError(format!("base58 string too short: {}", encoded)));
+    }
+    let (payload, check) = data.split_at(data.len() - 4);
+    if checksum(payload) != check {
+        return Err(BalanceError::ParseError(format!("bad base58 checksum: {}", encoded)));
+    }
+    Ok(payload.to_vec())
+}
+
+// scriptPubKey paid to by a signet address
+pub fn address_to_script(address: &str) -> Result<Vec<u8>, BalanceError> {
+    if address.to_lowercase().starts_with(&format!("{}1", SIGNET_HRP)) {
+        let (version, program) = decode_segwit_address(SIGNET_HRP, address)?;
+        let mut script = Vec::with_capacity(program.len() + 2);
+        // OP_0 or OP_1..OP_16
+        script.push(if version == 0 { 0x00 } else { 0x50 + version });
+        script.push(program.len() as u8);
+        script.extend(program);
+        return Ok(script);
+    }
+
+    let payload = base58check_decode(address)?;
+    if payload.len() != 21 {
+        return Err(BalanceError::ParseError(format!("invalid address length: {}", address)));
+    }
+    match payload[0] {
+        P2PKH_VERSION => {
+            let mut script = vec![0x76, 0xa9, 0x14]; // OP_DUP OP_HASH160 <20 bytes>
+            script.extend(&payload[1..]);
+            script.extend([0x88, 0xac]); // OP_EQUALVERIFY OP_CHECKSIG
+            Ok(script)
+        }
+        P2SH_VERSION => {
+            let mut script = vec![0xa9, 0x14]; // OP_HASH160 <20 bytes>
+            script.extend(&payload[1..]);
+            script.push(0x87); // OP_EQUAL
+            Ok(script)
+        }
+        _ => Err(BalanceError::ParseError(format!("not a signet address: {}", address))),
+    }
+}
+
+// Signet address for a scriptPubKey, None for scripts without an address form
+pub fn script_to_address(script: &[u8]) -> Option<String> {
+    match script {
+        [0x76, 0xa9, 0x14, hash @ .., 0x88, 0xac] if hash.len() == 20 => {
+            let mut payload = vec![P2PKH_VERSION];
+            payload.extend(hash);
+            Some(base58check_encode(&payload))
+        }
+        [0xa9, 0x14, hash @ .., 0x87] if hash.len() == 20 => {
+            let mut payload = vec![P2SH_VERSION];
+            payload.extend(hash);
+            Some(base58check_encode(&payload))
+        }
+        [version, len, program @ ..]
+            if (*version == 0x00 || (0x51..=0x60).contains(version))
+                && *len as usize == program.len()
+                && (2..=40).contains(&program.len()) =>
+        {
+            let version = if *version == 0 { 0 } else { version - 0x50 };
+            Some(encode_segwit_address(SIGNET_HRP, version, program))
+        }
+        _ => None,
+    }
+}
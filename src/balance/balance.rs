@@ -4,10 +4,13 @@ use hmac::{Hmac, Mac};
 use num_bigint::{BigInt, BigUint};
 use num_traits::Zero;
 use ripemd::Ripemd160;
-use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use crate::balance::address::{base58check_decode, base58check_encode};
+use crate::spend_funds::script::ScriptType;
+use crate::spend_funds::taproot::get_p2tr_program;
+use secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
 use serde_json::Value;
 use sha2::{Digest, Sha256, Sha512};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::{path::PathBuf, process::Command};
 
 #[derive(Clone)]
@@ -20,12 +23,44 @@ struct ExKey {
     key: [u8; 32],
 }
 
+#[derive(Default)]
 pub struct WalletState {
     //my utxo key tuple is (txid, vout), (script_pubkey, value)
     pub utxos: HashMap<(String, u32), (Vec<u8>, f64)>,
     pub witness_programs: Vec<Vec<u8>>,
     pub public_keys: Vec<Vec<u8>>,
     pub private_keys: Vec<Vec<u8>>,
+    // outputs paying to watched scripts (e.g. shared multisig), not part of the balance
+    pub watched_utxos: HashMap<(String, u32), (Vec<u8>, f64)>,
+    // fingerprint of the master key, used in PSBT key origins
+    pub fingerprint: [u8; 4],
+    // BIP86 private keys and their P2TR scriptPubKeys, same indexing
+    pub taproot_keys: Vec<Vec<u8>>,
+    pub taproot_programs: Vec<Vec<u8>>,
+    // BIP49 private keys and their P2SH-P2WPKH scriptPubKeys, same indexing
+    pub nested_keys: Vec<Vec<u8>>,
+    pub nested_scripts: Vec<Vec<u8>>,
+    // BIP44 private keys and their P2PKH scriptPubKeys, same indexing
+    pub legacy_keys: Vec<Vec<u8>>,
+    pub legacy_scripts: Vec<Vec<u8>>,
+    // BIP84 change (internal chain) private keys and their P2WPKH programs, same indexing
+    pub change_keys: Vec<Vec<u8>>,
+    pub change_programs: Vec<Vec<u8>>,
+    // account extended public keys by script type, as "[fingerprint/84h/1h/0h]tpub..."
+    // descriptor key expressions
+    pub account_keys: Vec<(ScriptType, String)>,
+    // our scripts that have received coins, spent or not
+    pub used_programs: HashSet<Vec<u8>>,
+    // outpoints coin selection must not spend
+    pub frozen: HashSet<(String, u32)>,
+}
+
+// One of our BIP84 keys with its full derivation path, for PSBT key origins
+#[derive(Clone, PartialEq)]
+pub struct P2wpkhKey {
+    pub private_key: [u8; 32],
+    pub public_key: Vec<u8>,
+    pub path: Vec<u32>,
 }
 
 impl WalletState {
@@ -33,8 +68,129 @@ impl WalletState {
     pub fn balance(&self) -> f64 {
         self.utxos.values().map(|(_, value)| value).sum()
     }
+
+    // Our BIP84 key at `index` on the receive or the change chain
+    fn p2wpkh_key(&self, change: bool, index: usize) -> Option<P2wpkhKey> {
+        let (chain, private_key, public_key) = if change {
+            let private_key = self.change_keys.get(index)?;
+            (P2WPKH_CHANGE_DERIVATION_PATH, private_key, derive_public_key_from_private(private_key))
+        } else {
+            (P2WPKH_DERIVATION_PATH, self.private_keys.get(index)?, self.public_keys.get(index)?.clone())
+        };
+        let mut path = parse_derivation_path(chain);
+        path.push(index as u32);
+        Some(P2wpkhKey {
+            private_key: private_key.clone().try_into().ok()?,
+            public_key,
+            path,
+        })
+    }
+
+    // Our BIP84 key paying to the P2WPKH `script`, on either chain
+    pub fn p2wpkh_key_for_script(&self, script: &[u8]) -> Option<P2wpkhKey> {
+        if let Some(index) = self.witness_programs.iter().position(|p| p == script) {
+            return self.p2wpkh_key(false, index);
+        }
+        let index = self.change_programs.iter().position(|p| p == script)?;
+        self.p2wpkh_key(true, index)
+    }
+
+    // Our BIP84 key at a full derivation path from our master key
+    pub fn p2wpkh_key_for_path(&self, fingerprint: &[u8; 4], path: &[u32]) -> Option<P2wpkhKey> {
+        let (last, parent) = path.split_last()?;
+        if *fingerprint != self.fingerprint {
+            return None;
+        }
+        if parent == parse_derivation_path(P2WPKH_DERIVATION_PATH) {
+            self.p2wpkh_key(false, *last as usize)
+        } else if parent == parse_derivation_path(P2WPKH_CHANGE_DERIVATION_PATH) {
+            self.p2wpkh_key(true, *last as usize)
+        } else {
+            None
+        }
+    }
+
+    // Our BIP84 receive key `pubkey`, the keys we put in multisig scripts
+    pub fn p2wpkh_key_for_pubkey(&self, pubkey: &[u8]) -> Option<P2wpkhKey> {
+        let index = self.public_keys.iter().position(|key| key == pubkey)?;
+        self.p2wpkh_key(false, index)
+    }
+
+    // Whether `script` is one of our single key scriptPubKeys, of any type
+    pub fn is_ours(&self, script: &[u8]) -> bool {
+        [
+            &self.witness_programs,
+            &self.change_programs,
+            &self.taproot_programs,
+            &self.nested_scripts,
+            &self.legacy_scripts,
+        ]
+        .iter()
+        .any(|scripts| scripts.iter().any(|p| p == script))
+    }
+
+    // Our coins that coin selection may spend
+    pub fn spendable_utxos(&self) -> impl Iterator<Item = (&(String, u32), &(Vec<u8>, f64))> {
+        self.utxos.iter().filter(|(outpoint, _)| !self.frozen.contains(*outpoint))
+    }
+
+    // Watched coins (multisig, timelocks, ...) that coin selection may spend
+    pub fn spendable_watched_utxos(&self) -> impl Iterator<Item = (&(String, u32), &(Vec<u8>, f64))> {
+        self.watched_utxos.iter().filter(|(outpoint, _)| !self.frozen.contains(*outpoint))
+    }
+
+    // Our first P2WPKH scriptPubKey that has never received coins
+    pub fn fresh_program(&self) -> Option<&Vec<u8>> {
+        self.witness_programs
+            .iter()
+            .find(|program| !self.used_programs.contains(*program))
+    }
+
+    // Our first change P2WPKH scriptPubKey that has never received coins
+    pub fn fresh_change_program(&self) -> Option<&Vec<u8>> {
+        self.change_programs
+            .iter()
+            .find(|program| !self.used_programs.contains(*program))
+    }
+}
+
+// "m/84h/1h/0" style path to child numbers, hardened ones offset by 2^31
+pub fn parse_derivation_path(derivation_path: &str) -> Vec<u32> {
+    derivation_path
+        .split('/')
+        .skip(1)
+        .map(|component| {
+            let hardened = component.ends_with('\'') || component.ends_with('h');
+            let index = component
+                .trim_end_matches('\'')
+                .trim_end_matches('h')
+                .parse::<u32>()
+                .expect("Invalid child number");
+            if hardened {
+                index + 2147483648
+            } else {
+                index
+            }
+        })
+        .collect()
+}
+
+// bitcoin-cli reports amounts in BTC, round to the nearest satoshi
+pub fn btc_to_sats(amount: f64) -> u64 {
+    (amount * 100_000_000.0).round() as u64
 }
 
+// BIP84 receive path, the wallet's keys are its children 0..2000
+pub const P2WPKH_DERIVATION_PATH: &str = "m/84h/1h/0h/0";
+// BIP84 change path, the internal chain next to the receive path
+pub const P2WPKH_CHANGE_DERIVATION_PATH: &str = "m/84h/1h/0h/1";
+// BIP86 receive path for single key taproot outputs
+pub const P2TR_DERIVATION_PATH: &str = "m/86h/1h/0h/0";
+// BIP49 receive path for P2SH-wrapped P2WPKH outputs
+pub const P2SH_P2WPKH_DERIVATION_PATH: &str = "m/49h/1h/0h/0";
+// BIP44 receive path for legacy P2PKH outputs
+pub const P2PKH_DERIVATION_PATH: &str = "m/44h/1h/0h/0";
+
 #[derive(Debug)]
 pub enum BalanceError {
     MissingCodeCantRun,
@@ -43,13 +199,26 @@ pub enum BalanceError {
     ParseError(String),
 }
 
+impl std::fmt::Display for BalanceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BalanceError::MissingCodeCantRun => write!(f, "failed to run bitcoin-cli"),
+            BalanceError::InvalidBase58Character => write!(f, "invalid base58 character"),
+            BalanceError::ParseError(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct ScanInputs {
     cpublic_keys: HashMap<String, bool>,
     cwitness_programs: HashMap<String, bool>,
+    cwatch_scripts: HashMap<String, bool>,
     outgoing_txs: Vec<Vec<u8>>,
     spending_txs: Vec<Vec<u8>>,
     utxos: HashMap<(String, u32), (Vec<u8>, f64)>,
+    watched_utxos: HashMap<(String, u32), (Vec<u8>, f64)>,
+    used_programs: HashSet<Vec<u8>>,
 }
 
 fn base58_decode(base58_string: &str) -> Vec<u8> {
@@ -77,7 +246,7 @@ fn base58_decode(base58_string: &str) -> Vec<u8> {
     hasher.update(data_with_version_byte);
     let hashed = hasher.finalize();
     let mut hasher2 = Sha256::new();
-    hasher2.update(&hashed);
+    hasher2.update(hashed);
     let hash_of_hash = hasher2.finalize();
     let calculated_checksum = &hash_of_hash[0..4];
     // println!("Calculated checksum: {:?}", calculated_checksum);
@@ -98,23 +267,33 @@ fn deserialize_key(bytes: &[u8]) -> ExKey {
 
 fn get_child_key_at_path(key: ExKey, derivation_path: &str) -> ExKey {
     let mut derived_key = key.clone();
-    // skip 'm'
-    for component in derivation_path.split('/').skip(1) {
-        let hardened = component.ends_with("'") || component.ends_with("h");
-        let index_str = component.trim_end_matches("'").trim_end_matches("h");
-        let child_num = index_str.parse::<u32>().expect("Invalid child number");
-
-        // Adjust child_num for hardened keys
-        let child_num = if hardened {
-            child_num + 2147483648
-        } else {
-            child_num
-        };
+    for child_num in parse_derivation_path(derivation_path) {
         derived_key = derive_priv_child(derived_key, child_num);
     }
     derived_key
 }
 
+// Version bytes of testnet/signet extended public keys
+const TPUB_VERSION: [u8; 4] = hex!("043587CF");
+
+// Extended public key (tpub) of a derived key
+fn serialize_xpub(key: &ExKey) -> String {
+    let mut data = TPUB_VERSION.to_vec();
+    data.extend(key.depth);
+    data.extend(key.finger_print);
+    data.extend(key.child_number);
+    data.extend(key.chaincode);
+    data.extend(derive_public_key_from_private(&key.key));
+    base58check_encode(&data)
+}
+
+// first 4 bytes of hash160 of the key's public key
+fn get_fingerprint(key: &ExKey) -> [u8; 4] {
+    let pubkey = derive_public_key_from_private(&key.key);
+    let ripemd_result = Ripemd160::digest(Sha256::digest(&pubkey));
+    ripemd_result[0..4].try_into().unwrap()
+}
+
 fn derive_priv_child(key: ExKey, child_num: u32) -> ExKey {
     let curve_order = hex!("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141");
     let big_n = BigUint::from_bytes_be(&curve_order);
@@ -148,7 +327,7 @@ fn derive_priv_child(key: ExKey, child_num: u32) -> ExKey {
 
     let mut child_key = [0u8; 32];
 
-    let sum = (BigUint::from_bytes_be(&key.key) + BigUint::from_bytes_be(&il)) % &big_n;
+    let sum = (BigUint::from_bytes_be(&key.key) + BigUint::from_bytes_be(il)) % &big_n;
     let mut sum_bytes = sum.to_bytes_be();
     while sum_bytes.len() < 32 {
         sum_bytes.insert(0, 0);
@@ -183,11 +362,50 @@ fn derive_priv_child(key: ExKey, child_num: u32) -> ExKey {
 
 fn derive_public_key_from_private(key: &[u8]) -> Vec<u8> {
     let secp = Secp256k1::new();
-    let secret_key = SecretKey::from_slice(&key).expect("Expected 32 bytes");
+    let secret_key = SecretKey::from_slice(key).expect("Expected 32 bytes");
     let public_key = PublicKey::from_secret_key(&secp, &secret_key);
     public_key.serialize().to_vec()
 }
 
+// Compressed public key of a co-signer's extended public key (tpub/xpub)
+// at a non-hardened path relative to it, e.g. "m/0/0"
+pub fn derive_xpub_child_key(xpub: &str, derivation_path: &str) -> Result<Vec<u8>, BalanceError> {
+    let decoded = base58check_decode(xpub)?;
+    if decoded.len() != 78 {
+        return Err(BalanceError::ParseError(format!("invalid extended public key: {}", xpub)));
+    }
+    let secp = Secp256k1::new();
+    let mut chaincode = decoded[13..45].to_vec();
+    let mut pubkey = PublicKey::from_slice(&decoded[45..78])
+        .map_err(|e| BalanceError::ParseError(e.to_string()))?;
+
+    for component in derivation_path.split('/').skip(1) {
+        let child_num = component
+            .parse::<u32>()
+            .ok()
+            .filter(|n| *n < 0x80000000)
+            .ok_or_else(|| {
+                BalanceError::ParseError(format!("invalid non-hardened child number: {}", component))
+            })?;
+
+        // Data = parent public key || ser32(i)
+        let mut hmac =
+            Hmac::<Sha512>::new_from_slice(&chaincode).expect("HMAC initialization failed");
+        hmac.update(&pubkey.serialize());
+        hmac.update(&child_num.to_be_bytes());
+        let result = hmac.finalize().into_bytes();
+
+        let tweak = Scalar::from_be_bytes(result[0..32].try_into().unwrap())
+            .map_err(|_| BalanceError::ParseError("invalid child key".to_string()))?;
+        pubkey = pubkey
+            .add_exp_tweak(&secp, &tweak)
+            .map_err(|_| BalanceError::ParseError("invalid child key".to_string()))?;
+        chaincode = result[32..].to_vec();
+    }
+
+    Ok(pubkey.serialize().to_vec())
+}
+
 fn get_keys_at_child_key_path(child_key: ExKey, num_keys: u32) -> Vec<ExKey> {
     let mut keys = Vec::with_capacity(num_keys as usize);
 
@@ -196,17 +414,17 @@ fn get_keys_at_child_key_path(child_key: ExKey, num_keys: u32) -> Vec<ExKey> {
         keys.push(current_key);
     }
 
-    return keys;
+    keys
 }
 
 // Derive the p2wpkh witness program (aka scriptPubKey) for a given compressed public key
-fn get_p2wpkh_program(pubkey: &[u8]) -> Vec<u8> {
+pub fn get_p2wpkh_program(pubkey: &[u8]) -> Vec<u8> {
     if pubkey.len() != 33 || (pubkey[0] != 0x02 && pubkey[0] != 0x03) {
         panic!("Invalid compressed public key");
     }
 
     let mut hasher = Sha256::new();
-    hasher.update(&pubkey);
+    hasher.update(pubkey);
     let sha256_result = hasher.finalize();
 
     let mut ripemd160 = Ripemd160::new();
@@ -221,6 +439,23 @@ fn get_p2wpkh_program(pubkey: &[u8]) -> Vec<u8> {
     witness_program
 }
 
+// OP_HASH160 <hash160 of the P2WPKH program> OP_EQUAL
+pub fn get_p2sh_p2wpkh_script(pubkey: &[u8]) -> Vec<u8> {
+    let redeem_script = get_p2wpkh_program(pubkey);
+    let mut script = vec![0xa9, 0x14];
+    script.extend(Ripemd160::digest(Sha256::digest(&redeem_script)));
+    script.push(0x87);
+    script
+}
+
+// OP_DUP OP_HASH160 <hash160 of the key> OP_EQUALVERIFY OP_CHECKSIG
+pub fn get_p2pkh_script(pubkey: &[u8]) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend(Ripemd160::digest(Sha256::digest(pubkey)));
+    script.extend([0x88, 0xac]);
+    script
+}
+
 pub fn bcli(cmd: &str) -> Result<Vec<u8>, BalanceError> {
     let mut args = vec!["-signet"];
     args.extend(cmd.split(' '));
@@ -231,9 +466,9 @@ pub fn bcli(cmd: &str) -> Result<Vec<u8>, BalanceError> {
         .map_err(|_| BalanceError::MissingCodeCantRun)?;
 
     if result.status.success() {
-        return Ok(result.stdout);
+        Ok(result.stdout)
     } else {
-        return Ok(result.stderr);
+        Ok(result.stderr)
     }
 }
 
@@ -245,10 +480,7 @@ fn fetch_block(block_number: u32) -> Result<Value, BalanceError> {
     };
 
     let block_cmd = format!("-signet getblock {} 2", block_hash);
-    let block_data = match bcli(&block_cmd) {
-        Ok(data) => data,
-        Err(e) => return Err(e),
-    };
+    let block_data = bcli(&block_cmd)?;
 
     //return it as serde_json::Value
     serde_json::from_slice(&block_data).map_err(|e| BalanceError::ParseError(e.to_string()))
@@ -292,13 +524,15 @@ fn parse_transaction(tx: &Value, scan_inputs: &mut ScanInputs) -> Result<(), Bal
                     }
                 }
             }
+        }
 
-            if let (Some(prev_txid), Some(prev_vout)) =
-                (input["txid"].as_str(), input["vout"].as_u64())
-            {
-                let outpoint_key = (prev_txid.to_string(), prev_vout as u32);
-                scan_inputs.utxos.remove(&outpoint_key);
-            }
+        // legacy (P2PKH) inputs spend our coins without a witness
+        if let (Some(prev_txid), Some(prev_vout)) =
+            (input["txid"].as_str(), input["vout"].as_u64())
+        {
+            let outpoint_key = (prev_txid.to_string(), prev_vout as u32);
+            scan_inputs.utxos.remove(&outpoint_key);
+            scan_inputs.watched_utxos.remove(&outpoint_key);
         }
     }
 
@@ -312,6 +546,13 @@ fn parse_transaction(tx: &Value, scan_inputs: &mut ScanInputs) -> Result<(), Bal
                     outpoint_key,
                     (hex::decode(script_pub_key).unwrap(), value.unwrap()),
                 );
+                scan_inputs.used_programs.insert(hex::decode(script_pub_key).unwrap());
+            } else if scan_inputs.cwatch_scripts.contains_key(script_pub_key) {
+                let value = output["value"].as_f64().unwrap_or(0.0);
+                scan_inputs.watched_utxos.insert(
+                    (txid.clone(), vout as u32),
+                    (hex::decode(script_pub_key).unwrap(), value),
+                );
             }
         }
     }
@@ -341,54 +582,149 @@ fn scan_blockchain(scan_inputs: &mut ScanInputs) -> Result<(), BalanceError> {
     Ok(())
 }
 
-pub fn recover_wallet_state(
-    extended_private_key: &str,
-    cookie_filepath: &str,
-) -> Result<WalletState, BalanceError> {
+// The wallet's keys and scripts derived from its master key, `num_keys` per
+// derivation path, without any coins
+pub fn derive_wallet_state(extended_private_key: &str, num_keys: u32) -> Result<WalletState, BalanceError> {
     let decoded_key = base58_decode(extended_private_key);
     let deserialize_key = deserialize_key(&decoded_key);
+    let fingerprint = get_fingerprint(&deserialize_key);
+
+    // account keys are the parents of the receive paths
+    let account_keys = [
+        (ScriptType::P2wpkh, P2WPKH_DERIVATION_PATH),
+        (ScriptType::P2tr, P2TR_DERIVATION_PATH),
+        (ScriptType::P2sh, P2SH_P2WPKH_DERIVATION_PATH),
+        (ScriptType::P2pkh, P2PKH_DERIVATION_PATH),
+    ]
+    .into_iter()
+    .map(|(script_type, receive_path)| {
+        let (account_path, _) = receive_path.rsplit_once('/').expect("receive paths have a parent");
+        let account_key = get_child_key_at_path(deserialize_key.clone(), account_path);
+        let origin = format!("[{}{}]", hex::encode(fingerprint), &account_path[1..]);
+        (script_type, origin + &serialize_xpub(&account_key))
+    })
+    .collect();
 
     //derive the key by using the path in descriptor
-    let derivation_path: &str = "m/84h/1h/0h/0";
-    let child_key = get_child_key_at_path(deserialize_key, derivation_path);
+    let derivation_path: &str = P2WPKH_DERIVATION_PATH;
+    let child_key = get_child_key_at_path(deserialize_key.clone(), derivation_path);
 
     // Get the child key at the derivation path
-    // Compute 2000 private keys from the child key path
-    let child_keys = get_keys_at_child_key_path(child_key, 2000);
+    // Compute num_keys private keys from the child key path
+    let child_keys = get_keys_at_child_key_path(child_key, num_keys);
 
     // For each private key, collect compressed public keys and witness programs
     let mut private_keys = vec![];
     let mut public_keys = vec![];
     let mut witness_programs = vec![];
-    let mut cprivate_keys = HashMap::<String, bool>::new();
-    let mut cpublic_keys = HashMap::<String, bool>::new();
-    let mut cwitness_programs = HashMap::<String, bool>::new();
 
     for cpriv_key in child_keys.clone() {
         let priv_key = cpriv_key.key;
-        cprivate_keys.insert(hex::encode(priv_key.to_vec()), true);
-
         let pub_key = derive_public_key_from_private(&priv_key);
-        cpublic_keys.insert(hex::encode(pub_key.clone()), true);
-
         let witness_program = get_p2wpkh_program(&pub_key);
-        cwitness_programs.insert(hex::encode(witness_program.clone()), true);
-
         private_keys.push(priv_key.to_vec());
         public_keys.push(pub_key.to_vec());
         witness_programs.push(witness_program.to_vec());
     }
 
+    // BIP84 change keys
+    let change_child_key = get_child_key_at_path(deserialize_key.clone(), P2WPKH_CHANGE_DERIVATION_PATH);
+    let mut change_keys = vec![];
+    let mut change_programs = vec![];
+    for cpriv_key in get_keys_at_child_key_path(change_child_key, num_keys) {
+        let program = get_p2wpkh_program(&derive_public_key_from_private(&cpriv_key.key));
+        change_keys.push(cpriv_key.key.to_vec());
+        change_programs.push(program);
+    }
+
+    // BIP86 keys
+    let taproot_child_key = get_child_key_at_path(deserialize_key.clone(), P2TR_DERIVATION_PATH);
+    let mut taproot_keys = vec![];
+    let mut taproot_programs = vec![];
+    for cpriv_key in get_keys_at_child_key_path(taproot_child_key, num_keys) {
+        let pub_key = derive_public_key_from_private(&cpriv_key.key);
+        let program = get_p2tr_program(&pub_key, None)
+            .map_err(|e| BalanceError::ParseError(e.to_string()))?;
+        taproot_keys.push(cpriv_key.key.to_vec());
+        taproot_programs.push(program);
+    }
+
+    // BIP49 and BIP44 keys
+    let nested_child_key = get_child_key_at_path(deserialize_key.clone(), P2SH_P2WPKH_DERIVATION_PATH);
+    let mut nested_keys = vec![];
+    let mut nested_scripts = vec![];
+    for cpriv_key in get_keys_at_child_key_path(nested_child_key, num_keys) {
+        let script = get_p2sh_p2wpkh_script(&derive_public_key_from_private(&cpriv_key.key));
+        nested_keys.push(cpriv_key.key.to_vec());
+        nested_scripts.push(script);
+    }
+    let legacy_child_key = get_child_key_at_path(deserialize_key, P2PKH_DERIVATION_PATH);
+    let mut legacy_keys = vec![];
+    let mut legacy_scripts = vec![];
+    for cpriv_key in get_keys_at_child_key_path(legacy_child_key, num_keys) {
+        let script = get_p2pkh_script(&derive_public_key_from_private(&cpriv_key.key));
+        legacy_keys.push(cpriv_key.key.to_vec());
+        legacy_scripts.push(script);
+    }
+
+    Ok(WalletState {
+        public_keys,
+        private_keys,
+        witness_programs,
+        fingerprint,
+        taproot_keys,
+        taproot_programs,
+        nested_keys,
+        nested_scripts,
+        legacy_keys,
+        legacy_scripts,
+        change_keys,
+        change_programs,
+        account_keys,
+        ..Default::default()
+    })
+}
+
+pub fn recover_wallet_state(
+    extended_private_key: &str,
+    cookie_filepath: &str,
+    watch_scripts: &[Vec<u8>],
+) -> Result<WalletState, BalanceError> {
+    let wallet_state = derive_wallet_state(extended_private_key, 2000)?;
+
+    // every single key script is scanned, and p2wpkh keys are also looked for in inputs
+    let cpublic_keys: HashMap<String, bool> =
+        wallet_state.public_keys.iter().map(|key| (hex::encode(key), true)).collect();
+    let cwitness_programs: HashMap<String, bool> = [
+        &wallet_state.witness_programs,
+        &wallet_state.change_programs,
+        &wallet_state.taproot_programs,
+        &wallet_state.nested_scripts,
+        &wallet_state.legacy_scripts,
+    ]
+    .into_iter()
+    .flatten()
+    .map(|script| (hex::encode(script), true))
+    .collect();
+
     let mut outgoing_txs: Vec<Vec<u8>> = vec![];
     let mut spending_txs: Vec<Vec<u8>> = vec![];
     let mut utxos: HashMap<(String, u32), (Vec<u8>, f64)> = HashMap::new();
 
+    let cwatch_scripts = watch_scripts
+        .iter()
+        .map(|script| (hex::encode(script), true))
+        .collect();
+
     let mut scan_inputs = ScanInputs {
         cpublic_keys: cpublic_keys.clone(),
         cwitness_programs: cwitness_programs.clone(),
-        outgoing_txs: outgoing_txs,
-        spending_txs: spending_txs,
+        cwatch_scripts,
+        outgoing_txs,
+        spending_txs,
         utxos: utxos.clone(),
+        watched_utxos: HashMap::new(),
+        used_programs: HashSet::new(),
     };
 
     // Scan blocks 0 to 300 for transactions
@@ -396,8 +732,8 @@ pub fn recover_wallet_state(
 
     Ok(WalletState {
         utxos: scan_inputs.utxos.clone(),
-        public_keys,
-        private_keys,
-        witness_programs,
+        watched_utxos: scan_inputs.watched_utxos,
+        used_programs: scan_inputs.used_programs,
+        ..wallet_state
     })
 }
@@ -0,0 +1,514 @@
+// Wallet data that can't be recovered from the chain, persisted as JSON
+// in wallets/<wallet name>.json next to the .env file.
+use crate::balance::address::address_to_script;
+use crate::balance::balance::{BalanceError, WalletState};
+use crate::spend_funds::timelock::Timelock;
+use crate::spend_funds::transaction::{Transaction, Utxo};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const CACHE_DIR: &str = "wallets";
+
+// A shared multisig wallet mixing our keys with co-signer keys
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MultisigPolicy {
+    pub name: String,
+    pub threshold: usize,
+    // compressed public keys in witness script order
+    pub keys: Vec<String>,
+    pub witness_script: String,
+    pub address: String,
+}
+
+// A taproot output committing to a tree of tapscripts
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TaprootPolicy {
+    pub name: String,
+    // x-only internal key
+    pub internal_key: String,
+    // leaf scripts in tree order
+    pub leaves: Vec<String>,
+    pub address: String,
+}
+
+// A P2WSH output spendable by the primary key, or by the recovery key once
+// the timelock has passed
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TimelockPolicy {
+    pub name: String,
+    pub primary_key: String,
+    pub recovery_key: String,
+    pub timelock: Timelock,
+    pub witness_script: String,
+    pub address: String,
+}
+
+// A hash time-locked contract: the recipient can spend with the preimage of
+// `hash`, the refund key after the timeout
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HtlcPolicy {
+    pub name: String,
+    // SHA256 of the preimage
+    pub hash: String,
+    pub recipient_key: String,
+    pub refund_key: String,
+    pub timeout: Timelock,
+    pub witness_script: String,
+    pub address: String,
+}
+
+// An output compiled from a miniscript policy: P2WSH, or with `taproot` P2TR
+// with a leaf per branch of the policy's top level or()s
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MiniscriptPolicy {
+    pub name: String,
+    // the policy with its keys resolved to hex
+    pub policy: String,
+    #[serde(default)]
+    pub taproot: bool,
+    pub descriptor: String,
+    pub address: String,
+}
+
+// An output of a transaction we broadcast that isn't confirmed yet
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UnconfirmedOutput {
+    pub txid: String,
+    pub vout: u32,
+    pub script_pubkey: String,
+    // in sats
+    pub amount: u64,
+}
+
+// An output spent by a transaction we broadcast
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SpentOutput {
+    pub txid: String,
+    pub vout: u32,
+    pub script_pubkey: String,
+    // in sats
+    pub amount: u64,
+}
+
+// A transaction we broadcast, with the fee it paid
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WalletTransaction {
+    pub txid: String,
+    pub hex: String,
+    pub fee: u64,
+    // the outputs it spends: once recorded they are no longer our coins, and
+    // the node only finds mined transactions with -txindex
+    #[serde(default)]
+    pub prevouts: Vec<SpentOutput>,
+    // left the mempool for a block: kept, as the chain scan may not reach it
+    #[serde(default)]
+    pub confirmed: bool,
+}
+
+// What became of a transaction that left the mempool
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MempoolExit {
+    // mined, with outputs still unspent
+    Confirmed,
+    // its inputs and outputs are all spent: mined, or replaced by a
+    // conflicting transaction, its inputs are spent either way
+    Spent,
+    // never mined, its inputs are unspent again
+    Dropped,
+}
+
+// What a label refers to (BIP329)
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelType {
+    // a txid
+    Tx,
+    // an address
+    Addr,
+    // a hex public key
+    Pubkey,
+    // a spent coin, <txid>:<vout>
+    Input,
+    // a coin, <txid>:<vout>
+    Output,
+    // an extended public key
+    Xpub,
+}
+
+impl FromStr for LabelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_lowercase()))
+            .map_err(|_| format!("unknown label type {}, expected tx, addr, pubkey, input, output or xpub", s))
+    }
+}
+
+// A BIP329 label record, one JSON object per line when exported
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Label {
+    #[serde(rename = "type")]
+    pub kind: LabelType,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    // descriptor the reference was derived from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    // outputs only, false for frozen coins
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spendable: Option<bool>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct WalletCache {
+    #[serde(default)]
+    pub multisig: Vec<MultisigPolicy>,
+    #[serde(default)]
+    pub taproot: Vec<TaprootPolicy>,
+    #[serde(default)]
+    pub timelock: Vec<TimelockPolicy>,
+    #[serde(default)]
+    pub htlc: Vec<HtlcPolicy>,
+    #[serde(default)]
+    pub miniscript: Vec<MiniscriptPolicy>,
+    // outpoints ("txid:vout") spent by our broadcast transactions
+    #[serde(default)]
+    pub spent: Vec<String>,
+    #[serde(default)]
+    pub unconfirmed: Vec<UnconfirmedOutput>,
+    #[serde(default)]
+    pub transactions: Vec<WalletTransaction>,
+    #[serde(default)]
+    pub labels: Vec<Label>,
+}
+
+fn cache_path(wallet_name: &str) -> PathBuf {
+    PathBuf::from(CACHE_DIR).join(format!("{}.json", wallet_name))
+}
+
+impl WalletCache {
+    // Load the cache for a wallet, empty if nothing has been saved yet
+    pub fn load(wallet_name: &str) -> Result<WalletCache, BalanceError> {
+        let path = cache_path(wallet_name);
+        if !path.exists() {
+            return Ok(WalletCache::default());
+        }
+        let data = fs::read_to_string(&path)
+            .map_err(|e| BalanceError::ParseError(format!("{}: {}", path.display(), e)))?;
+        serde_json::from_str(&data)
+            .map_err(|e| BalanceError::ParseError(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self, wallet_name: &str) -> Result<(), BalanceError> {
+        let path = cache_path(wallet_name);
+        let data = serde_json::to_string_pretty(self)
+            .map_err(|e| BalanceError::ParseError(e.to_string()))?;
+        fs::create_dir_all(CACHE_DIR)
+            .and_then(|_| fs::write(&path, data))
+            .map_err(|e| BalanceError::ParseError(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn multisig_policy(&self, name: &str) -> Option<&MultisigPolicy> {
+        self.multisig.iter().find(|policy| policy.name == name)
+    }
+
+    // Add a policy, replacing any existing one with the same name
+    pub fn add_multisig_policy(&mut self, policy: MultisigPolicy) {
+        self.multisig.retain(|existing| existing.name != policy.name);
+        self.multisig.push(policy);
+    }
+
+    pub fn taproot_policy(&self, name: &str) -> Option<&TaprootPolicy> {
+        self.taproot.iter().find(|policy| policy.name == name)
+    }
+
+    // Add a policy, replacing any existing one with the same name
+    pub fn add_taproot_policy(&mut self, policy: TaprootPolicy) {
+        self.taproot.retain(|existing| existing.name != policy.name);
+        self.taproot.push(policy);
+    }
+
+    pub fn timelock_policy(&self, name: &str) -> Option<&TimelockPolicy> {
+        self.timelock.iter().find(|policy| policy.name == name)
+    }
+
+    // Add a policy, replacing any existing one with the same name
+    pub fn add_timelock_policy(&mut self, policy: TimelockPolicy) {
+        self.timelock.retain(|existing| existing.name != policy.name);
+        self.timelock.push(policy);
+    }
+
+    pub fn htlc_policy(&self, name: &str) -> Option<&HtlcPolicy> {
+        self.htlc.iter().find(|policy| policy.name == name)
+    }
+
+    // Add a policy, replacing any existing one with the same name
+    pub fn add_htlc_policy(&mut self, policy: HtlcPolicy) {
+        self.htlc.retain(|existing| existing.name != policy.name);
+        self.htlc.push(policy);
+    }
+
+    pub fn miniscript_policy(&self, name: &str) -> Option<&MiniscriptPolicy> {
+        self.miniscript.iter().find(|policy| policy.name == name)
+    }
+
+    // Add a policy, replacing any existing one with the same name
+    pub fn add_miniscript_policy(&mut self, policy: MiniscriptPolicy) {
+        self.miniscript.retain(|existing| existing.name != policy.name);
+        self.miniscript.push(policy);
+    }
+
+    // scriptPubKeys of all multisig, taproot, timelock, HTLC and miniscript policies, for scanning
+    pub fn watch_scripts(&self) -> Vec<Vec<u8>> {
+        self.multisig
+            .iter()
+            .map(|policy| &policy.address)
+            .chain(self.taproot.iter().map(|policy| &policy.address))
+            .chain(self.timelock.iter().map(|policy| &policy.address))
+            .chain(self.htlc.iter().map(|policy| &policy.address))
+            .chain(self.miniscript.iter().map(|policy| &policy.address))
+            .filter_map(|address| address_to_script(address).ok())
+            .collect()
+    }
+
+    pub fn wallet_transaction(&self, txid: &str) -> Option<&WalletTransaction> {
+        self.transactions.iter().find(|tx| tx.txid == txid)
+    }
+
+    // Forget a transaction that was replaced: its outputs will never confirm.
+    // Its inputs stay spent by the replacement.
+    pub fn remove_transaction(&mut self, txid: &str) {
+        self.transactions.retain(|tx| tx.txid != txid);
+        self.unconfirmed.retain(|output| output.txid != txid);
+    }
+
+    // The output at `txid`:`vout` spent by one of our recorded transactions
+    pub fn spent_output(&self, txid: &str, vout: u32) -> Option<Utxo> {
+        let spent = self
+            .transactions
+            .iter()
+            .flat_map(|tx| &tx.prevouts)
+            .find(|prevout| prevout.txid == txid && prevout.vout == vout)?;
+        Some(Utxo {
+            script_pubkey: hex::decode(&spent.script_pubkey).ok()?,
+            amount: spent.amount,
+        })
+    }
+
+    // Outpoints ("txid:vout") spent by the transactions we still track
+    fn tracked_spends(&self) -> HashSet<String> {
+        self.transactions
+            .iter()
+            .filter_map(|tx| Transaction::parse(&hex::decode(&tx.hex).ok()?).ok())
+            .flat_map(|tx| tx.inputs)
+            .map(|input| format!("{}:{}", input.previous_output.txid_hex(), input.previous_output.index))
+            .collect()
+    }
+
+    // Forget a transaction that was dropped from the mempool: its outputs are
+    // no longer unconfirmed coins and the coins it spent are left to the chain
+    // scan, unless another transaction we track spends them.
+    // Returns whether it was tracked.
+    pub fn forget_transaction(&mut self, txid: &str) -> bool {
+        let tracked = self.wallet_transaction(txid).is_some();
+        self.remove_transaction(txid);
+        let still_spent = self.tracked_spends();
+        self.spent.retain(|outpoint| still_spent.contains(outpoint));
+        tracked
+    }
+
+    // Settle the transactions that are no longer in the node's mempool, as
+    // `exit` tells what became of them: confirmed ones are kept, spent ones
+    // lose their outputs and dropped ones are forgotten. Returns the txids and
+    // what became of them.
+    pub fn prune_transactions<E>(
+        &mut self,
+        mempool: &HashSet<String>,
+        exit: impl Fn(&Transaction) -> Result<MempoolExit, E>,
+    ) -> Result<Vec<(String, MempoolExit)>, E> {
+        let mut gone = Vec::new();
+        for wallet_tx in &self.transactions {
+            if wallet_tx.confirmed || mempool.contains(&wallet_tx.txid) {
+                continue;
+            }
+            let Some(tx) = hex::decode(&wallet_tx.hex).ok().and_then(|bytes| Transaction::parse(&bytes).ok()) else {
+                continue;
+            };
+            gone.push((wallet_tx.txid.clone(), exit(&tx)?));
+        }
+        for (txid, exit) in &gone {
+            match exit {
+                MempoolExit::Dropped => {
+                    self.forget_transaction(txid);
+                }
+                MempoolExit::Spent => self.unconfirmed.retain(|output| output.txid != *txid),
+                MempoolExit::Confirmed => {}
+            }
+            if let Some(wallet_tx) = self.transactions.iter_mut().find(|tx| tx.txid == *txid) {
+                wallet_tx.confirmed = true;
+            }
+        }
+        Ok(gone)
+    }
+
+    // Remember a broadcast transaction spending `prevouts`: its inputs are spent
+    // and the outputs paying to `is_ours` scripts (change) can be spent before
+    // it confirms
+    pub fn record_transaction(
+        &mut self,
+        tx: &Transaction,
+        prevouts: &[Utxo],
+        fee: u64,
+        is_ours: impl Fn(&[u8]) -> bool,
+    ) {
+        let txid = hex::encode(tx.txid());
+        for input in &tx.inputs {
+            let outpoint = format!("{}:{}", input.previous_output.txid_hex(), input.previous_output.index);
+            if !self.spent.contains(&outpoint) {
+                self.spent.push(outpoint);
+            }
+        }
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            if is_ours(&output.script_pubkey) {
+                self.unconfirmed.push(UnconfirmedOutput {
+                    txid: txid.clone(),
+                    vout: vout as u32,
+                    script_pubkey: hex::encode(&output.script_pubkey),
+                    amount: output.amount,
+                });
+            }
+        }
+        self.transactions.retain(|existing| existing.txid != txid);
+        self.transactions.push(WalletTransaction {
+            txid,
+            hex: hex::encode(tx.serialize()),
+            fee,
+            prevouts: tx
+                .inputs
+                .iter()
+                .zip(prevouts)
+                .map(|(input, prevout)| SpentOutput {
+                    txid: input.previous_output.txid_hex(),
+                    vout: input.previous_output.index,
+                    script_pubkey: hex::encode(&prevout.script_pubkey),
+                    amount: prevout.amount,
+                })
+                .collect(),
+            confirmed: false,
+        });
+    }
+
+    pub fn label(&self, kind: LabelType, reference: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|label| label.kind == kind && label.reference == reference)
+            .and_then(|label| label.label.as_deref())
+    }
+
+    // The record for `reference`, added if there is none yet
+    fn label_entry(&mut self, kind: LabelType, reference: &str) -> &mut Label {
+        let index = match self.labels.iter().position(|label| label.kind == kind && label.reference == reference) {
+            Some(index) => index,
+            None => {
+                self.labels.push(Label {
+                    kind,
+                    reference: reference.to_string(),
+                    label: None,
+                    origin: None,
+                    spendable: None,
+                });
+                self.labels.len() - 1
+            }
+        };
+        &mut self.labels[index]
+    }
+
+    // Records left with nothing to say after a label was removed or a coin unfrozen
+    fn drop_empty_labels(&mut self) {
+        self.labels
+            .retain(|label| label.label.is_some() || label.origin.is_some() || label.spendable.is_some());
+    }
+
+    // Set or, with None, remove the label of `reference`
+    pub fn set_label(&mut self, kind: LabelType, reference: &str, label: Option<String>) {
+        self.label_entry(kind, reference).label = label;
+        self.drop_empty_labels();
+    }
+
+    // Freeze or unfreeze the coin at `outpoint` ("txid:vout")
+    pub fn set_frozen(&mut self, outpoint: &str, frozen: bool) {
+        self.label_entry(LabelType::Output, outpoint).spendable = if frozen { Some(false) } else { None };
+        self.drop_empty_labels();
+    }
+
+    // Coins marked unspendable, which coin selection skips
+    pub fn frozen_outpoints(&self) -> HashSet<(String, u32)> {
+        self.labels
+            .iter()
+            .filter(|label| label.kind == LabelType::Output && label.spendable == Some(false))
+            .filter_map(|label| {
+                let (txid, vout) = label.reference.split_once(':')?;
+                Some((txid.to_string(), vout.parse().ok()?))
+            })
+            .collect()
+    }
+
+    // Merge BIP329 JSON lines into our labels, the imported fields winning.
+    // Returns the number of records read.
+    pub fn import_labels(&mut self, jsonl: &str) -> Result<usize, BalanceError> {
+        let mut count = 0;
+        for (line_number, line) in jsonl.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let imported: Label = serde_json::from_str(line)
+                .map_err(|e| BalanceError::ParseError(format!("line {}: {}", line_number + 1, e)))?;
+            let entry = self.label_entry(imported.kind, &imported.reference);
+            entry.label = imported.label.or(entry.label.take());
+            entry.origin = imported.origin.or(entry.origin.take());
+            entry.spendable = imported.spendable.or(entry.spendable.take());
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // Our labels as BIP329 JSON lines
+    pub fn export_labels(&self) -> String {
+        self.labels
+            .iter()
+            .filter_map(|label| serde_json::to_string(label).ok())
+            .map(|line| line + "\n")
+            .collect()
+    }
+
+    // Bring a state recovered from the chain up to date with what we broadcast
+    // since: spent coins are removed and unconfirmed outputs added
+    pub fn apply_unconfirmed(&self, wallet_state: &mut WalletState) {
+        for output in &self.unconfirmed {
+            let Ok(script_pubkey) = hex::decode(&output.script_pubkey) else {
+                continue;
+            };
+            let coins = if wallet_state.is_ours(&script_pubkey) {
+                wallet_state.used_programs.insert(script_pubkey.clone());
+                &mut wallet_state.utxos
+            } else {
+                &mut wallet_state.watched_utxos
+            };
+            coins
+                .entry((output.txid.clone(), output.vout))
+                .or_insert((script_pubkey, output.amount as f64 / 100_000_000.0));
+        }
+        for outpoint in &self.spent {
+            if let Some((txid, vout)) = outpoint.split_once(':') {
+                let key = (txid.to_string(), vout.parse().unwrap_or(u32::MAX));
+                wallet_state.utxos.remove(&key);
+                wallet_state.watched_utxos.remove(&key);
+            }
+        }
+    }
+}
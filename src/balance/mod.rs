@@ -1 +1,4 @@
+pub mod address;
+#[allow(clippy::module_inception)]
 pub mod balance;
+pub mod cache;
@@ -0,0 +1,84 @@
+// A stand-in for an HWI-compatible signing device, for exercising the
+// external signer protocol without hardware. It holds the keys of the BIP32
+// test vector 1 master key and signs with them like the wallet does.
+//
+//   mock_signer enumerate
+//   mock_signer --fingerprint <fp> --chain <chain> getdescriptors --account <n>
+//   echo "signtx <psbt>" | mock_signer --stdin --fingerprint <fp> --chain <chain>
+use rust::balance::balance::derive_wallet_state;
+use rust::psbt::psbt::Psbt;
+use rust::psbt::signer::{Signer, SoftwareSigner};
+use serde_json::{json, Value};
+use std::io::Read;
+use std::{env, process};
+
+// BIP32 test vector 1 master key (seed 000102030405060708090a0b0c0d0e0f)
+const MASTER_KEY: &str =
+    "tprv8ZgxMBicQKsPeDgjzdC36fs6bMjGApWDNLR9erAXMs5skhMv36j9MV5ecvfavji5khqjWaWSFhN3YcCUUdiKH6isR4Pwy3U5y5egddBr16m";
+// Keys derived per path, enough for tests and trying things out
+const NUM_KEYS: u32 = 20;
+
+// Print an HWI style error and exit with a failure code
+fn fail(error: &str) -> ! {
+    println!("{}", json!({ "error": error }));
+    process::exit(1);
+}
+
+// Answer the call: flags like --fingerprint take a value, the remaining words are the call
+fn respond(args: &[String]) -> Value {
+    let mut fingerprint = None;
+    let mut account = "0".to_string();
+    let mut stdin = false;
+    let mut words = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stdin" => stdin = true,
+            "--fingerprint" => fingerprint = args.next().cloned(),
+            "--account" => account = args.next().cloned().unwrap_or_default(),
+            "--chain" => {
+                args.next();
+            }
+            _ => words.push(arg.clone()),
+        }
+    }
+
+    if stdin {
+        let mut input = String::new();
+        if std::io::stdin().read_to_string(&mut input).is_err() {
+            fail("can't read stdin");
+        }
+        words.extend(input.split_whitespace().map(str::to_string));
+    }
+    let wallet_state = derive_wallet_state(MASTER_KEY, NUM_KEYS).unwrap_or_else(|e| fail(&e.to_string()));
+    let signer = SoftwareSigner::new(&wallet_state);
+    let our_fingerprint = hex::encode(signer.fingerprint());
+    if words.first().map(String::as_str) == Some("enumerate") {
+        return json!([{ "type": "mock", "model": "mock", "fingerprint": our_fingerprint }]);
+    }
+    if fingerprint.as_deref() != Some(our_fingerprint.as_str()) {
+        fail("Unexpected fingerprint");
+    }
+
+    match words.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["getdescriptors"] => {
+            let account = account.parse().unwrap_or_else(|_| fail(&format!("invalid account {}", account)));
+            let descriptors = signer.descriptors(account).unwrap_or_else(|e| fail(&e.to_string()));
+            json!({
+                "receive": descriptors.receive,
+                "internal": descriptors.internal,
+            })
+        }
+        ["signtx", psbt] => {
+            let mut psbt = Psbt::from_base64(psbt).unwrap_or_else(|e| fail(&e.to_string()));
+            signer.sign(&mut psbt).unwrap_or_else(|e| fail(&e.to_string()));
+            json!({ "psbt": psbt.to_base64() })
+        }
+        _ => fail(&format!("unknown command: {}", words.join(" "))),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    println!("{}", respond(&args));
+}
@@ -10,7 +10,6 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 #[allow(dead_code)]
-
 pub struct Mempool {
     transactions: Vec<Transaction>,
 }
@@ -5,7 +5,7 @@
 // # that will be included in the block.        #
 // ##############################################
 use crate::block_selection::blockparser::Transaction;
-use crate::test::{evaluate_method, print_results};
+use crate::block_selection::test::{evaluate_method, print_results};
 use std::collections::{HashMap, HashSet};
 
 pub fn select_transactions(
@@ -4,9 +4,11 @@ use std::{
     time::Instant,
 };
 
+type SelectionMethod = fn(Vec<String>, &HashMap<String, Transaction>, u64) -> Vec<String>;
+
 pub fn evaluate_method(
     method_name: &str,
-    method_fn: fn(Vec<String>, &HashMap<String, Transaction>, u64) -> Vec<String>,
+    method_fn: SelectionMethod,
     topological_order: Vec<String>,
     transactions: &HashMap<String, Transaction>,
     max_weight: u64,
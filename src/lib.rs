@@ -0,0 +1,6 @@
+// The wallet, PSBT and block building code, shared by the command line tool
+// and the mock signer
+pub mod balance;
+pub mod block_selection;
+pub mod psbt;
+pub mod spend_funds;
@@ -1,24 +1,47 @@
-mod balance;
-mod spend_funds;
-mod block_selection;
 use std::env;
 
-use balance::balance::recover_wallet_state;
+use rust::balance::balance::{recover_wallet_state, WalletState};
+use rust::balance::address::{address_to_script, script_to_address};
+use rust::balance::cache::{LabelType, MempoolExit, WalletCache};
 use dotenv::dotenv;
-use spend_funds::spend_p2wpkh::spend_p2wpkh;
-use spend_funds::spend_p2wsh::spend_p2wsh;
+use rust::psbt::psbt::Psbt;
+use rust::psbt::roles::{create_psbt, describe_psbt, extract_transaction, finalize_psbt};
+use rust::psbt::signer::{ExternalSigner, Signer, SoftwareSigner};
+use rust::spend_funds::broadcast::{broadcast_transaction, mempool_exit, mempool_txids};
+use rust::spend_funds::batch::read_recipients;
+use rust::spend_funds::builder::{build_payment, chosen_coins, select_payment, sign_wallet_inputs, Coin};
+use rust::spend_funds::cpfp::create_cpfp;
+use rust::spend_funds::decode::{transaction_json, transaction_table};
+use rust::spend_funds::rbf::bump_fee;
+use rust::spend_funds::htlc::{create_htlc_policy, spend_htlc};
+use rust::spend_funds::interpreter::{lookup_prevouts, verify_transaction};
+use rust::spend_funds::message::{sign_message, verify_message};
+use rust::spend_funds::miniscript::{compile_policy, create_miniscript_policy, spend_miniscript};
+use rust::spend_funds::policy::check_policy;
+use rust::spend_funds::sighash::{SighashType, SIGHASH_ALL};
+use rust::spend_funds::spend_multisig::{
+    create_multisig_policy, create_multisig_spend, finalize_multisig, sign_multisig_inputs, PartialSig,
+};
+use rust::spend_funds::spend_p2wpkh::{spend_p2wpkh, SpendError};
+use rust::spend_funds::spend_p2wsh::spend_p2wsh;
+use rust::spend_funds::sweep::{consolidate, sweep};
+use rust::spend_funds::script::{op_return_script, parse_data_push, script_to_asm};
+use rust::spend_funds::taproot::{
+    create_taproot_policy, spend_taproot, spend_taproot_script, tap_leaf_hash, taproot_policy_tree,
+};
+use rust::spend_funds::timelock::{create_timelock_policy, spend_timelock, Timelock};
+use rust::spend_funds::transaction::{Outpoint, Transaction, Utxo};
 
-use block_selection::blockparser::parse_mempool;
-use block_selection::degraph::build_and_sort;
-use block_selection::test;
-use block_selection::selection::select_transactions;
-use block_selection::write::write_block_to_file;
+use rust::block_selection::blockparser::parse_mempool;
+use rust::block_selection::degraph::build_and_sort;
+use rust::block_selection::selection::select_transactions;
+use rust::block_selection::write::write_block_to_file;
 
-use clap::{Parser, Subcommand, command};
+use clap::{Args, Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(name = "Signet Assistant", about = "A Bitcoin Signet utility for wallet management and block construction")]
-struct CLI {
+struct Cli {
     #[command(subcommand)]
     command: Commands,
 }
@@ -30,6 +53,30 @@ enum Commands {
         #[command(subcommand)]
         action: WalletAction,
     },
+    //partially signed bitcoin transactions (BIP174/BIP370)
+    Psbt {
+        #[command(subcommand)]
+        action: PsbtAction,
+    },
+    //the wallet's keys or an external (HWI-compatible) signer
+    Signer {
+        #[command(flatten)]
+        signer: SignerArgs,
+        #[command(subcommand)]
+        action: SignerAction,
+    },
+    //inspect raw transactions
+    Tx {
+        #[command(subcommand)]
+        action: TxAction,
+    },
+    //check a message signature (legacy or BIP322 simple) against an address
+    VerifyMessage {
+        address: String,
+        //base64 signature
+        signature: String,
+        message: String,
+    },
     //build optimized block from a mempool file
     Block {
         mempool_file:String,
@@ -37,29 +84,1076 @@ enum Commands {
     }
 }
 
+// Whether a signed transaction is only printed or also sent to the node
+#[derive(Args)]
+struct BroadcastArgs {
+    //check the transaction with testmempoolaccept, then send it with sendrawtransaction
+    #[arg(long, conflicts_with = "dry_run")]
+    broadcast: bool,
+    //only print the transaction (the default)
+    #[arg(long)]
+    dry_run: bool,
+}
+
+// Which keys sign: the wallet's own, or those of an external signer
+#[derive(Args)]
+struct SignerArgs {
+    //external signer command speaking HWI's protocol (e.g. "hwi"), instead of the wallet's keys
+    #[arg(long)]
+    signer: Option<String>,
+    //fingerprint of the signer's device to use, when it has several
+    #[arg(long, requires = "signer")]
+    fingerprint: Option<String>,
+}
+
+impl SignerArgs {
+    // Run `f` with the chosen signer. The wallet is only recovered for the software signer.
+    fn with_signer<T>(&self, f: impl FnOnce(&dyn Signer) -> Result<T, SpendError>) -> Result<T, SpendError> {
+        match &self.signer {
+            Some(command) => f(&ExternalSigner::connect(command, self.fingerprint.as_deref())?),
+            None => {
+                let (_, _, wallet_state) = load_wallet();
+                f(&SoftwareSigner::new(&wallet_state))
+            }
+        }
+    }
+}
+
+// Data carried by an extra OP_RETURN output
+#[derive(Args)]
+struct OpReturnArgs {
+    //data push for an OP_RETURN output, text or 0x-prefixed hex, repeatable
+    #[arg(long = "op-return")]
+    op_return: Vec<String>,
+}
+
+impl OpReturnArgs {
+    // The OP_RETURN scriptPubKey, None without any --op-return
+    fn script(&self) -> Result<Option<Vec<u8>>, SpendError> {
+        if self.op_return.is_empty() {
+            return Ok(None);
+        }
+        let pushes = self
+            .op_return
+            .iter()
+            .map(|data| parse_data_push(data))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(SpendError::InvalidScript)?;
+        op_return_script(&pushes).map(Some).map_err(SpendError::InvalidScript)
+    }
+
+    // The zero-value OP_RETURN output, None without any --op-return
+    fn output(&self) -> Result<Option<Utxo>, SpendError> {
+        Ok(self.script()?.map(|script| Utxo {
+            script_pubkey: script,
+            amount: 0,
+        }))
+    }
+}
+
+// Coins picked by hand instead of by coin selection
+#[derive(Args)]
+struct CoinControlArgs {
+    //coin to spend as <txid>:<vout>, repeatable
+    #[arg(long = "utxo")]
+    utxos: Vec<Outpoint>,
+}
+
+impl CoinControlArgs {
+    fn coins(&self, wallet_state: &WalletState) -> Result<Vec<Coin>, SpendError> {
+        chosen_coins(wallet_state, &self.utxos)
+    }
+}
+
 #[derive(Subcommand)]
 enum WalletAction{
     Recover,
-    SpendMultisig,
+    SpendMultisig {
+        //number of signatures required
+        #[arg(long, default_value_t = 2)]
+        threshold: usize,
+        //number of our keys in the multisig
+        #[arg(long, default_value_t = 2)]
+        keys: usize,
+        //sort the keys lexicographically (BIP67)
+        #[arg(long)]
+        sorted: bool,
+        //sighash type for the input, e.g. ALL, NONE, SINGLE|ANYONECANPAY
+        #[arg(long, default_value = "ALL")]
+        sighash: SighashType,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
     SpendOpreturn{
+        //multisig output to spend, as <txid>:<vout>
+        outpoint: Outpoint,
+        #[arg(long, default_value_t = 2)]
+        threshold: usize,
+        #[arg(long, default_value_t = 2)]
+        keys: usize,
+        #[arg(long)]
+        sorted: bool,
+        #[arg(long, default_value = "ALL")]
+        sighash: SighashType,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+    //spend our P2TR (BIP86) coins by key path
+    SpendTaproot {
+        #[arg(long)]
+        to: String,
+        //amount in sats
+        #[arg(long)]
+        amount: u64,
+        #[arg(long, default_value_t = 1000)]
+        fee: u64,
+        #[arg(long, default_value = "DEFAULT")]
+        sighash: SighashType,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+    //pay every <address>,<amount in sats> row of a CSV file in one transaction
+    SendMany {
+        file: String,
+        //fee rate in sat/vB
+        #[arg(long, default_value_t = 1.0)]
+        fee_rate: f64,
+        #[command(flatten)]
+        coins: CoinControlArgs,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+    //merge our smallest coins into one output at a fresh address
+    Consolidate {
+        //largest number of coins to merge
+        #[arg(long, default_value_t = 50)]
+        max_inputs: usize,
+        //fee rate in sat/vB
+        #[arg(long, default_value_t = 1.0)]
+        fee_rate: f64,
+        #[command(flatten)]
+        coins: CoinControlArgs,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+    //send the whole balance to an address, without change
+    Sweep {
+        address: String,
+        //fee rate in sat/vB
+        #[arg(long, default_value_t = 1.0)]
+        fee_rate: f64,
+        #[command(flatten)]
+        coins: CoinControlArgs,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+    //keep coins (<txid>:<vout>) out of coin selection
+    Freeze {
+        #[arg(required = true)]
+        outpoints: Vec<Outpoint>,
+    },
+    //let coin selection spend frozen coins again
+    Unfreeze {
+        #[arg(required = true)]
+        outpoints: Vec<Outpoint>,
+    },
+    //labels for transactions, coins and addresses (BIP329)
+    Label {
+        #[command(subcommand)]
+        action: LabelAction,
+    },
+    //sign a message with the key of one of our addresses
+    SignMessage {
+        message: String,
+        #[arg(long)]
+        address: String,
+        //BIP322 simple signature instead of the legacy format, always used for taproot addresses
+        #[arg(long)]
+        bip322: bool,
+    },
+    //replace one of our unconfirmed transactions with one paying a higher fee (BIP125)
+    BumpFee {
         txid: String,
+        //new fee rate in sat/vB
+        #[arg(long)]
+        fee_rate: f64,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+    //spend our outputs of a stuck transaction so the package pays a target fee rate
+    Cpfp {
+        txid: String,
+        //fee rate in sat/vB for the parent and child together
+        #[arg(long)]
+        target_fee_rate: f64,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+    //stop tracking a broadcast transaction that was dropped or replaced, so
+    //the coins it spent can be spent again
+    ForgetTx {
+        txid: String,
+    },
+    //shared multisig wallets with co-signers
+    Multisig {
+        #[command(subcommand)]
+        action: MultisigAction,
+    },
+    //taproot outputs committing to a tree of tapscripts
+    Taproot {
+        #[command(subcommand)]
+        action: TaprootAction,
+    },
+    //P2WSH outputs with a timelocked recovery key (CLTV/CSV)
+    Timelock {
+        #[command(subcommand)]
+        action: TimelockAction,
+    },
+    //hash time-locked contracts (BIP199)
+    Htlc {
+        #[command(subcommand)]
+        action: HtlcAction,
+    },
+    //P2WSH or P2TR outputs compiled from miniscript policies
+    Miniscript {
+        #[command(subcommand)]
+        action: MiniscriptAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum LabelAction {
+    //label a transaction (<txid>), coin (<txid>:<vout>) or address, an empty label removes it
+    Set {
+        reference: String,
+        label: String,
+        //what the reference is: tx, addr, pubkey, input, output or xpub
+        #[arg(long = "type")]
+        kind: Option<LabelType>,
+    },
+    //print the labels as BIP329 JSON lines, or write them to a file
+    Export {
+        #[arg(long)]
+        file: Option<String>,
+    },
+    //merge labels from a BIP329 JSON lines file
+    Import {
+        file: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum HtlcAction {
+    //create an HTLC output, and fund it from our coins with --amount
+    Create {
+        name: String,
+        //SHA256 hash (hex) of the preimage
+        #[arg(long)]
+        hash: String,
+        //key that can claim with the preimage: hex public key, extended public key or @<i>
+        #[arg(long)]
+        recipient: String,
+        //key that can take the coins back after the timeout
+        #[arg(long)]
+        refund: String,
+        //block height the refund is possible from (CLTV), or a number of blocks with --relative (CSV)
+        #[arg(long)]
+        timeout: u32,
+        #[arg(long)]
+        relative: bool,
+        //amount in sats to fund the HTLC with
+        #[arg(long)]
+        amount: Option<u64>,
+        //fee rate in sat/vB for the funding transaction
+        #[arg(long, default_value_t = 1.0)]
+        fee_rate: f64,
+        #[command(flatten)]
+        coins: CoinControlArgs,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+    //spend the HTLC's coins with the preimage
+    Claim {
+        name: String,
+        //preimage (hex) of the HTLC's hash
+        #[arg(long)]
+        preimage: String,
+        //destination address, our first address by default
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(long, default_value_t = 1000)]
+        fee: u64,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+    //take the HTLC's coins back after the timeout
+    Refund {
+        name: String,
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(long, default_value_t = 1000)]
+        fee: u64,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum MiniscriptAction {
+    //compile a policy such as "or(pk(@0),and(pk(@1),older(144)))" to an output
+    Create {
+        name: String,
+        //pk(KEY), after(N), older(N), sha256(H), hash256(H), ripemd160(H), hash160(H), and(X,Y), or(X,Y), thresh(K,X,...)
+        policy: String,
+        //P2TR output with a leaf per top level or() branch, instead of P2WSH
+        #[arg(long)]
+        taproot: bool,
+    },
+    //spend the output's coins with the cheapest witness our keys, the preimages and timelocks allow
+    Spend {
+        name: String,
+        #[arg(long)]
+        to: String,
+        //amount in sats
+        #[arg(long)]
+        amount: u64,
+        #[arg(long, default_value_t = 1000)]
+        fee: u64,
+        //preimage (hex) for a hash in the policy, repeatable
+        #[arg(long)]
+        preimage: Vec<String>,
+        //transaction locktime, for after()
+        #[arg(long)]
+        after: Option<u32>,
+        //input sequence for older(), as written in the policy: blocks, or 4194304 plus 512 second units
+        #[arg(long)]
+        older: Option<u32>,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum TimelockAction {
+    //create a "key, or recovery key after a timelock" output
+    Create {
+        name: String,
+        //hex public key, extended public key or @<i> for our key i
+        #[arg(long, default_value = "@0")]
+        key: String,
+        #[arg(long, default_value = "@1")]
+        recovery_key: String,
+        //absolute block height the recovery key can spend from (CLTV)
+        #[arg(long, conflicts_with = "after", required_unless_present = "after")]
+        at: Option<u32>,
+        //number of blocks after confirmation the recovery key can spend from (CSV)
+        #[arg(long)]
+        after: Option<u16>,
+    },
+    //spend the output's coins with the key, or the recovery key with --recovery
+    Spend {
+        name: String,
+        #[arg(long)]
+        to: String,
+        //amount in sats
+        #[arg(long)]
+        amount: u64,
+        #[arg(long, default_value_t = 1000)]
+        fee: u64,
+        #[arg(long)]
+        recovery: bool,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum TaprootAction {
+    //create a taproot output from an internal key and tapscript leaves
+    Create {
+        name: String,
+        //leaf script, e.g. "@1 OP_CHECKSIGVERIFY 144 OP_CSV", repeatable; @<i> is our BIP86 key i
+        #[arg(long = "leaf", required = true)]
+        leaves: Vec<String>,
+        //x-only or compressed public key (hex), or @<i>
+        #[arg(long, default_value = "@0")]
+        internal_key: String,
+    },
+    //spend the output's coins through one of its leaves
+    Spend {
+        name: String,
+        //index of the leaf to execute
+        #[arg(long)]
+        leaf: usize,
+        #[arg(long)]
+        to: String,
+        //amount in sats
+        #[arg(long)]
+        amount: u64,
+        #[arg(long, default_value_t = 1000)]
+        fee: u64,
+        #[arg(long, default_value = "DEFAULT")]
+        sighash: SighashType,
+        //extra witness item (hex) pushed above the signatures, e.g. a preimage, repeatable
+        #[arg(long = "witness-item")]
+        witness_items: Vec<String>,
+        #[command(flatten)]
+        data: OpReturnArgs,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum MultisigAction {
+    //create a multisig policy from our keys and co-signer pubkeys/xpubs
+    Create {
+        name: String,
+        //co-signer public key (hex) or extended public key, repeatable
+        #[arg(long = "pubkey")]
+        pubkeys: Vec<String>,
+        //index of one of our derived keys to include, repeatable
+        #[arg(long = "our-key", default_value = "0")]
+        our_keys: Vec<usize>,
+        #[arg(long)]
+        threshold: usize,
+        #[arg(long)]
+        sorted: bool,
+    },
+    //create a transaction from the multisig and add our signatures
+    Spend {
+        name: String,
+        #[arg(long)]
+        to: String,
+        //amount in sats
+        #[arg(long)]
+        amount: u64,
+        #[arg(long, default_value_t = 1000)]
+        fee: u64,
+        #[arg(long, default_value = "ALL")]
+        sighash: SighashType,
+        #[command(flatten)]
+        data: OpReturnArgs,
+    },
+    //add our signatures to a co-signer's unsigned transaction
+    Sign {
+        name: String,
+        tx_hex: String,
+        #[arg(long, default_value = "ALL")]
+        sighash: SighashType,
+    },
+    //combine signatures (<input>:<pubkey>:<signature>) into the final transaction
+    Finalize {
+        name: String,
+        tx_hex: String,
+        #[arg(long = "sig")]
+        sigs: Vec<PartialSig>,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum TxAction {
+    //print the fields, ids, sizes and output types of a raw transaction
+    Decode {
+        tx_hex: String,
+        //print decoderawtransaction style JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    //run every input's scripts, fetching the spent outputs from the node unless given
+    Verify {
+        tx_hex: String,
+        //spent output as <scriptPubKey hex>:<amount in sats>, one per input in order
+        #[arg(long = "prevout")]
+        prevouts: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum PsbtAction {
+    //create a PSBT and fill in what the wallet knows about its inputs and outputs
+    Create {
+        //input to spend as <txid>:<vout>, repeatable
+        #[arg(long = "input", required = true)]
+        inputs: Vec<Outpoint>,
+        //output as <address>:<amount in sats>, repeatable
+        #[arg(long = "output", required = true)]
+        outputs: Vec<String>,
+        //create a version 2 PSBT instead of version 0
+        #[arg(long)]
+        v2: bool,
+        #[command(flatten)]
+        data: OpReturnArgs,
+    },
+    //add our signatures to a base64 PSBT
+    Sign {
+        psbt: String,
+        #[command(flatten)]
+        signer: SignerArgs,
+    },
+    //merge the signatures and fields of several copies of a PSBT
+    Combine {
+        #[arg(required = true)]
+        psbts: Vec<String>,
+    },
+    //build final witnesses from the partial signatures
+    Finalize {
+        psbt: String,
+    },
+    //print the network transaction of a finalized PSBT
+    Extract {
+        psbt: String,
+        #[command(flatten)]
+        send: BroadcastArgs,
+    },
+    //print the contents of a PSBT
+    Decode {
+        psbt: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum SignerAction {
+    //list the external signer's devices, or the wallet's fingerprint
+    Enumerate,
+    //print the signer's receive and change descriptors
+    Descriptors {
+        #[arg(long, default_value_t = 0)]
+        account: u32,
+    },
+}
+
+fn run_signer(signer: SignerArgs, action: SignerAction) -> Result<(), SpendError> {
+    match action {
+        SignerAction::Enumerate => match &signer.signer {
+            Some(command) => {
+                for (fingerprint, model) in ExternalSigner::enumerate(command)? {
+                    println!("{} {}", hex::encode(fingerprint), model);
+                }
+            }
+            None => signer.with_signer(|signer| {
+                println!("{} software", hex::encode(signer.fingerprint()));
+                Ok(())
+            })?,
+        },
+        SignerAction::Descriptors { account } => {
+            let descriptors = signer.with_signer(|signer| signer.descriptors(account))?;
+            println!("Receive:");
+            for descriptor in descriptors.receive {
+                println!("  {}", descriptor);
+            }
+            println!("Change:");
+            for descriptor in descriptors.internal {
+                println!("  {}", descriptor);
+            }
+        }
     }
+    Ok(())
 }
 
-fn main() {
-    //load environment variables
-    dotenv().ok();
+fn run_multisig(
+    action: MultisigAction,
+    wallet_state: &WalletState,
+    wallet_cache: &mut WalletCache,
+    wallet_name: &str,
+) -> Result<(), SpendError> {
+    match action {
+        MultisigAction::Create { name, pubkeys, our_keys, threshold, sorted } => {
+            let policy = create_multisig_policy(wallet_state, &name, &our_keys, &pubkeys, threshold, sorted)?;
+            println!("Multisig {} ({}-of-{})", policy.name, policy.threshold, policy.keys.len());
+            println!("Address: {}", policy.address);
+            println!("Witness Script: {}", policy.witness_script);
+            wallet_cache.add_multisig_policy(policy);
+            wallet_cache.save(wallet_name)?;
+        }
+        MultisigAction::Spend { name, to, amount, fee, sighash, data } => {
+            let policy = wallet_cache
+                .multisig_policy(&name)
+                .ok_or(SpendError::InvalidScript(format!("unknown multisig {}", name)))?;
+            let tx = create_multisig_spend(wallet_state, policy, &to, amount, fee, data.script()?)?;
+            let signatures = sign_multisig_inputs(wallet_state, policy, &tx, sighash)?;
+            println!("Unsigned Transaction Hex: {}", hex::encode(tx.serialize()));
+            for sig in signatures {
+                println!("Signature: {}", sig);
+            }
+        }
+        MultisigAction::Sign { name, tx_hex, sighash } => {
+            let policy = wallet_cache
+                .multisig_policy(&name)
+                .ok_or(SpendError::InvalidScript(format!("unknown multisig {}", name)))?;
+            let tx = parse_transaction_hex(&tx_hex)?;
+            for sig in sign_multisig_inputs(wallet_state, policy, &tx, sighash)? {
+                println!("Signature: {}", sig);
+            }
+        }
+        MultisigAction::Finalize { name, tx_hex, mut sigs, send } => {
+            let policy = wallet_cache
+                .multisig_policy(&name)
+                .ok_or(SpendError::InvalidScript(format!("unknown multisig {}", name)))?;
+            let tx = parse_transaction_hex(&tx_hex)?;
+            // our own signatures, if we haven't already been given them
+            if let Ok(ours) = sign_multisig_inputs(wallet_state, policy, &tx, SighashType(SIGHASH_ALL)) {
+                sigs.extend(ours);
+            }
+            let tx = finalize_multisig(policy, tx, &sigs)?;
+            let prevouts = lookup_prevouts(&tx, Some(wallet_state))?;
+            verify_transaction(&tx, &prevouts)?;
+            check_policy(&tx, &prevouts, tx.weight() as u64)?;
+            println!("TXID: {}", hex::encode(tx.txid()));
+            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+            broadcast_if_requested(&tx, &send, wallet_state, wallet_cache, wallet_name)?;
+        }
+    }
+    Ok(())
+}
+
+// "<address>:<amount in sats>" as a transaction output
+fn parse_output(output: &str) -> Result<Utxo, SpendError> {
+    let (address, amount) = output
+        .rsplit_once(':')
+        .ok_or(SpendError::InvalidTransaction(format!("expected <address>:<amount>, got {}", output)))?;
+    let amount = amount
+        .parse()
+        .map_err(|_| SpendError::InvalidTransaction(format!("invalid amount: {}", amount)))?;
+    Ok(Utxo {
+        script_pubkey: address_to_script(address)?,
+        amount,
+    })
+}
+
+fn run_psbt(action: PsbtAction) -> Result<(), SpendError> {
+    match action {
+        PsbtAction::Create { inputs, outputs, v2, data } => {
+            let (_, wallet_cache, wallet_state) = load_wallet();
+            let mut outputs: Vec<Utxo> = outputs.iter().map(|output| parse_output(output)).collect::<Result<_, _>>()?;
+            outputs.extend(data.output()?);
+            let psbt = create_psbt(&wallet_state, &wallet_cache, &inputs, outputs, if v2 { 2 } else { 0 })?;
+            println!("{}", psbt.to_base64());
+        }
+        PsbtAction::Sign { psbt, signer } => {
+            let mut psbt = Psbt::from_base64(&psbt)?;
+            let signed = signer.with_signer(|signer| signer.sign(&mut psbt))?;
+            eprintln!("Added {} signature(s)", signed);
+            println!("{}", psbt.to_base64());
+        }
+        PsbtAction::Combine { psbts } => {
+            let mut psbts = psbts.iter().map(|psbt| Psbt::from_base64(psbt));
+            let mut combined = psbts.next().expect("at least one PSBT is required")?;
+            for psbt in psbts {
+                combined.combine(psbt?)?;
+            }
+            println!("{}", combined.to_base64());
+        }
+        PsbtAction::Finalize { psbt } => {
+            let mut psbt = Psbt::from_base64(&psbt)?;
+            let incomplete = finalize_psbt(&mut psbt)?;
+            if incomplete > 0 {
+                eprintln!("{} input(s) still need signatures", incomplete);
+            }
+            println!("{}", psbt.to_base64());
+        }
+        PsbtAction::Extract { psbt, send } => {
+            let tx = extract_transaction(&Psbt::from_base64(&psbt)?)?;
+            println!("TXID: {}", hex::encode(tx.txid()));
+            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+            if send.broadcast {
+                let (wallet_name, mut wallet_cache, wallet_state) = load_wallet();
+                broadcast_if_requested(&tx, &send, &wallet_state, &mut wallet_cache, &wallet_name)?;
+            }
+        }
+        PsbtAction::Decode { psbt } => {
+            println!("{}", describe_psbt(&Psbt::from_base64(&psbt)?)?);
+        }
+    }
+    Ok(())
+}
+
+fn run_taproot(
+    action: TaprootAction,
+    wallet_state: &WalletState,
+    wallet_cache: &mut WalletCache,
+    wallet_name: &str,
+) -> Result<(), SpendError> {
+    match action {
+        TaprootAction::Create { name, leaves, internal_key } => {
+            let policy = create_taproot_policy(wallet_state, &name, &internal_key, &leaves)?;
+            let tree = taproot_policy_tree(&policy)?;
+            let internal_key = hex::decode(&policy.internal_key).expect("internal key is hex");
+            println!("Taproot {} ({} leaves)", policy.name, tree.leaves.len());
+            println!("Address: {}", policy.address);
+            println!("Internal Key: {}", policy.internal_key);
+            println!("Merkle Root: {}", hex::encode(tree.merkle_root().unwrap_or_default()));
+            for (index, leaf) in tree.leaves.iter().enumerate() {
+                println!("- Leaf {}: {}", index, script_to_asm(leaf));
+                println!("  Leaf Hash: {}", hex::encode(tap_leaf_hash(leaf)));
+                println!("  Control Block: {}", hex::encode(tree.control_block(&internal_key, index)?));
+            }
+            wallet_cache.add_taproot_policy(policy);
+            wallet_cache.save(wallet_name)?;
+        }
+        TaprootAction::Spend { name, leaf, to, amount, fee, sighash, witness_items, data, send } => {
+            let policy = wallet_cache
+                .taproot_policy(&name)
+                .ok_or(SpendError::InvalidScript(format!("unknown taproot output {}", name)))?;
+            let extra_items = witness_items
+                .iter()
+                .map(hex::decode)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| SpendError::InvalidScript(e.to_string()))?;
+            let tx =
+                spend_taproot_script(wallet_state, policy, leaf, &to, amount, fee, sighash, &extra_items, data.script()?)?;
+            println!("TXID: {}", hex::encode(tx.txid()));
+            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+            broadcast_if_requested(&tx, &send, wallet_state, wallet_cache, wallet_name)?;
+        }
+    }
+    Ok(())
+}
+
+fn run_timelock(
+    action: TimelockAction,
+    wallet_state: &WalletState,
+    wallet_cache: &mut WalletCache,
+    wallet_name: &str,
+) -> Result<(), SpendError> {
+    match action {
+        TimelockAction::Create { name, key, recovery_key, at, after } => {
+            let timelock = Timelock::new(at, after)?;
+            let policy = create_timelock_policy(wallet_state, &name, &key, &recovery_key, timelock)?;
+            let script = hex::decode(&policy.witness_script).expect("witness script is hex");
+            println!("Timelock {} (recovery {})", policy.name, policy.timelock);
+            println!("Address: {}", policy.address);
+            println!("Witness Script: {}", script_to_asm(&script));
+            wallet_cache.add_timelock_policy(policy);
+            wallet_cache.save(wallet_name)?;
+        }
+        TimelockAction::Spend { name, to, amount, fee, recovery, data, send } => {
+            let policy = wallet_cache
+                .timelock_policy(&name)
+                .ok_or(SpendError::InvalidScript(format!("unknown timelock {}", name)))?;
+            let mut outputs = vec![Utxo {
+                script_pubkey: address_to_script(&to)?,
+                amount,
+            }];
+            outputs.extend(data.output()?);
+            let tx = spend_timelock(wallet_state, policy, outputs, fee, recovery)?;
+            println!("TXID: {}", hex::encode(tx.txid()));
+            println!("Locktime: {}, Sequence: {:#010x}", tx.locktime, tx.inputs[0].sequence);
+            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+            broadcast_if_requested(&tx, &send, wallet_state, wallet_cache, wallet_name)?;
+        }
+    }
+    Ok(())
+}
+
+fn run_htlc(
+    action: HtlcAction,
+    wallet_state: &WalletState,
+    wallet_cache: &mut WalletCache,
+    wallet_name: &str,
+) -> Result<(), SpendError> {
+    // claims and refunds go to our first address unless told otherwise
+    let destination = |to: Option<String>| match to {
+        Some(address) => address_to_script(&address).map_err(SpendError::from),
+        None => Ok(wallet_state.witness_programs[0].clone()),
+    };
+    match action {
+        HtlcAction::Create { name, hash, recipient, refund, timeout, relative, amount, fee_rate, coins, data, send } => {
+            let timeout = if relative {
+                let blocks = u16::try_from(timeout)
+                    .map_err(|_| SpendError::InvalidScript(format!("relative timeout {} is too long", timeout)))?;
+                Timelock::new(None, Some(blocks))?
+            } else {
+                Timelock::new(Some(timeout), None)?
+            };
+            let policy = create_htlc_policy(wallet_state, &name, &hash, &recipient, &refund, timeout)?;
+            let script = hex::decode(&policy.witness_script).expect("witness script is hex");
+            println!("HTLC {} (refund {})", policy.name, policy.timeout);
+            println!("Address: {}", policy.address);
+            println!("Witness Script: {}", script_to_asm(&script));
+            let address = policy.address.clone();
+            wallet_cache.add_htlc_policy(policy);
+            wallet_cache.save(wallet_name)?;
+
+            if let Some(amount) = amount {
+                let funding = Utxo {
+                    script_pubkey: address_to_script(&address)?,
+                    amount,
+                };
+                let outputs = [funding]
+                    .into_iter()
+                    .chain(data.output()?)
+                    .collect();
+                let (tx, fee) = build_payment(wallet_state, outputs, fee_rate, coins.coins(wallet_state)?)?;
+                println!("Funding TXID: {}", hex::encode(tx.txid()));
+                println!("Fee: {} sats", fee);
+                println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+                broadcast_if_requested(&tx, &send, wallet_state, wallet_cache, wallet_name)?;
+            }
+        }
+        HtlcAction::Claim { name, preimage, to, fee, data, send } => {
+            let policy = wallet_cache
+                .htlc_policy(&name)
+                .ok_or(SpendError::InvalidScript(format!("unknown HTLC {}", name)))?;
+            let preimage = hex::decode(&preimage).map_err(|e| SpendError::InvalidScript(e.to_string()))?;
+            let tx = spend_htlc(wallet_state, policy, destination(to)?, fee, Some(&preimage), data.script()?)?;
+            println!("TXID: {}", hex::encode(tx.txid()));
+            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+            broadcast_if_requested(&tx, &send, wallet_state, wallet_cache, wallet_name)?;
+        }
+        HtlcAction::Refund { name, to, fee, data, send } => {
+            let policy = wallet_cache
+                .htlc_policy(&name)
+                .ok_or(SpendError::InvalidScript(format!("unknown HTLC {}", name)))?;
+            let tx = spend_htlc(wallet_state, policy, destination(to)?, fee, None, data.script()?)?;
+            println!("TXID: {}", hex::encode(tx.txid()));
+            println!("Locktime: {}, Sequence: {:#010x}", tx.locktime, tx.inputs[0].sequence);
+            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+            broadcast_if_requested(&tx, &send, wallet_state, wallet_cache, wallet_name)?;
+        }
+    }
+    Ok(())
+}
+
+fn run_miniscript(
+    action: MiniscriptAction,
+    wallet_state: &WalletState,
+    wallet_cache: &mut WalletCache,
+    wallet_name: &str,
+) -> Result<(), SpendError> {
+    match action {
+        MiniscriptAction::Create { name, policy, taproot } => {
+            let policy = create_miniscript_policy(wallet_state, &name, &policy, taproot)?;
+            println!("Miniscript {} ({})", policy.name, policy.policy);
+            println!("Address: {}", policy.address);
+            println!("Descriptor: {}", policy.descriptor);
+            let miniscripts = compile_policy(&policy)?;
+            if taproot {
+                for (index, miniscript) in miniscripts.iter().enumerate() {
+                    println!("- Leaf {}: {}", index, miniscript);
+                    println!("  Script: {}", script_to_asm(&miniscript.encode()));
+                }
+            } else {
+                println!("Witness Script: {}", script_to_asm(&miniscripts[0].encode()));
+            }
+            wallet_cache.add_miniscript_policy(policy);
+            wallet_cache.save(wallet_name)?;
+        }
+        MiniscriptAction::Spend { name, to, amount, fee, preimage, after, older, data, send } => {
+            let policy = wallet_cache
+                .miniscript_policy(&name)
+                .ok_or(SpendError::InvalidScript(format!("unknown miniscript output {}", name)))?;
+            let preimages = preimage
+                .iter()
+                .map(hex::decode)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| SpendError::InvalidScript(e.to_string()))?;
+            let mut outputs = vec![Utxo {
+                script_pubkey: address_to_script(&to)?,
+                amount,
+            }];
+            outputs.extend(data.output()?);
+            let tx = spend_miniscript(wallet_state, policy, outputs, fee, &preimages, after, older)?;
+            println!("TXID: {}", hex::encode(tx.txid()));
+            println!("Locktime: {}, Sequence: {:#010x}", tx.locktime, tx.inputs[0].sequence);
+            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+            broadcast_if_requested(&tx, &send, wallet_state, wallet_cache, wallet_name)?;
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_send_many(
+    file: &str,
+    fee_rate: f64,
+    coins: &CoinControlArgs,
+    data: &OpReturnArgs,
+    send: &BroadcastArgs,
+    wallet_state: &WalletState,
+    wallet_cache: &mut WalletCache,
+    wallet_name: &str,
+) -> Result<(), SpendError> {
+    let recipients = read_recipients(file)?;
+    let mut outputs = recipients.iter().map(|recipient| recipient.output()).collect::<Result<Vec<_>, _>>()?;
+    outputs.extend(data.output()?);
+    let payments = outputs.len();
+    let (mut tx, prevouts, fee) = select_payment(wallet_state, outputs, fee_rate, coins.coins(wallet_state)?)?;
+
+    // what is about to be signed
+    let total: u64 = recipients.iter().map(|recipient| recipient.amount).sum();
+    println!("Paying {} recipients from {} inputs:", recipients.len(), prevouts.len());
+    for recipient in &recipients {
+        println!("- {}: {} sats", recipient.address, recipient.amount);
+    }
+    println!("Total: {} sats", total);
+    println!("Fee: {} sats ({:.2} sat/vB)", fee, fee as f64 / tx.vsize() as f64);
+    match tx.outputs.get(payments) {
+        Some(change) => println!("Change: {} sats", change.amount),
+        None => println!("Change: none"),
+    }
+
+    sign_wallet_inputs(wallet_state, &mut tx, &prevouts)?;
+    verify_transaction(&tx, &prevouts)?;
+    println!("TXID: {}", hex::encode(tx.txid()));
+    println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+    broadcast_if_requested(&tx, send, wallet_state, wallet_cache, wallet_name)
+}
+
+fn run_label(action: LabelAction, wallet_cache: &mut WalletCache, wallet_name: &str) -> Result<(), SpendError> {
+    match action {
+        LabelAction::Set { reference, label, kind } => {
+            // a coin, a txid, or else an address
+            let kind = match kind {
+                Some(kind) => kind,
+                None if reference.contains(':') => LabelType::Output,
+                None if reference.len() == 64 && hex::decode(&reference).is_ok() => LabelType::Tx,
+                None => {
+                    address_to_script(&reference)?;
+                    LabelType::Addr
+                }
+            };
+            wallet_cache.set_label(kind, &reference, Some(label).filter(|label| !label.is_empty()));
+            wallet_cache.save(wallet_name)?;
+        }
+        LabelAction::Export { file } => {
+            let labels = wallet_cache.export_labels();
+            match file {
+                Some(file) => {
+                    std::fs::write(&file, labels)
+                        .map_err(|e| SpendError::InvalidTransaction(format!("can't write {}: {}", file, e)))?;
+                    println!("Exported {} labels to {}", wallet_cache.labels.len(), file);
+                }
+                None => print!("{}", labels),
+            }
+        }
+        LabelAction::Import { file } => {
+            let jsonl = std::fs::read_to_string(&file)
+                .map_err(|e| SpendError::InvalidTransaction(format!("can't read {}: {}", file, e)))?;
+            let count = wallet_cache.import_labels(&jsonl)?;
+            wallet_cache.save(wallet_name)?;
+            println!("Imported {} labels from {}", count, file);
+        }
+    }
+    Ok(())
+}
+
+// "<scriptPubKey hex>:<amount in sats>" as a spent output
+fn parse_prevout(prevout: &str) -> Result<Utxo, SpendError> {
+    let invalid = || SpendError::InvalidTransaction(format!("expected <script hex>:<amount>, got {}", prevout));
+    let (script, amount) = prevout.split_once(':').ok_or_else(invalid)?;
+    Ok(Utxo {
+        script_pubkey: hex::decode(script).map_err(|_| invalid())?,
+        amount: amount.parse().map_err(|_| invalid())?,
+    })
+}
+
+fn verify_transaction_hex(tx_hex: &str, prevouts: &[String]) -> Result<(), SpendError> {
+    let tx = parse_transaction_hex(tx_hex)?;
+    let prevouts = if prevouts.is_empty() {
+        lookup_prevouts(&tx, None)?
+    } else {
+        prevouts.iter().map(|prevout| parse_prevout(prevout)).collect::<Result<_, _>>()?
+    };
+    verify_transaction(&tx, &prevouts)
+}
+
+// Send a transaction when --broadcast was given, recording it in the cache
+fn broadcast_if_requested(
+    tx: &Transaction,
+    send: &BroadcastArgs,
+    wallet_state: &WalletState,
+    wallet_cache: &mut WalletCache,
+    wallet_name: &str,
+) -> Result<(), SpendError> {
+    if !send.broadcast {
+        return Ok(());
+    }
+    let txid = broadcast_transaction(tx, wallet_state, wallet_cache)?;
+    wallet_cache.save(wallet_name)?;
+    println!("Broadcast transaction {}", txid);
+    Ok(())
+}
+
+fn parse_transaction_hex(tx_hex: &str) -> Result<Transaction, SpendError> {
+    let bytes = hex::decode(tx_hex.trim())
+        .map_err(|e| SpendError::InvalidTransaction(e.to_string()))?;
+    Transaction::parse(&bytes)
+}
+
+// Wallet name, cache and recovered state from the environment, for the
+// commands that need them
+fn load_wallet() -> (String, WalletCache, WalletState) {
     let extended_private_key = env::var("EXTENDED_PRIVATE_KEY").expect("EXTENDED_PRIVATE_KEY must be set");
     let wallet_name = env::var("WALLET_NAME").expect("WALLET_NAME must be set");
     let cookie_filepath = "~/.bitcoin/signet/.cookie";
 
+    let mut wallet_cache = WalletCache::load(&wallet_name).expect("Failed to load wallet cache");
+    // transactions that left the mempool were mined, which the scan may not
+    // reach so we keep them, or dropped, and will never be
+    if let Ok(mempool) = mempool_txids() {
+        match wallet_cache.prune_transactions(&mempool, mempool_exit) {
+            Ok(gone) => {
+                for (txid, exit) in &gone {
+                    match exit {
+                        MempoolExit::Confirmed => eprintln!("Transaction {} was confirmed", txid),
+                        MempoolExit::Spent => eprintln!("Transaction {} left the mempool, its inputs are spent", txid),
+                        MempoolExit::Dropped => eprintln!("Transaction {} was dropped from the mempool", txid),
+                    }
+                }
+                if !gone.is_empty() {
+                    wallet_cache.save(&wallet_name).expect("Failed to save wallet cache");
+                }
+            }
+            Err(e) => eprintln!("Can't tell what became of transactions that left the mempool: {}", e),
+        }
+    }
+    let mut wallet_state = recover_wallet_state(
+        &extended_private_key,
+        cookie_filepath,
+        &wallet_cache.watch_scripts(),
+    )
+    .expect("Failed to recover wallet state");
+    // transactions we broadcast that the scan hasn't seen confirmed
+    wallet_cache.apply_unconfirmed(&mut wallet_state);
+    wallet_state.frozen = wallet_cache.frozen_outpoints();
+    (wallet_name, wallet_cache, wallet_state)
+}
+
+fn main() {
+    //load environment variables
+    dotenv().ok();
+
     //parse cli arguments
-    let cli = CLI::parse();
+    let cli = Cli::parse();
     match cli.command {
         Commands::Wallet { action } => {
             // Recover wallet state for all wallet actions
-            let wallet_state = recover_wallet_state(&extended_private_key, cookie_filepath)
-                .expect("Failed to recover wallet state");
+            let (wallet_name, mut wallet_cache, wallet_state) = load_wallet();
 
             match action {
                 WalletAction::Recover => {
@@ -67,33 +1161,270 @@ fn main() {
                     println!("Wallet: {}\nBalance: {:.8} BTC", wallet_name, balance);
                     println!("UTXOs: {}", wallet_state.utxos.len());
                     for ((txid, vout), (script, amount)) in &wallet_state.utxos {
-                        println!("- TXID: {}, Vout: {}, Amount: {:.8} BTC, Script: {}", txid, vout, amount, hex::encode(script));
+                        print!("- TXID: {}, Vout: {}, Amount: {:.8} BTC, Script: {}", txid, vout, amount, hex::encode(script));
+                        // the coin's own label, or else its address's
+                        let label = wallet_cache.label(LabelType::Output, &format!("{}:{}", txid, vout)).or_else(|| {
+                            script_to_address(script).and_then(|address| wallet_cache.label(LabelType::Addr, &address))
+                        });
+                        if let Some(label) = label {
+                            print!(", Label: {}", label);
+                        }
+                        if wallet_state.frozen.contains(&(txid.clone(), *vout)) {
+                            print!(" (frozen)");
+                        }
+                        println!();
+                    }
+                    if let Some(address) = wallet_state.taproot_programs.first().and_then(|p| script_to_address(p)) {
+                        println!("Taproot Address: {}", address);
+                    }
+                    if let Some(address) = wallet_state.nested_scripts.first().and_then(|p| script_to_address(p)) {
+                        println!("P2SH-P2WPKH Address: {}", address);
+                    }
+                    if let Some(address) = wallet_state.legacy_scripts.first().and_then(|p| script_to_address(p)) {
+                        println!("P2PKH Address: {}", address);
                     }
                 }
-                WalletAction::SpendMultisig => {
-                    match spend_p2wpkh(&wallet_state) {
+                WalletAction::SpendMultisig { threshold, keys, sorted, sighash, data, send } => {
+                    let result = data
+                        .script()
+                        .and_then(|op_return| spend_p2wpkh(&wallet_state, threshold, keys, sorted, sighash, op_return));
+                    match result {
                         Ok((txid, tx_hex)) => {
                             println!("Created P2WPKH -> P2WSH multisig transaction:");
                             println!("TXID: {}", hex::encode(txid));
                             println!("Transaction Hex: {}", tx_hex);
+                            let result = parse_transaction_hex(&tx_hex).and_then(|tx| {
+                                broadcast_if_requested(&tx, &send, &wallet_state, &mut wallet_cache, &wallet_name)
+                            });
+                            if let Err(e) = result {
+                                println!("Failed to broadcast: {}", e);
+                            }
                         }
-                        Err(e) => println!("Failed to spend P2WPKH: {:?}", e),
+                        Err(e) => println!("Failed to spend P2WPKH: {}", e),
                     }
                 }
-                WalletAction::SpendOpreturn { txid } => {
-                    let txid_bytes = hex::decode(&txid).expect("Invalid TXID hex");
-                    let txid_array: [u8; 32] = txid_bytes.try_into().expect("TXID must be 32 bytes");
-                    match spend_p2wsh(&wallet_state, txid_array) {
+                WalletAction::SpendOpreturn { outpoint, threshold, keys, sorted, sighash, data, send } => {
+                    let result = data.script().and_then(|op_return| {
+                        let op_return = op_return
+                            .ok_or(SpendError::InvalidScript("at least one --op-return is required".to_string()))?;
+                        spend_p2wsh(&wallet_state, &wallet_cache, outpoint, threshold, keys, sorted, sighash, op_return)
+                    });
+                    match result {
                         Ok(transaction_data) => {
                             let txid2 = hex::encode(&transaction_data[0]);
                             let tx2 = hex::encode(&transaction_data[1]);
                             println!("Created P2WSH -> OP_RETURN transaction:");
                             println!("TXID: {}", txid2);
                             println!("Transaction Hex: {}", tx2);
+                            let result = Transaction::parse(&transaction_data[1]).and_then(|tx| {
+                                broadcast_if_requested(&tx, &send, &wallet_state, &mut wallet_cache, &wallet_name)
+                            });
+                            if let Err(e) = result {
+                                println!("Failed to broadcast: {}", e);
+                            }
+                        }
+                        Err(e) => println!("Failed to spend P2WSH: {}", e),
+                    }
+                }
+                WalletAction::SpendTaproot { to, amount, fee, sighash, data, send } => {
+                    let result = data
+                        .script()
+                        .and_then(|op_return| spend_taproot(&wallet_state, &to, amount, fee, sighash, op_return));
+                    match result {
+                        Ok(tx) => {
+                            println!("Created P2TR key path transaction:");
+                            println!("TXID: {}", hex::encode(tx.txid()));
+                            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+                            if let Err(e) =
+                                broadcast_if_requested(&tx, &send, &wallet_state, &mut wallet_cache, &wallet_name)
+                            {
+                                println!("Failed to broadcast: {}", e);
+                            }
+                        }
+                        Err(e) => println!("Failed to spend P2TR: {}", e),
+                    }
+                }
+                WalletAction::SendMany { file, fee_rate, coins, data, send } => {
+                    let result = run_send_many(
+                        &file,
+                        fee_rate,
+                        &coins,
+                        &data,
+                        &send,
+                        &wallet_state,
+                        &mut wallet_cache,
+                        &wallet_name,
+                    );
+                    if let Err(e) = result {
+                        println!("Failed to send payments: {}", e);
+                    }
+                }
+                WalletAction::Consolidate { max_inputs, fee_rate, coins, data, send } => {
+                    let result = data.output().and_then(|extra| {
+                        let chosen = coins.coins(&wallet_state)?;
+                        consolidate(&wallet_state, max_inputs, fee_rate, extra.into_iter().collect(), chosen)
+                    });
+                    match result {
+                        Ok((tx, fee)) => {
+                            let address = script_to_address(&tx.outputs[0].script_pubkey).unwrap_or_default();
+                            let (inputs, amount) = (tx.inputs.len(), tx.outputs[0].amount);
+                            println!("Consolidated {} coins into {} sats at {}:", inputs, amount, address);
+                            println!("Fee: {} sats ({:.2} sat/vB)", fee, fee as f64 / tx.vsize() as f64);
+                            println!("TXID: {}", hex::encode(tx.txid()));
+                            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+                            if let Err(e) =
+                                broadcast_if_requested(&tx, &send, &wallet_state, &mut wallet_cache, &wallet_name)
+                            {
+                                println!("Failed to broadcast: {}", e);
+                            }
+                        }
+                        Err(e) => println!("Failed to consolidate: {}", e),
+                    }
+                }
+                WalletAction::Sweep { address, fee_rate, coins, data, send } => {
+                    let result = data.output().and_then(|extra| {
+                        let destination = address_to_script(&address)?;
+                        let chosen = coins.coins(&wallet_state)?;
+                        sweep(&wallet_state, destination, fee_rate, extra.into_iter().collect(), chosen)
+                    });
+                    match result {
+                        Ok((tx, fee)) => {
+                            println!("Swept {} coins, {} sats to {}:", tx.inputs.len(), tx.outputs[0].amount, address);
+                            println!("Fee: {} sats ({:.2} sat/vB)", fee, fee as f64 / tx.vsize() as f64);
+                            println!("TXID: {}", hex::encode(tx.txid()));
+                            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+                            if let Err(e) =
+                                broadcast_if_requested(&tx, &send, &wallet_state, &mut wallet_cache, &wallet_name)
+                            {
+                                println!("Failed to broadcast: {}", e);
+                            }
                         }
-                        Err(e) => println!("Failed to spend P2WSH: {:?}", e),
+                        Err(e) => println!("Failed to sweep: {}", e),
                     }
                 }
+                WalletAction::Freeze { ref outpoints } | WalletAction::Unfreeze { ref outpoints } => {
+                    let frozen = matches!(action, WalletAction::Freeze { .. });
+                    for outpoint in outpoints {
+                        let outpoint = format!("{}:{}", outpoint.txid_hex(), outpoint.index);
+                        wallet_cache.set_frozen(&outpoint, frozen);
+                        println!("{} {}", if frozen { "Froze" } else { "Unfroze" }, outpoint);
+                    }
+                    if let Err(e) = wallet_cache.save(&wallet_name) {
+                        println!("Failed to save wallet cache: {}", e);
+                    }
+                }
+                WalletAction::Label { action } => {
+                    if let Err(e) = run_label(action, &mut wallet_cache, &wallet_name) {
+                        println!("Label command failed: {}", e);
+                    }
+                }
+                WalletAction::SignMessage { message, address, bip322 } => {
+                    match sign_message(&wallet_state, &address, &message, bip322) {
+                        Ok(signature) => println!("{}", signature),
+                        Err(e) => println!("Failed to sign message: {}", e),
+                    }
+                }
+                WalletAction::BumpFee { txid, fee_rate, send } => {
+                    match bump_fee(&wallet_state, &wallet_cache, &txid, fee_rate) {
+                        Ok((tx, fee, original_fee)) => {
+                            println!("Created replacement for {}:", txid);
+                            println!("Fee: {} sats ({:.2} sat/vB), was {} sats", fee, fee as f64 / tx.vsize() as f64, original_fee);
+                            println!("TXID: {}", hex::encode(tx.txid()));
+                            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+                            if let Err(e) =
+                                broadcast_if_requested(&tx, &send, &wallet_state, &mut wallet_cache, &wallet_name)
+                            {
+                                println!("Failed to broadcast: {}", e);
+                            } else if send.broadcast {
+                                // the original's outputs won't confirm once it is replaced
+                                wallet_cache.remove_transaction(&txid);
+                                if let Err(e) = wallet_cache.save(&wallet_name) {
+                                    println!("Failed to save wallet cache: {}", e);
+                                }
+                            }
+                        }
+                        Err(e) => println!("Failed to bump fee: {}", e),
+                    }
+                }
+                WalletAction::ForgetTx { txid } => {
+                    if !wallet_cache.forget_transaction(&txid) {
+                        println!("Transaction {} is not one we broadcast", txid);
+                    } else if let Err(e) = wallet_cache.save(&wallet_name) {
+                        println!("Failed to save wallet cache: {}", e);
+                    } else {
+                        println!("Forgot transaction {}", txid);
+                    }
+                }
+                WalletAction::Cpfp { txid, target_fee_rate, send } => {
+                    match create_cpfp(&wallet_state, &wallet_cache, &txid, target_fee_rate) {
+                        Ok((tx, fee, parent_fee, parent_vsize)) => {
+                            let package_rate = (fee + parent_fee) as f64 / (parent_vsize + tx.vsize() as u64) as f64;
+                            println!("Created child of {}:", txid);
+                            println!("Parent: {} sats over {} vbytes", parent_fee, parent_vsize);
+                            println!("Child fee: {} sats, package fee rate: {:.2} sat/vB", fee, package_rate);
+                            println!("TXID: {}", hex::encode(tx.txid()));
+                            println!("Transaction Hex: {}", hex::encode(tx.serialize()));
+                            if let Err(e) =
+                                broadcast_if_requested(&tx, &send, &wallet_state, &mut wallet_cache, &wallet_name)
+                            {
+                                println!("Failed to broadcast: {}", e);
+                            }
+                        }
+                        Err(e) => println!("Failed to create CPFP transaction: {}", e),
+                    }
+                }
+                WalletAction::Multisig { action } => {
+                    if let Err(e) = run_multisig(action, &wallet_state, &mut wallet_cache, &wallet_name) {
+                        println!("Multisig command failed: {}", e);
+                    }
+                }
+                WalletAction::Taproot { action } => {
+                    if let Err(e) = run_taproot(action, &wallet_state, &mut wallet_cache, &wallet_name) {
+                        println!("Taproot command failed: {}", e);
+                    }
+                }
+                WalletAction::Htlc { action } => {
+                    if let Err(e) = run_htlc(action, &wallet_state, &mut wallet_cache, &wallet_name) {
+                        println!("HTLC command failed: {}", e);
+                    }
+                }
+                WalletAction::Timelock { action } => {
+                    if let Err(e) = run_timelock(action, &wallet_state, &mut wallet_cache, &wallet_name) {
+                        println!("Timelock command failed: {}", e);
+                    }
+                }
+                WalletAction::Miniscript { action } => {
+                    if let Err(e) = run_miniscript(action, &wallet_state, &mut wallet_cache, &wallet_name) {
+                        println!("Miniscript command failed: {}", e);
+                    }
+                }
+            }
+        }
+        Commands::Psbt { action } => {
+            if let Err(e) = run_psbt(action) {
+                println!("PSBT command failed: {}", e);
+            }
+        }
+        Commands::Signer { signer, action } => {
+            if let Err(e) = run_signer(signer, action) {
+                println!("Signer command failed: {}", e);
+            }
+        }
+        Commands::Tx { action } => match action {
+            TxAction::Decode { tx_hex, json } => match parse_transaction_hex(&tx_hex) {
+                Ok(tx) if json => println!("{}", serde_json::to_string_pretty(&transaction_json(&tx)).unwrap()),
+                Ok(tx) => println!("{}", transaction_table(&tx)),
+                Err(e) => println!("Failed to decode transaction: {}", e),
+            },
+            TxAction::Verify { tx_hex, prevouts } => match verify_transaction_hex(&tx_hex, &prevouts) {
+                Ok(()) => println!("All inputs verified"),
+                Err(e) => println!("Verification failed: {}", e),
+            },
+        },
+        Commands::VerifyMessage { address, signature, message } => {
+            match verify_message(&address, &signature, &message) {
+                Ok(()) => println!("Signature is valid"),
+                Err(e) => println!("Signature is not valid: {}", e),
             }
         }
         Commands::Block { mempool_file, output_file } => {
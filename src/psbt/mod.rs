@@ -0,0 +1,7 @@
+#[allow(clippy::module_inception)]
+pub mod psbt;
+pub mod roles;
+pub mod signer;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,669 @@
+// PSBT data model and (de)serialization for version 0 (BIP174) and version 2 (BIP370)
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use crate::spend_funds::transaction::{compact_size, Outpoint, Transaction, TxIn, Utxo, SEQUENCE_FINAL};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::collections::BTreeMap;
+
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+// global types
+const PSBT_GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const PSBT_GLOBAL_XPUB: u8 = 0x01;
+const PSBT_GLOBAL_TX_VERSION: u8 = 0x02;
+const PSBT_GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const PSBT_GLOBAL_INPUT_COUNT: u8 = 0x04;
+const PSBT_GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const PSBT_GLOBAL_TX_MODIFIABLE: u8 = 0x06;
+const PSBT_GLOBAL_VERSION: u8 = 0xfb;
+
+// input types
+const PSBT_IN_NON_WITNESS_UTXO: u8 = 0x00;
+const PSBT_IN_WITNESS_UTXO: u8 = 0x01;
+const PSBT_IN_PARTIAL_SIG: u8 = 0x02;
+const PSBT_IN_SIGHASH_TYPE: u8 = 0x03;
+const PSBT_IN_REDEEM_SCRIPT: u8 = 0x04;
+const PSBT_IN_WITNESS_SCRIPT: u8 = 0x05;
+const PSBT_IN_BIP32_DERIVATION: u8 = 0x06;
+const PSBT_IN_FINAL_SCRIPTSIG: u8 = 0x07;
+const PSBT_IN_FINAL_SCRIPTWITNESS: u8 = 0x08;
+const PSBT_IN_PREVIOUS_TXID: u8 = 0x0e;
+const PSBT_IN_OUTPUT_INDEX: u8 = 0x0f;
+const PSBT_IN_SEQUENCE: u8 = 0x10;
+const PSBT_IN_REQUIRED_TIME_LOCKTIME: u8 = 0x11;
+const PSBT_IN_REQUIRED_HEIGHT_LOCKTIME: u8 = 0x12;
+
+// output types
+const PSBT_OUT_REDEEM_SCRIPT: u8 = 0x00;
+const PSBT_OUT_WITNESS_SCRIPT: u8 = 0x01;
+const PSBT_OUT_BIP32_DERIVATION: u8 = 0x02;
+const PSBT_OUT_AMOUNT: u8 = 0x03;
+const PSBT_OUT_SCRIPT: u8 = 0x04;
+
+// Master key fingerprint and derivation path of a key
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeySource {
+    pub fingerprint: [u8; 4],
+    pub path: Vec<u32>,
+}
+
+#[derive(Clone, Default)]
+pub struct PsbtInput {
+    pub non_witness_utxo: Option<Vec<u8>>,
+    pub witness_utxo: Option<Utxo>,
+    pub partial_sigs: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub sighash_type: Option<u32>,
+    pub redeem_script: Option<Vec<u8>>,
+    pub witness_script: Option<Vec<u8>>,
+    pub bip32_derivation: BTreeMap<Vec<u8>, KeySource>,
+    pub final_script_sig: Option<Vec<u8>>,
+    pub final_script_witness: Option<Vec<Vec<u8>>>,
+    // version 2 only, the unsigned transaction is spread over these
+    pub previous_txid: Option<[u8; 32]>,
+    pub output_index: Option<u32>,
+    pub sequence: Option<u32>,
+    pub required_time_locktime: Option<u32>,
+    pub required_height_locktime: Option<u32>,
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+#[derive(Clone, Default)]
+pub struct PsbtOutput {
+    pub redeem_script: Option<Vec<u8>>,
+    pub witness_script: Option<Vec<u8>>,
+    pub bip32_derivation: BTreeMap<Vec<u8>, KeySource>,
+    // version 2 only
+    pub amount: Option<u64>,
+    pub script: Option<Vec<u8>>,
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+#[derive(Clone)]
+pub struct Psbt {
+    pub version: u32,
+    // version 0 only
+    pub unsigned_tx: Option<Transaction>,
+    // version 2 only
+    pub tx_version: u32,
+    pub fallback_locktime: Option<u32>,
+    pub tx_modifiable: Option<u8>,
+    pub xpubs: BTreeMap<Vec<u8>, KeySource>,
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub inputs: Vec<PsbtInput>,
+    pub outputs: Vec<PsbtOutput>,
+}
+
+fn invalid(msg: &str) -> SpendError {
+    SpendError::InvalidTransaction(format!("invalid psbt: {}", msg))
+}
+
+impl KeySource {
+    fn parse(value: &[u8]) -> Result<KeySource, SpendError> {
+        if value.len() < 4 || !value.len().is_multiple_of(4) {
+            return Err(invalid("bad key origin"));
+        }
+        Ok(KeySource {
+            fingerprint: value[0..4].try_into().unwrap(),
+            path: value[4..]
+                .chunks(4)
+                .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+                .collect(),
+        })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = self.fingerprint.to_vec();
+        for index in &self.path {
+            data.extend(index.to_le_bytes());
+        }
+        data
+    }
+}
+
+impl std::fmt::Display for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[{}", hex::encode(self.fingerprint))?;
+        for index in &self.path {
+            if *index >= 0x80000000 {
+                write!(f, "/{}h", index - 0x80000000)?;
+            } else {
+                write!(f, "/{}", index)?;
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+// key-value pairs of one PSBT map, in serialized order
+type KeyValueMap = Vec<(Vec<u8>, Vec<u8>)>;
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], SpendError> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(invalid("unexpected end of data"))?;
+        let data = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(data)
+    }
+
+    fn read_compact_size(&mut self) -> Result<usize, SpendError> {
+        let n = match self.read_bytes(1)?[0] {
+            0xFD => u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()) as u64,
+            0xFE => u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()) as u64,
+            0xFF => u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()),
+            n => n as u64,
+        };
+        Ok(n as usize)
+    }
+
+    // key-value pairs up to the 0x00 separator
+    fn read_map(&mut self) -> Result<KeyValueMap, SpendError> {
+        let mut pairs: KeyValueMap = Vec::new();
+        loop {
+            let key_len = self.read_compact_size()?;
+            if key_len == 0 {
+                return Ok(pairs);
+            }
+            let key = self.read_bytes(key_len)?.to_vec();
+            let value_len = self.read_compact_size()?;
+            let value = self.read_bytes(value_len)?.to_vec();
+            if pairs.iter().any(|(k, _)| *k == key) {
+                return Err(invalid(&format!("duplicate key {}", hex::encode(&key))));
+            }
+            pairs.push((key, value));
+        }
+    }
+}
+
+fn u32_value(value: &[u8]) -> Result<u32, SpendError> {
+    Ok(u32::from_le_bytes(
+        value.try_into().map_err(|_| invalid("expected 4 byte value"))?,
+    ))
+}
+
+fn write_pair(data: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    data.extend(compact_size(key.len() as u64));
+    data.extend_from_slice(key);
+    data.extend(compact_size(value.len() as u64));
+    data.extend_from_slice(value);
+}
+
+fn key_with_data(key_type: u8, key_data: &[u8]) -> Vec<u8> {
+    let mut key = vec![key_type];
+    key.extend_from_slice(key_data);
+    key
+}
+
+fn parse_witness_stack(value: &[u8]) -> Result<Vec<Vec<u8>>, SpendError> {
+    let mut reader = Reader { bytes: value, position: 0 };
+    let count = reader.read_compact_size()?;
+    let mut stack = Vec::new();
+    for _ in 0..count {
+        let len = reader.read_compact_size()?;
+        stack.push(reader.read_bytes(len)?.to_vec());
+    }
+    Ok(stack)
+}
+
+fn parse_txout(value: &[u8]) -> Result<Utxo, SpendError> {
+    let mut reader = Reader { bytes: value, position: 0 };
+    let amount = u64::from_le_bytes(reader.read_bytes(8)?.try_into().unwrap());
+    let len = reader.read_compact_size()?;
+    Ok(Utxo {
+        amount,
+        script_pubkey: reader.read_bytes(len)?.to_vec(),
+    })
+}
+
+impl PsbtInput {
+    fn parse(pairs: KeyValueMap) -> Result<PsbtInput, SpendError> {
+        let mut input = PsbtInput::default();
+        for (key, value) in pairs {
+            let key_data = key[1..].to_vec();
+            match key[0] {
+                PSBT_IN_NON_WITNESS_UTXO => input.non_witness_utxo = Some(value),
+                PSBT_IN_WITNESS_UTXO => input.witness_utxo = Some(parse_txout(&value)?),
+                PSBT_IN_PARTIAL_SIG => {
+                    input.partial_sigs.insert(key_data, value);
+                }
+                PSBT_IN_SIGHASH_TYPE => input.sighash_type = Some(u32_value(&value)?),
+                PSBT_IN_REDEEM_SCRIPT => input.redeem_script = Some(value),
+                PSBT_IN_WITNESS_SCRIPT => input.witness_script = Some(value),
+                PSBT_IN_BIP32_DERIVATION => {
+                    input.bip32_derivation.insert(key_data, KeySource::parse(&value)?);
+                }
+                PSBT_IN_FINAL_SCRIPTSIG => input.final_script_sig = Some(value),
+                PSBT_IN_FINAL_SCRIPTWITNESS => {
+                    input.final_script_witness = Some(parse_witness_stack(&value)?)
+                }
+                PSBT_IN_PREVIOUS_TXID => {
+                    input.previous_txid =
+                        Some(value.try_into().map_err(|_| invalid("bad previous txid"))?)
+                }
+                PSBT_IN_OUTPUT_INDEX => input.output_index = Some(u32_value(&value)?),
+                PSBT_IN_SEQUENCE => input.sequence = Some(u32_value(&value)?),
+                PSBT_IN_REQUIRED_TIME_LOCKTIME => {
+                    input.required_time_locktime = Some(u32_value(&value)?)
+                }
+                PSBT_IN_REQUIRED_HEIGHT_LOCKTIME => {
+                    input.required_height_locktime = Some(u32_value(&value)?)
+                }
+                _ => {
+                    input.unknown.insert(key, value);
+                }
+            }
+        }
+        Ok(input)
+    }
+
+    fn serialize(&self, version: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        if let Some(tx) = &self.non_witness_utxo {
+            write_pair(&mut data, &[PSBT_IN_NON_WITNESS_UTXO], tx);
+        }
+        if let Some(utxo) = &self.witness_utxo {
+            write_pair(&mut data, &[PSBT_IN_WITNESS_UTXO], &utxo.serialize());
+        }
+        for (pubkey, sig) in &self.partial_sigs {
+            write_pair(&mut data, &key_with_data(PSBT_IN_PARTIAL_SIG, pubkey), sig);
+        }
+        if let Some(sighash) = self.sighash_type {
+            write_pair(&mut data, &[PSBT_IN_SIGHASH_TYPE], &sighash.to_le_bytes());
+        }
+        if let Some(script) = &self.redeem_script {
+            write_pair(&mut data, &[PSBT_IN_REDEEM_SCRIPT], script);
+        }
+        if let Some(script) = &self.witness_script {
+            write_pair(&mut data, &[PSBT_IN_WITNESS_SCRIPT], script);
+        }
+        for (pubkey, source) in &self.bip32_derivation {
+            write_pair(
+                &mut data,
+                &key_with_data(PSBT_IN_BIP32_DERIVATION, pubkey),
+                &source.serialize(),
+            );
+        }
+        if let Some(script_sig) = &self.final_script_sig {
+            write_pair(&mut data, &[PSBT_IN_FINAL_SCRIPTSIG], script_sig);
+        }
+        if let Some(witness) = &self.final_script_witness {
+            let mut value = compact_size(witness.len() as u64);
+            for item in witness {
+                value.extend(compact_size(item.len() as u64));
+                value.extend(item);
+            }
+            write_pair(&mut data, &[PSBT_IN_FINAL_SCRIPTWITNESS], &value);
+        }
+        if version >= 2 {
+            if let Some(txid) = &self.previous_txid {
+                write_pair(&mut data, &[PSBT_IN_PREVIOUS_TXID], txid);
+            }
+            if let Some(index) = self.output_index {
+                write_pair(&mut data, &[PSBT_IN_OUTPUT_INDEX], &index.to_le_bytes());
+            }
+            if let Some(sequence) = self.sequence {
+                write_pair(&mut data, &[PSBT_IN_SEQUENCE], &sequence.to_le_bytes());
+            }
+            if let Some(locktime) = self.required_time_locktime {
+                write_pair(&mut data, &[PSBT_IN_REQUIRED_TIME_LOCKTIME], &locktime.to_le_bytes());
+            }
+            if let Some(locktime) = self.required_height_locktime {
+                write_pair(&mut data, &[PSBT_IN_REQUIRED_HEIGHT_LOCKTIME], &locktime.to_le_bytes());
+            }
+        }
+        for (key, value) in &self.unknown {
+            write_pair(&mut data, key, value);
+        }
+        data.push(0x00);
+        data
+    }
+
+    // Merge another signer's copy of this input
+    fn combine(&mut self, other: PsbtInput) {
+        if self.non_witness_utxo.is_none() {
+            self.non_witness_utxo = other.non_witness_utxo;
+        }
+        if self.witness_utxo.is_none() {
+            self.witness_utxo = other.witness_utxo;
+        }
+        self.partial_sigs.extend(other.partial_sigs);
+        self.sighash_type = self.sighash_type.or(other.sighash_type);
+        if self.redeem_script.is_none() {
+            self.redeem_script = other.redeem_script;
+        }
+        if self.witness_script.is_none() {
+            self.witness_script = other.witness_script;
+        }
+        self.bip32_derivation.extend(other.bip32_derivation);
+        if self.final_script_sig.is_none() {
+            self.final_script_sig = other.final_script_sig;
+        }
+        if self.final_script_witness.is_none() {
+            self.final_script_witness = other.final_script_witness;
+        }
+        self.unknown.extend(other.unknown);
+    }
+}
+
+impl PsbtOutput {
+    fn parse(pairs: KeyValueMap) -> Result<PsbtOutput, SpendError> {
+        let mut output = PsbtOutput::default();
+        for (key, value) in pairs {
+            match key[0] {
+                PSBT_OUT_REDEEM_SCRIPT => output.redeem_script = Some(value),
+                PSBT_OUT_WITNESS_SCRIPT => output.witness_script = Some(value),
+                PSBT_OUT_BIP32_DERIVATION => {
+                    output.bip32_derivation.insert(key[1..].to_vec(), KeySource::parse(&value)?);
+                }
+                PSBT_OUT_AMOUNT => {
+                    let amount = value.try_into().map_err(|_| invalid("bad output amount"))?;
+                    output.amount = Some(u64::from_le_bytes(amount));
+                }
+                PSBT_OUT_SCRIPT => output.script = Some(value),
+                _ => {
+                    output.unknown.insert(key, value);
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    fn serialize(&self, version: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        if let Some(script) = &self.redeem_script {
+            write_pair(&mut data, &[PSBT_OUT_REDEEM_SCRIPT], script);
+        }
+        if let Some(script) = &self.witness_script {
+            write_pair(&mut data, &[PSBT_OUT_WITNESS_SCRIPT], script);
+        }
+        for (pubkey, source) in &self.bip32_derivation {
+            write_pair(
+                &mut data,
+                &key_with_data(PSBT_OUT_BIP32_DERIVATION, pubkey),
+                &source.serialize(),
+            );
+        }
+        if version >= 2 {
+            if let Some(amount) = self.amount {
+                write_pair(&mut data, &[PSBT_OUT_AMOUNT], &amount.to_le_bytes());
+            }
+            if let Some(script) = &self.script {
+                write_pair(&mut data, &[PSBT_OUT_SCRIPT], script);
+            }
+        }
+        for (key, value) in &self.unknown {
+            write_pair(&mut data, key, value);
+        }
+        data.push(0x00);
+        data
+    }
+
+    fn combine(&mut self, other: PsbtOutput) {
+        if self.redeem_script.is_none() {
+            self.redeem_script = other.redeem_script;
+        }
+        if self.witness_script.is_none() {
+            self.witness_script = other.witness_script;
+        }
+        self.bip32_derivation.extend(other.bip32_derivation);
+        self.unknown.extend(other.unknown);
+    }
+}
+
+impl Psbt {
+    // Creator role: wrap an unsigned transaction in an empty PSBT of the given version
+    pub fn from_unsigned_tx(tx: Transaction, version: u32) -> Psbt {
+        let mut inputs = vec![PsbtInput::default(); tx.inputs.len()];
+        let mut outputs = vec![PsbtOutput::default(); tx.outputs.len()];
+        if version >= 2 {
+            for (psbt_input, txin) in inputs.iter_mut().zip(&tx.inputs) {
+                psbt_input.previous_txid = Some(txin.previous_output.txid);
+                psbt_input.output_index = Some(txin.previous_output.index);
+                psbt_input.sequence = Some(txin.sequence);
+            }
+            for (psbt_output, txout) in outputs.iter_mut().zip(&tx.outputs) {
+                psbt_output.amount = Some(txout.amount);
+                psbt_output.script = Some(txout.script_pubkey.clone());
+            }
+        }
+        Psbt {
+            version,
+            tx_version: tx.version,
+            fallback_locktime: Some(tx.locktime).filter(|_| version >= 2),
+            tx_modifiable: None,
+            unsigned_tx: Some(tx).filter(|_| version < 2),
+            xpubs: BTreeMap::new(),
+            unknown: BTreeMap::new(),
+            inputs,
+            outputs,
+        }
+    }
+
+    // The transaction being signed, rebuilt from the per-input/output fields for version 2
+    pub fn unsigned_tx(&self) -> Result<Transaction, SpendError> {
+        if let Some(tx) = &self.unsigned_tx {
+            return Ok(tx.clone());
+        }
+        let mut inputs = Vec::new();
+        for input in &self.inputs {
+            let mut txin = TxIn::new(Outpoint {
+                txid: input.previous_txid.ok_or(invalid("input missing previous txid"))?,
+                index: input.output_index.ok_or(invalid("input missing output index"))?,
+            });
+            // BIP370: inputs without a sequence are final
+            txin.sequence = input.sequence.unwrap_or(SEQUENCE_FINAL);
+            inputs.push(txin);
+        }
+        let mut outputs = Vec::new();
+        for output in &self.outputs {
+            outputs.push(Utxo {
+                amount: output.amount.ok_or(invalid("output missing amount"))?,
+                script_pubkey: output.script.clone().ok_or(invalid("output missing script"))?,
+            });
+        }
+
+        // BIP370: the largest required locktime of the type every input with a
+        // requirement accepts, heights preferred when both are
+        let locked: Vec<&PsbtInput> = self
+            .inputs
+            .iter()
+            .filter(|i| i.required_height_locktime.is_some() || i.required_time_locktime.is_some())
+            .collect();
+        let heights: Option<Vec<u32>> = locked.iter().map(|i| i.required_height_locktime).collect();
+        let times: Option<Vec<u32>> = locked.iter().map(|i| i.required_time_locktime).collect();
+        let locktime = match (heights, times) {
+            _ if locked.is_empty() => self.fallback_locktime.unwrap_or(0),
+            (Some(heights), _) => heights.into_iter().max().unwrap(),
+            (None, Some(times)) => times.into_iter().max().unwrap(),
+            (None, None) => {
+                return Err(invalid(
+                    "inputs require both a height and a time locktime, no locktime satisfies them all",
+                ))
+            }
+        };
+
+        Ok(Transaction {
+            version: self.tx_version,
+            inputs,
+            outputs,
+            locktime,
+        })
+    }
+
+    // Value and script of the output spent by input `index`
+    pub fn spent_output(&self, index: usize) -> Result<Utxo, SpendError> {
+        let input = &self.inputs[index];
+        if let Some(utxo) = &input.witness_utxo {
+            return Ok(utxo.clone());
+        }
+        if let Some(raw) = &input.non_witness_utxo {
+            let prev_tx = Transaction::parse(raw)?;
+            let outpoint = self.unsigned_tx()?.inputs[index].previous_output;
+            // outpoints hold the txid in internal byte order
+            let mut prev_txid = prev_tx.txid();
+            prev_txid.reverse();
+            if prev_txid != outpoint.txid {
+                return Err(invalid(&format!(
+                    "non-witness utxo of input {} is not the transaction it spends",
+                    index
+                )));
+            }
+            return prev_tx
+                .outputs
+                .get(outpoint.index as usize)
+                .cloned()
+                .ok_or(invalid("previous output index out of range"));
+        }
+        Err(invalid(&format!("input {} has no utxo information", index)))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Psbt, SpendError> {
+        if !bytes.starts_with(PSBT_MAGIC) {
+            return Err(invalid("missing magic bytes"));
+        }
+        let mut reader = Reader {
+            bytes,
+            position: PSBT_MAGIC.len(),
+        };
+
+        let mut psbt = Psbt {
+            version: 0,
+            unsigned_tx: None,
+            tx_version: 2,
+            fallback_locktime: None,
+            tx_modifiable: None,
+            xpubs: BTreeMap::new(),
+            unknown: BTreeMap::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        };
+        let mut input_count = None;
+        let mut output_count = None;
+        for (key, value) in reader.read_map()? {
+            match key[0] {
+                PSBT_GLOBAL_UNSIGNED_TX => psbt.unsigned_tx = Some(Transaction::parse(&value)?),
+                PSBT_GLOBAL_XPUB => {
+                    psbt.xpubs.insert(key[1..].to_vec(), KeySource::parse(&value)?);
+                }
+                PSBT_GLOBAL_TX_VERSION => psbt.tx_version = u32_value(&value)?,
+                PSBT_GLOBAL_FALLBACK_LOCKTIME => psbt.fallback_locktime = Some(u32_value(&value)?),
+                PSBT_GLOBAL_INPUT_COUNT => {
+                    input_count = Some(Reader { bytes: &value, position: 0 }.read_compact_size()?)
+                }
+                PSBT_GLOBAL_OUTPUT_COUNT => {
+                    output_count = Some(Reader { bytes: &value, position: 0 }.read_compact_size()?)
+                }
+                PSBT_GLOBAL_TX_MODIFIABLE => psbt.tx_modifiable = value.first().copied(),
+                PSBT_GLOBAL_VERSION => psbt.version = u32_value(&value)?,
+                _ => {
+                    psbt.unknown.insert(key, value);
+                }
+            }
+        }
+
+        let (input_count, output_count) = match (&psbt.unsigned_tx, psbt.version) {
+            (Some(tx), 0) => (tx.inputs.len(), tx.outputs.len()),
+            (None, 2) => (
+                input_count.ok_or(invalid("missing input count"))?,
+                output_count.ok_or(invalid("missing output count"))?,
+            ),
+            (None, 0) => return Err(invalid("missing unsigned transaction")),
+            (Some(_), 2) => return Err(invalid("version 2 must not contain an unsigned transaction")),
+            _ => return Err(invalid(&format!("unsupported version {}", psbt.version))),
+        };
+
+        for _ in 0..input_count {
+            psbt.inputs.push(PsbtInput::parse(reader.read_map()?)?);
+        }
+        for _ in 0..output_count {
+            psbt.outputs.push(PsbtOutput::parse(reader.read_map()?)?);
+        }
+        if reader.position != bytes.len() {
+            return Err(invalid("trailing data"));
+        }
+        if psbt.version >= 2 {
+            // make sure the transaction fields are all present
+            psbt.unsigned_tx()?;
+        }
+        Ok(psbt)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = PSBT_MAGIC.to_vec();
+        if let Some(tx) = &self.unsigned_tx {
+            write_pair(&mut data, &[PSBT_GLOBAL_UNSIGNED_TX], &tx.serialize_without_witness());
+        }
+        for (xpub, source) in &self.xpubs {
+            write_pair(&mut data, &key_with_data(PSBT_GLOBAL_XPUB, xpub), &source.serialize());
+        }
+        if self.version >= 2 {
+            write_pair(&mut data, &[PSBT_GLOBAL_TX_VERSION], &self.tx_version.to_le_bytes());
+            if let Some(locktime) = self.fallback_locktime {
+                write_pair(&mut data, &[PSBT_GLOBAL_FALLBACK_LOCKTIME], &locktime.to_le_bytes());
+            }
+            write_pair(
+                &mut data,
+                &[PSBT_GLOBAL_INPUT_COUNT],
+                &compact_size(self.inputs.len() as u64),
+            );
+            write_pair(
+                &mut data,
+                &[PSBT_GLOBAL_OUTPUT_COUNT],
+                &compact_size(self.outputs.len() as u64),
+            );
+            if let Some(flags) = self.tx_modifiable {
+                write_pair(&mut data, &[PSBT_GLOBAL_TX_MODIFIABLE], &[flags]);
+            }
+        }
+        if self.version > 0 {
+            write_pair(&mut data, &[PSBT_GLOBAL_VERSION], &self.version.to_le_bytes());
+        }
+        for (key, value) in &self.unknown {
+            write_pair(&mut data, key, value);
+        }
+        data.push(0x00);
+
+        for input in &self.inputs {
+            data.extend(input.serialize(self.version));
+        }
+        for output in &self.outputs {
+            data.extend(output.serialize(self.version));
+        }
+        data
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Psbt, SpendError> {
+        let bytes = BASE64
+            .decode(encoded.trim())
+            .map_err(|e| invalid(&e.to_string()))?;
+        Psbt::parse(&bytes)
+    }
+
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(self.serialize())
+    }
+
+    // Combiner role: merge the signatures and metadata of PSBTs for the same transaction
+    pub fn combine(&mut self, other: Psbt) -> Result<(), SpendError> {
+        if self.unsigned_tx()?.txid() != other.unsigned_tx()?.txid() {
+            return Err(invalid("cannot combine PSBTs for different transactions"));
+        }
+        for (input, other_input) in self.inputs.iter_mut().zip(other.inputs) {
+            input.combine(other_input);
+        }
+        for (output, other_output) in self.outputs.iter_mut().zip(other.outputs) {
+            output.combine(other_output);
+        }
+        self.xpubs.extend(other.xpubs);
+        self.unknown.extend(other.unknown);
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+// PSBT roles: creator/updater, signer, finalizer and extractor
+use crate::balance::address::script_to_address;
+use crate::balance::balance::{btc_to_sats, get_p2wpkh_program, P2wpkhKey, WalletState};
+use crate::balance::cache::WalletCache;
+use crate::psbt::psbt::{KeySource, Psbt};
+use crate::spend_funds::interpreter::verify_transaction;
+use crate::spend_funds::policy::check_policy;
+use crate::spend_funds::script::{parse_multisig_script, script_type, ScriptType};
+use crate::spend_funds::sighash::{get_commitment_hash, SighashType, SIGHASH_ALL};
+use crate::spend_funds::spend_p2wpkh::{get_p2wpkh_scriptcode, get_p2wsh_program, sign, SpendError};
+use crate::spend_funds::transaction::{Outpoint, Transaction, TxIn, Utxo};
+
+fn is_p2wpkh(script: &[u8]) -> bool {
+    script_type(script) == ScriptType::P2wpkh
+}
+
+fn is_p2wsh(script: &[u8]) -> bool {
+    script_type(script) == ScriptType::P2wsh
+}
+
+// Creator and updater: a PSBT spending `inputs` to `outputs`, filled in with
+// everything our wallet knows about them
+pub fn create_psbt(
+    wallet_state: &WalletState,
+    wallet_cache: &WalletCache,
+    inputs: &[Outpoint],
+    outputs: Vec<Utxo>,
+    version: u32,
+) -> Result<Psbt, SpendError> {
+    let tx = Transaction::new(inputs.iter().map(|outpoint| TxIn::new(*outpoint)).collect(), outputs);
+    let mut psbt = Psbt::from_unsigned_tx(tx, version);
+    update_psbt(&mut psbt, wallet_state, wallet_cache)?;
+
+    // our coins we can't give key origins for could never be signed
+    for (index, input) in psbt.inputs.iter().enumerate() {
+        let Some(spent) = &input.witness_utxo else {
+            continue;
+        };
+        if wallet_state.is_ours(&spent.script_pubkey) && input.bip32_derivation.is_empty() {
+            return Err(SpendError::InvalidTransaction(format!(
+                "input {} spends one of our {} coins, PSBTs only support our P2WPKH and multisig coins",
+                index,
+                script_type(&spent.script_pubkey)
+            )));
+        }
+    }
+    Ok(psbt)
+}
+
+fn key_source(wallet_state: &WalletState, path: Vec<u32>) -> KeySource {
+    KeySource {
+        fingerprint: wallet_state.fingerprint,
+        path,
+    }
+}
+
+// Updater: add spent outputs, scripts and key origins for inputs and outputs we own
+pub fn update_psbt(
+    psbt: &mut Psbt,
+    wallet_state: &WalletState,
+    wallet_cache: &WalletCache,
+) -> Result<(), SpendError> {
+    let tx = psbt.unsigned_tx()?;
+
+    for (psbt_input, txin) in psbt.inputs.iter_mut().zip(&tx.inputs) {
+        let outpoint = (txin.previous_output.txid_hex(), txin.previous_output.index);
+        let coin = wallet_state
+            .utxos
+            .get(&outpoint)
+            .or_else(|| wallet_state.watched_utxos.get(&outpoint));
+        if let Some((script_pubkey, value)) = coin {
+            psbt_input.witness_utxo = Some(Utxo {
+                script_pubkey: script_pubkey.clone(),
+                amount: btc_to_sats(*value),
+            });
+        }
+        let Some(spent) = &psbt_input.witness_utxo else {
+            continue;
+        };
+
+        let mut keys: Vec<P2wpkhKey> = wallet_state
+            .p2wpkh_key_for_script(&spent.script_pubkey)
+            .into_iter()
+            .collect();
+        for policy in &wallet_cache.multisig {
+            let script = hex::decode(&policy.witness_script).unwrap_or_default();
+            if get_p2wsh_program(&script, Some(0)) == spent.script_pubkey {
+                for key in &policy.keys {
+                    let pubkey = hex::decode(key).unwrap_or_default();
+                    keys.extend(wallet_state.p2wpkh_key_for_pubkey(&pubkey));
+                }
+                psbt_input.witness_script = Some(script);
+            }
+        }
+        for key in keys {
+            psbt_input.bip32_derivation.insert(key.public_key, key_source(wallet_state, key.path));
+        }
+    }
+
+    // mark our own outputs (change) so signers can recognise them
+    for (psbt_output, txout) in psbt.outputs.iter_mut().zip(&tx.outputs) {
+        if let Some(key) = wallet_state.p2wpkh_key_for_script(&txout.script_pubkey) {
+            psbt_output.bip32_derivation.insert(key.public_key, key_source(wallet_state, key.path));
+        }
+    }
+    Ok(())
+}
+
+// Signer: add our signatures to every input we can sign, returns how many were added.
+// Keys are found through the inputs' BIP32 derivations, or directly by public key.
+// Inputs without utxo information are someone else's to sign and are skipped.
+pub fn sign_psbt(psbt: &mut Psbt, wallet_state: &WalletState) -> Result<usize, SpendError> {
+    let tx = psbt.unsigned_tx()?;
+    let mut signed = 0;
+
+    for index in 0..psbt.inputs.len() {
+        if psbt.inputs[index].final_script_witness.is_some() {
+            continue;
+        }
+        let Ok(spent) = psbt.spent_output(index) else {
+            continue;
+        };
+        let input = &psbt.inputs[index];
+
+        let (scriptcode, candidate_keys) = if is_p2wpkh(&spent.script_pubkey) {
+            let key = wallet_state.p2wpkh_key_for_script(&spent.script_pubkey);
+            (get_p2wpkh_scriptcode(&spent), key.into_iter().collect::<Vec<_>>())
+        } else if is_p2wsh(&spent.script_pubkey) {
+            let Some(witness_script) = input.witness_script.clone() else {
+                continue;
+            };
+            if get_p2wsh_program(&witness_script, Some(0)) != spent.script_pubkey {
+                return Err(SpendError::InvalidScript(format!(
+                    "witness script of input {} does not match its output",
+                    index
+                )));
+            }
+            let keys = parse_multisig_script(&witness_script)
+                .map(|(_, keys)| keys)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|key| wallet_state.p2wpkh_key_for_pubkey(key))
+                .collect();
+            (witness_script, keys)
+        } else {
+            continue;
+        };
+
+        // keys the updater told us about take priority over public key matches
+        let mut keys: Vec<P2wpkhKey> = input
+            .bip32_derivation
+            .values()
+            .filter_map(|source| wallet_state.p2wpkh_key_for_path(&source.fingerprint, &source.path))
+            .collect();
+        for key in candidate_keys {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+
+        let sighash_type = SighashType(input.sighash_type.unwrap_or(SIGHASH_ALL));
+        let commitment_hash = get_commitment_hash(&tx, index, &scriptcode, spent.amount, sighash_type);
+
+        for key in keys {
+            let pubkey = key.public_key;
+            let in_script = is_p2wpkh(&spent.script_pubkey) && get_p2wpkh_program(&pubkey) == spent.script_pubkey
+                || scriptcode.windows(pubkey.len()).any(|w| w == pubkey.as_slice());
+            if !in_script || psbt.inputs[index].partial_sigs.contains_key(&pubkey) {
+                continue;
+            }
+            let signature = sign(&key.private_key, commitment_hash.clone(), sighash_type);
+            psbt.inputs[index].partial_sigs.insert(pubkey, signature);
+            signed += 1;
+        }
+    }
+    Ok(signed)
+}
+
+// Finalizer: turn partial signatures into final witnesses where there are enough of them.
+// Returns the number of inputs still missing signatures.
+pub fn finalize_psbt(psbt: &mut Psbt) -> Result<usize, SpendError> {
+    let mut incomplete = 0;
+    for index in 0..psbt.inputs.len() {
+        if psbt.inputs[index].final_script_witness.is_some() {
+            continue;
+        }
+        let spent = psbt.spent_output(index)?;
+        let input = &mut psbt.inputs[index];
+
+        let witness = if is_p2wpkh(&spent.script_pubkey) {
+            input
+                .partial_sigs
+                .iter()
+                .next()
+                .map(|(pubkey, sig)| vec![sig.clone(), pubkey.clone()])
+        } else if is_p2wsh(&spent.script_pubkey) {
+            input.witness_script.as_ref().and_then(|witness_script| {
+                let (threshold, keys) = parse_multisig_script(witness_script)?;
+                let mut witness = vec![Vec::new()];
+                witness.extend(
+                    keys.iter()
+                        .filter_map(|key| input.partial_sigs.get(key).cloned())
+                        .take(threshold),
+                );
+                if witness.len() <= threshold {
+                    return None;
+                }
+                witness.push(witness_script.clone());
+                Some(witness)
+            })
+        } else {
+            None
+        };
+
+        match witness {
+            Some(witness) => {
+                // BIP174: drop everything but the utxo and final fields
+                input.final_script_witness = Some(witness);
+                input.partial_sigs.clear();
+                input.sighash_type = None;
+                input.redeem_script = None;
+                input.witness_script = None;
+                input.bip32_derivation.clear();
+            }
+            None => incomplete += 1,
+        }
+    }
+    Ok(incomplete)
+}
+
+// Extractor: the network transaction of a fully finalized PSBT, checked
+// against the outputs it spends and against relay policy
+pub fn extract_transaction(psbt: &Psbt) -> Result<Transaction, SpendError> {
+    let mut tx = psbt.unsigned_tx()?;
+    for (index, (txin, input)) in tx.inputs.iter_mut().zip(&psbt.inputs).enumerate() {
+        if input.final_script_witness.is_none() && input.final_script_sig.is_none() {
+            return Err(SpendError::SigningError(format!("input {} is not finalized", index)));
+        }
+        txin.script_sig = input.final_script_sig.clone().unwrap_or_default();
+        txin.witness = input.final_script_witness.clone().unwrap_or_default();
+    }
+    let prevouts = (0..tx.inputs.len())
+        .map(|index| psbt.spent_output(index))
+        .collect::<Result<Vec<_>, _>>()?;
+    verify_transaction(&tx, &prevouts)?;
+    check_policy(&tx, &prevouts, tx.weight() as u64)?;
+    Ok(tx)
+}
+
+// Human readable summary of a PSBT
+pub fn describe_psbt(psbt: &Psbt) -> Result<String, SpendError> {
+    let tx = psbt.unsigned_tx()?;
+    let mut lines = vec![
+        format!("PSBT version: {}", psbt.version),
+        format!("TXID: {}", hex::encode(tx.txid())),
+        format!("Transaction version: {}, locktime: {}", tx.version, tx.locktime),
+    ];
+
+    let mut total_in = Some(0);
+    lines.push(format!("Inputs: {}", tx.inputs.len()));
+    for (index, (txin, input)) in tx.inputs.iter().zip(&psbt.inputs).enumerate() {
+        let spent = psbt.spent_output(index).ok();
+        total_in = total_in.zip(spent.as_ref()).map(|(total, utxo)| total + utxo.amount);
+        let status = if input.final_script_witness.is_some() || input.final_script_sig.is_some() {
+            "finalized".to_string()
+        } else {
+            format!("{} signature(s)", input.partial_sigs.len())
+        };
+        lines.push(format!(
+            "- {}:{}, Amount: {}, {}",
+            txin.previous_output.txid_hex(),
+            txin.previous_output.index,
+            spent.map_or("unknown".to_string(), |utxo| format!("{} sats", utxo.amount)),
+            status
+        ));
+        for (pubkey, source) in &input.bip32_derivation {
+            lines.push(format!("    key {} {}", hex::encode(pubkey), source));
+        }
+    }
+
+    lines.push(format!("Outputs: {}", tx.outputs.len()));
+    for (output, psbt_output) in tx.outputs.iter().zip(&psbt.outputs) {
+        let destination = script_to_address(&output.script_pubkey)
+            .unwrap_or_else(|| hex::encode(&output.script_pubkey));
+        let change = if psbt_output.bip32_derivation.is_empty() { "" } else { " (ours)" };
+        lines.push(format!("- {}, Amount: {} sats{}", destination, output.amount, change));
+    }
+
+    if let Some(total_in) = total_in {
+        let total_out: u64 = tx.outputs.iter().map(|output| output.amount).sum();
+        lines.push(format!("Fee: {} sats", total_in.saturating_sub(total_out)));
+    }
+    Ok(lines.join("\n"))
+}
@@ -0,0 +1,251 @@
+// Signers: who holds the keys that sign our PSBTs. Either the wallet itself
+// or an external process speaking HWI's command line protocol, the way
+// bitcoind's -signer talks to hardware wallets.
+use crate::balance::balance::WalletState;
+use crate::psbt::psbt::Psbt;
+use crate::psbt::roles::sign_psbt;
+use crate::spend_funds::script::ScriptType;
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use serde_json::Value;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// Chain name passed to external signers
+const CHAIN: &str = "signet";
+
+// Output descriptors of an account's receive and change addresses
+pub struct Descriptors {
+    pub receive: Vec<String>,
+    pub internal: Vec<String>,
+}
+
+pub trait Signer {
+    // Fingerprint of the signer's master key
+    fn fingerprint(&self) -> [u8; 4];
+    // Descriptors of account `account` for every script type the signer supports
+    fn descriptors(&self, account: u32) -> Result<Descriptors, SpendError>;
+    // Add signatures to every input the signer holds keys for, returns how many were added
+    fn sign(&self, psbt: &mut Psbt) -> Result<usize, SpendError>;
+}
+
+fn invalid(msg: &str) -> SpendError {
+    SpendError::SigningError(msg.to_string())
+}
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn polymod(c: u64, value: u64) -> u64 {
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7ffffffff) << 5) ^ value;
+    for (bit, generator) in [0xf5dee51989, 0xa9fdca3312, 0x1bab10e32d, 0x3706b1677a, 0x644d626ffd]
+        .into_iter()
+        .enumerate()
+    {
+        if c0 & (1 << bit) != 0 {
+            c ^= generator;
+        }
+    }
+    c
+}
+
+// `descriptor` followed by "#" and its 8 character checksum (BIP380)
+pub fn add_checksum(descriptor: &str) -> Result<String, SpendError> {
+    let mut c = 1;
+    let mut class = 0;
+    let mut class_count = 0;
+    for ch in descriptor.chars() {
+        let position = INPUT_CHARSET
+            .find(ch)
+            .ok_or(invalid(&format!("invalid character {:?} in descriptor", ch)))? as u64;
+        c = polymod(c, position & 31);
+        class = class * 3 + (position >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            c = polymod(c, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        c = polymod(c, class);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+
+    let checksum: String = (0..8)
+        .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+        .collect();
+    Ok(format!("{}#{}", descriptor, checksum))
+}
+
+// The wallet's own keys
+pub struct SoftwareSigner<'a> {
+    wallet_state: &'a WalletState,
+}
+
+impl<'a> SoftwareSigner<'a> {
+    pub fn new(wallet_state: &'a WalletState) -> Self {
+        SoftwareSigner { wallet_state }
+    }
+}
+
+impl Signer for SoftwareSigner<'_> {
+    fn fingerprint(&self) -> [u8; 4] {
+        self.wallet_state.fingerprint
+    }
+
+    fn descriptors(&self, account: u32) -> Result<Descriptors, SpendError> {
+        if account != 0 {
+            return Err(invalid("the wallet only derives account 0"));
+        }
+        let mut descriptors = Descriptors {
+            receive: Vec::new(),
+            internal: Vec::new(),
+        };
+        // only what sign_psbt can sign: BIP84 keys, receive and change
+        for (script_type, key) in &self.wallet_state.account_keys {
+            if *script_type == ScriptType::P2wpkh {
+                descriptors.receive.push(add_checksum(&format!("wpkh({}/0/*)", key))?);
+                descriptors.internal.push(add_checksum(&format!("wpkh({}/1/*)", key))?);
+            }
+        }
+        Ok(descriptors)
+    }
+
+    fn sign(&self, psbt: &mut Psbt) -> Result<usize, SpendError> {
+        sign_psbt(psbt, self.wallet_state)
+    }
+}
+
+// A device or program run as `<command> [--stdin] --fingerprint <fp> --chain signet <call>`,
+// answering with JSON on stdout, e.g. HWI or a script wrapping another wallet
+pub struct ExternalSigner {
+    command: String,
+    fingerprint: [u8; 4],
+}
+
+// Run `command` with `args`, writing `input` to its stdin, and parse its JSON answer
+fn run(command: &str, args: &[&str], input: Option<&str>) -> Result<Value, SpendError> {
+    let mut words = command.split_whitespace();
+    let program = words.next().ok_or(invalid("empty signer command"))?;
+    let mut child = Command::new(program)
+        .args(words)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| invalid(&format!("can't run {}: {}", program, e)))?;
+    if let Some(input) = input {
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        stdin
+            .write_all(input.as_bytes())
+            .map_err(|e| invalid(&format!("can't write to {}: {}", program, e)))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| invalid(&format!("{} failed: {}", program, e)))?;
+    // HWI reports errors as {"error": ...} along with a failure exit code
+    let value = serde_json::from_slice::<Value>(&output.stdout);
+    if let Some(error) = value.as_ref().ok().and_then(|value| value.get("error")) {
+        return Err(invalid(&format!("{}: {}", program, error.as_str().unwrap_or(&error.to_string()))));
+    }
+    if !output.status.success() {
+        return Err(invalid(&format!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    value.map_err(|e| invalid(&format!("{} returned invalid JSON: {}", program, e)))
+}
+
+fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 4], SpendError> {
+    hex::decode(fingerprint)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(invalid(&format!("invalid fingerprint {}", fingerprint)))
+}
+
+fn string_list(value: &Value, field: &str) -> Result<Vec<String>, SpendError> {
+    value[field]
+        .as_array()
+        .and_then(|items| items.iter().map(|item| item.as_str().map(str::to_string)).collect())
+        .ok_or(invalid(&format!("signer returned no {} descriptors", field)))
+}
+
+impl ExternalSigner {
+    // Devices `command` can sign with: their fingerprints and models
+    pub fn enumerate(command: &str) -> Result<Vec<([u8; 4], String)>, SpendError> {
+        let devices = run(command, &["enumerate"], None)?;
+        devices
+            .as_array()
+            .ok_or(invalid("enumerate did not return a list"))?
+            .iter()
+            .map(|device| {
+                let fingerprint = device["fingerprint"].as_str().ok_or(invalid("device without fingerprint"))?;
+                let model = device["model"].as_str().or(device["type"].as_str()).unwrap_or("unknown");
+                Ok((parse_fingerprint(fingerprint)?, model.to_string()))
+            })
+            .collect()
+    }
+
+    // The device of `command` with `fingerprint`, or its only device
+    pub fn connect(command: &str, fingerprint: Option<&str>) -> Result<Self, SpendError> {
+        let devices = Self::enumerate(command)?;
+        let fingerprint = match fingerprint {
+            Some(fingerprint) => {
+                let fingerprint = parse_fingerprint(fingerprint)?;
+                if !devices.iter().any(|(device, _)| *device == fingerprint) {
+                    return Err(invalid(&format!("no device with fingerprint {}", hex::encode(fingerprint))));
+                }
+                fingerprint
+            }
+            None => match devices.as_slice() {
+                [(fingerprint, _)] => *fingerprint,
+                [] => return Err(invalid("no signing device found")),
+                _ => return Err(invalid("several signing devices found, choose one with --fingerprint")),
+            },
+        };
+        Ok(ExternalSigner {
+            command: command.to_string(),
+            fingerprint,
+        })
+    }
+}
+
+impl Signer for ExternalSigner {
+    fn fingerprint(&self) -> [u8; 4] {
+        self.fingerprint
+    }
+
+    fn descriptors(&self, account: u32) -> Result<Descriptors, SpendError> {
+        let fingerprint = hex::encode(self.fingerprint);
+        let account = account.to_string();
+        let args = ["--fingerprint", &fingerprint, "--chain", CHAIN, "getdescriptors", "--account", &account];
+        let value = run(&self.command, &args, None)?;
+        Ok(Descriptors {
+            receive: string_list(&value, "receive")?,
+            internal: string_list(&value, "internal")?,
+        })
+    }
+
+    // The PSBT goes through stdin, it can be too large for the command line
+    fn sign(&self, psbt: &mut Psbt) -> Result<usize, SpendError> {
+        let fingerprint = hex::encode(self.fingerprint);
+        let args = ["--stdin", "--fingerprint", &fingerprint, "--chain", CHAIN];
+        let value = run(&self.command, &args, Some(&format!("signtx {}\n", psbt.to_base64())))?;
+        let signed = Psbt::from_base64(value["psbt"].as_str().ok_or(invalid("signer returned no PSBT"))?)?;
+
+        let count = |psbt: &Psbt| psbt.inputs.iter().map(|input| input.partial_sigs.len()).sum::<usize>();
+        let before = count(psbt);
+        // combine refuses a PSBT for another transaction
+        psbt.combine(signed)?;
+        Ok(count(psbt) - before)
+    }
+}
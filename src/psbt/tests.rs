@@ -0,0 +1,242 @@
+// PSBT roles end to end: two wallets each signing their own input of a shared
+// transaction, in both PSBT versions
+use crate::balance::balance::{get_p2wpkh_program, parse_derivation_path, WalletState};
+use crate::balance::cache::WalletCache;
+use crate::psbt::psbt::Psbt;
+use crate::psbt::roles::{create_psbt, extract_transaction, finalize_psbt, sign_psbt, update_psbt};
+use crate::psbt::signer::add_checksum;
+use crate::spend_funds::transaction::{Outpoint, Transaction, TxIn, Utxo};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+fn pubkey(privkey: &[u8; 32]) -> Vec<u8> {
+    let secret_key = SecretKey::from_slice(privkey).unwrap();
+    PublicKey::from_secret_key(&Secp256k1::new(), &secret_key)
+        .serialize()
+        .to_vec()
+}
+
+// A wallet with a single P2WPKH key holding one coin at `outpoint`
+fn wallet(privkey: [u8; 32], fingerprint: [u8; 4], outpoint: &Outpoint, amount: f64) -> WalletState {
+    let pubkey = pubkey(&privkey);
+    let program = get_p2wpkh_program(&pubkey);
+    let mut wallet_state = WalletState {
+        fingerprint,
+        ..Default::default()
+    };
+    wallet_state.utxos.insert(
+        (outpoint.txid_hex(), outpoint.index),
+        (program.clone(), amount),
+    );
+    wallet_state.private_keys.push(privkey.to_vec());
+    wallet_state.public_keys.push(pubkey);
+    wallet_state.witness_programs.push(program);
+    wallet_state
+}
+
+const OURS: Outpoint = Outpoint {
+    txid: [0xaa; 32],
+    index: 0,
+};
+const THEIRS: Outpoint = Outpoint {
+    txid: [0xbb; 32],
+    index: 1,
+};
+
+fn round_trip(version: u32) {
+    let ours = wallet([0x11; 32], [1, 1, 1, 1], &OURS, 0.001);
+    let theirs = wallet([0x22; 32], [2, 2, 2, 2], &THEIRS, 0.0005);
+    let destination = Utxo {
+        script_pubkey: get_p2wpkh_program(&pubkey(&[0x33; 32])),
+        amount: 149_000,
+    };
+
+    // creator: we only know our own coin
+    let psbt = create_psbt(
+        &ours,
+        &WalletCache::default(),
+        &[OURS, THEIRS],
+        vec![destination.clone()],
+        version,
+    )
+    .unwrap();
+    assert_eq!(psbt.version, version);
+    assert!(psbt.inputs[0].witness_utxo.is_some());
+    assert!(psbt.inputs[1].witness_utxo.is_none());
+
+    // the PSBT survives serialization unchanged
+    let encoded = psbt.to_base64();
+    assert_eq!(Psbt::from_base64(&encoded).unwrap().serialize(), psbt.serialize());
+
+    // each signer skips the input it can't sign
+    let mut our_copy = Psbt::from_base64(&encoded).unwrap();
+    assert_eq!(sign_psbt(&mut our_copy, &ours).unwrap(), 1);
+    let mut their_copy = Psbt::from_base64(&encoded).unwrap();
+    update_psbt(&mut their_copy, &theirs, &WalletCache::default()).unwrap();
+    assert_eq!(sign_psbt(&mut their_copy, &theirs).unwrap(), 1);
+    // signing again adds nothing
+    assert_eq!(sign_psbt(&mut their_copy, &theirs).unwrap(), 0);
+
+    // neither half can be finalized alone
+    assert!(finalize_psbt(&mut our_copy.clone()).is_err());
+    assert_eq!(finalize_psbt(&mut their_copy.clone()).unwrap(), 1);
+
+    // combiner, finalizer and extractor
+    let mut combined = Psbt::from_base64(&our_copy.to_base64()).unwrap();
+    combined.combine(their_copy).unwrap();
+    assert_eq!(combined.inputs[0].partial_sigs.len(), 1);
+    assert_eq!(combined.inputs[1].partial_sigs.len(), 1);
+    assert_eq!(finalize_psbt(&mut combined).unwrap(), 0);
+    assert!(combined.inputs.iter().all(|input| input.partial_sigs.is_empty()));
+
+    let tx = extract_transaction(&Psbt::from_base64(&combined.to_base64()).unwrap()).unwrap();
+    assert_eq!(tx.txid(), psbt.unsigned_tx().unwrap().txid());
+    assert_eq!(tx.outputs.len(), 1);
+    assert_eq!(tx.outputs[0].serialize(), destination.serialize());
+    assert_eq!(tx.inputs[0].witness[1], ours.public_keys[0]);
+    assert_eq!(tx.inputs[1].witness[1], theirs.public_keys[0]);
+}
+
+#[test]
+fn round_trip_v0() {
+    round_trip(0);
+}
+
+#[test]
+fn round_trip_v2() {
+    round_trip(2);
+}
+
+#[test]
+fn non_witness_utxo_must_match_outpoint() {
+    let prev_tx = Transaction::new(
+        vec![TxIn::new(THEIRS)],
+        vec![
+            Utxo {
+                script_pubkey: get_p2wpkh_program(&pubkey(&[0x11; 32])),
+                amount: 100_000,
+            };
+            2
+        ],
+    );
+    let mut prev_txid = prev_tx.txid();
+    prev_txid.reverse();
+    let outpoint = Outpoint {
+        txid: prev_txid,
+        index: 1,
+    };
+
+    for version in [0, 2] {
+        let tx = Transaction::new(vec![TxIn::new(outpoint)], Vec::new());
+        let mut psbt = Psbt::from_unsigned_tx(tx, version);
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx.serialize());
+        assert_eq!(
+            psbt.spent_output(0).unwrap().serialize(),
+            prev_tx.outputs[1].serialize()
+        );
+
+        // a different transaction with an output at the same index
+        let mut other = prev_tx.clone();
+        other.locktime = 1;
+        psbt.inputs[0].non_witness_utxo = Some(other.serialize());
+        assert!(psbt.spent_output(0).is_err());
+    }
+}
+
+#[test]
+fn extract_checks_policy() {
+    let ours = wallet([0x11; 32], [1, 1, 1, 1], &OURS, 0.001);
+    for (amount, allowed) in [(99_000, true), (200, false)] {
+        let destination = Utxo {
+            script_pubkey: get_p2wpkh_program(&pubkey(&[0x33; 32])),
+            amount,
+        };
+        let mut psbt = create_psbt(&ours, &WalletCache::default(), &[OURS], vec![destination], 2).unwrap();
+        assert_eq!(sign_psbt(&mut psbt, &ours).unwrap(), 1);
+        assert_eq!(finalize_psbt(&mut psbt).unwrap(), 0);
+        // a valid spend of a dust output is still refused
+        assert_eq!(extract_transaction(&psbt).is_ok(), allowed);
+    }
+}
+
+// A coin on our BIP84 change chain gets its key origin, and a change output
+// is marked as ours, so it can be signed like a receive coin
+#[test]
+fn change_chain_psbt() {
+    let mut ours = wallet([0x11; 32], [1, 1, 1, 1], &OURS, 0.001);
+    let change_keys = [[0x61; 32], [0x62; 32]];
+    ours.change_keys = change_keys.iter().map(|key| key.to_vec()).collect();
+    ours.change_programs = change_keys.iter().map(|key| get_p2wpkh_program(&pubkey(key))).collect();
+    ours.utxos.insert((THEIRS.txid_hex(), THEIRS.index), (ours.change_programs[1].clone(), 0.0005));
+    let outputs = vec![
+        Utxo {
+            script_pubkey: get_p2wpkh_program(&pubkey(&[0x33; 32])),
+            amount: 30_000,
+        },
+        Utxo {
+            script_pubkey: ours.change_programs[0].clone(),
+            amount: 19_000,
+        },
+    ];
+
+    let mut psbt = create_psbt(&ours, &WalletCache::default(), &[THEIRS], outputs, 2).unwrap();
+    let origin = &psbt.inputs[0].bip32_derivation[&pubkey(&[0x62; 32])];
+    assert_eq!(origin.fingerprint, [1, 1, 1, 1]);
+    assert_eq!(origin.path, parse_derivation_path("m/84h/1h/0h/1/1"));
+    assert!(psbt.outputs[0].bip32_derivation.is_empty());
+    let origin = &psbt.outputs[1].bip32_derivation[&pubkey(&[0x61; 32])];
+    assert_eq!(origin.path, parse_derivation_path("m/84h/1h/0h/1/0"));
+
+    assert_eq!(sign_psbt(&mut psbt, &ours).unwrap(), 1);
+    assert_eq!(finalize_psbt(&mut psbt).unwrap(), 0);
+    let tx = extract_transaction(&psbt).unwrap();
+    assert_eq!(tx.inputs[0].witness[1], pubkey(&[0x62; 32]));
+}
+
+// Our coins a PSBT can't describe are refused rather than left unsignable
+#[test]
+fn create_refuses_undescribed_coins() {
+    let mut ours = wallet([0x11; 32], [1, 1, 1, 1], &OURS, 0.001);
+    let taproot_program = [[0x51, 0x20].as_slice(), &[0x44; 32]].concat();
+    ours.taproot_programs.push(taproot_program.clone());
+    ours.utxos.insert((THEIRS.txid_hex(), THEIRS.index), (taproot_program, 0.0005));
+    let destination = Utxo {
+        script_pubkey: get_p2wpkh_program(&pubkey(&[0x33; 32])),
+        amount: 40_000,
+    };
+    assert!(create_psbt(&ours, &WalletCache::default(), &[THEIRS], vec![destination.clone()], 2).is_err());
+    assert!(create_psbt(&ours, &WalletCache::default(), &[OURS], vec![destination], 2).is_ok());
+}
+
+// BIP370: the locktime type every input with a requirement accepts, heights
+// preferred, and no transaction when they disagree
+#[test]
+fn required_locktimes() {
+    let tx = Transaction::new(vec![TxIn::new(OURS), TxIn::new(THEIRS)], Vec::new());
+    let mut psbt = Psbt::from_unsigned_tx(tx, 2);
+    psbt.fallback_locktime = Some(7);
+    assert_eq!(psbt.unsigned_tx().unwrap().locktime, 7);
+
+    let cases = [
+        ([(Some(100), None), (Some(200), Some(600_000_000))], Some(200)),
+        ([(Some(100), Some(500_000_000)), (None, Some(600_000_000))], Some(600_000_000)),
+        ([(None, None), (None, Some(600_000_000))], Some(600_000_000)),
+        ([(Some(100), None), (None, Some(600_000_000))], None),
+    ];
+    for (locks, expected) in cases {
+        for (input, (height, time)) in psbt.inputs.iter_mut().zip(locks) {
+            input.required_height_locktime = height;
+            input.required_time_locktime = time;
+        }
+        assert_eq!(psbt.unsigned_tx().ok().map(|tx| tx.locktime), expected);
+    }
+}
+
+// BIP380 test vectors
+#[test]
+fn descriptor_checksums() {
+    assert_eq!(add_checksum("raw(deadbeef)").unwrap(), "raw(deadbeef)#89f8spxm");
+    // an error in the payload changes the checksum
+    assert_ne!(add_checksum("raw(dedbeef)").unwrap(), "raw(dedbeef)#89f8spxm");
+    // characters outside the descriptor character set
+    assert!(add_checksum("raw(Ü)").is_err());
+}
@@ -0,0 +1,70 @@
+// Batch payments: many recipients paid by a single transaction
+use crate::balance::address::address_to_script;
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use crate::spend_funds::transaction::Utxo;
+use csv::ReaderBuilder;
+use std::fs::File;
+
+// One row of a payments file
+pub struct Recipient {
+    pub address: String,
+    pub amount: u64,
+}
+
+impl Recipient {
+    pub fn output(&self) -> Result<Utxo, SpendError> {
+        Ok(Utxo {
+            script_pubkey: address_to_script(&self.address)?,
+            amount: self.amount,
+        })
+    }
+}
+
+// Read "<address>,<amount in sats>" rows, with an optional "address,amount" header
+pub fn read_recipients(file_path: &str) -> Result<Vec<Recipient>, SpendError> {
+    let file = File::open(file_path)
+        .map_err(|e| SpendError::InvalidTransaction(format!("can't read {}: {}", file_path, e)))?;
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_reader(file);
+
+    let mut recipients = Vec::new();
+    for (index, result) in reader.records().enumerate() {
+        let record = result.map_err(|e| SpendError::InvalidTransaction(format!("{}: {}", file_path, e)))?;
+        let line = record.position().map_or(index as u64 + 1, |position| position.line());
+        if record.len() != 2 {
+            return Err(SpendError::InvalidTransaction(format!(
+                "{} line {}: expected <address>,<amount>",
+                file_path, line
+            )));
+        }
+        if index == 0 && record[1].eq_ignore_ascii_case("amount") {
+            continue;
+        }
+
+        let amount = record[1]
+            .parse()
+            .ok()
+            .filter(|amount| *amount > 0)
+            .ok_or(SpendError::InvalidTransaction(format!(
+                "{} line {}: invalid amount {}",
+                file_path, line, &record[1]
+            )))?;
+        let recipient = Recipient {
+            address: record[0].to_string(),
+            amount,
+        };
+        // catch bad addresses with their line number
+        recipient
+            .output()
+            .map_err(|e| SpendError::InvalidTransaction(format!("{} line {}: {}", file_path, line, e)))?;
+        recipients.push(recipient);
+    }
+
+    if recipients.is_empty() {
+        return Err(SpendError::InvalidTransaction(format!("{} has no recipients", file_path)));
+    }
+    Ok(recipients)
+}
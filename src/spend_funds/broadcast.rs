@@ -0,0 +1,152 @@
+// Sending transactions to the node: a testmempoolaccept preflight, then
+// sendrawtransaction, then recording the spend in the wallet cache
+use crate::balance::balance::{bcli, BalanceError, WalletState};
+use crate::balance::cache::{MempoolExit, WalletCache};
+use crate::spend_funds::interpreter::lookup_prevout;
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use crate::spend_funds::transaction::{Transaction, Utxo};
+use serde_json::Value;
+use std::collections::HashSet;
+
+// Ask the node whether it would accept the transaction, with its reject reason if not
+pub fn test_mempool_accept(tx: &Transaction) -> Result<(), SpendError> {
+    let tx_hex = hex::encode(tx.serialize());
+    let output = bcli(&format!("-signet testmempoolaccept [\"{}\"]", tx_hex))?;
+    let output = String::from_utf8_lossy(&output);
+    let result: Value = serde_json::from_str(&output)
+        .map_err(|_| SpendError::InvalidTransaction(format!("testmempoolaccept failed: {}", output.trim())))?;
+
+    let result = &result[0];
+    if result["allowed"].as_bool() == Some(true) {
+        return Ok(());
+    }
+    let reason = result["reject-reason"].as_str().unwrap_or("unknown reason");
+    Err(SpendError::InvalidTransaction(format!("rejected by the mempool: {}", reason)))
+}
+
+// Submit the transaction, returning its txid
+pub fn send_raw_transaction(tx: &Transaction) -> Result<String, SpendError> {
+    let txid = hex::encode(tx.txid());
+    let output = bcli(&format!("-signet sendrawtransaction {}", hex::encode(tx.serialize())))?;
+    let output = String::from_utf8_lossy(&output);
+    // on failure bitcoin-cli prints the error instead of the txid
+    if output.trim() != txid {
+        return Err(SpendError::InvalidTransaction(format!(
+            "sendrawtransaction failed: {}",
+            output.trim()
+        )));
+    }
+    Ok(txid)
+}
+
+// Txids of the transactions in the node's mempool
+pub fn mempool_txids() -> Result<HashSet<String>, SpendError> {
+    let output = bcli("-signet getrawmempool")?;
+    let output = String::from_utf8_lossy(&output);
+    serde_json::from_str(&output)
+        .map_err(|_| SpendError::InvalidTransaction(format!("getrawmempool failed: {}", output.trim())))
+}
+
+// Whether output `vout` of `txid` is in the node's chain UTXO set, mempool spends ignored
+fn is_unspent(txid: &str, vout: u32) -> Result<bool, SpendError> {
+    let output = bcli(&format!("-signet gettxout {} {} false", txid, vout))?;
+    let output = String::from_utf8_lossy(&output);
+    // bitcoin-cli prints nothing for a spent or unknown output
+    if output.trim().is_empty() {
+        return Ok(false);
+    }
+    match serde_json::from_str::<Value>(&output) {
+        Ok(coin) if coin.is_object() => Ok(true),
+        _ => Err(SpendError::InvalidTransaction(format!("gettxout failed: {}", output.trim()))),
+    }
+}
+
+// What became of one of our transactions that left the mempool. Without
+// -txindex getrawtransaction can't find it once mined, so the UTXO set tells:
+// an unspent output means it was mined, an unspent input that it never was.
+pub fn mempool_exit(tx: &Transaction) -> Result<MempoolExit, SpendError> {
+    let txid = hex::encode(tx.txid());
+    let verbose = bcli(&format!("-signet getrawtransaction {} true", txid))?;
+    let confirmations = serde_json::from_slice::<Value>(&verbose)
+        .ok()
+        .and_then(|verbose| verbose["confirmations"].as_u64())
+        .unwrap_or(0);
+    if confirmations > 0 {
+        return Ok(MempoolExit::Confirmed);
+    }
+    for vout in 0..tx.outputs.len() {
+        if is_unspent(&txid, vout as u32)? {
+            return Ok(MempoolExit::Confirmed);
+        }
+    }
+    for input in &tx.inputs {
+        if is_unspent(&input.previous_output.txid_hex(), input.previous_output.index)? {
+            return Ok(MempoolExit::Dropped);
+        }
+    }
+    Ok(MempoolExit::Spent)
+}
+
+// The outputs `tx` spends. Those spent by our recorded transactions come from
+// the cache: they are no longer our coins, and the node only finds them with
+// -txindex once mined. The others are our coins or else the node's.
+pub fn wallet_prevouts(
+    tx: &Transaction,
+    wallet_state: &WalletState,
+    wallet_cache: &WalletCache,
+) -> Result<Vec<Utxo>, SpendError> {
+    tx.inputs
+        .iter()
+        .map(|input| {
+            let outpoint = &input.previous_output;
+            match wallet_cache.spent_output(&outpoint.txid_hex(), outpoint.index) {
+                Some(prevout) => Ok(prevout),
+                None => lookup_prevout(outpoint, Some(wallet_state)),
+            }
+        })
+        .collect()
+}
+
+// Preflight and send a transaction, then mark its inputs as spent and its
+// outputs to our scripts as unconfirmed coins. The caller saves the cache.
+pub fn broadcast_transaction(
+    tx: &Transaction,
+    wallet_state: &WalletState,
+    wallet_cache: &mut WalletCache,
+) -> Result<String, SpendError> {
+    let prevouts = wallet_prevouts(tx, wallet_state, wallet_cache)?;
+    let total_in: u64 = prevouts.iter().map(|prevout| prevout.amount).sum();
+    let total_out: u64 = tx.outputs.iter().map(|output| output.amount).sum();
+
+    test_mempool_accept(tx)?;
+    let txid = send_raw_transaction(tx)?;
+
+    let watch_scripts = wallet_cache.watch_scripts();
+    wallet_cache.record_transaction(tx, &prevouts, total_in.saturating_sub(total_out), |script| {
+        wallet_state.is_ours(script)
+            || watch_scripts.iter().any(|p| p == script)
+    });
+    Ok(txid)
+}
+
+// One of our transactions and the fee it paid, from the cache or else the node
+pub fn fetch_transaction(
+    wallet_state: &WalletState,
+    wallet_cache: &WalletCache,
+    txid: &str,
+) -> Result<(Transaction, u64), SpendError> {
+    if let Some(cached) = wallet_cache.wallet_transaction(txid) {
+        let bytes = hex::decode(&cached.hex).map_err(|e| BalanceError::ParseError(e.to_string()))?;
+        return Ok((Transaction::parse(&bytes)?, cached.fee));
+    }
+
+    let raw = bcli(&format!("-signet getrawtransaction {}", txid))?;
+    let raw = String::from_utf8_lossy(&raw);
+    let tx = hex::decode(raw.trim())
+        .ok()
+        .and_then(|bytes| Transaction::parse(&bytes).ok())
+        .ok_or(SpendError::InvalidTransaction(format!("can't find transaction {}: {}", txid, raw.trim())))?;
+    let total_in: u64 = wallet_prevouts(&tx, wallet_state, wallet_cache)?.iter().map(|prevout| prevout.amount).sum();
+    let total_out: u64 = tx.outputs.iter().map(|output| output.amount).sum();
+    Ok((tx, total_in.saturating_sub(total_out)))
+}
@@ -0,0 +1,266 @@
+// Building blocks for transactions spending the wallet's single key coins
+// (P2WPKH, BIP86 P2TR, BIP49 P2SH-P2WPKH and BIP44 P2PKH): coin listing,
+// weight estimates and signing
+use crate::balance::balance::{btc_to_sats, get_p2wpkh_program, WalletState};
+use crate::spend_funds::interpreter::verify_transaction;
+use crate::spend_funds::policy::{check_policy, dust_threshold};
+use crate::spend_funds::script::push_data;
+use crate::spend_funds::sighash::{
+    get_commitment_hash, get_legacy_commitment_hash, get_taproot_commitment_hash, SighashType, SIGHASH_ALL,
+    SIGHASH_DEFAULT,
+};
+use crate::spend_funds::spend_p2wpkh::{get_p2wpkh_scriptcode, sign, SpendError};
+use crate::spend_funds::taproot::sign_schnorr;
+use crate::spend_funds::transaction::{compact_size, Outpoint, Transaction, TxIn, Utxo};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+// version, input and output counts, locktime (4 WU per byte) and the segwit marker and flag
+const TX_OVERHEAD_WEIGHT: u64 = 10 * 4 + 2;
+// outpoint, empty scriptSig and sequence
+const INPUT_BASE_WEIGHT: u64 = (36 + 1 + 4) * 4;
+
+// A coin the wallet can spend on its own
+pub struct Coin {
+    pub outpoint: Outpoint,
+    pub utxo: Utxo,
+}
+
+// Our single key coins, largest first
+pub fn wallet_coins(wallet_state: &WalletState) -> Vec<Coin> {
+    let mut coins: Vec<Coin> = wallet_state
+        .spendable_utxos()
+        .filter(|(_, (script, _))| input_weight(wallet_state, script).is_some())
+        .filter_map(|((txid, vout), (script, value))| {
+            Some(Coin {
+                outpoint: format!("{}:{}", txid, vout).parse().ok()?,
+                utxo: Utxo {
+                    script_pubkey: script.clone(),
+                    amount: btc_to_sats(*value),
+                },
+            })
+        })
+        .collect();
+    coins.sort_by_key(|coin| std::cmp::Reverse(coin.utxo.amount));
+    coins
+}
+
+// Our coins at `outpoints`, chosen by hand (coin control)
+pub fn chosen_coins(wallet_state: &WalletState, outpoints: &[Outpoint]) -> Result<Vec<Coin>, SpendError> {
+    let mut coins: Vec<Coin> = Vec::new();
+    for outpoint in outpoints {
+        let key = (outpoint.txid_hex(), outpoint.index);
+        if coins.iter().any(|coin| coin.outpoint == *outpoint) {
+            return Err(SpendError::InvalidTransaction(format!("{}:{} is chosen twice", key.0, key.1)));
+        }
+        if wallet_state.frozen.contains(&key) {
+            return Err(SpendError::InvalidTransaction(format!("{}:{} is frozen", key.0, key.1)));
+        }
+        let (script, value) = wallet_state
+            .utxos
+            .get(&key)
+            .filter(|(script, _)| input_weight(wallet_state, script).is_some())
+            .ok_or(SpendError::InvalidTransaction(format!(
+                "{}:{} is not an unspent coin of this wallet",
+                key.0, key.1
+            )))?;
+        coins.push(Coin {
+            outpoint: *outpoint,
+            utxo: Utxo {
+                script_pubkey: script.clone(),
+                amount: btc_to_sats(*value),
+            },
+        });
+    }
+    Ok(coins)
+}
+
+// Weight of a signed input spending one of our single key coins, None for other scripts
+pub fn input_weight(wallet_state: &WalletState, script_pubkey: &[u8]) -> Option<u64> {
+    if wallet_state.witness_programs.iter().chain(&wallet_state.change_programs).any(|p| p == script_pubkey) {
+        // item count, DER signature with sighash byte (at most 72 bytes), compressed key
+        Some(INPUT_BASE_WEIGHT + 1 + (1 + 72) + (1 + 33))
+    } else if wallet_state.taproot_programs.iter().any(|p| p == script_pubkey) {
+        // item count, 64 byte SIGHASH_DEFAULT signature
+        Some(INPUT_BASE_WEIGHT + 1 + (1 + 64))
+    } else if wallet_state.nested_scripts.iter().any(|p| p == script_pubkey) {
+        // scriptSig pushing the 22 byte P2WPKH program, then the P2WPKH witness
+        Some(INPUT_BASE_WEIGHT + 23 * 4 + 1 + (1 + 72) + (1 + 33))
+    } else if wallet_state.legacy_scripts.iter().any(|p| p == script_pubkey) {
+        // scriptSig with the signature and key, and an empty witness among segwit inputs
+        Some(INPUT_BASE_WEIGHT + ((1 + 72) + (1 + 33)) * 4 + 1)
+    } else {
+        None
+    }
+}
+
+pub fn output_weight(output: &Utxo) -> u64 {
+    (8 + compact_size(output.script_pubkey.len() as u64).len() as u64 + output.script_pubkey.len() as u64) * 4
+}
+
+// Weight of a transaction spending `prevouts` (all ours) to `outputs` once signed
+pub fn estimate_weight(wallet_state: &WalletState, prevouts: &[Utxo], outputs: &[Utxo]) -> Result<u64, SpendError> {
+    let mut weight = TX_OVERHEAD_WEIGHT + outputs.iter().map(output_weight).sum::<u64>();
+    for (index, prevout) in prevouts.iter().enumerate() {
+        weight += input_weight(wallet_state, &prevout.script_pubkey).ok_or(SpendError::InvalidTransaction(
+            format!("input {} is not a coin of this wallet", index),
+        ))?;
+    }
+    Ok(weight)
+}
+
+// Fee in sats for `weight` at `fee_rate` sat/vB, rounded up
+pub fn fee_for_weight(weight: u64, fee_rate: f64) -> u64 {
+    (weight.div_ceil(4) as f64 * fee_rate).ceil() as u64
+}
+
+// Private key and compressed public key of a derived key
+fn key_pair(privkey: &[u8]) -> ([u8; 32], Vec<u8>) {
+    let secret_key = SecretKey::from_slice(privkey).expect("private key length is not 32 bytes");
+    let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+    (secret_key.secret_bytes(), pubkey.serialize().to_vec())
+}
+
+// Sign every input of `tx` spending `prevouts` with our P2WPKH, P2SH-P2WPKH or
+// P2PKH (SIGHASH_ALL) or BIP86 key path (SIGHASH_DEFAULT) keys
+pub fn sign_wallet_inputs(
+    wallet_state: &WalletState,
+    tx: &mut Transaction,
+    prevouts: &[Utxo],
+) -> Result<(), SpendError> {
+    for (index, prevout) in prevouts.iter().enumerate() {
+        let script = &prevout.script_pubkey;
+        if let Some(key_index) = wallet_state.witness_programs.iter().position(|p| p == script) {
+            let privkey: [u8; 32] = wallet_state.private_keys[key_index]
+                .clone()
+                .try_into()
+                .expect("private key length is not 32 bytes");
+            let sighash_type = SighashType(SIGHASH_ALL);
+            let commitment_hash =
+                get_commitment_hash(tx, index, &get_p2wpkh_scriptcode(prevout), prevout.amount, sighash_type);
+            let signature = sign(&privkey, commitment_hash, sighash_type);
+            tx.inputs[index].witness = vec![signature, wallet_state.public_keys[key_index].clone()];
+        } else if let Some(key_index) = wallet_state.change_programs.iter().position(|p| p == script) {
+            let (privkey, pubkey) = key_pair(&wallet_state.change_keys[key_index]);
+            let sighash_type = SighashType(SIGHASH_ALL);
+            let commitment_hash =
+                get_commitment_hash(tx, index, &get_p2wpkh_scriptcode(prevout), prevout.amount, sighash_type);
+            let signature = sign(&privkey, commitment_hash, sighash_type);
+            tx.inputs[index].witness = vec![signature, pubkey];
+        } else if let Some(key_index) = wallet_state.taproot_programs.iter().position(|p| p == script) {
+            let privkey: [u8; 32] = wallet_state.taproot_keys[key_index]
+                .clone()
+                .try_into()
+                .expect("private key length is not 32 bytes");
+            let sighash_type = SighashType(SIGHASH_DEFAULT);
+            let commitment_hash = get_taproot_commitment_hash(tx, index, prevouts, sighash_type, None)?;
+            tx.inputs[index].witness = vec![sign_schnorr(&privkey, None, &commitment_hash, sighash_type)?];
+        } else if let Some(key_index) = wallet_state.nested_scripts.iter().position(|p| p == script) {
+            // BIP143 like P2WPKH, with the program as the redeem script
+            let (privkey, pubkey) = key_pair(&wallet_state.nested_keys[key_index]);
+            let redeem_script = get_p2wpkh_program(&pubkey);
+            let sighash_type = SighashType(SIGHASH_ALL);
+            let scriptcode = get_p2wpkh_scriptcode(&Utxo {
+                script_pubkey: redeem_script.clone(),
+                amount: prevout.amount,
+            });
+            let commitment_hash = get_commitment_hash(tx, index, &scriptcode, prevout.amount, sighash_type);
+            let signature = sign(&privkey, commitment_hash, sighash_type);
+            let mut script_sig = Vec::new();
+            push_data(&mut script_sig, &redeem_script);
+            tx.inputs[index].script_sig = script_sig;
+            tx.inputs[index].witness = vec![signature, pubkey];
+        } else if let Some(key_index) = wallet_state.legacy_scripts.iter().position(|p| p == script) {
+            let (privkey, pubkey) = key_pair(&wallet_state.legacy_keys[key_index]);
+            let sighash_type = SighashType(SIGHASH_ALL);
+            let commitment_hash = get_legacy_commitment_hash(tx, index, script, sighash_type);
+            let signature = sign(&privkey, commitment_hash, sighash_type);
+            let mut script_sig = Vec::new();
+            push_data(&mut script_sig, &signature);
+            push_data(&mut script_sig, &pubkey);
+            tx.inputs[index].script_sig = script_sig;
+        } else {
+            return Err(SpendError::SigningError(format!("input {} is not a coin of this wallet", index)));
+        }
+    }
+    Ok(())
+}
+
+// An unsigned transaction paying `outputs` from our coins at `fee_rate`
+// sat/vB, with change appended as the last output, going to our first unused
+// change address unless it would be dust. The `chosen` coins are always spent, then
+// other coins (largest first) as needed. Returns the transaction, the outputs
+// it spends and its fee.
+pub fn select_payment(
+    wallet_state: &WalletState,
+    mut outputs: Vec<Utxo>,
+    fee_rate: f64,
+    chosen: Vec<Coin>,
+) -> Result<(Transaction, Vec<Utxo>, u64), SpendError> {
+    let payments: u64 = outputs.iter().map(|output| output.amount).sum();
+    let change = Utxo {
+        script_pubkey: wallet_state
+            .fresh_change_program()
+            .ok_or(SpendError::MissingCodeCantRun)?
+            .clone(),
+        amount: 0,
+    };
+
+    let mut inputs = Vec::new();
+    let mut prevouts = Vec::new();
+    let mut total_in = 0;
+    let chosen_count = chosen.len();
+    let chosen_outpoints: Vec<Outpoint> = chosen.iter().map(|coin| coin.outpoint).collect();
+    let others = wallet_coins(wallet_state)
+        .into_iter()
+        .filter(|coin| !chosen_outpoints.contains(&coin.outpoint));
+    for coin in chosen.into_iter().chain(others) {
+        inputs.push(TxIn::new(coin.outpoint));
+        total_in += coin.utxo.amount;
+        prevouts.push(coin.utxo);
+        if inputs.len() < chosen_count {
+            continue;
+        }
+
+        let with_change: Vec<Utxo> = outputs.iter().cloned().chain([change.clone()]).collect();
+        let fee = fee_for_weight(estimate_weight(wallet_state, &prevouts, &with_change)?, fee_rate);
+        // smaller change than the dust threshold goes to the fee
+        if total_in >= payments + fee + dust_threshold(&change.script_pubkey) {
+            outputs.push(Utxo {
+                amount: total_in - payments - fee,
+                ..change
+            });
+            break;
+        }
+        let fee = fee_for_weight(estimate_weight(wallet_state, &prevouts, &outputs)?, fee_rate);
+        if total_in >= payments + fee {
+            break;
+        }
+    }
+    let fee = total_in.saturating_sub(outputs.iter().map(|output| output.amount).sum());
+    let weight = estimate_weight(wallet_state, &prevouts, &outputs)?;
+    let required = payments + fee_for_weight(weight, fee_rate);
+    if total_in < required {
+        return Err(SpendError::InsufficientFunds(format!(
+            "wallet has {} sats, need {}",
+            total_in, required
+        )));
+    }
+
+    let tx = Transaction::new(inputs, outputs);
+    check_policy(&tx, &prevouts, weight)?;
+    Ok((tx, prevouts, fee))
+}
+
+// A signed transaction paying `outputs` from our coins, see select_payment.
+// Returns the transaction and its fee.
+pub fn build_payment(
+    wallet_state: &WalletState,
+    outputs: Vec<Utxo>,
+    fee_rate: f64,
+    chosen: Vec<Coin>,
+) -> Result<(Transaction, u64), SpendError> {
+    let (mut tx, prevouts, fee) = select_payment(wallet_state, outputs, fee_rate, chosen)?;
+    sign_wallet_inputs(wallet_state, &mut tx, &prevouts)?;
+    verify_transaction(&tx, &prevouts)?;
+    Ok((tx, fee))
+}
@@ -0,0 +1,78 @@
+// Child-pays-for-parent: spend our outputs of a stuck transaction with a fee
+// high enough for the parent and child together to reach a target fee rate
+use crate::balance::balance::WalletState;
+use crate::balance::cache::WalletCache;
+use crate::spend_funds::broadcast::fetch_transaction;
+use crate::spend_funds::builder::{estimate_weight, fee_for_weight, input_weight, sign_wallet_inputs};
+use crate::spend_funds::interpreter::verify_transaction;
+use crate::spend_funds::policy::{check_policy, dust_threshold};
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use crate::spend_funds::transaction::{Outpoint, Transaction, TxIn, Utxo};
+
+// Relay minimum, the child on its own must pay at least this
+const MIN_RELAY_FEE_RATE: f64 = 1.0;
+
+// A signed child of `txid` sweeping our outputs of it to a fresh change address,
+// with the child's fee and the parent's fee and vsize
+pub fn create_cpfp(
+    wallet_state: &WalletState,
+    wallet_cache: &WalletCache,
+    txid: &str,
+    target_fee_rate: f64,
+) -> Result<(Transaction, u64, u64, u64), SpendError> {
+    let (parent, parent_fee) = fetch_transaction(wallet_state, wallet_cache, txid)?;
+    let parent_vsize = parent.vsize() as u64;
+    let parent_txid: [u8; 32] = {
+        let mut txid = parent.txid();
+        txid.reverse();
+        txid
+    };
+
+    let mut inputs = Vec::new();
+    let mut prevouts = Vec::new();
+    for (vout, output) in parent.outputs.iter().enumerate() {
+        if input_weight(wallet_state, &output.script_pubkey).is_some() {
+            inputs.push(TxIn::new(Outpoint {
+                txid: parent_txid,
+                index: vout as u32,
+            }));
+            prevouts.push(output.clone());
+        }
+    }
+    if inputs.is_empty() {
+        return Err(SpendError::InvalidTransaction(format!(
+            "{} has no outputs this wallet can spend",
+            txid
+        )));
+    }
+
+    let destination = wallet_state
+        .fresh_change_program()
+        .ok_or(SpendError::MissingCodeCantRun)?
+        .clone();
+    let mut outputs = vec![Utxo {
+        script_pubkey: destination,
+        amount: 0,
+    }];
+    let child_weight = estimate_weight(wallet_state, &prevouts, &outputs)?;
+    // the package (parent + child) pays the target rate over both sizes
+    let package_fee = ((parent_vsize + child_weight.div_ceil(4)) as f64 * target_fee_rate).ceil() as u64;
+    let fee = package_fee
+        .saturating_sub(parent_fee)
+        .max(fee_for_weight(child_weight, MIN_RELAY_FEE_RATE));
+
+    let total_in: u64 = prevouts.iter().map(|prevout| prevout.amount).sum();
+    if total_in < fee + dust_threshold(&outputs[0].script_pubkey) {
+        return Err(SpendError::InsufficientFunds(format!(
+            "our outputs of {} hold {} sats, the child needs {} sats in fees",
+            txid, total_in, fee
+        )));
+    }
+    outputs[0].amount = total_in - fee;
+
+    let mut tx = Transaction::new(inputs, outputs);
+    check_policy(&tx, &prevouts, child_weight)?;
+    sign_wallet_inputs(wallet_state, &mut tx, &prevouts)?;
+    verify_transaction(&tx, &prevouts)?;
+    Ok((tx, fee, parent_fee, parent_vsize))
+}
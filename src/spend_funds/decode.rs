@@ -0,0 +1,104 @@
+// Human and machine readable views of a transaction, for `tx decode`
+use crate::balance::address::script_to_address;
+use crate::spend_funds::script::{script_to_asm, script_type};
+use crate::spend_funds::transaction::Transaction;
+use serde_json::{json, Value};
+
+// decoderawtransaction style JSON
+pub fn transaction_json(tx: &Transaction) -> Value {
+    let inputs: Vec<Value> = tx
+        .inputs
+        .iter()
+        .map(|input| {
+            json!({
+                "txid": input.previous_output.txid_hex(),
+                "vout": input.previous_output.index,
+                "scriptSig": {
+                    "asm": script_to_asm(&input.script_sig),
+                    "hex": hex::encode(&input.script_sig),
+                },
+                "txinwitness": input.witness.iter().map(hex::encode).collect::<Vec<_>>(),
+                "sequence": input.sequence,
+            })
+        })
+        .collect();
+
+    let outputs: Vec<Value> = tx
+        .outputs
+        .iter()
+        .enumerate()
+        .map(|(n, output)| {
+            let mut script_pubkey = json!({
+                "asm": script_to_asm(&output.script_pubkey),
+                "hex": hex::encode(&output.script_pubkey),
+                "type": script_type(&output.script_pubkey).to_string(),
+            });
+            if let Some(address) = script_to_address(&output.script_pubkey) {
+                script_pubkey["address"] = json!(address);
+            }
+            json!({
+                "value": output.amount as f64 / 100_000_000.0,
+                "n": n,
+                "scriptPubKey": script_pubkey,
+            })
+        })
+        .collect();
+
+    json!({
+        "txid": hex::encode(tx.txid()),
+        "hash": hex::encode(tx.wtxid()),
+        "version": tx.version,
+        "size": tx.size(),
+        "vsize": tx.vsize(),
+        "weight": tx.weight(),
+        "locktime": tx.locktime,
+        "vin": inputs,
+        "vout": outputs,
+    })
+}
+
+// Summary lines followed by an input and an output table
+pub fn transaction_table(tx: &Transaction) -> String {
+    let mut lines = vec![
+        format!("TXID:     {}", hex::encode(tx.txid())),
+        format!("WTXID:    {}", hex::encode(tx.wtxid())),
+        format!("Version:  {}", tx.version),
+        format!("Locktime: {}", tx.locktime),
+        format!(
+            "Size: {} bytes, Virtual size: {} vbytes, Weight: {} WU",
+            tx.size(),
+            tx.vsize(),
+            tx.weight()
+        ),
+        String::new(),
+        format!("{:<5} | {:<70} | {:<10} | Witness", "Input", "Outpoint", "Sequence"),
+        "-".repeat(110),
+    ];
+    for (index, input) in tx.inputs.iter().enumerate() {
+        let outpoint = format!("{}:{}", input.previous_output.txid_hex(), input.previous_output.index);
+        let witness = input.witness.iter().map(hex::encode).collect::<Vec<_>>().join(" ");
+        lines.push(format!("{:<5} | {:<70} | {:#010x} | {}", index, outpoint, input.sequence, witness));
+        if !input.script_sig.is_empty() {
+            lines.push(format!("{:<5} | scriptSig: {}", "", script_to_asm(&input.script_sig)));
+        }
+    }
+
+    lines.push(String::new());
+    lines.push(format!(
+        "{:<6} | {:>16} | {:<21} | Address / Script",
+        "Output", "Amount (sats)", "Type"
+    ));
+    lines.push("-".repeat(110));
+    for (index, output) in tx.outputs.iter().enumerate() {
+        let destination = script_to_address(&output.script_pubkey)
+            .unwrap_or_else(|| script_to_asm(&output.script_pubkey));
+        lines.push(format!(
+            "{:<6} | {:>16} | {:<21} | {}",
+            index,
+            output.amount,
+            script_type(&output.script_pubkey).to_string(),
+            destination
+        ));
+    }
+    lines.join("\n")
+}
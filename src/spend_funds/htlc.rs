@@ -0,0 +1,115 @@
+// Hash time-locked contracts (BIP199) as P2WSH outputs: the recipient claims
+// with the preimage of a SHA256 hash, the sender takes the coins back after a timeout
+use crate::balance::address::{encode_segwit_address, SIGNET_HRP};
+use crate::balance::balance::{btc_to_sats, WalletState};
+use crate::balance::cache::HtlcPolicy;
+use crate::spend_funds::script::{
+    push_data, OP_CHECKSIG, OP_DUP, OP_ELSE, OP_ENDIF, OP_EQUALVERIFY, OP_HASH160, OP_IF, OP_SHA256,
+};
+use crate::spend_funds::spend_p2wpkh::{get_p2wsh_program, SpendError};
+use crate::spend_funds::timelock::{resolve_pubkey, spend_witness_script, Timelock};
+use crate::spend_funds::transaction::{Transaction, Utxo};
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+
+// OP_IF
+//     OP_SHA256 <hash> OP_EQUALVERIFY OP_DUP OP_HASH160 <recipient pubkey hash>
+// OP_ELSE
+//     <timeout> OP_CHECKLOCKTIMEVERIFY|OP_CHECKSEQUENCEVERIFY OP_DROP OP_DUP OP_HASH160 <refund pubkey hash>
+// OP_ENDIF
+// OP_EQUALVERIFY OP_CHECKSIG
+pub fn htlc_script(hash: &[u8], recipient_key: &[u8], refund_key: &[u8], timeout: Timelock) -> Vec<u8> {
+    let mut script = vec![OP_IF, OP_SHA256];
+    push_data(&mut script, hash);
+    script.extend([OP_EQUALVERIFY, OP_DUP, OP_HASH160]);
+    push_data(&mut script, &Ripemd160::digest(Sha256::digest(recipient_key)));
+    script.push(OP_ELSE);
+    timeout.push(&mut script);
+    script.extend([OP_DUP, OP_HASH160]);
+    push_data(&mut script, &Ripemd160::digest(Sha256::digest(refund_key)));
+    script.extend([OP_ENDIF, OP_EQUALVERIFY, OP_CHECKSIG]);
+    script
+}
+
+pub fn create_htlc_policy(
+    wallet_state: &WalletState,
+    name: &str,
+    hash: &str,
+    recipient_key: &str,
+    refund_key: &str,
+    timeout: Timelock,
+) -> Result<HtlcPolicy, SpendError> {
+    let hash_bytes = hex::decode(hash)
+        .ok()
+        .filter(|hash| hash.len() == 32)
+        .ok_or(SpendError::InvalidScript(format!("not a SHA256 hash: {}", hash)))?;
+    let recipient_key = resolve_pubkey(wallet_state, recipient_key)?;
+    let refund_key = resolve_pubkey(wallet_state, refund_key)?;
+    let script = htlc_script(&hash_bytes, &recipient_key, &refund_key, timeout);
+    let program = get_p2wsh_program(&script, Some(0));
+
+    Ok(HtlcPolicy {
+        name: name.to_string(),
+        hash: hex::encode(hash_bytes),
+        recipient_key: hex::encode(recipient_key),
+        refund_key: hex::encode(refund_key),
+        timeout,
+        witness_script: hex::encode(&script),
+        address: encode_segwit_address(SIGNET_HRP, 0, &program[2..]),
+    })
+}
+
+// Sweep all of the HTLC's coins to `destination`: with the preimage through
+// the recipient's branch, or without through the refund branch after the timeout
+pub fn spend_htlc(
+    wallet_state: &WalletState,
+    policy: &HtlcPolicy,
+    destination: Vec<u8>,
+    fee: u64,
+    preimage: Option<&[u8]>,
+    op_return: Option<Vec<u8>>,
+) -> Result<Transaction, SpendError> {
+    let script = hex::decode(&policy.witness_script)
+        .map_err(|_| SpendError::InvalidScript(format!("bad witness script for {}", policy.name)))?;
+    if let Some(preimage) = preimage {
+        if hex::encode(Sha256::digest(preimage)) != policy.hash {
+            return Err(SpendError::InvalidScript(format!(
+                "preimage doesn't hash to {}",
+                policy.hash
+            )));
+        }
+    }
+
+    let program = get_p2wsh_program(&script, Some(0));
+    let total: u64 = wallet_state
+        .spendable_watched_utxos()
+        .filter(|(_, (script, _))| *script == program)
+        .map(|(_, (_, value))| btc_to_sats(*value))
+        .sum();
+    let payment = Utxo {
+        script_pubkey: destination,
+        amount: total.checked_sub(fee).ok_or(SpendError::InsufficientFunds(format!(
+            "{} holds {} sats, less than the {} sat fee",
+            policy.name, total, fee
+        )))?,
+    };
+
+    let (key, timeout) = match preimage {
+        Some(_) => (&policy.recipient_key, None),
+        None => (&policy.refund_key, Some(policy.timeout)),
+    };
+    let key = hex::decode(key).map_err(|_| SpendError::InvalidScript(format!("bad key in {}", policy.name)))?;
+    let outputs = [payment]
+        .into_iter()
+        .chain(op_return.map(|script| Utxo {
+            script_pubkey: script,
+            amount: 0,
+        }))
+        .collect();
+    spend_witness_script(wallet_state, &script, outputs, fee, timeout, &key, |signature| match preimage {
+        // <sig> <pubkey> <preimage> 1
+        Some(preimage) => vec![signature, key.clone(), preimage.to_vec(), vec![1]],
+        // <sig> <pubkey> <empty>
+        None => vec![signature, key.clone(), Vec::new()],
+    })
+}
@@ -0,0 +1,760 @@
+// Script interpreter, used to check that the transactions we build actually
+// satisfy the scripts they spend before they're printed or broadcast.
+// Covers the standard output types (P2PKH, P2SH, P2WPKH, P2WSH, P2TR) and
+// the opcodes used by multisig, timelock and hashlock scripts.
+use crate::balance::balance::{bcli, btc_to_sats, WalletState};
+use crate::spend_funds::script::{decode_script, script_type, Instruction, ScriptType};
+use crate::spend_funds::sighash::{
+    get_commitment_hash, get_legacy_commitment_hash, get_taproot_commitment_hash, SighashType,
+    SIGHASH_DEFAULT,
+};
+use crate::spend_funds::spend_p2wpkh::{get_p2wpkh_scriptcode, SpendError};
+use crate::spend_funds::taproot::{tap_branch_hash, tap_leaf_hash, taproot_output_key, TAPSCRIPT_LEAF_VERSION};
+use crate::spend_funds::transaction::{Outpoint, Transaction, Utxo, SEQUENCE_FINAL};
+use ripemd::Ripemd160;
+use secp256k1::{ecdsa, schnorr, Message, PublicKey, Secp256k1, XOnlyPublicKey};
+use sha2::{Digest, Sha256};
+
+const MAX_SCRIPT_SIZE: usize = 10_000;
+const MAX_ELEMENT_SIZE: usize = 520;
+const MAX_OPS_PER_SCRIPT: usize = 201;
+const MAX_STACK_SIZE: usize = 1000;
+const LOCKTIME_THRESHOLD: i64 = 500_000_000;
+const SEQUENCE_DISABLE_FLAG: i64 = 1 << 31;
+const SEQUENCE_TYPE_FLAG: i64 = 1 << 22;
+const SEQUENCE_MASK: i64 = 0x0000ffff;
+
+// Which signature rules and digest apply to the script being run
+#[derive(Clone, Copy, PartialEq)]
+enum SigVersion {
+    Base,
+    WitnessV0,
+    Tapscript,
+}
+
+// The input being verified, for signature and timelock checks
+struct Checker<'a> {
+    tx: &'a Transaction,
+    input_index: usize,
+    prevouts: &'a [Utxo],
+    leaf_hash: Option<[u8; 32]>,
+}
+
+type Stack = Vec<Vec<u8>>;
+
+fn fail(msg: &str) -> SpendError {
+    SpendError::InvalidScript(msg.to_string())
+}
+
+pub fn hash160(data: &[u8]) -> Vec<u8> {
+    Ripemd160::digest(Sha256::digest(data)).to_vec()
+}
+
+fn cast_to_bool(data: &[u8]) -> bool {
+    for (i, byte) in data.iter().enumerate() {
+        if *byte != 0 {
+            // negative zero is false
+            return !(i == data.len() - 1 && *byte == 0x80);
+        }
+    }
+    false
+}
+
+// Decode a script number of at most `max_len` bytes
+fn decode_num(data: &[u8], max_len: usize) -> Result<i64, SpendError> {
+    if data.len() > max_len {
+        return Err(fail("script number overflow"));
+    }
+    let mut n: i64 = 0;
+    for (i, byte) in data.iter().enumerate() {
+        n |= (*byte as i64) << (8 * i);
+    }
+    match data.last() {
+        Some(last) if last & 0x80 != 0 => Ok(-(n & !(0x80i64 << (8 * (data.len() - 1))))),
+        _ => Ok(n),
+    }
+}
+
+fn encode_num(n: i64) -> Vec<u8> {
+    crate::spend_funds::script::script_num(n)
+}
+
+fn encode_bool(b: bool) -> Vec<u8> {
+    if b {
+        vec![1]
+    } else {
+        Vec::new()
+    }
+}
+
+fn pop(stack: &mut Stack) -> Result<Vec<u8>, SpendError> {
+    stack.pop().ok_or(fail("stack underflow"))
+}
+
+fn pop_num(stack: &mut Stack) -> Result<i64, SpendError> {
+    decode_num(&pop(stack)?, 4)
+}
+
+// element `depth` from the top, 0 being the top
+fn peek(stack: &Stack, depth: usize) -> Result<&Vec<u8>, SpendError> {
+    stack
+        .len()
+        .checked_sub(depth + 1)
+        .map(|i| &stack[i])
+        .ok_or(fail("stack underflow"))
+}
+
+impl Checker<'_> {
+    fn check_ecdsa(&self, sig: &[u8], pubkey: &[u8], script_code: &[u8], sig_version: SigVersion) -> bool {
+        let Some((hash_type, der)) = sig.split_last() else {
+            return false;
+        };
+        if sig_version == SigVersion::WitnessV0 && pubkey.len() != 33 {
+            return false;
+        }
+        let (Ok(signature), Ok(pubkey)) = (ecdsa::Signature::from_der(der), PublicKey::from_slice(pubkey)) else {
+            return false;
+        };
+        let sighash_type = SighashType(*hash_type as u32);
+        let digest = match sig_version {
+            SigVersion::Base => get_legacy_commitment_hash(self.tx, self.input_index, script_code, sighash_type),
+            _ => get_commitment_hash(
+                self.tx,
+                self.input_index,
+                script_code,
+                self.prevouts[self.input_index].amount,
+                sighash_type,
+            ),
+        };
+        let message = Message::from_digest_slice(&digest).expect("digest is 32 bytes");
+        // libsecp256k1 only accepts low-S signatures, as does standardness
+        Secp256k1::verification_only()
+            .verify_ecdsa(&message, &signature, &pubkey)
+            .is_ok()
+    }
+
+    fn check_schnorr(&self, sig: &[u8], pubkey: &[u8], leaf_hash: Option<[u8; 32]>) -> Result<bool, SpendError> {
+        let (sig, sighash_type) = match sig.len() {
+            64 => (sig, SighashType(SIGHASH_DEFAULT)),
+            65 if sig[64] != SIGHASH_DEFAULT as u8 => (&sig[..64], SighashType(sig[64] as u32)),
+            _ => return Ok(false),
+        };
+        let (Ok(signature), Ok(pubkey)) = (schnorr::Signature::from_slice(sig), XOnlyPublicKey::from_slice(pubkey))
+        else {
+            return Ok(false);
+        };
+        let digest = get_taproot_commitment_hash(self.tx, self.input_index, self.prevouts, sighash_type, leaf_hash)?;
+        Ok(Secp256k1::verification_only()
+            .verify_schnorr(&signature, &digest, &pubkey)
+            .is_ok())
+    }
+
+    // BIP65: the transaction's locktime must be at least `locktime`, of the same kind
+    fn check_locktime(&self, locktime: i64) -> bool {
+        let tx_locktime = self.tx.locktime as i64;
+        (tx_locktime < LOCKTIME_THRESHOLD) == (locktime < LOCKTIME_THRESHOLD)
+            && locktime <= tx_locktime
+            && self.tx.inputs[self.input_index].sequence != SEQUENCE_FINAL
+    }
+
+    // BIP112: the input's relative locktime must be at least `sequence`, of the same kind
+    fn check_sequence(&self, sequence: i64) -> bool {
+        let tx_sequence = self.tx.inputs[self.input_index].sequence as i64;
+        if self.tx.version < 2 || tx_sequence & SEQUENCE_DISABLE_FLAG != 0 {
+            return false;
+        }
+        let mask = SEQUENCE_TYPE_FLAG | SEQUENCE_MASK;
+        let (sequence, tx_sequence) = (sequence & mask, tx_sequence & mask);
+        (sequence < SEQUENCE_TYPE_FLAG) == (tx_sequence < SEQUENCE_TYPE_FLAG) && sequence <= tx_sequence
+    }
+}
+
+// Run `script` on `stack`
+fn execute(script: &[u8], stack: &mut Stack, checker: &Checker, sig_version: SigVersion) -> Result<(), SpendError> {
+    if sig_version != SigVersion::Tapscript && script.len() > MAX_SCRIPT_SIZE {
+        return Err(fail("script too large"));
+    }
+    let instructions = decode_script(script).ok_or(fail("push past end of script"))?;
+    let mut alt_stack: Stack = Vec::new();
+    // one entry per open IF, whether its branch is being executed
+    let mut branches: Vec<bool> = Vec::new();
+    let mut op_count = 0;
+    let segwit = sig_version != SigVersion::Base;
+
+    for instruction in &instructions {
+        let executing = branches.iter().all(|b| *b);
+        let op = match instruction {
+            Instruction::Push(data) => {
+                if data.len() > MAX_ELEMENT_SIZE {
+                    return Err(fail("push larger than 520 bytes"));
+                }
+                if executing {
+                    stack.push(data.clone());
+                }
+                continue;
+            }
+            Instruction::Op(op) => *op,
+        };
+
+        if op > 0x60 && sig_version != SigVersion::Tapscript {
+            op_count += 1;
+            if op_count > MAX_OPS_PER_SCRIPT {
+                return Err(fail("too many opcodes"));
+            }
+        }
+
+        // flow control is followed even in branches that aren't executed
+        match op {
+            0x63 | 0x64 => {
+                // OP_IF / OP_NOTIF
+                let mut value = false;
+                if executing {
+                    let top = pop(stack)?;
+                    // MINIMALIF: segwit scripts must branch on an empty item or 0x01
+                    if segwit && !(top.is_empty() || top == [1]) {
+                        return Err(fail("OP_IF argument must be empty or 1"));
+                    }
+                    value = cast_to_bool(&top) == (op == 0x63);
+                }
+                branches.push(value);
+                continue;
+            }
+            0x67 => {
+                // OP_ELSE
+                let last = branches.last_mut().ok_or(fail("OP_ELSE without OP_IF"))?;
+                *last = !*last;
+                continue;
+            }
+            0x68 => {
+                // OP_ENDIF
+                branches.pop().ok_or(fail("OP_ENDIF without OP_IF"))?;
+                continue;
+            }
+            _ if !executing => continue,
+            _ => {}
+        }
+
+        match op {
+            // OP_0 and OP_1NEGATE, OP_1..OP_16
+            0x00 => stack.push(Vec::new()),
+            0x4f => stack.push(encode_num(-1)),
+            0x51..=0x60 => stack.push(encode_num((op - 0x50) as i64)),
+            // OP_NOP and the upgradable NOPs
+            0x61 | 0xb0 | 0xb3..=0xb9 => {}
+            0x69 => {
+                // OP_VERIFY
+                if !cast_to_bool(&pop(stack)?) {
+                    return Err(fail("OP_VERIFY failed"));
+                }
+            }
+            0x6a => return Err(fail("OP_RETURN executed")),
+            0x6b => alt_stack.push(pop(stack)?),
+            0x6c => stack.push(alt_stack.pop().ok_or(fail("alt stack underflow"))?),
+            0x6d => {
+                // OP_2DROP
+                pop(stack)?;
+                pop(stack)?;
+            }
+            0x6e => {
+                // OP_2DUP
+                let (a, b) = (peek(stack, 1)?.clone(), peek(stack, 0)?.clone());
+                stack.extend([a, b]);
+            }
+            0x6f => {
+                // OP_3DUP
+                let items = [peek(stack, 2)?.clone(), peek(stack, 1)?.clone(), peek(stack, 0)?.clone()];
+                stack.extend(items);
+            }
+            0x70 => {
+                // OP_2OVER
+                let (a, b) = (peek(stack, 3)?.clone(), peek(stack, 2)?.clone());
+                stack.extend([a, b]);
+            }
+            0x71 => {
+                // OP_2ROT
+                peek(stack, 5)?;
+                let items: Vec<Vec<u8>> = stack.drain(stack.len() - 6..stack.len() - 4).collect();
+                stack.extend(items);
+            }
+            0x72 => {
+                // OP_2SWAP
+                peek(stack, 3)?;
+                let len = stack.len();
+                stack[len - 4..].rotate_left(2);
+            }
+            0x73 => {
+                // OP_IFDUP
+                let top = peek(stack, 0)?.clone();
+                if cast_to_bool(&top) {
+                    stack.push(top);
+                }
+            }
+            0x74 => stack.push(encode_num(stack.len() as i64)),
+            0x75 => {
+                pop(stack)?;
+            }
+            0x76 => stack.push(peek(stack, 0)?.clone()),
+            0x77 => {
+                // OP_NIP
+                peek(stack, 1)?;
+                stack.remove(stack.len() - 2);
+            }
+            0x78 => stack.push(peek(stack, 1)?.clone()),
+            0x79 | 0x7a => {
+                // OP_PICK / OP_ROLL
+                let n = pop_num(stack)?;
+                let n = usize::try_from(n).map_err(|_| fail("negative OP_PICK/OP_ROLL index"))?;
+                let item = peek(stack, n)?.clone();
+                if op == 0x7a {
+                    stack.remove(stack.len() - 1 - n);
+                }
+                stack.push(item);
+            }
+            0x7b => {
+                // OP_ROT
+                peek(stack, 2)?;
+                let len = stack.len();
+                stack[len - 3..].rotate_left(1);
+            }
+            0x7c => {
+                // OP_SWAP
+                peek(stack, 1)?;
+                let len = stack.len();
+                stack.swap(len - 1, len - 2);
+            }
+            0x7d => {
+                // OP_TUCK
+                let top = peek(stack, 0)?.clone();
+                peek(stack, 1)?;
+                stack.insert(stack.len() - 2, top);
+            }
+            0x82 => stack.push(encode_num(peek(stack, 0)?.len() as i64)),
+            0x87 | 0x88 => {
+                // OP_EQUAL / OP_EQUALVERIFY
+                let equal = pop(stack)? == pop(stack)?;
+                if op == 0x88 {
+                    if !equal {
+                        return Err(fail("OP_EQUALVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(equal));
+                }
+            }
+            0x8b..=0x92 => {
+                // unary arithmetic
+                let n = pop_num(stack)?;
+                stack.push(match op {
+                    0x8b => encode_num(n + 1),
+                    0x8c => encode_num(n - 1),
+                    0x8f => encode_num(-n),
+                    0x90 => encode_num(n.abs()),
+                    0x91 => encode_bool(n == 0),
+                    0x92 => encode_bool(n != 0),
+                    _ => return Err(fail("disabled opcode")),
+                });
+            }
+            0x93..=0xa4 => {
+                // binary arithmetic and comparisons
+                let b = pop_num(stack)?;
+                let a = pop_num(stack)?;
+                let result = match op {
+                    0x93 => encode_num(a + b),
+                    0x94 => encode_num(a - b),
+                    0x9a => encode_bool(a != 0 && b != 0),
+                    0x9b => encode_bool(a != 0 || b != 0),
+                    0x9c | 0x9d => encode_bool(a == b),
+                    0x9e => encode_bool(a != b),
+                    0x9f => encode_bool(a < b),
+                    0xa0 => encode_bool(a > b),
+                    0xa1 => encode_bool(a <= b),
+                    0xa2 => encode_bool(a >= b),
+                    0xa3 => encode_num(a.min(b)),
+                    0xa4 => encode_num(a.max(b)),
+                    _ => return Err(fail("disabled opcode")),
+                };
+                if op == 0x9d {
+                    if result.is_empty() {
+                        return Err(fail("OP_NUMEQUALVERIFY failed"));
+                    }
+                } else {
+                    stack.push(result);
+                }
+            }
+            0xa5 => {
+                // OP_WITHIN: min <= x < max
+                let max = pop_num(stack)?;
+                let min = pop_num(stack)?;
+                let x = pop_num(stack)?;
+                stack.push(encode_bool(min <= x && x < max));
+            }
+            0xa6 => {
+                let data = pop(stack)?;
+                stack.push(Ripemd160::digest(&data).to_vec());
+            }
+            0xa8 => {
+                let data = pop(stack)?;
+                stack.push(Sha256::digest(&data).to_vec());
+            }
+            0xa9 => {
+                let data = pop(stack)?;
+                stack.push(hash160(&data));
+            }
+            0xaa => {
+                let data = pop(stack)?;
+                stack.push(Sha256::digest(Sha256::digest(&data)).to_vec());
+            }
+            0xac | 0xad => {
+                // OP_CHECKSIG / OP_CHECKSIGVERIFY
+                let pubkey = pop(stack)?;
+                let sig = pop(stack)?;
+                let valid = if sig_version == SigVersion::Tapscript {
+                    if pubkey.is_empty() {
+                        return Err(fail("empty public key in tapscript"));
+                    }
+                    // keys that aren't 32 bytes are reserved for upgrades and always succeed
+                    !sig.is_empty() && (pubkey.len() != 32 || checker.check_schnorr(&sig, &pubkey, checker.leaf_hash)?)
+                } else {
+                    checker.check_ecdsa(&sig, &pubkey, script, sig_version)
+                };
+                // NULLFAIL: a failed check must use an empty signature
+                if !valid && !sig.is_empty() {
+                    return Err(fail("signature check failed"));
+                }
+                if op == 0xad {
+                    if !valid {
+                        return Err(fail("OP_CHECKSIGVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(valid));
+                }
+            }
+            0xae | 0xaf => {
+                // OP_CHECKMULTISIG / OP_CHECKMULTISIGVERIFY
+                if sig_version == SigVersion::Tapscript {
+                    return Err(fail("OP_CHECKMULTISIG is disabled in tapscript"));
+                }
+                let key_count = usize::try_from(pop_num(stack)?).map_err(|_| fail("invalid key count"))?;
+                if key_count > 20 {
+                    return Err(fail("too many multisig keys"));
+                }
+                op_count += key_count;
+                if op_count > MAX_OPS_PER_SCRIPT {
+                    return Err(fail("too many opcodes"));
+                }
+                let mut keys = Vec::new();
+                for _ in 0..key_count {
+                    keys.push(pop(stack)?);
+                }
+                keys.reverse();
+                let sig_count = usize::try_from(pop_num(stack)?).map_err(|_| fail("invalid signature count"))?;
+                if sig_count > key_count {
+                    return Err(fail("more signatures than keys"));
+                }
+                let mut sigs = Vec::new();
+                for _ in 0..sig_count {
+                    sigs.push(pop(stack)?);
+                }
+                sigs.reverse();
+                // NULLDUMMY: the extra item consumed by the off-by-one bug must be empty
+                if !pop(stack)?.is_empty() {
+                    return Err(fail("CHECKMULTISIG dummy element must be empty"));
+                }
+
+                // signatures must appear in the same order as their keys
+                let mut key_iter = keys.iter();
+                let mut valid = true;
+                for sig in &sigs {
+                    if !key_iter.any(|key| checker.check_ecdsa(sig, key, script, sig_version)) {
+                        valid = false;
+                        break;
+                    }
+                }
+                if !valid && sigs.iter().any(|sig| !sig.is_empty()) {
+                    return Err(fail("signature check failed"));
+                }
+                if op == 0xaf {
+                    if !valid {
+                        return Err(fail("OP_CHECKMULTISIGVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(valid));
+                }
+            }
+            0xb1 => {
+                // OP_CHECKLOCKTIMEVERIFY, leaves its argument on the stack
+                let locktime = decode_num(peek(stack, 0)?, 5)?;
+                if locktime < 0 || !checker.check_locktime(locktime) {
+                    return Err(fail("locktime requirement not satisfied"));
+                }
+            }
+            0xb2 => {
+                // OP_CHECKSEQUENCEVERIFY, leaves its argument on the stack
+                let sequence = decode_num(peek(stack, 0)?, 5)?;
+                if sequence < 0 {
+                    return Err(fail("negative relative locktime"));
+                }
+                if sequence & SEQUENCE_DISABLE_FLAG == 0 && !checker.check_sequence(sequence) {
+                    return Err(fail("relative locktime requirement not satisfied"));
+                }
+            }
+            0xba => {
+                // OP_CHECKSIGADD
+                if sig_version != SigVersion::Tapscript {
+                    return Err(fail("OP_CHECKSIGADD is only valid in tapscript"));
+                }
+                let pubkey = pop(stack)?;
+                let n = pop_num(stack)?;
+                let sig = pop(stack)?;
+                if pubkey.is_empty() {
+                    return Err(fail("empty public key in tapscript"));
+                }
+                let valid =
+                    !sig.is_empty() && (pubkey.len() != 32 || checker.check_schnorr(&sig, &pubkey, checker.leaf_hash)?);
+                if !valid && !sig.is_empty() {
+                    return Err(fail("signature check failed"));
+                }
+                stack.push(encode_num(n + valid as i64));
+            }
+            _ => return Err(SpendError::InvalidScript(format!("unsupported opcode {:#04x}", op))),
+        }
+
+        if stack.len() + alt_stack.len() > MAX_STACK_SIZE {
+            return Err(fail("stack size limit exceeded"));
+        }
+    }
+
+    if !branches.is_empty() {
+        return Err(fail("unbalanced OP_IF"));
+    }
+    Ok(())
+}
+
+// Segwit scripts must leave exactly one true item
+fn check_clean_stack(stack: &Stack) -> Result<(), SpendError> {
+    if stack.len() != 1 || !cast_to_bool(&stack[0]) {
+        return Err(fail("script did not leave a single true item"));
+    }
+    Ok(())
+}
+
+fn verify_witness_program(
+    checker: &mut Checker,
+    version: u8,
+    program: &[u8],
+    witness: &[Vec<u8>],
+) -> Result<(), SpendError> {
+    match (version, program.len()) {
+        (0, 20) => {
+            // P2WPKH: <signature> <pubkey> against the p2pkh template
+            if witness.len() != 2 {
+                return Err(fail("P2WPKH witness must have 2 items"));
+            }
+            let mut spk = vec![0x00, 0x14];
+            spk.extend(program);
+            let script_code = get_p2wpkh_scriptcode(&Utxo {
+                script_pubkey: spk,
+                amount: 0,
+            });
+            let mut stack = witness.to_vec();
+            execute(&script_code, &mut stack, checker, SigVersion::WitnessV0)?;
+            check_clean_stack(&stack)
+        }
+        (0, 32) => {
+            // P2WSH: the last item is the witness script, committed to by its SHA256
+            let (witness_script, items) = witness.split_last().ok_or(fail("empty P2WSH witness"))?;
+            if Sha256::digest(witness_script).as_slice() != program {
+                return Err(fail("witness script does not match the P2WSH program"));
+            }
+            if items.iter().any(|item| item.len() > MAX_ELEMENT_SIZE) {
+                return Err(fail("witness item larger than 520 bytes"));
+            }
+            let mut stack = items.to_vec();
+            execute(witness_script, &mut stack, checker, SigVersion::WitnessV0)?;
+            check_clean_stack(&stack)
+        }
+        (0, _) => Err(fail("invalid v0 witness program length")),
+        (1, 32) => verify_taproot(checker, program, witness),
+        // unknown witness versions are anyone-can-spend, reserved for upgrades
+        _ => Ok(()),
+    }
+}
+
+// BIP341: a single signature for the key path, or script, control block
+// and the script's inputs for the script path
+fn verify_taproot(checker: &mut Checker, output_key: &[u8], witness: &[Vec<u8>]) -> Result<(), SpendError> {
+    let mut witness = witness.to_vec();
+    if witness.len() >= 2 && witness.last().is_some_and(|item| item.first() == Some(&0x50)) {
+        return Err(fail("taproot annex is not supported"));
+    }
+    match witness.len() {
+        0 => Err(fail("empty taproot witness")),
+        1 => {
+            if checker.check_schnorr(&witness[0], output_key, None)? {
+                Ok(())
+            } else {
+                Err(fail("invalid taproot key path signature"))
+            }
+        }
+        _ => {
+            let control_block = witness.pop().unwrap();
+            let script = witness.pop().unwrap();
+            if control_block.len() < 33 || !(control_block.len() - 33).is_multiple_of(32) || control_block.len() > 33 + 128 * 32 {
+                return Err(fail("invalid control block size"));
+            }
+            if control_block[0] & 0xfe != TAPSCRIPT_LEAF_VERSION {
+                return Err(fail("unsupported tapleaf version"));
+            }
+
+            // walk the merkle path up from the leaf and check the output key commits to it
+            let leaf_hash = tap_leaf_hash(&script);
+            let mut node = leaf_hash;
+            for sibling in control_block[33..].chunks(32) {
+                node = tap_branch_hash(&node, sibling.try_into().unwrap());
+            }
+            let (expected_key, parity) = taproot_output_key(&control_block[1..33], Some(node))?;
+            if expected_key.serialize() != output_key || parity.to_u8() != control_block[0] & 1 {
+                return Err(fail("control block does not match the output key"));
+            }
+
+            if witness.iter().any(|item| item.len() > MAX_ELEMENT_SIZE) {
+                return Err(fail("witness item larger than 520 bytes"));
+            }
+            checker.leaf_hash = Some(leaf_hash);
+            execute(&script, &mut witness, checker, SigVersion::Tapscript)?;
+            check_clean_stack(&witness)
+        }
+    }
+}
+
+// (version, program) of a witness program scriptPubKey
+fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    match script_type(script) {
+        ScriptType::P2wpkh | ScriptType::P2wsh => Some((0, &script[2..])),
+        ScriptType::P2tr | ScriptType::WitnessUnknown => Some((script[0] - 0x50, &script[2..])),
+        _ => None,
+    }
+}
+
+fn is_push_only(script: &[u8]) -> bool {
+    decode_script(script).is_some_and(|instructions| {
+        instructions
+            .iter()
+            .all(|instruction| matches!(instruction, Instruction::Push(_) | Instruction::Op(0x00 | 0x4f | 0x51..=0x60)))
+    })
+}
+
+// Check that input `input_index` of `tx` satisfies the output it spends.
+// `prevouts` are the outputs spent by every input, in input order.
+pub fn verify_input(tx: &Transaction, input_index: usize, prevouts: &[Utxo]) -> Result<(), SpendError> {
+    let input = &tx.inputs[input_index];
+    let script_pubkey = &prevouts[input_index].script_pubkey;
+    let mut checker = Checker {
+        tx,
+        input_index,
+        prevouts,
+        leaf_hash: None,
+    };
+
+    // native segwit: the scriptSig must be empty and the witness does the work
+    if let Some((version, program)) = witness_program(script_pubkey) {
+        if !input.script_sig.is_empty() {
+            return Err(fail("native witness spend with a non-empty scriptSig"));
+        }
+        return verify_witness_program(&mut checker, version, program, &input.witness);
+    }
+
+    if !is_push_only(&input.script_sig) && script_type(script_pubkey) == ScriptType::P2sh {
+        return Err(fail("P2SH scriptSig must only push data"));
+    }
+    let mut stack = Vec::new();
+    execute(&input.script_sig, &mut stack, &checker, SigVersion::Base)?;
+    let script_sig_stack = stack.clone();
+    execute(script_pubkey, &mut stack, &checker, SigVersion::Base)?;
+    if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+        return Err(fail("scriptPubKey evaluated to false"));
+    }
+
+    if script_type(script_pubkey) != ScriptType::P2sh {
+        if !input.witness.is_empty() {
+            return Err(fail("unexpected witness"));
+        }
+        return Ok(());
+    }
+
+    // P2SH: run the redeem script, the last item pushed by the scriptSig
+    let mut stack = script_sig_stack;
+    let redeem_script = pop(&mut stack)?;
+    if let Some((version, program)) = witness_program(&redeem_script) {
+        // nested segwit, the scriptSig must be exactly the redeem script push
+        if !stack.is_empty() {
+            return Err(fail("nested witness scriptSig must only push the redeem script"));
+        }
+        return verify_witness_program(&mut checker, version, program, &input.witness);
+    }
+    execute(&redeem_script, &mut stack, &checker, SigVersion::Base)?;
+    if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+        return Err(fail("redeem script evaluated to false"));
+    }
+    if !input.witness.is_empty() {
+        return Err(fail("unexpected witness"));
+    }
+    Ok(())
+}
+
+// Verify every input of `tx`
+pub fn verify_transaction(tx: &Transaction, prevouts: &[Utxo]) -> Result<(), SpendError> {
+    if prevouts.len() != tx.inputs.len() {
+        return Err(SpendError::InvalidTransaction(format!(
+            "{} spent outputs given for {} inputs",
+            prevouts.len(),
+            tx.inputs.len()
+        )));
+    }
+    for index in 0..tx.inputs.len() {
+        verify_input(tx, index, prevouts)
+            .map_err(|e| SpendError::InvalidScript(format!("input {}: {}", index, e)))?;
+    }
+    Ok(())
+}
+
+// Outputs spent by `tx`, from the wallet's coins where it has them and
+// otherwise from the node (which needs -txindex for confirmed transactions)
+pub fn lookup_prevouts(tx: &Transaction, wallet_state: Option<&WalletState>) -> Result<Vec<Utxo>, SpendError> {
+    tx.inputs
+        .iter()
+        .map(|input| lookup_prevout(&input.previous_output, wallet_state))
+        .collect()
+}
+
+// The output spent by `outpoint`, from the wallet's coins or else the node
+pub fn lookup_prevout(outpoint: &Outpoint, wallet_state: Option<&WalletState>) -> Result<Utxo, SpendError> {
+    let outpoint = (outpoint.txid_hex(), outpoint.index);
+    let coin = wallet_state.and_then(|wallet_state| {
+        wallet_state
+            .utxos
+            .get(&outpoint)
+            .or_else(|| wallet_state.watched_utxos.get(&outpoint))
+    });
+    if let Some((script_pubkey, value)) = coin {
+        return Ok(Utxo {
+            script_pubkey: script_pubkey.clone(),
+            amount: btc_to_sats(*value),
+        });
+    }
+
+    let raw = bcli(&format!("-signet getrawtransaction {}", outpoint.0))?;
+    let raw = String::from_utf8_lossy(&raw);
+    let prev_tx = hex::decode(raw.trim())
+        .ok()
+        .and_then(|bytes| Transaction::parse(&bytes).ok())
+        .ok_or(SpendError::InvalidTransaction(format!(
+            "can't find transaction {}: {}",
+            outpoint.0,
+            raw.trim()
+        )))?;
+    let output = prev_tx.outputs.get(outpoint.1 as usize).ok_or(SpendError::InvalidTransaction(format!(
+        "{} has no output {}",
+        outpoint.0, outpoint.1
+    )))?;
+    Ok(output.clone())
+}
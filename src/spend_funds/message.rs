@@ -0,0 +1,192 @@
+// Message signing with the keys of our addresses: the legacy "Bitcoin Signed
+// Message" compact signatures and BIP322 simple signatures
+use crate::balance::address::{address_to_script, script_to_address};
+use crate::balance::balance::WalletState;
+use crate::spend_funds::builder::sign_wallet_inputs;
+use crate::spend_funds::interpreter::{hash160, verify_transaction};
+use crate::spend_funds::script::{push_data, script_type, ScriptType, OP_RETURN};
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use crate::spend_funds::taproot::tagged_hash;
+use crate::spend_funds::transaction::{
+    compact_size, hash256, parse_witness, serialize_witness, Outpoint, Transaction, TxIn, Utxo,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use secp256k1::{Message, Secp256k1, SecretKey};
+
+const MESSAGE_MAGIC: &[u8] = b"\x18Bitcoin Signed Message:\n";
+// BIP137 header bytes: 27 + recovery id, +4 for a compressed key, +8 for
+// P2SH-P2WPKH and +12 for P2WPKH addresses
+const HEADER_BASE: u8 = 27;
+const HEADER_P2PKH: u8 = HEADER_BASE + 4;
+const HEADER_P2SH_P2WPKH: u8 = HEADER_BASE + 8;
+const HEADER_P2WPKH: u8 = HEADER_BASE + 12;
+
+fn invalid(msg: &str) -> SpendError {
+    SpendError::SigningError(msg.to_string())
+}
+
+// Double SHA256 of the magic prefix and the length-prefixed message
+fn message_hash(message: &str) -> [u8; 32] {
+    let mut data = MESSAGE_MAGIC.to_vec();
+    data.extend(compact_size(message.len() as u64));
+    data.extend(message.as_bytes());
+    hash256(&data).try_into().unwrap()
+}
+
+// The to_spend transaction of BIP322: a virtual output paying to
+// `script_pubkey` that commits to the message
+fn to_spend(script_pubkey: &[u8], message: &str) -> Transaction {
+    let mut script_sig = vec![0x00];
+    push_data(&mut script_sig, &tagged_hash("BIP0322-signed-message", message.as_bytes()));
+    let mut input = TxIn::new(Outpoint {
+        txid: [0; 32],
+        index: 0xFFFFFFFF,
+    });
+    input.script_sig = script_sig;
+    input.sequence = 0;
+    let mut tx = Transaction::new(
+        vec![input],
+        vec![Utxo {
+            script_pubkey: script_pubkey.to_vec(),
+            amount: 0,
+        }],
+    );
+    tx.version = 0;
+    tx
+}
+
+// The to_sign transaction of BIP322, spending to_spend to an empty OP_RETURN.
+// Returns it with the output it spends.
+fn to_sign(script_pubkey: &[u8], message: &str) -> (Transaction, Utxo) {
+    let to_spend = to_spend(script_pubkey, message);
+    let mut txid = to_spend.txid();
+    txid.reverse();
+    let mut input = TxIn::new(Outpoint { txid, index: 0 });
+    input.sequence = 0;
+    let mut tx = Transaction::new(
+        vec![input],
+        vec![Utxo {
+            script_pubkey: vec![OP_RETURN],
+            amount: 0,
+        }],
+    );
+    tx.version = 0;
+    (tx, to_spend.outputs[0].clone())
+}
+
+// Legacy compact signature with the P2WPKH (receive or change), P2SH-P2WPKH or
+// P2PKH key of `script_pubkey`, with the header of its address type
+fn sign_legacy(wallet_state: &WalletState, script_pubkey: &[u8], message: &str) -> Result<String, SpendError> {
+    let key_for = |keys: &[Vec<u8>], scripts: &[Vec<u8>]| {
+        let index = scripts.iter().position(|script| script == script_pubkey)?;
+        keys.get(index).cloned()
+    };
+    let (private_key, header) = match script_type(script_pubkey) {
+        ScriptType::P2wpkh => (
+            wallet_state.p2wpkh_key_for_script(script_pubkey).map(|key| key.private_key.to_vec()),
+            HEADER_P2WPKH,
+        ),
+        ScriptType::P2sh => (key_for(&wallet_state.nested_keys, &wallet_state.nested_scripts), HEADER_P2SH_P2WPKH),
+        _ => (key_for(&wallet_state.legacy_keys, &wallet_state.legacy_scripts), HEADER_P2PKH),
+    };
+    let private_key = private_key.ok_or_else(|| {
+        let address = script_to_address(script_pubkey).unwrap_or_else(|| hex::encode(script_pubkey));
+        SpendError::SigningError(format!("no key for address {}", address))
+    })?;
+    let secret_key = SecretKey::from_slice(&private_key).map_err(|e| SpendError::SigningError(e.to_string()))?;
+    let signature = Secp256k1::new()
+        .sign_ecdsa_recoverable(&Message::from_digest(message_hash(message)), &secret_key);
+    let (recovery_id, compact) = signature.serialize_compact();
+
+    let mut data = vec![header + i32::from(recovery_id) as u8];
+    data.extend(compact);
+    Ok(BASE64.encode(data))
+}
+
+// BIP322 simple signature: the witness of to_sign, signed like any input of ours
+fn sign_bip322(wallet_state: &WalletState, script_pubkey: &[u8], message: &str) -> Result<String, SpendError> {
+    let (mut tx, prevout) = to_sign(script_pubkey, message);
+    let prevouts = [prevout];
+    sign_wallet_inputs(wallet_state, &mut tx, &prevouts)?;
+    verify_transaction(&tx, &prevouts)?;
+    Ok(BASE64.encode(serialize_witness(&tx.inputs[0].witness)))
+}
+
+// Sign `message` with the key of our `address`. P2WPKH addresses give a
+// legacy signature unless `bip322` is set, taproot addresses always BIP322
+// and P2PKH and P2SH-P2WPKH addresses always legacy.
+pub fn sign_message(
+    wallet_state: &WalletState,
+    address: &str,
+    message: &str,
+    bip322: bool,
+) -> Result<String, SpendError> {
+    let script_pubkey = address_to_script(address)?;
+    if !wallet_state.is_ours(&script_pubkey) {
+        return Err(invalid(&format!("{} is not an address of this wallet", address)));
+    }
+    match script_type(&script_pubkey) {
+        ScriptType::P2wpkh if bip322 => sign_bip322(wallet_state, &script_pubkey, message),
+        ScriptType::P2tr => sign_bip322(wallet_state, &script_pubkey, message),
+        ScriptType::P2sh if bip322 => Err(invalid("BIP322 simple signatures need a native segwit address")),
+        _ => sign_legacy(wallet_state, &script_pubkey, message),
+    }
+}
+
+// Check a legacy signature by recovering its key and matching it against the
+// address, whichever of the P2PKH, P2SH-P2WPKH or P2WPKH headers it uses
+fn verify_legacy(script_pubkey: &[u8], data: &[u8], message: &str) -> Result<(), SpendError> {
+    let header = data[0]
+        .checked_sub(HEADER_BASE)
+        .filter(|header| *header < 16)
+        .ok_or(invalid("bad signature header"))?;
+    let recovery_id = RecoveryId::try_from(i32::from(header & 3)).map_err(|e| SpendError::SigningError(e.to_string()))?;
+    let signature = RecoverableSignature::from_compact(&data[1..], recovery_id)
+        .map_err(|e| SpendError::SigningError(e.to_string()))?;
+    let pubkey = Secp256k1::new()
+        .recover_ecdsa(&Message::from_digest(message_hash(message)), &signature)
+        .map_err(|e| SpendError::SigningError(e.to_string()))?;
+
+    let key = if header >= 4 { pubkey.serialize().to_vec() } else { pubkey.serialize_uncompressed().to_vec() };
+    let key_hash = hash160(&key);
+    let matches = match script_type(script_pubkey) {
+        ScriptType::P2pkh => script_pubkey[3..23] == key_hash[..],
+        // segwit keys must be compressed
+        ScriptType::P2wpkh => header >= 4 && script_pubkey[2..] == key_hash[..],
+        ScriptType::P2sh => {
+            let mut redeem_script = vec![0x00, 0x14];
+            redeem_script.extend(&key_hash);
+            header >= 4 && script_pubkey[2..22] == hash160(&redeem_script)[..]
+        }
+        _ => return Err(invalid("legacy signatures are only for P2PKH and P2WPKH addresses")),
+    };
+    if !matches {
+        return Err(invalid("signature is not from the key of this address"));
+    }
+    Ok(())
+}
+
+// Check a BIP322 simple signature by running the to_sign transaction it signs
+fn verify_bip322(script_pubkey: &[u8], data: &[u8], message: &str) -> Result<(), SpendError> {
+    let (mut tx, prevout) = to_sign(script_pubkey, message);
+    tx.inputs[0].witness = parse_witness(data)?;
+    verify_transaction(&tx, &[prevout])
+}
+
+// Check that `signature` (base64, legacy or BIP322 simple) signs `message`
+// for `address`
+pub fn verify_message(address: &str, signature: &str, message: &str) -> Result<(), SpendError> {
+    let script_pubkey = address_to_script(address)?;
+    let data = BASE64
+        .decode(signature.trim())
+        .map_err(|e| SpendError::SigningError(format!("bad signature encoding: {}", e)))?;
+    // a 65-byte compact signature can't be a witness: its first byte would
+    // count 27 or more items
+    if data.len() == 65 && (HEADER_BASE..HEADER_BASE + 16).contains(&data[0]) {
+        verify_legacy(&script_pubkey, &data, message)
+    } else {
+        verify_bip322(&script_pubkey, &data, message)
+    }
+}
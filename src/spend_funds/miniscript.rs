@@ -0,0 +1,904 @@
+// Miniscript (BIP379): spending policies such as "or(pk(A),and(pk(B),older(144)))"
+// compiled to P2WSH witness scripts or tapscript leaves, their output
+// descriptors, and a satisfier building witnesses from the signatures,
+// preimages and timelocks at hand
+use crate::balance::address::{address_to_script, encode_segwit_address, SIGNET_HRP};
+use crate::balance::balance::WalletState;
+use crate::balance::cache::MiniscriptPolicy;
+use crate::psbt::signer::add_checksum;
+use crate::spend_funds::interpreter::hash160;
+use crate::spend_funds::script::{
+    push_data, push_int, MAX_MULTISIG_KEYS, OP_0, OP_0NOTEQUAL, OP_ADD, OP_CHECKLOCKTIMEVERIFY,
+    OP_CHECKMULTISIG, OP_CHECKMULTISIGVERIFY, OP_CHECKSEQUENCEVERIFY, OP_CHECKSIG, OP_CHECKSIGADD,
+    OP_CHECKSIGVERIFY, OP_ELSE, OP_ENDIF, OP_EQUAL, OP_EQUALVERIFY, OP_FROMALTSTACK, OP_HASH160, OP_HASH256,
+    OP_IF, OP_IFDUP, OP_NOTIF, OP_NUMEQUAL, OP_NUMEQUALVERIFY, OP_RIPEMD160, OP_SHA256, OP_SIZE, OP_SWAP,
+    OP_TOALTSTACK, OP_VERIFY,
+};
+use crate::spend_funds::sighash::{
+    get_commitment_hash, get_taproot_commitment_hash, SighashType, SIGHASH_ALL, SIGHASH_DEFAULT,
+};
+use crate::spend_funds::spend_p2wpkh::{get_p2wsh_program, sign, SpendError};
+use crate::spend_funds::taproot::{
+    get_p2tr_program, our_taproot_key, resolve_key, sign_tapscript, tap_leaf_hash, x_only_key, TapTree,
+};
+use crate::spend_funds::timelock::{resolve_pubkey, spend_script_output};
+use crate::spend_funds::transaction::{Transaction, Utxo, SEQUENCE_FINAL, SEQUENCE_RBF};
+use hex_literal::hex;
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+// BIP341's provably unspendable internal key H, so that the coins of a P2TR
+// policy can only be spent through its leaves
+const NUMS_KEY: [u8; 32] = hex!("50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0");
+// Largest standard P2WSH witness script
+const MAX_STANDARD_WITNESS_SCRIPT_SIZE: usize = 3600;
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_MASK: u32 = 0x0000ffff;
+
+fn invalid(msg: String) -> SpendError {
+    SpendError::InvalidScript(msg)
+}
+
+// Where a miniscript runs: keys are compressed in P2WSH scripts and x-only in
+// tapscript, which has CHECKSIGADD instead of CHECKMULTISIG
+#[derive(Clone, Copy, PartialEq)]
+pub enum Context {
+    Segwitv0,
+    Tapscript,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum HashType {
+    Sha256,
+    Hash256,
+    Ripemd160,
+    Hash160,
+}
+
+impl HashType {
+    fn name(self) -> &'static str {
+        match self {
+            HashType::Sha256 => "sha256",
+            HashType::Hash256 => "hash256",
+            HashType::Ripemd160 => "ripemd160",
+            HashType::Hash160 => "hash160",
+        }
+    }
+
+    fn opcode(self) -> u8 {
+        match self {
+            HashType::Sha256 => OP_SHA256,
+            HashType::Hash256 => OP_HASH256,
+            HashType::Ripemd160 => OP_RIPEMD160,
+            HashType::Hash160 => OP_HASH160,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            HashType::Sha256 | HashType::Hash256 => 32,
+            HashType::Ripemd160 | HashType::Hash160 => 20,
+        }
+    }
+
+    fn digest(self, preimage: &[u8]) -> Vec<u8> {
+        match self {
+            HashType::Sha256 => Sha256::digest(preimage).to_vec(),
+            HashType::Hash256 => Sha256::digest(Sha256::digest(preimage)).to_vec(),
+            HashType::Ripemd160 => Ripemd160::digest(preimage).to_vec(),
+            HashType::Hash160 => hash160(preimage),
+        }
+    }
+}
+
+// A spending policy: who can spend and when, without saying how
+#[derive(Clone)]
+pub enum Policy {
+    // a signature with the key: hex, an extended public key or @<i> until resolved
+    Key(String),
+    // from this block height or time on
+    After(u32),
+    // a relative lock after the coin confirmed, as an nSequence value (BIP68):
+    // blocks, or with the type flag set 512 second units
+    Older(u32),
+    // the preimage of the hash
+    Hash(HashType, Vec<u8>),
+    And(Box<Policy>, Box<Policy>),
+    Or(Box<Policy>, Box<Policy>),
+    // k of the policies
+    Thresh(usize, Vec<Policy>),
+}
+
+// "name(a,b(c,d))" split into "name" and its arguments ["a", "b(c,d)"]
+fn split_call(s: &str) -> Result<(&str, Vec<&str>), SpendError> {
+    let (name, rest) = s.split_once('(').ok_or(invalid(format!("expected a fragment, got {:?}", s)))?;
+    let inner = rest.strip_suffix(')').ok_or(invalid(format!("missing ')' in {:?}", s)))?;
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, ch) in inner.char_indices() {
+        match ch {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err(invalid(format!("unbalanced ')' in {:?}", s))),
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(&inner[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(invalid(format!("unbalanced '(' in {:?}", s)));
+    }
+    args.push(&inner[start..]);
+    Ok((name, args))
+}
+
+// Timelock values must fit the 31 bits CLTV and CSV take, and not be 0
+fn parse_lock(arg: &str) -> Result<u32, SpendError> {
+    arg.parse::<u32>()
+        .ok()
+        .filter(|n| (1..SEQUENCE_DISABLE_FLAG).contains(n))
+        .ok_or(invalid(format!("invalid timelock {}", arg)))
+}
+
+impl FromStr for Policy {
+    type Err = SpendError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s: String = s.chars().filter(|ch| !ch.is_whitespace()).collect();
+        let (name, args) = split_call(&s)?;
+        let hash_type = match name {
+            "sha256" => Some(HashType::Sha256),
+            "hash256" => Some(HashType::Hash256),
+            "ripemd160" => Some(HashType::Ripemd160),
+            "hash160" => Some(HashType::Hash160),
+            _ => None,
+        };
+        let policy = match (name, args.as_slice()) {
+            ("pk", [key]) if !key.is_empty() => Policy::Key(key.to_string()),
+            ("after", [n]) => Policy::After(parse_lock(n)?),
+            ("older", [n]) => Policy::Older(parse_lock(n)?),
+            (_, [hash]) if hash_type.is_some() => {
+                let hash_type = hash_type.expect("checked above");
+                let bytes = hex::decode(hash)
+                    .ok()
+                    .filter(|bytes| bytes.len() == hash_type.size())
+                    .ok_or(invalid(format!("not a {} hash: {}", name, hash)))?;
+                Policy::Hash(hash_type, bytes)
+            }
+            ("and", [a, b]) => Policy::And(Box::new(a.parse()?), Box::new(b.parse()?)),
+            ("or", [a, b]) => Policy::Or(Box::new(a.parse()?), Box::new(b.parse()?)),
+            ("thresh", [k, subs @ ..]) if !subs.is_empty() => {
+                let k = k
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|k| (1..=subs.len()).contains(k))
+                    .ok_or(invalid(format!("invalid threshold {} of {}", k, subs.len())))?;
+                Policy::Thresh(k, subs.iter().map(|sub| sub.parse()).collect::<Result<_, _>>()?)
+            }
+            _ => return Err(invalid(format!("unknown policy fragment {}", s))),
+        };
+        Ok(policy)
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Policy::Key(key) => write!(f, "pk({})", key),
+            Policy::After(n) => write!(f, "after({})", n),
+            Policy::Older(n) => write!(f, "older({})", n),
+            Policy::Hash(hash_type, hash) => write!(f, "{}({})", hash_type.name(), hex::encode(hash)),
+            Policy::And(a, b) => write!(f, "and({},{})", a, b),
+            Policy::Or(a, b) => write!(f, "or({},{})", a, b),
+            Policy::Thresh(k, subs) => {
+                write!(f, "thresh({}", k)?;
+                for sub in subs {
+                    write!(f, ",{}", sub)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+// The key of a resolved policy as it goes in a script of `context`
+fn key_bytes(key: &str, context: Context) -> Result<Vec<u8>, SpendError> {
+    let bytes = hex::decode(key).map_err(|_| invalid(format!("unresolved key {}", key)))?;
+    match context {
+        Context::Segwitv0 if bytes.len() == 33 && secp256k1::PublicKey::from_slice(&bytes).is_ok() => Ok(bytes),
+        Context::Segwitv0 => Err(invalid(format!("not a compressed public key: {}", key))),
+        Context::Tapscript => Ok(x_only_key(&bytes)?.serialize().to_vec()),
+    }
+}
+
+impl Policy {
+    // The policy with every key as hex: @<i> is our BIP84 key i in P2WSH
+    // scripts and our BIP86 key i in tapscript, extended keys give their /0/0 child
+    pub fn resolve_keys(&self, wallet_state: &WalletState, context: Context) -> Result<Policy, SpendError> {
+        let resolve = |policy: &Policy| policy.resolve_keys(wallet_state, context).map(Box::new);
+        Ok(match self {
+            Policy::Key(key) => Policy::Key(match context {
+                Context::Segwitv0 => hex::encode(resolve_pubkey(wallet_state, key)?),
+                Context::Tapscript if key.starts_with('@') => resolve_key(wallet_state, key)?,
+                Context::Tapscript if key.len() == 64 => hex::encode(key_bytes(key, context)?),
+                Context::Tapscript => hex::encode(x_only_key(&resolve_pubkey(wallet_state, key)?)?.serialize()),
+            }),
+            Policy::And(a, b) => Policy::And(resolve(a)?, resolve(b)?),
+            Policy::Or(a, b) => Policy::Or(resolve(a)?, resolve(b)?),
+            Policy::Thresh(k, subs) => Policy::Thresh(
+                *k,
+                subs.iter()
+                    .map(|sub| sub.resolve_keys(wallet_state, context))
+                    .collect::<Result<_, _>>()?,
+            ),
+            other => other.clone(),
+        })
+    }
+
+    // Compile a resolved policy. The result is always of miniscript's type B:
+    // it leaves a single non-zero element when satisfied. It isn't the
+    // smallest script for the policy, but the same policy always compiles to
+    // the same script.
+    pub fn compile(&self, context: Context) -> Result<Miniscript, SpendError> {
+        match self {
+            Policy::Key(key) => Ok(Miniscript::Pk(key_bytes(key, context)?)),
+            Policy::After(n) => Ok(Miniscript::After(*n)),
+            Policy::Older(n) => Ok(Miniscript::Older(*n)),
+            Policy::Hash(hash_type, hash) => Ok(Miniscript::Hash(*hash_type, hash.clone())),
+            Policy::And(a, b) => {
+                let (mut x, mut y) = (a.compile(context)?, b.compile(context)?);
+                // the part that runs last gives the result, better a unit one
+                if !y.is_unit() && x.is_unit() {
+                    std::mem::swap(&mut x, &mut y);
+                }
+                Ok(Miniscript::AndV(Box::new(Miniscript::Verify(Box::new(x))), Box::new(y)))
+            }
+            Policy::Or(a, b) => {
+                let (x, z) = (a.compile(context)?, b.compile(context)?);
+                // or_d runs its second part only once the first failed, which
+                // it must be able to do cleanly
+                Ok(if x.is_dissatisfiable() && x.is_unit() {
+                    Miniscript::OrD(Box::new(x), Box::new(z))
+                } else if z.is_dissatisfiable() && z.is_unit() {
+                    Miniscript::OrD(Box::new(z), Box::new(x))
+                } else {
+                    Miniscript::OrI(Box::new(x), Box::new(z))
+                })
+            }
+            Policy::Thresh(k, subs) => {
+                let keys: Option<Vec<&String>> = subs
+                    .iter()
+                    .map(|sub| match sub {
+                        Policy::Key(key) => Some(key),
+                        _ => None,
+                    })
+                    .collect();
+                if let Some(keys) = keys {
+                    let keys = keys
+                        .iter()
+                        .map(|key| key_bytes(key, context))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    return match context {
+                        Context::Segwitv0 if keys.len() > MAX_MULTISIG_KEYS => Err(invalid(format!(
+                            "multi() takes at most {} keys, got {}",
+                            MAX_MULTISIG_KEYS,
+                            keys.len()
+                        ))),
+                        Context::Segwitv0 => Ok(Miniscript::Multi(*k, keys)),
+                        Context::Tapscript => Ok(Miniscript::MultiA(*k, keys)),
+                    };
+                }
+
+                // all of or one of: a chain of and()s or or()s
+                let (first, rest) = subs.split_first().expect("thresh has parts");
+                if rest.is_empty() {
+                    return first.compile(context);
+                }
+                if *k == subs.len() || *k == 1 {
+                    let rest = match rest {
+                        [single] => single.clone(),
+                        _ => Policy::Thresh(if *k == 1 { 1 } else { rest.len() }, rest.to_vec()),
+                    };
+                    let policy = if *k == 1 {
+                        Policy::Or(Box::new(first.clone()), Box::new(rest))
+                    } else {
+                        Policy::And(Box::new(first.clone()), Box::new(rest))
+                    };
+                    return policy.compile(context);
+                }
+
+                // thresh() adds up the parts' results, so each must give 0 or 1:
+                // n: makes a part give 1, and j: or l: lets it give 0
+                let mut parts = Vec::new();
+                for (index, sub) in subs.iter().enumerate() {
+                    let mut part = sub.compile(context)?;
+                    let wrapped = !part.is_dissatisfiable() || !part.is_unit();
+                    if !part.is_unit() {
+                        part = Miniscript::ZeroNotEqual(Box::new(part));
+                    }
+                    if !part.is_dissatisfiable() {
+                        part = match part.is_nonzero() {
+                            true => Miniscript::NonZero(Box::new(part)),
+                            false => Miniscript::Likely(Box::new(part)),
+                        };
+                    }
+                    // later parts run with the sum on the stack: wrapped parts
+                    // taking a single item swap it out of the way, the rest
+                    // (and all parts before wrapping existed) move it with a:
+                    parts.push(match index {
+                        0 => part,
+                        _ if wrapped && part.is_one_arg() => Miniscript::Swap(Box::new(part)),
+                        _ => Miniscript::Alt(Box::new(part)),
+                    });
+                }
+                Ok(Miniscript::Thresh(*k, parts))
+            }
+        }
+    }
+}
+
+// A miniscript fragment, named as in BIP379
+#[derive(Clone)]
+pub enum Miniscript {
+    // <key> CHECKSIG
+    Pk(Vec<u8>),
+    // <k> <keys> <n> CHECKMULTISIG
+    Multi(usize, Vec<Vec<u8>>),
+    // <key1> CHECKSIG <key2> CHECKSIGADD ... <k> NUMEQUAL
+    MultiA(usize, Vec<Vec<u8>>),
+    // <n> CHECKSEQUENCEVERIFY
+    Older(u32),
+    // <n> CHECKLOCKTIMEVERIFY
+    After(u32),
+    // SIZE <32> EQUALVERIFY <hash op> <hash> EQUAL
+    Hash(HashType, Vec<u8>),
+    // [X] [Y], X a v: fragment
+    AndV(Box<Miniscript>, Box<Miniscript>),
+    // [X] IFDUP NOTIF [Z] ENDIF
+    OrD(Box<Miniscript>, Box<Miniscript>),
+    // IF [X] ELSE [Z] ENDIF
+    OrI(Box<Miniscript>, Box<Miniscript>),
+    // [X1] [X2] ADD ... [Xn] ADD <k> EQUAL
+    Thresh(usize, Vec<Miniscript>),
+    // v: [X] VERIFY, merged into X's last opcode when it has a VERIFY form
+    Verify(Box<Miniscript>),
+    // a: TOALTSTACK [X] FROMALTSTACK
+    Alt(Box<Miniscript>),
+    // s: SWAP [X]
+    Swap(Box<Miniscript>),
+    // n: [X] 0NOTEQUAL
+    ZeroNotEqual(Box<Miniscript>),
+    // j: SIZE 0NOTEQUAL IF [X] ENDIF
+    NonZero(Box<Miniscript>),
+    // l: IF 0 ELSE [X] ENDIF
+    Likely(Box<Miniscript>),
+}
+
+// Witness stack items, bottom first
+type Witness = Vec<Vec<u8>>;
+
+fn witness_size(witness: &Witness) -> usize {
+    witness.iter().map(|item| 1 + item.len()).sum()
+}
+
+fn cheapest(a: Option<Witness>, b: Option<Witness>) -> Option<Witness> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if witness_size(&b) < witness_size(&a) { b } else { a }),
+        (a, b) => a.or(b),
+    }
+}
+
+// `top` pushed above `bottom`, for `top`'s fragment runs first
+fn stacked(bottom: &Option<Witness>, top: &Option<Witness>) -> Option<Witness> {
+    Some([bottom.clone()?, top.clone()?].concat())
+}
+
+// What a satisfaction can use: signatures by public key, hash preimages, and
+// the nLockTime and nSequence of the spending transaction
+pub struct Satisfier<'a> {
+    pub signature: &'a dyn Fn(&[u8]) -> Option<Vec<u8>>,
+    pub preimages: &'a [Vec<u8>],
+    pub locktime: u32,
+    pub sequence: u32,
+}
+
+impl Satisfier<'_> {
+    // BIP65, as the interpreter checks it
+    fn after(&self, n: u32) -> bool {
+        (self.locktime < LOCKTIME_THRESHOLD) == (n < LOCKTIME_THRESHOLD)
+            && n <= self.locktime
+            && self.sequence != SEQUENCE_FINAL
+    }
+
+    // BIP112, as the interpreter checks it
+    fn older(&self, n: u32) -> bool {
+        let mask = SEQUENCE_TYPE_FLAG | SEQUENCE_MASK;
+        let (n, sequence) = (n & mask, self.sequence & mask);
+        self.sequence & SEQUENCE_DISABLE_FLAG == 0
+            && (n < SEQUENCE_TYPE_FLAG) == (sequence < SEQUENCE_TYPE_FLAG)
+            && n <= sequence
+    }
+}
+
+impl Miniscript {
+    // d: has a satisfaction-free way to fail without aborting the script
+    fn is_dissatisfiable(&self) -> bool {
+        match self {
+            Miniscript::Pk(_) | Miniscript::Multi(..) | Miniscript::MultiA(..) | Miniscript::Hash(..) => true,
+            Miniscript::Thresh(..) => true,
+            Miniscript::Older(_) | Miniscript::After(_) | Miniscript::AndV(..) | Miniscript::Verify(_) => false,
+            Miniscript::OrD(_, z) => z.is_dissatisfiable(),
+            Miniscript::OrI(x, z) => x.is_dissatisfiable() || z.is_dissatisfiable(),
+            Miniscript::Alt(x) | Miniscript::Swap(x) | Miniscript::ZeroNotEqual(x) => x.is_dissatisfiable(),
+            Miniscript::NonZero(_) | Miniscript::Likely(_) => true,
+        }
+    }
+
+    // u: leaves exactly 1 when satisfied
+    fn is_unit(&self) -> bool {
+        match self {
+            Miniscript::Pk(_) | Miniscript::Multi(..) | Miniscript::MultiA(..) | Miniscript::Hash(..) => true,
+            Miniscript::Thresh(..) => true,
+            Miniscript::Older(_) | Miniscript::After(_) | Miniscript::Verify(_) => false,
+            Miniscript::AndV(_, y) => y.is_unit(),
+            Miniscript::OrD(_, z) => z.is_unit(),
+            Miniscript::OrI(x, z) => x.is_unit() && z.is_unit(),
+            Miniscript::Alt(x) | Miniscript::Swap(x) | Miniscript::NonZero(x) | Miniscript::Likely(x) => x.is_unit(),
+            Miniscript::ZeroNotEqual(_) => true,
+        }
+    }
+
+    // z: takes nothing from the stack
+    fn is_zero_arg(&self) -> bool {
+        match self {
+            Miniscript::Older(_) | Miniscript::After(_) => true,
+            Miniscript::AndV(x, y) => x.is_zero_arg() && y.is_zero_arg(),
+            Miniscript::Verify(x) | Miniscript::ZeroNotEqual(x) => x.is_zero_arg(),
+            _ => false,
+        }
+    }
+
+    // o: takes exactly one item from the stack
+    fn is_one_arg(&self) -> bool {
+        match self {
+            Miniscript::Pk(_) | Miniscript::Hash(..) => true,
+            Miniscript::AndV(x, y) => {
+                x.is_zero_arg() && y.is_one_arg() || x.is_one_arg() && y.is_zero_arg()
+            }
+            Miniscript::Verify(x) | Miniscript::ZeroNotEqual(x) | Miniscript::NonZero(x) => x.is_one_arg(),
+            Miniscript::Likely(x) => x.is_zero_arg(),
+            _ => false,
+        }
+    }
+
+    // n: its satisfactions never have an empty item on top
+    fn is_nonzero(&self) -> bool {
+        match self {
+            Miniscript::Pk(_) | Miniscript::Hash(..) => true,
+            Miniscript::AndV(x, y) => x.is_nonzero() || x.is_zero_arg() && y.is_nonzero(),
+            Miniscript::Verify(x) | Miniscript::Alt(x) | Miniscript::Swap(x) | Miniscript::ZeroNotEqual(x) => {
+                x.is_nonzero()
+            }
+            _ => false,
+        }
+    }
+
+    // The letter and fragment of a wrapper such as v:X
+    fn wrapper(&self) -> Option<(char, &Miniscript)> {
+        match self {
+            Miniscript::Verify(x) => Some(('v', x)),
+            Miniscript::Alt(x) => Some(('a', x)),
+            Miniscript::Swap(x) => Some(('s', x)),
+            Miniscript::ZeroNotEqual(x) => Some(('n', x)),
+            Miniscript::NonZero(x) => Some(('j', x)),
+            Miniscript::Likely(x) => Some(('l', x)),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut script = Vec::new();
+        self.push(&mut script);
+        script
+    }
+
+    fn push(&self, script: &mut Vec<u8>) {
+        match self {
+            Miniscript::Pk(key) => {
+                push_data(script, key);
+                script.push(OP_CHECKSIG);
+            }
+            Miniscript::Multi(k, keys) => {
+                push_int(script, *k as i64);
+                for key in keys {
+                    push_data(script, key);
+                }
+                push_int(script, keys.len() as i64);
+                script.push(OP_CHECKMULTISIG);
+            }
+            Miniscript::MultiA(k, keys) => {
+                for (index, key) in keys.iter().enumerate() {
+                    push_data(script, key);
+                    script.push(if index == 0 { OP_CHECKSIG } else { OP_CHECKSIGADD });
+                }
+                push_int(script, *k as i64);
+                script.push(OP_NUMEQUAL);
+            }
+            Miniscript::Older(n) => {
+                push_int(script, *n as i64);
+                script.push(OP_CHECKSEQUENCEVERIFY);
+            }
+            Miniscript::After(n) => {
+                push_int(script, *n as i64);
+                script.push(OP_CHECKLOCKTIMEVERIFY);
+            }
+            Miniscript::Hash(hash_type, hash) => {
+                script.push(OP_SIZE);
+                push_int(script, 32);
+                script.extend([OP_EQUALVERIFY, hash_type.opcode()]);
+                push_data(script, hash);
+                script.push(OP_EQUAL);
+            }
+            Miniscript::AndV(x, y) => {
+                x.push(script);
+                y.push(script);
+            }
+            Miniscript::OrD(x, z) => {
+                x.push(script);
+                script.extend([OP_IFDUP, OP_NOTIF]);
+                z.push(script);
+                script.push(OP_ENDIF);
+            }
+            Miniscript::OrI(x, z) => {
+                script.push(OP_IF);
+                x.push(script);
+                script.push(OP_ELSE);
+                z.push(script);
+                script.push(OP_ENDIF);
+            }
+            Miniscript::Thresh(k, parts) => {
+                for (index, part) in parts.iter().enumerate() {
+                    part.push(script);
+                    if index > 0 {
+                        script.push(OP_ADD);
+                    }
+                }
+                push_int(script, *k as i64);
+                script.push(OP_EQUAL);
+            }
+            Miniscript::Verify(x) => {
+                x.push(script);
+                // every fragment ends with an opcode
+                let last = script.last_mut().expect("fragments aren't empty");
+                match *last {
+                    OP_EQUAL => *last = OP_EQUALVERIFY,
+                    OP_CHECKSIG => *last = OP_CHECKSIGVERIFY,
+                    OP_CHECKMULTISIG => *last = OP_CHECKMULTISIGVERIFY,
+                    OP_NUMEQUAL => *last = OP_NUMEQUALVERIFY,
+                    _ => script.push(OP_VERIFY),
+                }
+            }
+            Miniscript::Alt(x) => {
+                script.push(OP_TOALTSTACK);
+                x.push(script);
+                script.push(OP_FROMALTSTACK);
+            }
+            Miniscript::Swap(x) => {
+                script.push(OP_SWAP);
+                x.push(script);
+            }
+            Miniscript::ZeroNotEqual(x) => {
+                x.push(script);
+                script.push(OP_0NOTEQUAL);
+            }
+            Miniscript::NonZero(x) => {
+                script.extend([OP_SIZE, OP_0NOTEQUAL, OP_IF]);
+                x.push(script);
+                script.push(OP_ENDIF);
+            }
+            Miniscript::Likely(x) => {
+                script.extend([OP_IF, OP_0, OP_ELSE]);
+                x.push(script);
+                script.push(OP_ENDIF);
+            }
+        }
+    }
+
+    // The smallest satisfaction and dissatisfaction of the fragment, if any
+    fn satisfactions(&self, satisfier: &Satisfier) -> (Option<Witness>, Option<Witness>) {
+        match self {
+            Miniscript::Pk(key) => ((satisfier.signature)(key).map(|signature| vec![signature]), Some(vec![Vec::new()])),
+            Miniscript::Multi(k, keys) => {
+                // signatures in key order, after the item CHECKMULTISIG drops
+                let signatures: Witness = keys.iter().filter_map(|key| (satisfier.signature)(key)).take(*k).collect();
+                let sat = (signatures.len() == *k).then(|| [vec![Vec::new()], signatures].concat());
+                (sat, Some(vec![Vec::new(); k + 1]))
+            }
+            Miniscript::MultiA(k, keys) => {
+                // the first key's signature on top, empty items for the keys not used
+                let mut signed = 0;
+                let mut witness: Witness = keys
+                    .iter()
+                    .map(|key| match (signed < *k).then(|| (satisfier.signature)(key)).flatten() {
+                        Some(signature) => {
+                            signed += 1;
+                            signature
+                        }
+                        None => Vec::new(),
+                    })
+                    .collect();
+                witness.reverse();
+                ((signed == *k).then_some(witness), Some(vec![Vec::new(); keys.len()]))
+            }
+            Miniscript::Older(n) => (satisfier.older(*n).then(Vec::new), None),
+            Miniscript::After(n) => (satisfier.after(*n).then(Vec::new), None),
+            Miniscript::Hash(hash_type, hash) => {
+                let preimage = satisfier
+                    .preimages
+                    .iter()
+                    .find(|preimage| preimage.len() == 32 && hash_type.digest(preimage) == *hash);
+                (preimage.map(|preimage| vec![preimage.clone()]), Some(vec![vec![0; 32]]))
+            }
+            Miniscript::AndV(x, y) => {
+                let ((x_sat, _), (y_sat, _)) = (x.satisfactions(satisfier), y.satisfactions(satisfier));
+                (stacked(&y_sat, &x_sat), None)
+            }
+            Miniscript::OrD(x, z) => {
+                let ((x_sat, x_dsat), (z_sat, z_dsat)) = (x.satisfactions(satisfier), z.satisfactions(satisfier));
+                (cheapest(x_sat, stacked(&z_sat, &x_dsat)), stacked(&z_dsat, &x_dsat))
+            }
+            Miniscript::OrI(x, z) => {
+                let ((x_sat, x_dsat), (z_sat, z_dsat)) = (x.satisfactions(satisfier), z.satisfactions(satisfier));
+                let (one, zero) = (Some(vec![vec![1]]), Some(vec![Vec::new()]));
+                (
+                    cheapest(stacked(&x_sat, &one), stacked(&z_sat, &zero)),
+                    cheapest(stacked(&x_dsat, &one), stacked(&z_dsat, &zero)),
+                )
+            }
+            Miniscript::Thresh(k, parts) => {
+                let options: Vec<_> = parts.iter().map(|part| part.satisfactions(satisfier)).collect();
+                let Some(dsats) = options.iter().map(|(_, dsat)| dsat.clone()).collect::<Option<Vec<_>>>() else {
+                    return (None, None);
+                };
+                // satisfy the k parts that cost the least over their dissatisfaction
+                let mut candidates: Vec<usize> = (0..parts.len()).filter(|index| options[*index].0.is_some()).collect();
+                candidates.sort_by_key(|index| {
+                    witness_size(options[*index].0.as_ref().expect("filtered")) as i64
+                        - witness_size(&dsats[*index]) as i64
+                });
+                let sat = (candidates.len() >= *k).then(|| {
+                    let chosen = &candidates[..*k];
+                    // the first part runs first, so its items go on top
+                    (0..parts.len())
+                        .rev()
+                        .flat_map(|index| match chosen.contains(&index) {
+                            true => options[index].0.clone().expect("filtered"),
+                            false => dsats[index].clone(),
+                        })
+                        .collect()
+                });
+                (sat, Some(dsats.into_iter().rev().flatten().collect()))
+            }
+            Miniscript::Verify(x) => (x.satisfactions(satisfier).0, None),
+            Miniscript::Alt(x) | Miniscript::Swap(x) | Miniscript::ZeroNotEqual(x) => x.satisfactions(satisfier),
+            // an empty item skips X
+            Miniscript::NonZero(x) => (x.satisfactions(satisfier).0, Some(vec![Vec::new()])),
+            // 1 takes the 0 branch, an empty item runs X
+            Miniscript::Likely(x) => {
+                let sat = stacked(&x.satisfactions(satisfier).0, &Some(vec![Vec::new()]));
+                (sat, Some(vec![vec![1]]))
+            }
+        }
+    }
+
+    // The smallest witness stack (without the script) satisfying the
+    // miniscript with what `satisfier` has
+    pub fn satisfy(&self, satisfier: &Satisfier) -> Option<Witness> {
+        self.satisfactions(satisfier).0
+    }
+}
+
+impl fmt::Display for Miniscript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let multi = |f: &mut fmt::Formatter, name: &str, k: &usize, keys: &[Vec<u8>]| {
+            write!(f, "{}({}", name, k)?;
+            for key in keys {
+                write!(f, ",{}", hex::encode(key))?;
+            }
+            write!(f, ")")
+        };
+        match self {
+            Miniscript::Pk(key) => write!(f, "pk({})", hex::encode(key)),
+            Miniscript::Multi(k, keys) => multi(f, "multi", k, keys),
+            Miniscript::MultiA(k, keys) => multi(f, "multi_a", k, keys),
+            Miniscript::Older(n) => write!(f, "older({})", n),
+            Miniscript::After(n) => write!(f, "after({})", n),
+            Miniscript::Hash(hash_type, hash) => write!(f, "{}({})", hash_type.name(), hex::encode(hash)),
+            Miniscript::AndV(x, y) => write!(f, "and_v({},{})", x, y),
+            Miniscript::OrD(x, z) => write!(f, "or_d({},{})", x, z),
+            Miniscript::OrI(x, z) => write!(f, "or_i({},{})", x, z),
+            Miniscript::Thresh(k, parts) => {
+                write!(f, "thresh({}", k)?;
+                for part in parts {
+                    write!(f, ",{}", part)?;
+                }
+                write!(f, ")")
+            }
+            _ => {
+                // wrappers are written together, as in sln:older(144)
+                let mut inner = self;
+                while let Some((letter, x)) = inner.wrapper() {
+                    write!(f, "{}", letter)?;
+                    inner = x;
+                }
+                write!(f, ":{}", inner)
+            }
+        }
+    }
+}
+
+// The policies the leaves of a P2TR output are compiled from: one per
+// branch of the policy's top level or()s
+fn taproot_branches(policy: &Policy) -> Vec<&Policy> {
+    match policy {
+        Policy::Or(a, b) => [taproot_branches(a), taproot_branches(b)].concat(),
+        Policy::Thresh(1, subs) => subs.iter().flat_map(taproot_branches).collect(),
+        _ => vec![policy],
+    }
+}
+
+// The miniscripts of a policy: its witness script, or its tapscript leaves
+pub fn compile_policy(policy: &MiniscriptPolicy) -> Result<Vec<Miniscript>, SpendError> {
+    let parsed: Policy = policy.policy.parse()?;
+    if !policy.taproot {
+        return Ok(vec![parsed.compile(Context::Segwitv0)?]);
+    }
+    taproot_branches(&parsed)
+        .into_iter()
+        .map(|branch| branch.compile(Context::Tapscript))
+        .collect()
+}
+
+// tr(<internal key>,<tree>), the tree nested the way TapTree pairs its leaves
+fn tr_descriptor(leaves: &[Miniscript]) -> Result<String, SpendError> {
+    let mut level: Vec<String> = leaves.iter().map(|leaf| leaf.to_string()).collect();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => format!("{{{},{}}}", left, right),
+                [single] => single.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    add_checksum(&format!("tr({},{})", hex::encode(NUMS_KEY), level[0]))
+}
+
+// Compile `policy`, e.g. "or(pk(@0),and(pk(@1),older(144)))", to a P2WSH
+// output, or with `taproot` to a P2TR output with an unspendable internal key
+// and a leaf per branch of its top level or()s
+pub fn create_miniscript_policy(
+    wallet_state: &WalletState,
+    name: &str,
+    policy: &str,
+    taproot: bool,
+) -> Result<MiniscriptPolicy, SpendError> {
+    let context = if taproot { Context::Tapscript } else { Context::Segwitv0 };
+    let mut result = MiniscriptPolicy {
+        name: name.to_string(),
+        policy: policy.parse::<Policy>()?.resolve_keys(wallet_state, context)?.to_string(),
+        taproot,
+        descriptor: String::new(),
+        address: String::new(),
+    };
+    let miniscripts = compile_policy(&result)?;
+
+    if taproot {
+        let tree = TapTree {
+            leaves: miniscripts.iter().map(Miniscript::encode).collect(),
+        };
+        let program = get_p2tr_program(&NUMS_KEY, tree.merkle_root())?;
+        result.descriptor = tr_descriptor(&miniscripts)?;
+        result.address = encode_segwit_address(SIGNET_HRP, 1, &program[2..]);
+    } else {
+        let script = miniscripts[0].encode();
+        if script.len() > MAX_STANDARD_WITNESS_SCRIPT_SIZE {
+            return Err(invalid(format!(
+                "the witness script is {} bytes, more than the standard {}",
+                script.len(),
+                MAX_STANDARD_WITNESS_SCRIPT_SIZE
+            )));
+        }
+        let program = get_p2wsh_program(&script, Some(0));
+        result.descriptor = add_checksum(&format!("wsh({})", miniscripts[0]))?;
+        result.address = encode_segwit_address(SIGNET_HRP, 0, &program[2..]);
+    }
+    Ok(result)
+}
+
+// Spend a miniscript policy's coins with the cheapest witness our keys,
+// `preimages` and the timelocks allow. `after` sets the transaction's
+// nLockTime and `older` its inputs' nSequence, for after() and older(): the
+// same value as in the policy, blocks or with the BIP68 time flag set.
+pub fn spend_miniscript(
+    wallet_state: &WalletState,
+    policy: &MiniscriptPolicy,
+    outputs: Vec<Utxo>,
+    fee: u64,
+    preimages: &[Vec<u8>],
+    after: Option<u32>,
+    older: Option<u32>,
+) -> Result<Transaction, SpendError> {
+    let miniscripts = compile_policy(policy)?;
+    let program = address_to_script(&policy.address)?;
+    let locktime = after.unwrap_or(0);
+    let sequence = older.unwrap_or(SEQUENCE_RBF);
+    let unsatisfiable = || {
+        SpendError::SigningError(format!(
+            "{} can't be satisfied with our keys and the preimages and timelocks given",
+            policy.name
+        ))
+    };
+
+    if !policy.taproot {
+        let miniscript = &miniscripts[0];
+        let script = miniscript.encode();
+        let sighash_type = SighashType(SIGHASH_ALL);
+        return spend_script_output(wallet_state, &program, outputs, fee, locktime, sequence, |tx, index, prevouts| {
+            let commitment_hash = get_commitment_hash(tx, index, &script, prevouts[index].amount, sighash_type);
+            let signature = |pubkey: &[u8]| {
+                let key_index = wallet_state.public_keys.iter().position(|key| key == pubkey)?;
+                let privkey: [u8; 32] = wallet_state.private_keys[key_index].clone().try_into().ok()?;
+                Some(sign(&privkey, commitment_hash.clone(), sighash_type))
+            };
+            let satisfier = Satisfier {
+                signature: &signature,
+                preimages,
+                locktime,
+                sequence,
+            };
+            let mut witness = miniscript.satisfy(&satisfier).ok_or_else(unsatisfiable)?;
+            witness.push(script.clone());
+            Ok(witness)
+        });
+    }
+
+    let tree = TapTree {
+        leaves: miniscripts.iter().map(Miniscript::encode).collect(),
+    };
+    let sighash_type = SighashType(SIGHASH_DEFAULT);
+    spend_script_output(wallet_state, &program, outputs, fee, locktime, sequence, |tx, index, prevouts| {
+        // the leaf with the smallest witness, control block included
+        let mut best = None;
+        for (leaf_index, (miniscript, leaf)) in miniscripts.iter().zip(&tree.leaves).enumerate() {
+            let leaf_hash = tap_leaf_hash(leaf);
+            let signature = |pubkey: &[u8]| {
+                let privkey: [u8; 32] =
+                    wallet_state.taproot_keys[our_taproot_key(wallet_state, pubkey)?].clone().try_into().ok()?;
+                let commitment_hash =
+                    get_taproot_commitment_hash(tx, index, prevouts, sighash_type, Some(leaf_hash)).ok()?;
+                sign_tapscript(&privkey, &commitment_hash, sighash_type).ok()
+            };
+            let satisfier = Satisfier {
+                signature: &signature,
+                preimages,
+                locktime,
+                sequence,
+            };
+            if let Some(mut witness) = miniscript.satisfy(&satisfier) {
+                witness.push(leaf.clone());
+                witness.push(tree.control_block(&NUMS_KEY, leaf_index)?);
+                best = cheapest(best, Some(witness));
+            }
+        }
+        best.ok_or_else(unsatisfiable)
+    })
+}
@@ -1,2 +1,23 @@
+pub mod batch;
+pub mod broadcast;
+pub mod builder;
+pub mod cpfp;
+pub mod decode;
+pub mod htlc;
+pub mod interpreter;
+pub mod message;
+pub mod miniscript;
+pub mod policy;
+pub mod rbf;
+pub mod script;
+pub mod sighash;
+pub mod spend_multisig;
 pub mod spend_p2wpkh;
 pub mod spend_p2wsh;
+pub mod sweep;
+pub mod taproot;
+pub mod timelock;
+pub mod transaction;
+
+#[cfg(test)]
+mod tests;
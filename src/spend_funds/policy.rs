@@ -0,0 +1,114 @@
+// Relay policy and sanity checks for the transactions we build, so that
+// nothing we hand out is non-standard, creates dust or overpays the fee
+use crate::spend_funds::script::{
+    decode_script, parse_multisig_script, script_type, Instruction, ScriptType, MAX_OP_RETURN_SIZE, OP_16,
+};
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use crate::spend_funds::transaction::{compact_size, Transaction, Utxo};
+
+// Largest transaction weight relayed by default
+pub const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+// Fee caps, as bitcoind's -maxtxfee (0.1 BTC) and sendrawtransaction's maxfeerate (0.1 BTC/kvB)
+pub const MAX_FEE: u64 = 10_000_000;
+pub const MAX_FEE_RATE: f64 = 10_000.0;
+// Fee rate in sat/vB dust is measured against (-dustrelayfee)
+const DUST_RELAY_FEE_RATE: u64 = 3;
+// Bare multisig outputs are only relayed with up to 3 keys
+const MAX_BARE_MULTISIG_KEYS: usize = 3;
+
+// Smallest amount an output paying to `script_pubkey` can have without being
+// dust: what spending it would cost at the dust relay fee rate, as bitcoind
+// computes it (546 sats for P2PKH, 294 for P2WPKH, 330 for P2WSH and P2TR)
+pub fn dust_threshold(script_pubkey: &[u8]) -> u64 {
+    let output_size = 8 + compact_size(script_pubkey.len() as u64).len() + script_pubkey.len();
+    let input_size = match script_type(script_pubkey) {
+        ScriptType::OpReturn => return 0,
+        // outpoint, empty scriptSig, sequence and a signature and key in the witness discount
+        ScriptType::P2wpkh | ScriptType::P2wsh | ScriptType::P2tr | ScriptType::WitnessUnknown => 32 + 4 + 1 + 107 / 4 + 4,
+        // outpoint, scriptSig with a signature and key, sequence
+        _ => 32 + 4 + 1 + 107 + 4,
+    };
+    (output_size + input_size) as u64 * DUST_RELAY_FEE_RATE
+}
+
+fn check_output(index: usize, output: &Utxo) -> Result<(), SpendError> {
+    let invalid = |reason: String| Err(SpendError::InvalidTransaction(format!("output {}: {}", index, reason)));
+    match script_type(&output.script_pubkey) {
+        ScriptType::NonStandard => return invalid("non-standard scriptPubKey".to_string()),
+        ScriptType::Multisig => {
+            let (_, keys) = parse_multisig_script(&output.script_pubkey).expect("script type is multisig");
+            if keys.len() > MAX_BARE_MULTISIG_KEYS {
+                return invalid(format!("bare multisig with {} keys is non-standard", keys.len()));
+            }
+        }
+        ScriptType::OpReturn => {
+            if output.amount > 0 {
+                return invalid(format!("OP_RETURN output would burn {} sats", output.amount));
+            }
+            if output.script_pubkey.len() > MAX_OP_RETURN_SIZE {
+                return invalid(format!(
+                    "OP_RETURN script is {} bytes, over our limit of {}",
+                    output.script_pubkey.len(),
+                    MAX_OP_RETURN_SIZE
+                ));
+            }
+            let push_only = decode_script(&output.script_pubkey[1..]).is_some_and(|instructions| {
+                instructions.iter().all(|instruction| match instruction {
+                    Instruction::Op(opcode) => *opcode <= OP_16,
+                    Instruction::Push(_) => true,
+                })
+            });
+            if !push_only {
+                return invalid("OP_RETURN data must be pushes".to_string());
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    let dust = dust_threshold(&output.script_pubkey);
+    if output.amount < dust {
+        return invalid(format!("{} sats is dust, below {}", output.amount, dust));
+    }
+    Ok(())
+}
+
+// Check `tx` spending `prevouts` against relay policy before it is signed or
+// handed out. `weight` is its (estimated) weight once signed.
+pub fn check_policy(tx: &Transaction, prevouts: &[Utxo], weight: u64) -> Result<(), SpendError> {
+    for (index, output) in tx.outputs.iter().enumerate() {
+        check_output(index, output)?;
+    }
+    // our own rule, Bitcoin Core relays several data carrier outputs since version 30
+    if tx.outputs.iter().filter(|output| script_type(&output.script_pubkey) == ScriptType::OpReturn).count() > 1 {
+        return Err(SpendError::InvalidTransaction(
+            "we create at most one OP_RETURN output".to_string(),
+        ));
+    }
+    if weight > MAX_STANDARD_TX_WEIGHT {
+        return Err(SpendError::InvalidTransaction(format!(
+            "weight {} is over the standard {}",
+            weight, MAX_STANDARD_TX_WEIGHT
+        )));
+    }
+
+    let total_in: u64 = prevouts.iter().map(|prevout| prevout.amount).sum();
+    let total_out: u64 = tx.outputs.iter().map(|output| output.amount).sum();
+    let fee = total_in.checked_sub(total_out).ok_or(SpendError::InsufficientFunds(format!(
+        "outputs pay {} sats, more than the {} sats spent",
+        total_out, total_in
+    )))?;
+    if fee > MAX_FEE {
+        return Err(SpendError::InvalidTransaction(format!(
+            "fee of {} sats is over the {} sat limit",
+            fee, MAX_FEE
+        )));
+    }
+    let fee_rate = fee as f64 / weight.div_ceil(4) as f64;
+    if fee_rate > MAX_FEE_RATE {
+        return Err(SpendError::InvalidTransaction(format!(
+            "fee rate of {:.2} sat/vB is over the {} sat/vB limit",
+            fee_rate, MAX_FEE_RATE
+        )));
+    }
+    Ok(())
+}
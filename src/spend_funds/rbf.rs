@@ -0,0 +1,112 @@
+// Replace-by-fee (BIP125): rebuild one of our unconfirmed transactions with a
+// higher fee, taking it from the change or from extra confirmed coins
+use crate::balance::balance::WalletState;
+use crate::balance::cache::WalletCache;
+use crate::spend_funds::broadcast::{fetch_transaction, wallet_prevouts};
+use crate::spend_funds::builder::{estimate_weight, fee_for_weight, output_weight, sign_wallet_inputs, wallet_coins};
+use crate::spend_funds::interpreter::verify_transaction;
+use crate::spend_funds::policy::{check_policy, dust_threshold};
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use crate::spend_funds::transaction::{Transaction, TxIn, Utxo};
+
+// Bitcoin Core's default incremental relay fee: a replacement must pay for its
+// own size at this rate on top of the fee of the transaction it replaces
+const INCREMENTAL_RELAY_FEE: f64 = 1.0;
+
+// A signed replacement for `txid` paying at least `fee_rate` sat/vB, with its
+// fee and the fee of the original
+pub fn bump_fee(
+    wallet_state: &WalletState,
+    wallet_cache: &WalletCache,
+    txid: &str,
+    fee_rate: f64,
+) -> Result<(Transaction, u64, u64), SpendError> {
+    let (original, original_fee) = fetch_transaction(wallet_state, wallet_cache, txid)?;
+    // rule 1: the original must signal replaceability
+    if !original.inputs.iter().any(|input| input.signals_rbf()) {
+        return Err(SpendError::InvalidTransaction(format!(
+            "{} doesn't signal replace-by-fee",
+            txid
+        )));
+    }
+    let original_rate = original_fee as f64 / original.vsize() as f64;
+    if fee_rate <= original_rate {
+        return Err(SpendError::InvalidTransaction(format!(
+            "{} already pays {:.2} sat/vB",
+            txid, original_rate
+        )));
+    }
+
+    let mut prevouts = wallet_prevouts(&original, wallet_state, wallet_cache)?;
+    let mut tx = original.clone();
+    for input in &mut tx.inputs {
+        input.script_sig.clear();
+        input.witness.clear();
+    }
+    // the change: an output on our change chain, else one to another of our
+    // scripts (older transactions sent change to receive addresses)
+    let mut change_index = tx
+        .outputs
+        .iter()
+        .position(|output| wallet_state.change_programs.contains(&output.script_pubkey))
+        .or_else(|| tx.outputs.iter().position(|output| wallet_state.is_ours(&output.script_pubkey)));
+
+    // rule 2: extra inputs must be confirmed, so skip our unconfirmed change
+    let mut extra_coins = wallet_coins(wallet_state).into_iter().filter(|coin| {
+        let txid = coin.outpoint.txid_hex();
+        let already_spent = original
+            .inputs
+            .iter()
+            .any(|input| input.previous_output.txid == coin.outpoint.txid && input.previous_output.index == coin.outpoint.index);
+        !already_spent && !wallet_cache.unconfirmed.iter().any(|output| output.txid == txid)
+    });
+
+    let (fee, weight) = loop {
+        let weight = estimate_weight(wallet_state, &prevouts, &tx.outputs)?;
+        // rules 3, 4 and 6: more than the original's fee and fee rate
+        let fee = fee_for_weight(weight, fee_rate).max(original_fee + fee_for_weight(weight, INCREMENTAL_RELAY_FEE));
+        let total_in: u64 = prevouts.iter().map(|prevout| prevout.amount).sum();
+        let payments: u64 = tx
+            .outputs
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| Some(*index) != change_index)
+            .map(|(_, output)| output.amount)
+            .sum();
+        match change_index {
+            Some(index) if total_in >= payments + fee + dust_threshold(&tx.outputs[index].script_pubkey) => {
+                tx.outputs[index].amount = total_in - payments - fee;
+                break (fee, weight);
+            }
+            // change that would be dust goes to the fee
+            Some(index) if total_in >= payments + fee => {
+                let change = tx.outputs.remove(index);
+                break (total_in - payments, weight - output_weight(&change));
+            }
+            None if total_in >= payments + fee => break (total_in - payments, weight),
+            _ => {}
+        }
+
+        let coin = extra_coins.next().ok_or(SpendError::InsufficientFunds(format!(
+            "not enough confirmed coins to pay {} sats in fees",
+            fee
+        )))?;
+        tx.inputs.push(TxIn::new(coin.outpoint));
+        prevouts.push(coin.utxo);
+        if change_index.is_none() {
+            tx.outputs.push(Utxo {
+                script_pubkey: wallet_state
+                    .fresh_change_program()
+                    .ok_or(SpendError::MissingCodeCantRun)?
+                    .clone(),
+                amount: 0,
+            });
+            change_index = Some(tx.outputs.len() - 1);
+        }
+    };
+
+    check_policy(&tx, &prevouts, weight)?;
+    sign_wallet_inputs(wallet_state, &mut tx, &prevouts)?;
+    verify_transaction(&tx, &prevouts)?;
+    Ok((tx, fee, original_fee))
+}
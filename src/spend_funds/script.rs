@@ -0,0 +1,373 @@
+// Opcodes and helpers for building and reading scripts
+
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_PUSHDATA4: u8 = 0x4e;
+pub const OP_1NEGATE: u8 = 0x4f;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_IF: u8 = 0x63;
+pub const OP_NOTIF: u8 = 0x64;
+pub const OP_ELSE: u8 = 0x67;
+pub const OP_ENDIF: u8 = 0x68;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_TOALTSTACK: u8 = 0x6b;
+pub const OP_FROMALTSTACK: u8 = 0x6c;
+pub const OP_IFDUP: u8 = 0x73;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_SWAP: u8 = 0x7c;
+pub const OP_SIZE: u8 = 0x82;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_0NOTEQUAL: u8 = 0x92;
+pub const OP_ADD: u8 = 0x93;
+pub const OP_NUMEQUAL: u8 = 0x9c;
+pub const OP_NUMEQUALVERIFY: u8 = 0x9d;
+pub const OP_RIPEMD160: u8 = 0xa6;
+pub const OP_SHA256: u8 = 0xa8;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_HASH256: u8 = 0xaa;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+pub const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+pub const OP_CHECKSIGADD: u8 = 0xba;
+
+pub const MAX_MULTISIG_KEYS: usize = 20;
+// Largest OP_RETURN scriptPubKey we create. This is our own policy, the old
+// -datacarriersize default: Bitcoin Core 30 relays larger data carrier outputs.
+pub const MAX_OP_RETURN_SIZE: usize = 83;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+    Op(u8),
+    Push(Vec<u8>),
+}
+
+// Append a data push using the smallest push opcode for its length
+pub fn push_data(script: &mut Vec<u8>, data: &[u8]) {
+    let len = data.len();
+    if len < OP_PUSHDATA1 as usize {
+        script.push(len as u8);
+    } else if len <= 0xff {
+        script.push(OP_PUSHDATA1);
+        script.push(len as u8);
+    } else if len <= 0xffff {
+        script.push(OP_PUSHDATA2);
+        script.extend((len as u16).to_le_bytes());
+    } else {
+        script.push(OP_PUSHDATA4);
+        script.extend((len as u32).to_le_bytes());
+    }
+    script.extend_from_slice(data);
+}
+
+// An OP_RETURN payload: "0x" followed by hex, or else UTF-8 text
+pub fn parse_data_push(data: &str) -> Result<Vec<u8>, String> {
+    match data.strip_prefix("0x") {
+        Some(hex_data) => hex::decode(hex_data).map_err(|e| format!("invalid hex payload {}: {}", data, e)),
+        None => Ok(data.as_bytes().to_vec()),
+    }
+}
+
+// OP_RETURN followed by one push per payload, within MAX_OP_RETURN_SIZE
+pub fn op_return_script(pushes: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+    let mut script = vec![OP_RETURN];
+    for data in pushes {
+        push_data(&mut script, data);
+    }
+    if script.len() > MAX_OP_RETURN_SIZE {
+        return Err(format!(
+            "OP_RETURN script is {} bytes, over our limit of {}",
+            script.len(),
+            MAX_OP_RETURN_SIZE
+        ));
+    }
+    Ok(script)
+}
+
+// Minimal little-endian sign-magnitude encoding used for script numbers
+pub fn script_num(n: i64) -> Vec<u8> {
+    let mut result = Vec::new();
+    let negative = n < 0;
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        result.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if let Some(last) = result.last_mut() {
+        if *last & 0x80 != 0 {
+            result.push(if negative { 0x80 } else { 0x00 });
+        } else if negative {
+            *last |= 0x80;
+        }
+    }
+    result
+}
+
+// Append a number, using OP_0/OP_1NEGATE/OP_1..OP_16 where possible
+pub fn push_int(script: &mut Vec<u8>, n: i64) {
+    match n {
+        0 => script.push(OP_0),
+        -1 => script.push(OP_1NEGATE),
+        1..=16 => script.push(OP_1 + (n as u8) - 1),
+        _ => push_data(script, &script_num(n)),
+    }
+}
+
+// Split a script into opcodes and pushes, None if a push runs past the end
+pub fn decode_script(script: &[u8]) -> Option<Vec<Instruction>> {
+    let mut instructions = Vec::new();
+    let mut i = 0;
+    while i < script.len() {
+        let opcode = script[i];
+        i += 1;
+        let len = match opcode {
+            0x01..=0x4b => opcode as usize,
+            OP_PUSHDATA1 => {
+                let len = *script.get(i)? as usize;
+                i += 1;
+                len
+            }
+            OP_PUSHDATA2 => {
+                let len = u16::from_le_bytes(script.get(i..i + 2)?.try_into().ok()?) as usize;
+                i += 2;
+                len
+            }
+            OP_PUSHDATA4 => {
+                let len = u32::from_le_bytes(script.get(i..i + 4)?.try_into().ok()?) as usize;
+                i += 4;
+                len
+            }
+            _ => {
+                instructions.push(Instruction::Op(opcode));
+                continue;
+            }
+        };
+        instructions.push(Instruction::Push(script.get(i..i + len)?.to_vec()));
+        i += len;
+    }
+    Some(instructions)
+}
+
+// Read a small number written by push_int
+pub fn read_int(instruction: &Instruction) -> Option<i64> {
+    match instruction {
+        Instruction::Op(OP_0) => Some(0),
+        Instruction::Op(OP_1NEGATE) => Some(-1),
+        Instruction::Op(op) if (OP_1..=OP_16).contains(op) => Some((op - OP_1 + 1) as i64),
+        Instruction::Push(data) if data.len() <= 4 => {
+            let mut n: i64 = 0;
+            for (i, byte) in data.iter().enumerate() {
+                n |= (*byte as i64) << (8 * i);
+            }
+            match data.last() {
+                Some(last) if last & 0x80 != 0 => {
+                    Some(-(n & !(0x80i64 << (8 * (data.len() - 1)))))
+                }
+                _ => Some(n),
+            }
+        }
+        _ => None,
+    }
+}
+
+// Threshold and keys (in script order) of a bare multisig script
+pub fn parse_multisig_script(script: &[u8]) -> Option<(usize, Vec<Vec<u8>>)> {
+    let instructions = decode_script(script)?;
+    if instructions.len() < 4 || instructions.last()? != &Instruction::Op(OP_CHECKMULTISIG) {
+        return None;
+    }
+    let threshold = read_int(&instructions[0])?;
+    let key_count = read_int(&instructions[instructions.len() - 2])?;
+    let keys: Vec<Vec<u8>> = instructions[1..instructions.len() - 2]
+        .iter()
+        .map(|instruction| match instruction {
+            Instruction::Push(key) => Some(key.clone()),
+            Instruction::Op(_) => None,
+        })
+        .collect::<Option<_>>()?;
+    if keys.len() as i64 != key_count || threshold < 1 || threshold > key_count {
+        return None;
+    }
+    Some((threshold as usize, keys))
+}
+
+// Standard output script templates
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    // witness programs of unassigned versions/lengths
+    WitnessUnknown,
+    Multisig,
+    OpReturn,
+    NonStandard,
+}
+
+impl std::fmt::Display for ScriptType {
+    // the names bitcoind uses in decoderawtransaction
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ScriptType::P2pkh => "pubkeyhash",
+            ScriptType::P2sh => "scripthash",
+            ScriptType::P2wpkh => "witness_v0_keyhash",
+            ScriptType::P2wsh => "witness_v0_scripthash",
+            ScriptType::P2tr => "witness_v1_taproot",
+            ScriptType::WitnessUnknown => "witness_unknown",
+            ScriptType::Multisig => "multisig",
+            ScriptType::OpReturn => "nulldata",
+            ScriptType::NonStandard => "nonstandard",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub fn script_type(script: &[u8]) -> ScriptType {
+    match script {
+        [0x76, 0xa9, 0x14, .., 0x88, 0xac] if script.len() == 25 => ScriptType::P2pkh,
+        [0xa9, 0x14, .., 0x87] if script.len() == 23 => ScriptType::P2sh,
+        [0x00, 0x14, ..] if script.len() == 22 => ScriptType::P2wpkh,
+        [0x00, 0x20, ..] if script.len() == 34 => ScriptType::P2wsh,
+        [0x51, 0x20, ..] if script.len() == 34 => ScriptType::P2tr,
+        [version, len, ..]
+            if (OP_1..=OP_16).contains(version)
+                && *len as usize == script.len() - 2
+                && (2..=40).contains(len) =>
+        {
+            ScriptType::WitnessUnknown
+        }
+        [0x6a, ..] => ScriptType::OpReturn,
+        _ if parse_multisig_script(script).is_some() => ScriptType::Multisig,
+        _ => ScriptType::NonStandard,
+    }
+}
+
+// Names of the opcodes that aren't pushes or small numbers
+const OPCODE_NAMES: &[(u8, &str)] = &[
+    (0x61, "OP_NOP"),
+    (0x63, "OP_IF"),
+    (0x64, "OP_NOTIF"),
+    (0x67, "OP_ELSE"),
+    (0x68, "OP_ENDIF"),
+    (0x69, "OP_VERIFY"),
+    (0x6a, "OP_RETURN"),
+    (0x6b, "OP_TOALTSTACK"),
+    (0x6c, "OP_FROMALTSTACK"),
+    (0x6d, "OP_2DROP"),
+    (0x6e, "OP_2DUP"),
+    (0x6f, "OP_3DUP"),
+    (0x70, "OP_2OVER"),
+    (0x71, "OP_2ROT"),
+    (0x72, "OP_2SWAP"),
+    (0x73, "OP_IFDUP"),
+    (0x74, "OP_DEPTH"),
+    (0x75, "OP_DROP"),
+    (0x76, "OP_DUP"),
+    (0x77, "OP_NIP"),
+    (0x78, "OP_OVER"),
+    (0x79, "OP_PICK"),
+    (0x7a, "OP_ROLL"),
+    (0x7b, "OP_ROT"),
+    (0x7c, "OP_SWAP"),
+    (0x7d, "OP_TUCK"),
+    (0x82, "OP_SIZE"),
+    (0x87, "OP_EQUAL"),
+    (0x88, "OP_EQUALVERIFY"),
+    (0x8b, "OP_1ADD"),
+    (0x8c, "OP_1SUB"),
+    (0x8f, "OP_NEGATE"),
+    (0x90, "OP_ABS"),
+    (0x91, "OP_NOT"),
+    (0x92, "OP_0NOTEQUAL"),
+    (0x93, "OP_ADD"),
+    (0x94, "OP_SUB"),
+    (0x9a, "OP_BOOLAND"),
+    (0x9b, "OP_BOOLOR"),
+    (0x9c, "OP_NUMEQUAL"),
+    (0x9d, "OP_NUMEQUALVERIFY"),
+    (0x9e, "OP_NUMNOTEQUAL"),
+    (0x9f, "OP_LESSTHAN"),
+    (0xa0, "OP_GREATERTHAN"),
+    (0xa1, "OP_LESSTHANOREQUAL"),
+    (0xa2, "OP_GREATERTHANOREQUAL"),
+    (0xa3, "OP_MIN"),
+    (0xa4, "OP_MAX"),
+    (0xa5, "OP_WITHIN"),
+    (0xa6, "OP_RIPEMD160"),
+    (0xa7, "OP_SHA1"),
+    (0xa8, "OP_SHA256"),
+    (0xa9, "OP_HASH160"),
+    (0xaa, "OP_HASH256"),
+    (0xab, "OP_CODESEPARATOR"),
+    (0xac, "OP_CHECKSIG"),
+    (0xad, "OP_CHECKSIGVERIFY"),
+    (0xae, "OP_CHECKMULTISIG"),
+    (0xaf, "OP_CHECKMULTISIGVERIFY"),
+    (0xb1, "OP_CHECKLOCKTIMEVERIFY"),
+    (0xb2, "OP_CHECKSEQUENCEVERIFY"),
+    (0xba, "OP_CHECKSIGADD"),
+];
+
+// Script from its text form, e.g. "<pubkey> OP_CHECKSIG" or "144 OP_CSV OP_DROP".
+// Decimal numbers are pushed as script numbers, anything else as hex data,
+// optionally written in angle brackets.
+pub fn parse_asm(asm: &str) -> Result<Vec<u8>, String> {
+    let mut script = Vec::new();
+    for token in asm.split_whitespace() {
+        let name = token.to_uppercase();
+        let name = match name.as_str() {
+            "OP_CLTV" => "OP_CHECKLOCKTIMEVERIFY".to_string(),
+            "OP_CSV" => "OP_CHECKSEQUENCEVERIFY".to_string(),
+            "OP_FALSE" => "OP_0".to_string(),
+            "OP_TRUE" => "OP_1".to_string(),
+            _ => name,
+        };
+        if let Some((opcode, _)) = OPCODE_NAMES.iter().find(|(_, op_name)| *op_name == name) {
+            script.push(*opcode);
+        } else if let Some(n) = name.strip_prefix("OP_").and_then(|n| n.parse::<i64>().ok()) {
+            if !(0..=16).contains(&n) {
+                return Err(format!("unknown opcode {}", token));
+            }
+            push_int(&mut script, n);
+        } else if name == "OP_1NEGATE" {
+            script.push(OP_1NEGATE);
+        } else if let Some(n) = token.parse::<i64>().ok().filter(|_| token.len() <= 10) {
+            push_int(&mut script, n);
+        } else {
+            let data = token.trim_start_matches('<').trim_end_matches('>');
+            let data = hex::decode(data).map_err(|_| format!("invalid script token {}", token))?;
+            push_data(&mut script, &data);
+        }
+    }
+    Ok(script)
+}
+
+// Text form of a script, the inverse of parse_asm
+pub fn script_to_asm(script: &[u8]) -> String {
+    let Some(instructions) = decode_script(script) else {
+        return format!("<invalid script {}>", hex::encode(script));
+    };
+    instructions
+        .iter()
+        .map(|instruction| match instruction {
+            Instruction::Push(data) => format!("<{}>", hex::encode(data)),
+            Instruction::Op(op) => match read_int(instruction) {
+                Some(n) => n.to_string(),
+                None => OPCODE_NAMES
+                    .iter()
+                    .find(|(opcode, _)| opcode == op)
+                    .map_or(format!("OP_UNKNOWN_{:#04x}", op), |(_, name)| name.to_string()),
+            },
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,298 @@
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use crate::spend_funds::taproot::tagged_hash;
+use crate::spend_funds::transaction::{compact_size, hash256, Transaction, Utxo};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+// taproot only: commits to everything like ALL, with a 64 byte signature
+pub const SIGHASH_DEFAULT: u32 = 0x00;
+pub const SIGHASH_ALL: u32 = 0x01;
+pub const SIGHASH_NONE: u32 = 0x02;
+pub const SIGHASH_SINGLE: u32 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+// Which parts of the transaction a signature commits to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SighashType(pub u32);
+
+impl SighashType {
+    // the base type with the ANYONECANPAY flag stripped
+    pub fn base(self) -> u32 {
+        self.0 & 0x1f
+    }
+
+    pub fn anyone_can_pay(self) -> bool {
+        self.0 & SIGHASH_ANYONECANPAY != 0
+    }
+
+    // the byte appended to a DER signature
+    pub fn to_byte(self) -> u8 {
+        self.0 as u8
+    }
+
+    // the base type with ANYONECANPAY stripped, DEFAULT counting as ALL
+    fn taproot_base(self) -> u32 {
+        match self.base() {
+            SIGHASH_DEFAULT => SIGHASH_ALL,
+            base => base,
+        }
+    }
+}
+
+// Accepts the names used by bitcoind, e.g. "ALL", "DEFAULT" or "SINGLE|ANYONECANPAY"
+impl FromStr for SighashType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_uppercase();
+        let (base, anyone_can_pay) = match upper.strip_suffix("|ANYONECANPAY") {
+            Some(base) => (base, true),
+            None => (upper.as_str(), false),
+        };
+        let base = match base {
+            "DEFAULT" if !anyone_can_pay => SIGHASH_DEFAULT,
+            "ALL" => SIGHASH_ALL,
+            "NONE" => SIGHASH_NONE,
+            "SINGLE" => SIGHASH_SINGLE,
+            _ => return Err(format!("unknown sighash type: {}", s)),
+        };
+        if anyone_can_pay {
+            Ok(SighashType(base | SIGHASH_ANYONECANPAY))
+        } else {
+            Ok(SighashType(base))
+        }
+    }
+}
+
+impl fmt::Display for SighashType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let base = match self.base() {
+            SIGHASH_DEFAULT => "DEFAULT",
+            SIGHASH_NONE => "NONE",
+            SIGHASH_SINGLE => "SINGLE",
+            _ => "ALL",
+        };
+        if self.anyone_can_pay() {
+            write!(f, "{}|ANYONECANPAY", base)
+        } else {
+            write!(f, "{}", base)
+        }
+    }
+}
+
+// BIP143 signature digest for segwit v0 input `input_index` of `tx`.
+// `scriptcode` is the script being satisfied (p2pkh template for p2wpkh,
+// the witness script for p2wsh) and `value` the amount of the output being spent.
+pub fn get_commitment_hash(
+    tx: &Transaction,
+    input_index: usize,
+    scriptcode: &[u8],
+    value: u64,
+    sighash_type: SighashType,
+) -> Vec<u8> {
+    let input = &tx.inputs[input_index];
+    let base = sighash_type.base();
+    let mut data = Vec::new();
+
+    // Version
+    data.extend(tx.version.to_le_bytes());
+
+    // hashPrevouts: all TX input outpoints, zero with ANYONECANPAY
+    if sighash_type.anyone_can_pay() {
+        data.extend([0u8; 32]);
+    } else {
+        let mut outpoints = Vec::new();
+        for txin in &tx.inputs {
+            outpoints.extend(txin.previous_output.serialize());
+        }
+        data.extend(hash256(&outpoints));
+    }
+
+    // hashSequence: all TX input sequences, only committed to with ALL
+    if sighash_type.anyone_can_pay() || base == SIGHASH_NONE || base == SIGHASH_SINGLE {
+        data.extend([0u8; 32]);
+    } else {
+        let mut sequences = Vec::new();
+        for txin in &tx.inputs {
+            sequences.extend(txin.sequence.to_le_bytes());
+        }
+        data.extend(hash256(&sequences));
+    }
+
+    // Single outpoint being spent
+    data.extend(input.previous_output.serialize());
+
+    // Scriptcode (the scriptPubKey in/implied by the output being spent, see BIP 143)
+    data.extend(compact_size(scriptcode.len() as u64));
+    data.extend_from_slice(scriptcode);
+
+    // Value of output being spent
+    data.extend(value.to_le_bytes());
+
+    // Sequence of the input being signed
+    data.extend(input.sequence.to_le_bytes());
+
+    // hashOutputs: all outputs for ALL, the output at the same index for SINGLE
+    if base != SIGHASH_NONE && base != SIGHASH_SINGLE {
+        let mut outputs = Vec::new();
+        for output in &tx.outputs {
+            outputs.extend(output.serialize());
+        }
+        data.extend(hash256(&outputs));
+    } else if base == SIGHASH_SINGLE && input_index < tx.outputs.len() {
+        data.extend(hash256(&tx.outputs[input_index].serialize()));
+    } else {
+        data.extend([0u8; 32]);
+    }
+
+    // Locktime
+    data.extend(tx.locktime.to_le_bytes());
+
+    // Sighash type
+    data.extend(sighash_type.0.to_le_bytes());
+
+    hash256(&data)
+}
+
+// Pre-segwit signature digest for input `input_index` of `tx`, signing a copy of
+// the transaction with `scriptcode` as the input's scriptSig and the other
+// scriptSigs emptied
+pub fn get_legacy_commitment_hash(
+    tx: &Transaction,
+    input_index: usize,
+    scriptcode: &[u8],
+    sighash_type: SighashType,
+) -> Vec<u8> {
+    let base = sighash_type.base();
+    // SINGLE without a matching output signs the number one, a quirk kept by consensus
+    if base == SIGHASH_SINGLE && input_index >= tx.outputs.len() {
+        let mut one = vec![0u8; 32];
+        one[0] = 1;
+        return one;
+    }
+
+    let mut tx_copy = tx.clone();
+    for (index, input) in tx_copy.inputs.iter_mut().enumerate() {
+        input.witness.clear();
+        if index == input_index {
+            input.script_sig = scriptcode.to_vec();
+        } else {
+            input.script_sig.clear();
+            // other inputs are free to change their sequence with NONE and SINGLE
+            if base == SIGHASH_NONE || base == SIGHASH_SINGLE {
+                input.sequence = 0;
+            }
+        }
+    }
+    if base == SIGHASH_NONE {
+        tx_copy.outputs.clear();
+    } else if base == SIGHASH_SINGLE {
+        tx_copy.outputs.truncate(input_index + 1);
+        for output in tx_copy.outputs.iter_mut().take(input_index) {
+            output.amount = u64::MAX;
+            output.script_pubkey.clear();
+        }
+    }
+    if sighash_type.anyone_can_pay() {
+        tx_copy.inputs = vec![tx_copy.inputs[input_index].clone()];
+    }
+
+    let mut data = tx_copy.serialize_without_witness();
+    data.extend(sighash_type.0.to_le_bytes());
+    hash256(&data)
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+// BIP341 signature digest for taproot input `input_index` of `tx`.
+// Unlike BIP143 it commits to the amounts and scriptPubKeys of all the
+// outputs being spent, given in input order as `prevouts`. Script path
+// spends pass the hash of the leaf being executed (BIP342).
+pub fn get_taproot_commitment_hash(
+    tx: &Transaction,
+    input_index: usize,
+    prevouts: &[Utxo],
+    sighash_type: SighashType,
+    leaf_hash: Option<[u8; 32]>,
+) -> Result<Vec<u8>, SpendError> {
+    if !matches!(sighash_type.0, 0x00..=0x03 | 0x81..=0x83) {
+        return Err(SpendError::SigningError(format!("invalid taproot sighash type {}", sighash_type.0)));
+    }
+    if prevouts.len() != tx.inputs.len() {
+        return Err(SpendError::SigningError("missing spent outputs".to_string()));
+    }
+    let input = &tx.inputs[input_index];
+    let base = sighash_type.taproot_base();
+
+    // sighash epoch
+    let mut data = vec![0x00];
+
+    // Control: hash type, version and locktime
+    data.push(sighash_type.to_byte());
+    data.extend(tx.version.to_le_bytes());
+    data.extend(tx.locktime.to_le_bytes());
+
+    // Single SHA256 of prevouts, amounts, scriptPubKeys and sequences of all inputs
+    if !sighash_type.anyone_can_pay() {
+        let mut outpoints = Vec::new();
+        let mut amounts = Vec::new();
+        let mut script_pubkeys = Vec::new();
+        let mut sequences = Vec::new();
+        for (txin, prevout) in tx.inputs.iter().zip(prevouts) {
+            outpoints.extend(txin.previous_output.serialize());
+            amounts.extend(prevout.amount.to_le_bytes());
+            script_pubkeys.extend(compact_size(prevout.script_pubkey.len() as u64));
+            script_pubkeys.extend(&prevout.script_pubkey);
+            sequences.extend(txin.sequence.to_le_bytes());
+        }
+        data.extend(sha256(&outpoints));
+        data.extend(sha256(&amounts));
+        data.extend(sha256(&script_pubkeys));
+        data.extend(sha256(&sequences));
+    }
+
+    // sha_outputs, only with ALL
+    if base == SIGHASH_ALL {
+        let mut outputs = Vec::new();
+        for output in &tx.outputs {
+            outputs.extend(output.serialize());
+        }
+        data.extend(sha256(&outputs));
+    }
+
+    // spend_type: ext_flag 1 for script path, no annex
+    data.push(if leaf_hash.is_some() { 0x02 } else { 0x00 });
+
+    // The input being signed, in full with ANYONECANPAY and by index otherwise
+    if sighash_type.anyone_can_pay() {
+        let prevout = &prevouts[input_index];
+        data.extend(input.previous_output.serialize());
+        data.extend(prevout.amount.to_le_bytes());
+        data.extend(compact_size(prevout.script_pubkey.len() as u64));
+        data.extend(&prevout.script_pubkey);
+        data.extend(input.sequence.to_le_bytes());
+    } else {
+        data.extend((input_index as u32).to_le_bytes());
+    }
+
+    // The output at the same index for SINGLE
+    if base == SIGHASH_SINGLE {
+        let output = tx.outputs.get(input_index).ok_or(SpendError::SigningError(format!(
+            "SINGLE sighash for input {} without a matching output",
+            input_index
+        )))?;
+        data.extend(sha256(&output.serialize()));
+    }
+
+    // BIP342 extension: the leaf, key_version 0 and no OP_CODESEPARATOR executed
+    if let Some(leaf_hash) = leaf_hash {
+        data.extend(leaf_hash);
+        data.push(0x00);
+        data.extend(0xffffffffu32.to_le_bytes());
+    }
+
+    Ok(tagged_hash("TapSighash", &data).to_vec())
+}
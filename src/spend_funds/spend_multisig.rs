@@ -0,0 +1,240 @@
+use crate::balance::address::{address_to_script, encode_segwit_address, SIGNET_HRP};
+use crate::balance::balance::{btc_to_sats, derive_xpub_child_key, WalletState};
+use crate::balance::cache::MultisigPolicy;
+use crate::spend_funds::policy::dust_threshold;
+use crate::spend_funds::script::parse_multisig_script;
+use crate::spend_funds::sighash::{get_commitment_hash, SighashType};
+use crate::spend_funds::spend_p2wpkh::{create_multisig_script, get_p2wsh_program, sign, SpendError};
+use crate::spend_funds::transaction::{Outpoint, Transaction, TxIn, Utxo};
+use std::fmt;
+use std::str::FromStr;
+
+// One co-signer's signature for one input, exchanged as "<input>:<pubkey>:<signature>"
+#[derive(Clone)]
+pub struct PartialSig {
+    pub input: usize,
+    pub pubkey: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl fmt::Display for PartialSig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.input,
+            hex::encode(&self.pubkey),
+            hex::encode(&self.signature)
+        )
+    }
+}
+
+impl FromStr for PartialSig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() != 3 {
+            return Err(format!("expected <input>:<pubkey>:<signature>, got {}", s));
+        }
+        Ok(PartialSig {
+            input: parts[0].parse().map_err(|_| format!("invalid input index: {}", parts[0]))?,
+            pubkey: hex::decode(parts[1]).map_err(|e| e.to_string())?,
+            signature: hex::decode(parts[2]).map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+// A co-signer key is either a hex public key or an extended public key,
+// in which case its first receive key (/0/0) is used
+pub fn parse_cosigner_key(key: &str) -> Result<Vec<u8>, SpendError> {
+    if let Ok(pubkey) = hex::decode(key) {
+        return Ok(pubkey);
+    }
+    Ok(derive_xpub_child_key(key, "m/0/0")?)
+}
+
+fn witness_script(policy: &MultisigPolicy) -> Result<Vec<u8>, SpendError> {
+    hex::decode(&policy.witness_script)
+        .map_err(|_| SpendError::InvalidScript(format!("bad witness script for {}", policy.name)))
+}
+
+// Combine our keys at `our_keys` with the co-signers' keys into an m-of-n policy
+pub fn create_multisig_policy(
+    wallet_state: &WalletState,
+    name: &str,
+    our_keys: &[usize],
+    cosigners: &[String],
+    threshold: usize,
+    sort_keys: bool,
+) -> Result<MultisigPolicy, SpendError> {
+    let mut keys = Vec::new();
+    for index in our_keys {
+        let pubkey = wallet_state
+            .public_keys
+            .get(*index)
+            .ok_or(SpendError::MissingCodeCantRun)?;
+        keys.push(pubkey.clone());
+    }
+    for cosigner in cosigners {
+        keys.push(parse_cosigner_key(cosigner)?);
+    }
+
+    let script = create_multisig_script(keys, threshold, sort_keys)?;
+    let (_, script_keys) = parse_multisig_script(&script)
+        .ok_or(SpendError::InvalidScript("not a multisig script".to_string()))?;
+    let program = get_p2wsh_program(&script, Some(0));
+
+    Ok(MultisigPolicy {
+        name: name.to_string(),
+        threshold,
+        keys: script_keys.iter().map(hex::encode).collect(),
+        witness_script: hex::encode(&script),
+        address: encode_segwit_address(SIGNET_HRP, 0, &program[2..]),
+    })
+}
+
+// Unsigned transaction paying `amount` to `destination` from the policy's
+// coins, with change going back to the multisig address
+pub fn create_multisig_spend(
+    wallet_state: &WalletState,
+    policy: &MultisigPolicy,
+    destination: &str,
+    amount: u64,
+    fee: u64,
+    op_return: Option<Vec<u8>>,
+) -> Result<Transaction, SpendError> {
+    let program = address_to_script(&policy.address)?;
+    let destination_script = address_to_script(destination)?;
+
+    // largest coins first
+    let mut coins: Vec<(&(String, u32), u64)> = wallet_state
+        .spendable_watched_utxos()
+        .filter(|(_, (script, _))| *script == program)
+        .map(|(outpoint, (_, value))| (outpoint, btc_to_sats(*value)))
+        .collect();
+    coins.sort_by_key(|coin| std::cmp::Reverse(coin.1));
+
+    let required = amount + fee;
+    let mut inputs = Vec::new();
+    let mut selected = 0;
+    for ((txid, vout), value) in coins {
+        if selected >= required {
+            break;
+        }
+        let mut txid_bytes: [u8; 32] = hex::decode(txid)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(SpendError::InvalidTransaction(format!("bad txid {}", txid)))?;
+        txid_bytes.reverse();
+        inputs.push(TxIn::new(Outpoint {
+            txid: txid_bytes,
+            index: *vout,
+        }));
+        selected += value;
+    }
+    if selected < required {
+        return Err(SpendError::InsufficientFunds(format!(
+            "{} has {} sats, need {}",
+            policy.name, selected, required
+        )));
+    }
+
+    let mut outputs = vec![Utxo {
+        script_pubkey: destination_script,
+        amount,
+    }];
+    outputs.extend(op_return.map(|script| Utxo {
+        script_pubkey: script,
+        amount: 0,
+    }));
+    // change below the dust threshold goes to the fee
+    if selected >= required + dust_threshold(&program) {
+        outputs.push(Utxo {
+            script_pubkey: program,
+            amount: selected - required,
+        });
+    }
+    Ok(Transaction::new(inputs, outputs))
+}
+
+// Our signatures for every input of `tx` spending from the policy's address
+pub fn sign_multisig_inputs(
+    wallet_state: &WalletState,
+    policy: &MultisigPolicy,
+    tx: &Transaction,
+    sighash_type: SighashType,
+) -> Result<Vec<PartialSig>, SpendError> {
+    let script = witness_script(policy)?;
+    let program = address_to_script(&policy.address)?;
+
+    let mut signatures = Vec::new();
+    for (index, input) in tx.inputs.iter().enumerate() {
+        let outpoint = (input.previous_output.txid_hex(), input.previous_output.index);
+        let value = match wallet_state.watched_utxos.get(&outpoint) {
+            Some((script_pubkey, value)) if *script_pubkey == program => btc_to_sats(*value),
+            _ => continue,
+        };
+        let commitment_hash = get_commitment_hash(tx, index, &script, value, sighash_type);
+
+        for key in &policy.keys {
+            let pubkey = hex::decode(key).map_err(|e| SpendError::InvalidScript(e.to_string()))?;
+            let Some(key_index) = wallet_state.public_keys.iter().position(|k| *k == pubkey) else {
+                continue;
+            };
+            let privkey: [u8; 32] = wallet_state.private_keys[key_index]
+                .clone()
+                .try_into()
+                .expect("private key length is not 32 bytes");
+            signatures.push(PartialSig {
+                input: index,
+                pubkey,
+                signature: sign(&privkey, commitment_hash.clone(), sighash_type),
+            });
+        }
+    }
+
+    if signatures.is_empty() {
+        return Err(SpendError::SigningError(format!(
+            "no inputs from {} that we hold keys for",
+            policy.name
+        )));
+    }
+    Ok(signatures)
+}
+
+// Build the witness of every input from the collected signatures, taking
+// the first m signatures in witness script key order
+pub fn finalize_multisig(
+    policy: &MultisigPolicy,
+    mut tx: Transaction,
+    signatures: &[PartialSig],
+) -> Result<Transaction, SpendError> {
+    let script = witness_script(policy)?;
+
+    for (index, input) in tx.inputs.iter_mut().enumerate() {
+        let mut witness = vec![Vec::new()];
+        for key in &policy.keys {
+            if witness.len() > policy.threshold {
+                break;
+            }
+            let signature = signatures
+                .iter()
+                .find(|sig| sig.input == index && hex::encode(&sig.pubkey) == *key);
+            if let Some(sig) = signature {
+                witness.push(sig.signature.clone());
+            }
+        }
+        if witness.len() <= policy.threshold {
+            return Err(SpendError::SigningError(format!(
+                "input {} has {} of {} signatures",
+                index,
+                witness.len() - 1,
+                policy.threshold
+            )));
+        }
+        witness.push(script.clone());
+        input.witness = witness;
+    }
+    Ok(tx)
+}
@@ -1,28 +1,46 @@
-use crate::balance::balance::WalletState;
+use crate::balance::balance::{btc_to_sats, BalanceError, WalletState};
+use crate::spend_funds::interpreter::verify_transaction;
+use crate::spend_funds::policy::{check_policy, dust_threshold};
+use crate::spend_funds::script::{parse_multisig_script, push_data, push_int, MAX_MULTISIG_KEYS, OP_CHECKMULTISIG};
+use crate::spend_funds::sighash::{get_commitment_hash, SighashType};
+use crate::spend_funds::transaction::{compact_size, hash256, Outpoint, Transaction, TxIn, Utxo, SEQUENCE_RBF};
 use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
 use sha2::{Digest, Sha256};
+use std::fmt;
 
 #[derive(Debug)]
 pub enum SpendError {
     MissingCodeCantRun,
     // Add more relevant error variants
     InsufficientFunds(String),
+    InvalidScript(String),
+    SigningError(String),
+    InvalidTransaction(String),
+    WalletError(BalanceError),
 }
 
-#[derive(Clone)]
-pub struct Utxo {
-    pub script_pubkey: Vec<u8>,
-    pub amount: u64,
+impl From<BalanceError> for SpendError {
+    fn from(e: BalanceError) -> Self {
+        SpendError::WalletError(e)
+    }
 }
 
-pub struct Outpoint {
-    pub txid: [u8; 32],
-    pub index: u32,
+impl fmt::Display for SpendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpendError::MissingCodeCantRun => write!(f, "missing key or wallet data"),
+            SpendError::InsufficientFunds(msg) => write!(f, "{}", msg),
+            SpendError::InvalidScript(msg) => write!(f, "invalid script: {}", msg),
+            SpendError::SigningError(msg) => write!(f, "signing failed: {}", msg),
+            SpendError::InvalidTransaction(msg) => write!(f, "invalid transaction: {}", msg),
+            SpendError::WalletError(e) => write!(f, "{}", e),
+        }
+    }
 }
 
-// create a serialized transaction input
-// Use hard-coded defaults for sequence and scriptSig
-pub fn input_from_utxo(txid: &[u8], index: u32) -> Vec<u8> {
+// create a serialized transaction input with an empty scriptSig.
+// `sequence` is SEQUENCE_RBF unless the input needs a relative locktime (BIP68)
+pub fn input_from_utxo(txid: &[u8], index: u32, sequence: u32) -> Vec<u8> {
     let mut input = Vec::new();
     input.extend(txid);
     //add the index as little-endianc
@@ -30,29 +48,54 @@ pub fn input_from_utxo(txid: &[u8], index: u32) -> Vec<u8> {
     //add a spending scriptsig(since we are spending a segwit output)
     input.push(0x00); //empty scriptSig
                       //add the sequence
-    input.extend((0xFFFFFFFFu32).to_le_bytes());
+    input.extend(sequence.to_le_bytes());
     input
 }
 
-// a 2-of-2 multisig output script. No length byte prefix is necessary.
-pub fn create_multisig_script(keys: Vec<Vec<u8>>) -> Vec<u8> {
-    if keys.len() < 2 {
-        panic!("Not enough keys to create multisig script");
+// an m-of-n multisig output script. No length byte prefix is necessary.
+// Keys are sorted lexicographically (BIP67) first when `sort` is set.
+pub fn create_multisig_script(
+    mut keys: Vec<Vec<u8>>,
+    threshold: usize,
+    sort: bool,
+) -> Result<Vec<u8>, SpendError> {
+    if keys.is_empty() || keys.len() > MAX_MULTISIG_KEYS {
+        return Err(SpendError::InvalidScript(format!(
+            "multisig needs between 1 and {} keys, got {}",
+            MAX_MULTISIG_KEYS,
+            keys.len()
+        )));
+    }
+    if threshold == 0 || threshold > keys.len() {
+        return Err(SpendError::InvalidScript(format!(
+            "threshold {} is not possible with {} keys",
+            threshold,
+            keys.len()
+        )));
+    }
+    if let Some(key) = keys.iter().find(|k| k.len() != 33 || (k[0] != 0x02 && k[0] != 0x03)) {
+        return Err(SpendError::InvalidScript(format!(
+            "not a compressed public key: {}",
+            hex::encode(key)
+        )));
     }
+    if sort {
+        keys.sort();
+    }
+
     let mut script = Vec::new();
-    script.push(0x52); // OP_2
-    for key in &keys[0..2] {
-        script.push(key.len() as u8);
-        script.extend(key);
+    push_int(&mut script, threshold as i64); // OP_m
+    for key in &keys {
+        push_data(&mut script, key);
     }
-    script.push(0x52); // OP_2
-    script.push(0xAE); // OP_CHECKMULTISIG
-    script
+    push_int(&mut script, keys.len() as i64); // OP_n
+    script.push(OP_CHECKMULTISIG);
+    Ok(script)
 }
 
-fn get_p2wsh_program(script: &[u8], version: Option<u32>) -> Vec<u8> {
+pub fn get_p2wsh_program(script: &[u8], version: Option<u32>) -> Vec<u8> {
     let mut program = Vec::new();
-    let redeem_script_hash = Sha256::digest(&script);
+    let redeem_script_hash = Sha256::digest(script);
 
     assert_eq!(redeem_script_hash.len(), 32, "Invalid hash length");
 
@@ -93,14 +136,14 @@ pub fn output_from_options(script: &[u8], amount: u64) -> Vec<u8> {
     //add amount as little endian bytes
     output.extend(&amount.to_le_bytes());
     //add the script length
-    output.push(script.len() as u8);
+    output.extend(compact_size(script.len() as u64));
     //add the script
     output.extend_from_slice(script);
 
     output
 }
 
-fn get_p2wpkh_scriptcode(utxo: &Utxo) -> Vec<u8> {
+pub fn get_p2wpkh_scriptcode(utxo: &Utxo) -> Vec<u8> {
     //we need to extract the publickey from the scriptcode
     let mut pubkey_hash = [0u8; 20];
     let script = &utxo.script_pubkey;
@@ -122,67 +165,7 @@ fn get_p2wpkh_scriptcode(utxo: &Utxo) -> Vec<u8> {
     script_code
 }
 
-// this function helps in performing SHA256 double hashing
-fn hash256(data: &[u8]) -> Vec<u8> {
-    let first_hash = Sha256::digest(data);
-    Sha256::digest(&first_hash).to_vec()
-}
-
-pub fn get_commitment_hash(
-    outpoint: Outpoint,
-    scriptcode: &[u8],
-    value: u64,
-    outputs: Vec<Utxo>,
-) -> Vec<u8> {
-    let mut data = Vec::new();
-
-    // Version
-    data.extend(&2u32.to_le_bytes());
-
-    // All TX input outpoints (only one in our case)
-    let mut outpoints = Vec::new();
-    outpoints.extend_from_slice(&outpoint.txid);
-    outpoints.extend(&outpoint.index.to_le_bytes());
-    data.extend(&hash256(&outpoints));
-
-    // All TX input sequences (only one for us, always default value)
-    let sequence = 0xffffffffu32;
-    data.extend(&hash256(&sequence.to_le_bytes()));
-
-    // Single outpoint being spent
-    data.extend_from_slice(&outpoint.txid);
-    data.extend(&outpoint.index.to_le_bytes());
-
-    // Scriptcode (the scriptPubKey in/implied by the output being spent, see BIP 143)
-    data.push(scriptcode.len() as u8);
-    data.extend_from_slice(scriptcode);
-
-    // Value of output being spent
-    data.extend(&(value as u64).to_le_bytes());
-
-    // Sequence of output being spent (always default for us)
-    data.extend(&sequence.to_le_bytes());
-
-    // All TX outputs
-    let mut outputs_info = Vec::new();
-    for output in outputs {
-        outputs_info.extend(&output.amount.to_le_bytes());
-        outputs_info.push(output.script_pubkey.len() as u8);
-        outputs_info.extend_from_slice(&output.script_pubkey);
-    }
-    data.extend(&hash256(&outputs_info));
-
-    // Locktime (always default for us)
-    let locktime = 0u32;
-    data.extend(&locktime.to_le_bytes());
-
-    // SIGHASH_ALL (always default for us)
-    data.extend(&1u32.to_le_bytes());
-
-    hash256(&data)
-}
-
-fn sign(privkey: &[u8; 32], msg: Vec<u8>) -> Vec<u8> {
+pub fn sign(privkey: &[u8; 32], msg: Vec<u8>, sighash_type: SighashType) -> Vec<u8> {
     // Keep signing until we produce a signature with "low s value"
     // We will have to decode the DER-encoded signature and extract the s value to check it
     // Format: 0x30 [total-length] 0x02 [R-length] [R] 0x02 [S-length] [S] [sighash]
@@ -195,24 +178,24 @@ fn sign(privkey: &[u8; 32], msg: Vec<u8>) -> Vec<u8> {
     //to keep the s value as low as possible, we normalize the signature
     signature.normalize_s();
     let mut der_signature = signature.serialize_der().as_ref().to_vec();
-    der_signature.push(0x01); // SIGHASH_ALL
+    der_signature.push(sighash_type.to_byte());
 
     der_signature
 }
 
-pub fn get_txid(inputs: Vec<Vec<u8>>, outputs: Vec<Vec<u8>>) -> [u8; 32] {
+pub fn get_txid(inputs: Vec<Vec<u8>>, outputs: Vec<Vec<u8>>, locktime: u32) -> [u8; 32] {
     let mut transaction = Vec::new();
     transaction.extend(&2u32.to_le_bytes());
-    transaction.push(inputs.len() as u8);
+    transaction.extend(compact_size(inputs.len() as u64));
 
     for input in inputs {
         transaction.extend(&input);
     }
-    transaction.push(outputs.len() as u8);
+    transaction.extend(compact_size(outputs.len() as u64));
     for output in outputs {
         transaction.extend_from_slice(&output);
     }
-    transaction.extend(&0u32.to_le_bytes());
+    transaction.extend(&locktime.to_le_bytes());
     let hash = hash256(&transaction);
     let mut txid = [0u8; 32];
     txid.copy_from_slice(&hash);
@@ -220,31 +203,61 @@ pub fn get_txid(inputs: Vec<Vec<u8>>, outputs: Vec<Vec<u8>>) -> [u8; 32] {
     txid
 }
 
-pub fn get_p2wsh_witness(privs: Vec<&[u8; 32]>, msg: Vec<u8>, redeem_script: &[u8]) -> Vec<u8> {
+// Witness for a p2wsh multisig input. Signs with the first m keys of the
+// redeem script we hold a private key for, in the order they appear in it.
+pub fn get_p2wsh_witness(
+    privs: Vec<&[u8; 32]>,
+    msg: Vec<u8>,
+    redeem_script: &[u8],
+    sighash_type: SighashType,
+) -> Result<Vec<u8>, SpendError> {
+    let (threshold, script_keys) = parse_multisig_script(redeem_script)
+        .ok_or(SpendError::InvalidScript("not a multisig script".to_string()))?;
+
+    let secp = Secp256k1::new();
+    let mut signatures = Vec::new();
+    for key in &script_keys {
+        if signatures.len() == threshold {
+            break;
+        }
+        let privkey = privs.iter().find(|privkey| {
+            SecretKey::from_slice(&privkey[..])
+                .map(|sk| PublicKey::from_secret_key(&secp, &sk).serialize().as_slice() == key.as_slice())
+                .unwrap_or(false)
+        });
+        if let Some(privkey) = privkey {
+            signatures.push(sign(privkey, msg.clone(), sighash_type));
+        }
+    }
+    if signatures.len() < threshold {
+        return Err(SpendError::SigningError(format!(
+            "need {} signatures but only hold keys for {}",
+            threshold,
+            signatures.len()
+        )));
+    }
+
     let mut witness = Vec::new();
-    witness.push(0);
+    // empty item consumed by the OP_CHECKMULTISIG off-by-one bug
+    witness.extend(compact_size(threshold as u64 + 2));
     witness.push(0x00);
-    let mut witness_count = 1;
 
-    for privkey in privs {
-        let der_signature = sign(privkey, msg.clone());
-        witness.push(der_signature.len() as u8);
+    for der_signature in signatures {
+        witness.extend(compact_size(der_signature.len() as u64));
         witness.extend(&der_signature);
-        witness_count += 1;
     }
 
-    witness.push(redeem_script.len() as u8);
+    witness.extend(compact_size(redeem_script.len() as u64));
     witness.extend_from_slice(redeem_script);
-    witness_count += 1;
 
-    witness[0] = witness_count as u8;
-    witness
+    Ok(witness)
 }
 
 pub fn assemble_transaction(
     inputs: Vec<Vec<u8>>,
     outputs: Vec<Vec<u8>>,
     witnesses: Vec<Vec<u8>>,
+    locktime: u32,
 ) -> Vec<u8> {
     let mut transaction = Vec::new();
 
@@ -252,13 +265,13 @@ pub fn assemble_transaction(
     transaction.extend(&2u32.to_le_bytes()); // 4 bytes
     transaction.push(0x00); // 1 byte
     transaction.push(0x01); // 1 byte
-    transaction.push(inputs.len() as u8); // 1 byte
+    transaction.extend(compact_size(inputs.len() as u64));
 
     for input in &inputs {
         transaction.extend(input);
     }
 
-    transaction.push(outputs.len() as u8);
+    transaction.extend(compact_size(outputs.len() as u64));
 
     for output in &outputs {
         transaction.extend(output);
@@ -268,45 +281,50 @@ pub fn assemble_transaction(
         transaction.extend(witness);
     }
 
-    transaction.extend_from_slice(&0u32.to_le_bytes());
+    transaction.extend_from_slice(&locktime.to_le_bytes());
 
     transaction
 }
 
-pub fn get_p2wpkh_witness(privkey: &[u8; 32], msg: Vec<u8>) -> Vec<u8> {
+pub fn get_p2wpkh_witness(privkey: &[u8; 32], msg: Vec<u8>, sighash_type: SighashType) -> Vec<u8> {
     let secp = Secp256k1::new();
     let secret_key = SecretKey::from_slice(privkey).expect("32 bytes, within curve order");
     let public_key = PublicKey::from_secret_key(&secp, &secret_key);
     let compressed_pubkey = public_key.serialize();
 
-    let signature = sign(privkey, msg);
+    let signature = sign(privkey, msg, sighash_type);
 
     // Witness stack: [signature, compressed_pubkey]
-    let mut witness = Vec::new();
-    witness.push(signature);
-    witness.push(compressed_pubkey.to_vec());
+    let witness = vec![signature, compressed_pubkey.to_vec()];
 
     // Serialize the witness stack
     let mut serialized_witness = Vec::new();
-    serialized_witness.push(witness.len() as u8); // Number of stack items
+    serialized_witness.extend(compact_size(witness.len() as u64)); // Number of stack items
     for item in witness {
-        serialized_witness.push(item.len() as u8); // Length of each item
+        serialized_witness.extend(compact_size(item.len() as u64)); // Length of each item
         serialized_witness.extend(item); // The item itself
     }
 
     serialized_witness
 }
 
-pub fn spend_p2wpkh(wallet_state: &WalletState) -> Result<([u8; 32], String), SpendError> {
+pub fn spend_p2wpkh(
+    wallet_state: &WalletState,
+    threshold: usize,
+    key_count: usize,
+    sort_keys: bool,
+    sighash_type: SighashType,
+    op_return: Option<Vec<u8>>,
+) -> Result<([u8; 32], String), SpendError> {
     const FEE: u64 = 1000; // Fixed fee for the transaction
     const AMT: u64 = 1000000; // Amount to send to the multisig output
     let required_amount = AMT + FEE; // Total amount needed (AMT + fee)
 
-    // Choose an unspent coin worth more than the required amount
+    // Choose an unspent p2wpkh coin worth more than the required amount
     let utxo = wallet_state
-        .utxos
-        .iter()
-        .find(|&(_, &(_, amount))| (amount * 100_000_000.0) as u64 > required_amount)
+        .spendable_utxos()
+        .filter(|(_, (script, _))| wallet_state.witness_programs.contains(script))
+        .find(|&(_, &(_, amount))| btc_to_sats(amount) > required_amount)
         .ok_or(SpendError::InsufficientFunds(
             "Insufficient funds".to_string(),
         ))?;
@@ -326,19 +344,22 @@ pub fn spend_p2wpkh(wallet_state: &WalletState) -> Result<([u8; 32], String), Sp
     // );
 
     // Reverse the TXID hash so it's little-endian
-    let txid_bytes = hex::decode(&txid).unwrap();
+    let txid_bytes = hex::decode(txid).unwrap();
     let reversed_txid: Vec<u8> = txid_bytes.iter().rev().cloned().collect();
 
     // Convert the UTXO amount to satoshis
-    let utxo_amount_sats = (amount * 100_000_000.0) as u64;
-    let change_amount_sats = utxo_amount_sats - required_amount;
+    let utxo_amount_sats = btc_to_sats(*amount);
+    let change_amount_sats = utxo_amount_sats.checked_sub(required_amount).ok_or(SpendError::InsufficientFunds(
+        format!("coin has {} sats, need {}", utxo_amount_sats, required_amount),
+    ))?;
     let vout_index = *vout_index;
 
     // Create the transaction input from the UTXO
-    let transaction_input = input_from_utxo(&reversed_txid, vout_index);
+    let transaction_input = input_from_utxo(&reversed_txid, vout_index, SEQUENCE_RBF);
 
-    // Create the 2-of-2 multisig script using the first two public keys
-    let multisig_redeem_script = create_multisig_script(wallet_state.public_keys.clone());
+    // Create the m-of-n multisig script using the first n public keys
+    let multisig_keys = wallet_state.public_keys.iter().take(key_count).cloned().collect();
+    let multisig_redeem_script = create_multisig_script(multisig_keys, threshold, sort_keys)?;
 
     let multisig_witness_program = get_p2wsh_program(&multisig_redeem_script, Some(0));
 
@@ -355,8 +376,13 @@ pub fn spend_p2wpkh(wallet_state: &WalletState) -> Result<([u8; 32], String), Sp
     let input_scriptcode = get_p2wpkh_scriptcode(&input_utxo);
     // println!("Input ScriptCode: {:?}", hex::encode(&input_scriptcode));
 
-    // Create the change output (sending change back to the 0th key's P2WPKH address)
-    let change_output = output_from_options(&wallet_state.witness_programs[0], change_amount_sats);
+    // Create the change output (sending change back to the 0th key's P2WPKH address),
+    // left out and paid as fee when it would be dust
+    let change = Some(Utxo {
+        script_pubkey: wallet_state.witness_programs[0].clone(),
+        amount: change_amount_sats,
+    })
+    .filter(|change| change.amount >= dust_threshold(&change.script_pubkey));
     // println!("Change Output: {:?}", hex::encode(&change_output));
 
     // Create the outpoint for the input being spent
@@ -365,24 +391,29 @@ pub fn spend_p2wpkh(wallet_state: &WalletState) -> Result<([u8; 32], String), Sp
         index: vout_index,
     };
 
-    // Define the outputs for the transaction
-    let transaction_outputs = vec![
-        Utxo {
-            script_pubkey: multisig_witness_program.clone(),
-            amount: AMT,
-        },
-        Utxo {
-            script_pubkey: wallet_state.witness_programs[0].clone(),
-            amount: change_amount_sats,
-        },
-    ];
+    // Define the outputs for the transaction, with the optional OP_RETURN
+    // between the multisig and the change
+    let op_return_output = op_return.map(|script| Utxo {
+        script_pubkey: script,
+        amount: 0,
+    });
+    let transaction_outputs: Vec<Utxo> = [Utxo {
+        script_pubkey: multisig_witness_program.clone(),
+        amount: AMT,
+    }]
+    .into_iter()
+    .chain(op_return_output.clone())
+    .chain(change.clone())
+    .collect();
 
     // Compute the commitment hash (digest to sign) for the input
+    let unsigned_tx = Transaction::new(vec![TxIn::new(outpoint)], transaction_outputs);
     let commitment_hash = get_commitment_hash(
-        outpoint,
+        &unsigned_tx,
+        0,
         &input_scriptcode,
         utxo_amount_sats,
-        transaction_outputs,
+        sighash_type,
     );
     // println!("Commitment Hash: {:?}", hex::encode(&commitment_hash));
 
@@ -390,7 +421,7 @@ pub fn spend_p2wpkh(wallet_state: &WalletState) -> Result<([u8; 32], String), Sp
     let witness_programs: Vec<String> = wallet_state
         .witness_programs
         .iter()
-        .map(|x| hex::encode(x))
+        .map(hex::encode)
         .collect();
     let witness_programs: Vec<&str> = witness_programs.iter().map(|s| s.as_str()).collect();
 
@@ -408,11 +439,15 @@ pub fn spend_p2wpkh(wallet_state: &WalletState) -> Result<([u8; 32], String), Sp
     // println!("Private Key: {:?}", hex::encode(&private_key));
 
     // Sign the transaction input
-    let witness = get_p2wpkh_witness(&private_key, commitment_hash);
+    let witness = get_p2wpkh_witness(&private_key, commitment_hash, sighash_type);
 
     // Assemble the transaction
     let transaction_inputs = vec![transaction_input.clone()];
-    let transaction_outputs = vec![multisig_output.clone(), change_output.clone()];
+    let transaction_outputs: Vec<Vec<u8>> = [multisig_output.clone()]
+        .into_iter()
+        .chain(op_return_output.map(|output| output.serialize()))
+        .chain(change.map(|output| output.serialize()))
+        .collect();
     let transaction_witnesses = vec![witness.clone()];
 
     // Serialize the transaction and compute the TXID
@@ -420,10 +455,18 @@ pub fn spend_p2wpkh(wallet_state: &WalletState) -> Result<([u8; 32], String), Sp
         transaction_inputs.clone(),
         transaction_outputs.clone(),
         transaction_witnesses,
+        0,
     );
 
+    // Make sure the signature satisfies the coin's script and the transaction
+    // would be relayed before handing it out
+    let signed_tx = Transaction::parse(&transaction)?;
+    let prevouts = [input_utxo];
+    verify_transaction(&signed_tx, &prevouts)?;
+    check_policy(&signed_tx, &prevouts, signed_tx.weight() as u64)?;
+
     // Compute the TXID (hash of the transaction without witness data)
-    let txid = get_txid(transaction_inputs, transaction_outputs);
+    let txid = get_txid(transaction_inputs, transaction_outputs, 0);
 
     // println!("Transaction ID (hex): {}", hex::encode(&txid));
 
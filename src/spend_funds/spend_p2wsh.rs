@@ -1,102 +1,153 @@
 use crate::balance::balance::WalletState;
+use crate::balance::cache::WalletCache;
+use crate::spend_funds::interpreter::{lookup_prevout, verify_transaction};
+use crate::spend_funds::policy::{check_policy, dust_threshold};
+use crate::spend_funds::sighash::{get_commitment_hash, SighashType};
 use crate::spend_funds::spend_p2wpkh::{
-    create_multisig_script, input_from_utxo, output_from_options, SpendError, Outpoint, Utxo, get_commitment_hash, get_txid, assemble_transaction, get_p2wsh_witness
+    create_multisig_script, input_from_utxo, output_from_options, SpendError, get_txid, assemble_transaction, get_p2wsh_witness,
+    get_p2wsh_program,
 };
+use crate::spend_funds::transaction::{Outpoint, Transaction, TxIn, Utxo, SEQUENCE_RBF};
+
+// The output spent by `outpoint`: from one of our unconfirmed transactions in
+// the cache, our coins, or the node
+fn find_prevout(
+    wallet_state: &WalletState,
+    wallet_cache: &WalletCache,
+    outpoint: &Outpoint,
+) -> Result<Utxo, SpendError> {
+    let Some(cached) = wallet_cache.wallet_transaction(&outpoint.txid_hex()) else {
+        return lookup_prevout(outpoint, Some(wallet_state));
+    };
+    let bytes = hex::decode(&cached.hex).map_err(|e| SpendError::InvalidTransaction(e.to_string()))?;
+    Transaction::parse(&bytes)?
+        .outputs
+        .get(outpoint.index as usize)
+        .cloned()
+        .ok_or(SpendError::InvalidTransaction(format!(
+            "{} has no output {}",
+            outpoint.txid_hex(),
+            outpoint.index
+        )))
+}
 
-pub fn spend_p2wsh(wallet_state: &WalletState, txid: [u8; 32]) -> Result<Vec<Vec<u8>>, SpendError> {
-    // COIN_VALUE = 1000000
-    // FEE = 1000
-    // AMT = 0
-    // Create the input from the utxo
+#[allow(clippy::too_many_arguments)]
+pub fn spend_p2wsh(
+    wallet_state: &WalletState,
+    wallet_cache: &WalletCache,
+    outpoint: Outpoint,
+    threshold: usize,
+    key_count: usize,
+    sort_keys: bool,
+    sighash_type: SighashType,
+    op_return_script: Vec<u8>,
+) -> Result<Vec<Vec<u8>>, SpendError> {
+    // Create the input from the utxo (the outpoint's txid is already little-endian)
     const FEE: u64 = 1000;
-    const AMT: u64 = 1000000;
-
-    let vout_index = 0; //index of the ouput AMT was sent
-
-    // Reverse the txid hash so it's little-endian
-    let reversed_txid: Vec<u8> = txid.iter().rev().cloned().collect();
-
-    let transaction_input = input_from_utxo(&reversed_txid, vout_index);
-
-    let pubkey1 = wallet_state.public_keys[0].clone();
-    let pubkey2 = wallet_state.public_keys[1].clone();
 
-    let multisig_redeem_script = create_multisig_script(vec![pubkey1, pubkey2]);
-    // let multisig_witness_program = get_p2wsh_program(&multisig_redeem_script, Some(0));;
+    let transaction_input = input_from_utxo(&outpoint.txid, outpoint.index, SEQUENCE_RBF);
+
+    // Rebuild the m-of-n redeem script from the first n public keys
+    let multisig_keys = wallet_state.public_keys.iter().take(key_count).cloned().collect();
+    let multisig_redeem_script = create_multisig_script(multisig_keys, threshold, sort_keys)?;
+    let multisig_witness_program = get_p2wsh_program(&multisig_redeem_script, Some(0));
+
+    // The signature commits to the spent amount, so use the output's real value
+    let spent_output = find_prevout(wallet_state, wallet_cache, &outpoint)?;
+    if spent_output.script_pubkey != multisig_witness_program {
+        return Err(SpendError::InvalidScript(format!(
+            "{}:{} pays to {}, not the {}-of-{} multisig {}",
+            outpoint.txid_hex(),
+            outpoint.index,
+            hex::encode(&spent_output.script_pubkey),
+            threshold,
+            key_count,
+            hex::encode(&multisig_witness_program)
+        )));
+    }
 
     // Compute destination output script and output
-    // Create the OP_RETURN output with your name (or nym) in ASCII
-    let name = "Shallom Micah Bawa"; // Replace with your name or nym
-    let mut op_return_script = vec![
-        0x6a,             // OP_RETURN
-        name.len() as u8, // Pushdata length
-    ];
-    op_return_script.extend_from_slice(name.as_bytes());
     let op_return_output = output_from_options(&op_return_script, 0);
 
-    // Compute change output script and output
-    let change_amount = AMT - FEE;
-    let change_output = output_from_options(&wallet_state.witness_programs[0], change_amount);
+    // Compute change output script and output, paid as fee when it would be dust
+    let change_amount = spent_output.amount.checked_sub(FEE).ok_or(SpendError::InsufficientFunds(format!(
+        "the output has {} sats, less than the {} sat fee",
+        spent_output.amount, FEE
+    )))?;
+    let change = Some(Utxo {
+        script_pubkey: wallet_state
+            .fresh_change_program()
+            .ok_or(SpendError::MissingCodeCantRun)?
+            .clone(),
+        amount: change_amount,
+    })
+    .filter(|change| change.amount >= dust_threshold(&change.script_pubkey));
 
     // Get the message to sign
-    // 1.create an outpoint
-    let outpoint = Outpoint {
-        txid: reversed_txid.try_into().unwrap(),
-        index: vout_index,
-    };
-    // 2.define the outputs for the transaction
-    let transaction_outputs = vec![
+    // define the outputs for the transaction
+    let transaction_outputs: Vec<Utxo> = [
         //output from p2wsh; input to the p2wpkh
         Utxo {
             script_pubkey: op_return_script.clone(),
             amount: 0,
         },
-        //change into the sender
-        Utxo {
-            script_pubkey: wallet_state.witness_programs[0].clone(),
-            amount: change_amount,
-        },
-    ];
+    ]
+    .into_iter()
+    //change into the sender
+    .chain(change.clone())
+    .collect();
 
     // Sign!
+    let unsigned_tx = Transaction::new(vec![TxIn::new(outpoint)], transaction_outputs);
     let commitment_hash = get_commitment_hash(
-        outpoint,
+        &unsigned_tx,
+        0,
         &multisig_redeem_script, //scriptcode for p2wsh is the redeem script,
-        AMT,
-        transaction_outputs,
+        spent_output.amount,
+        sighash_type,
     );
 
     // Fetch the private keys needed to sign the input
-    let privkey1: [u8; 32] = wallet_state.private_keys[0]
-        .clone()
-        .try_into()
-        .expect("private key length is not 32 bytes");
-    let privkey2: [u8; 32] = wallet_state.private_keys[1]
-        .clone()
-        .try_into()
-        .expect("private key length is not 32 bytes");
-
-    // Sign the transaction input
+    let privkeys: Vec<[u8; 32]> = wallet_state
+        .private_keys
+        .iter()
+        .take(key_count)
+        .map(|key| key.clone().try_into().expect("private key length is not 32 bytes"))
+        .collect();
+
+    // Sign the transaction input, m signatures in redeem script key order
     let witness = get_p2wsh_witness(
-        vec![&privkey1, &privkey2],
+        privkeys.iter().collect(),
         commitment_hash,
         &multisig_redeem_script,
-    );
+        sighash_type,
+    )?;
 
     // Assemble
     let transaction_inputs = vec![transaction_input.clone()];
-    let transaction_outputs = vec![op_return_output.clone(), change_output.clone()];
+    let transaction_outputs: Vec<Vec<u8>> = [op_return_output.clone()]
+        .into_iter()
+        .chain(change.map(|output| output.serialize()))
+        .collect();
     let transaction_witnesses = vec![witness.clone()];
 
     let transaction = assemble_transaction(
         transaction_inputs.clone(),
         transaction_outputs.clone(),
         transaction_witnesses,
+        0,
     );
 
+    // Check the witness against the multisig output and the transaction against
+    // relay policy before handing it out
+    let signed_tx = Transaction::parse(&transaction)?;
+    let prevouts = [spent_output];
+    verify_transaction(&signed_tx, &prevouts)?;
+    check_policy(&signed_tx, &prevouts, signed_tx.weight() as u64)?;
+
     // For debugging you can use RPC `testmempoolaccept ["<final hex>"]` here
     // return txid final-tx
-    let txid2 = get_txid(transaction_inputs, transaction_outputs);
+    let txid2 = get_txid(transaction_inputs, transaction_outputs, 0);
 
     // println!("Transaction ID (hex): {}", hex::encode(&txid));
 
@@ -0,0 +1,94 @@
+// Transactions spending a set of our coins in full to a single output:
+// consolidating small coins into one, or sweeping the whole wallet away
+use crate::balance::balance::WalletState;
+use crate::spend_funds::builder::{
+    estimate_weight, fee_for_weight, sign_wallet_inputs, wallet_coins, Coin,
+};
+use crate::spend_funds::interpreter::verify_transaction;
+use crate::spend_funds::policy::{check_policy, dust_threshold};
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use crate::spend_funds::transaction::{Transaction, TxIn, Utxo};
+
+// Spend all of `coins` to `destination` and any zero-value `extra_outputs`
+// (OP_RETURN), the fee taken from the destination's amount
+fn spend_coins(
+    wallet_state: &WalletState,
+    coins: Vec<Coin>,
+    destination: Vec<u8>,
+    extra_outputs: Vec<Utxo>,
+    fee_rate: f64,
+) -> Result<(Transaction, u64), SpendError> {
+    let total: u64 = coins.iter().map(|coin| coin.utxo.amount).sum();
+    let inputs = coins.iter().map(|coin| TxIn::new(coin.outpoint)).collect();
+    let prevouts: Vec<Utxo> = coins.into_iter().map(|coin| coin.utxo).collect();
+
+    let mut outputs: Vec<Utxo> = [Utxo {
+        script_pubkey: destination,
+        amount: 0,
+    }]
+    .into_iter()
+    .chain(extra_outputs)
+    .collect();
+    let weight = estimate_weight(wallet_state, &prevouts, &outputs)?;
+    let fee = fee_for_weight(weight, fee_rate);
+    outputs[0].amount = total.saturating_sub(fee);
+    let dust = dust_threshold(&outputs[0].script_pubkey);
+    if outputs[0].amount < dust {
+        return Err(SpendError::InsufficientFunds(format!(
+            "{} sats leave {} after the {} sat fee, below the {} sat dust threshold",
+            total, outputs[0].amount, fee, dust
+        )));
+    }
+
+    let mut tx = Transaction::new(inputs, outputs);
+    check_policy(&tx, &prevouts, weight)?;
+    sign_wallet_inputs(wallet_state, &mut tx, &prevouts)?;
+    verify_transaction(&tx, &prevouts)?;
+    Ok((tx, fee))
+}
+
+// Merge up to `max_inputs` of our smallest coins, or the `chosen` ones, into
+// one output at our first unused change address. Returns the transaction and its fee.
+pub fn consolidate(
+    wallet_state: &WalletState,
+    max_inputs: usize,
+    fee_rate: f64,
+    extra_outputs: Vec<Utxo>,
+    chosen: Vec<Coin>,
+) -> Result<(Transaction, u64), SpendError> {
+    let coins = if chosen.is_empty() {
+        let mut coins = wallet_coins(wallet_state);
+        coins.reverse();
+        coins.truncate(max_inputs);
+        coins
+    } else {
+        chosen
+    };
+    if coins.len() < 2 {
+        return Err(SpendError::InsufficientFunds(format!(
+            "need at least 2 coins to consolidate, have {}",
+            coins.len()
+        )));
+    }
+    let destination = wallet_state
+        .fresh_change_program()
+        .ok_or(SpendError::MissingCodeCantRun)?
+        .clone();
+    spend_coins(wallet_state, coins, destination, extra_outputs, fee_rate)
+}
+
+// Send every coin of the wallet, or only the `chosen` ones, to `destination`
+// without change. Returns the transaction and its fee.
+pub fn sweep(
+    wallet_state: &WalletState,
+    destination: Vec<u8>,
+    fee_rate: f64,
+    extra_outputs: Vec<Utxo>,
+    chosen: Vec<Coin>,
+) -> Result<(Transaction, u64), SpendError> {
+    let coins = if chosen.is_empty() { wallet_coins(wallet_state) } else { chosen };
+    if coins.is_empty() {
+        return Err(SpendError::InsufficientFunds("the wallet has no coins".to_string()));
+    }
+    spend_coins(wallet_state, coins, destination, extra_outputs, fee_rate)
+}
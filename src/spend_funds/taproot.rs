@@ -0,0 +1,453 @@
+// Taproot (segwit v1) outputs, key path and script path spending, BIP340/BIP341/BIP342
+use crate::balance::address::{address_to_script, encode_segwit_address, SIGNET_HRP};
+use crate::balance::balance::{btc_to_sats, WalletState};
+use crate::balance::cache::TaprootPolicy;
+use crate::spend_funds::interpreter::verify_transaction;
+use crate::spend_funds::policy::{check_policy, dust_threshold};
+use crate::spend_funds::script::{
+    decode_script, parse_asm, Instruction, OP_CHECKSIG, OP_CHECKSIGADD, OP_CHECKSIGVERIFY,
+};
+use crate::spend_funds::sighash::{get_taproot_commitment_hash, SighashType, SIGHASH_DEFAULT};
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use crate::spend_funds::transaction::{compact_size, Outpoint, Transaction, TxIn, Utxo};
+use secp256k1::{Keypair, Parity, PublicKey, Scalar, Secp256k1, SecretKey, XOnlyPublicKey};
+use sha2::{Digest, Sha256};
+
+// leaf version of BIP342 tapscript
+pub const TAPSCRIPT_LEAF_VERSION: u8 = 0xc0;
+
+// BIP340 tagged hash: SHA256(SHA256(tag) || SHA256(tag) || data)
+pub fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(tag_hash);
+    hasher.update(tag_hash);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+// x-only public key from a 32 byte x-only or 33 byte compressed key
+pub fn x_only_key(pubkey: &[u8]) -> Result<XOnlyPublicKey, SpendError> {
+    let invalid = |_| SpendError::InvalidScript(format!("invalid public key {}", hex::encode(pubkey)));
+    if pubkey.len() == 33 {
+        Ok(PublicKey::from_slice(pubkey).map_err(invalid)?.x_only_public_key().0)
+    } else {
+        XOnlyPublicKey::from_slice(pubkey).map_err(invalid)
+    }
+}
+
+// BIP341 tweak of an internal key, committing to the script tree's merkle root if any
+fn taproot_tweak(internal_key: &XOnlyPublicKey, merkle_root: Option<[u8; 32]>) -> Scalar {
+    let mut data = internal_key.serialize().to_vec();
+    if let Some(root) = merkle_root {
+        data.extend(root);
+    }
+    Scalar::from_be_bytes(tagged_hash("TapTweak", &data)).expect("tweak hash is below the curve order")
+}
+
+// The tweaked output key Q = P + t*G and its parity
+pub fn taproot_output_key(
+    internal_key: &[u8],
+    merkle_root: Option<[u8; 32]>,
+) -> Result<(XOnlyPublicKey, Parity), SpendError> {
+    let secp = Secp256k1::verification_only();
+    let internal_key = x_only_key(internal_key)?;
+    internal_key
+        .add_tweak(&secp, &taproot_tweak(&internal_key, merkle_root))
+        .map_err(|e| SpendError::InvalidScript(e.to_string()))
+}
+
+// OP_1 <32 byte output key>
+pub fn get_p2tr_program(internal_key: &[u8], merkle_root: Option<[u8; 32]>) -> Result<Vec<u8>, SpendError> {
+    let (output_key, _) = taproot_output_key(internal_key, merkle_root)?;
+    let mut program = vec![0x51, 0x20];
+    program.extend(output_key.serialize());
+    Ok(program)
+}
+
+fn keypair(privkey: &[u8; 32]) -> Result<Keypair, SpendError> {
+    let secret_key = SecretKey::from_slice(privkey).map_err(|e| SpendError::SigningError(e.to_string()))?;
+    Ok(Keypair::from_secret_key(&Secp256k1::signing_only(), &secret_key))
+}
+
+// BIP340 signature, with the sighash byte only appended for types other than DEFAULT
+fn schnorr_signature(keypair: &Keypair, msg: &[u8], sighash_type: SighashType) -> Vec<u8> {
+    let signature = Secp256k1::signing_only().sign_schnorr_no_aux_rand(msg, keypair);
+    let mut signature = signature.as_ref().to_vec();
+    if sighash_type.0 != SIGHASH_DEFAULT {
+        signature.push(sighash_type.to_byte());
+    }
+    signature
+}
+
+// Schnorr signature with the tweaked private key, for a key path spend
+pub fn sign_schnorr(
+    privkey: &[u8; 32],
+    merkle_root: Option<[u8; 32]>,
+    msg: &[u8],
+    sighash_type: SighashType,
+) -> Result<Vec<u8>, SpendError> {
+    let keypair = keypair(privkey)?;
+    let (internal_key, _) = keypair.x_only_public_key();
+    let tweaked = keypair
+        .add_xonly_tweak(&Secp256k1::new(), &taproot_tweak(&internal_key, merkle_root))
+        .map_err(|e| SpendError::SigningError(e.to_string()))?;
+    Ok(schnorr_signature(&tweaked, msg, sighash_type))
+}
+
+// Schnorr signature with the untweaked private key, for a key inside a tapscript
+pub fn sign_tapscript(privkey: &[u8; 32], msg: &[u8], sighash_type: SighashType) -> Result<Vec<u8>, SpendError> {
+    Ok(schnorr_signature(&keypair(privkey)?, msg, sighash_type))
+}
+
+pub fn tap_leaf_hash(script: &[u8]) -> [u8; 32] {
+    let mut data = vec![TAPSCRIPT_LEAF_VERSION];
+    data.extend(compact_size(script.len() as u64));
+    data.extend_from_slice(script);
+    tagged_hash("TapLeaf", &data)
+}
+
+// Branches hash their children in lexicographic order, so a merkle path
+// doesn't need to say which side each sibling is on
+pub fn tap_branch_hash(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    let mut data = left.to_vec();
+    data.extend(right);
+    tagged_hash("TapBranch", &data)
+}
+
+// A balanced tree of tapscript leaves: leaves are paired up level by level,
+// an odd leaf out moving up a level unpaired
+pub struct TapTree {
+    pub leaves: Vec<Vec<u8>>,
+}
+
+impl TapTree {
+    // merkle root and, for each leaf, the sibling hashes from the leaf up to the root
+    fn build(&self) -> (Option<[u8; 32]>, Vec<Vec<[u8; 32]>>) {
+        let mut paths = vec![Vec::new(); self.leaves.len()];
+        let mut level: Vec<([u8; 32], Vec<usize>)> = self
+            .leaves
+            .iter()
+            .enumerate()
+            .map(|(index, leaf)| (tap_leaf_hash(leaf), vec![index]))
+            .collect();
+
+        while level.len() > 1 {
+            let mut next = Vec::new();
+            for pair in level.chunks(2) {
+                match pair {
+                    [(left, left_leaves), (right, right_leaves)] => {
+                        for leaf in left_leaves {
+                            paths[*leaf].push(*right);
+                        }
+                        for leaf in right_leaves {
+                            paths[*leaf].push(*left);
+                        }
+                        let mut leaves = left_leaves.clone();
+                        leaves.extend(right_leaves);
+                        next.push((tap_branch_hash(left, right), leaves));
+                    }
+                    [single] => next.push(single.clone()),
+                    _ => unreachable!(),
+                }
+            }
+            level = next;
+        }
+        (level.first().map(|(root, _)| *root), paths)
+    }
+
+    pub fn merkle_root(&self) -> Option<[u8; 32]> {
+        self.build().0
+    }
+
+    // Control block proving `leaf_index` is committed to by the output key:
+    // leaf version | output key parity, internal key, merkle path
+    pub fn control_block(&self, internal_key: &[u8], leaf_index: usize) -> Result<Vec<u8>, SpendError> {
+        let (root, paths) = self.build();
+        let path = paths
+            .get(leaf_index)
+            .ok_or(SpendError::InvalidScript(format!("no leaf {}", leaf_index)))?;
+        let (_, parity) = taproot_output_key(internal_key, root)?;
+
+        let mut control_block = vec![TAPSCRIPT_LEAF_VERSION | parity.to_u8()];
+        control_block.extend(x_only_key(internal_key)?.serialize());
+        for sibling in path {
+            control_block.extend(sibling);
+        }
+        Ok(control_block)
+    }
+}
+
+// Index of our BIP86 key with this x-only public key
+pub fn our_taproot_key(wallet_state: &WalletState, xonly: &[u8]) -> Option<usize> {
+    wallet_state.taproot_keys.iter().position(|privkey| {
+        let privkey: [u8; 32] = privkey.clone().try_into().expect("private key length is not 32 bytes");
+        keypair(&privkey).is_ok_and(|keypair| keypair.x_only_public_key().0.serialize() == xonly)
+    })
+}
+
+// Replace "@<index>" with the x-only public key of our BIP86 key at that index
+pub fn resolve_key(wallet_state: &WalletState, token: &str) -> Result<String, SpendError> {
+    let Some(index) = token.strip_prefix('@') else {
+        return Ok(token.to_string());
+    };
+    let privkey = index
+        .parse::<usize>()
+        .ok()
+        .and_then(|index| wallet_state.taproot_keys.get(index))
+        .ok_or(SpendError::InvalidScript(format!("no taproot key {}", token)))?;
+    let privkey: [u8; 32] = privkey.clone().try_into().expect("private key length is not 32 bytes");
+    Ok(hex::encode(keypair(&privkey)?.x_only_public_key().0.serialize()))
+}
+
+// A taproot output with `internal_key` committing to the tapscripts `leaves`.
+// Keys may be given as "@<index>" to use our own BIP86 keys.
+pub fn create_taproot_policy(
+    wallet_state: &WalletState,
+    name: &str,
+    internal_key: &str,
+    leaves: &[String],
+) -> Result<TaprootPolicy, SpendError> {
+    let internal_key = resolve_key(wallet_state, internal_key)?;
+    let internal_key_bytes =
+        hex::decode(&internal_key).map_err(|_| SpendError::InvalidScript(format!("invalid key {}", internal_key)))?;
+
+    let mut scripts = Vec::new();
+    for leaf in leaves {
+        let asm = leaf
+            .split_whitespace()
+            .map(|token| resolve_key(wallet_state, token))
+            .collect::<Result<Vec<_>, _>>()?
+            .join(" ");
+        scripts.push(parse_asm(&asm).map_err(SpendError::InvalidScript)?);
+    }
+    if scripts.is_empty() {
+        return Err(SpendError::InvalidScript("a script tree needs at least one leaf".to_string()));
+    }
+
+    let tree = TapTree { leaves: scripts };
+    let program = get_p2tr_program(&internal_key_bytes, tree.merkle_root())?;
+    Ok(TaprootPolicy {
+        name: name.to_string(),
+        internal_key: hex::encode(x_only_key(&internal_key_bytes)?.serialize()),
+        leaves: tree.leaves.iter().map(hex::encode).collect(),
+        address: encode_segwit_address(SIGNET_HRP, 1, &program[2..]),
+    })
+}
+
+pub fn taproot_policy_tree(policy: &TaprootPolicy) -> Result<TapTree, SpendError> {
+    let leaves = policy
+        .leaves
+        .iter()
+        .map(hex::decode)
+        .collect::<Result<_, _>>()
+        .map_err(|_| SpendError::InvalidScript(format!("bad leaf script in {}", policy.name)))?;
+    Ok(TapTree { leaves })
+}
+
+// Spend the wallet's P2TR coins to `destination` by key path, change going
+// back to our first taproot address
+pub fn spend_taproot(
+    wallet_state: &WalletState,
+    destination: &str,
+    amount: u64,
+    fee: u64,
+    sighash_type: SighashType,
+    op_return: Option<Vec<u8>>,
+) -> Result<Transaction, SpendError> {
+    let destination_script = address_to_script(destination)?;
+    let change_script = wallet_state
+        .taproot_programs
+        .first()
+        .ok_or(SpendError::MissingCodeCantRun)?
+        .clone();
+
+    // largest coins first
+    let mut coins: Vec<(&(String, u32), &Vec<u8>, u64)> = wallet_state
+        .spendable_utxos()
+        .filter(|(_, (script, _))| wallet_state.taproot_programs.contains(script))
+        .map(|(outpoint, (script, value))| (outpoint, script, btc_to_sats(*value)))
+        .collect();
+    coins.sort_by_key(|coin| std::cmp::Reverse(coin.2));
+
+    let required = amount + fee;
+    let mut inputs = Vec::new();
+    let mut prevouts = Vec::new();
+    let mut key_indices = Vec::new();
+    let mut selected = 0;
+    for ((txid, vout), script, value) in coins {
+        if selected >= required {
+            break;
+        }
+        let outpoint: Outpoint = format!("{}:{}", txid, vout)
+            .parse()
+            .map_err(SpendError::InvalidTransaction)?;
+        inputs.push(TxIn::new(outpoint));
+        prevouts.push(Utxo {
+            script_pubkey: script.clone(),
+            amount: value,
+        });
+        key_indices.push(
+            wallet_state
+                .taproot_programs
+                .iter()
+                .position(|program| program == script)
+                .expect("coin was selected by its program"),
+        );
+        selected += value;
+    }
+    if selected < required {
+        return Err(SpendError::InsufficientFunds(format!(
+            "taproot coins have {} sats, need {}",
+            selected, required
+        )));
+    }
+
+    let mut outputs = vec![Utxo {
+        script_pubkey: destination_script,
+        amount,
+    }];
+    outputs.extend(op_return.map(|script| Utxo {
+        script_pubkey: script,
+        amount: 0,
+    }));
+    // change below the dust threshold goes to the fee
+    if selected >= required + dust_threshold(&change_script) {
+        outputs.push(Utxo {
+            script_pubkey: change_script,
+            amount: selected - required,
+        });
+    }
+
+    let mut tx = Transaction::new(inputs, outputs);
+    for (index, key_index) in key_indices.into_iter().enumerate() {
+        let privkey: [u8; 32] = wallet_state.taproot_keys[key_index]
+            .clone()
+            .try_into()
+            .expect("private key length is not 32 bytes");
+        let commitment_hash = get_taproot_commitment_hash(&tx, index, &prevouts, sighash_type, None)?;
+        let signature = sign_schnorr(&privkey, None, &commitment_hash, sighash_type)?;
+        tx.inputs[index].witness = vec![signature];
+    }
+    verify_transaction(&tx, &prevouts)?;
+    check_policy(&tx, &prevouts, tx.weight() as u64)?;
+    Ok(tx)
+}
+
+// Spend the coins of a taproot policy through leaf `leaf_index`, change going
+// back to the policy's address. The witness is a signature (or an empty item)
+// for each key in the leaf, last key on top, then `extra_items` on top of those.
+#[allow(clippy::too_many_arguments)]
+pub fn spend_taproot_script(
+    wallet_state: &WalletState,
+    policy: &TaprootPolicy,
+    leaf_index: usize,
+    destination: &str,
+    amount: u64,
+    fee: u64,
+    sighash_type: SighashType,
+    extra_items: &[Vec<u8>],
+    op_return: Option<Vec<u8>>,
+) -> Result<Transaction, SpendError> {
+    let tree = taproot_policy_tree(policy)?;
+    let leaf = tree
+        .leaves
+        .get(leaf_index)
+        .ok_or(SpendError::InvalidScript(format!("{} has no leaf {}", policy.name, leaf_index)))?
+        .clone();
+    let internal_key = hex::decode(&policy.internal_key)
+        .map_err(|_| SpendError::InvalidScript(format!("bad internal key in {}", policy.name)))?;
+    let control_block = tree.control_block(&internal_key, leaf_index)?;
+    let leaf_hash = tap_leaf_hash(&leaf);
+    let program = address_to_script(&policy.address)?;
+
+    // largest coins first
+    let mut coins: Vec<(&(String, u32), u64)> = wallet_state
+        .spendable_watched_utxos()
+        .filter(|(_, (script, _))| *script == program)
+        .map(|(outpoint, (_, value))| (outpoint, btc_to_sats(*value)))
+        .collect();
+    coins.sort_by_key(|coin| std::cmp::Reverse(coin.1));
+
+    let required = amount + fee;
+    let mut inputs = Vec::new();
+    let mut prevouts = Vec::new();
+    let mut selected = 0;
+    for ((txid, vout), value) in coins {
+        if selected >= required {
+            break;
+        }
+        let outpoint: Outpoint = format!("{}:{}", txid, vout)
+            .parse()
+            .map_err(SpendError::InvalidTransaction)?;
+        inputs.push(TxIn::new(outpoint));
+        prevouts.push(Utxo {
+            script_pubkey: program.clone(),
+            amount: value,
+        });
+        selected += value;
+    }
+    if selected < required {
+        return Err(SpendError::InsufficientFunds(format!(
+            "{} has {} sats, need {}",
+            policy.name, selected, required
+        )));
+    }
+
+    let mut outputs = vec![Utxo {
+        script_pubkey: address_to_script(destination)?,
+        amount,
+    }];
+    outputs.extend(op_return.map(|script| Utxo {
+        script_pubkey: script,
+        amount: 0,
+    }));
+    // change below the dust threshold goes to the fee
+    if selected >= required + dust_threshold(&program) {
+        outputs.push(Utxo {
+            script_pubkey: program.clone(),
+            amount: selected - required,
+        });
+    }
+
+    // the keys of the leaf, 32 byte pushes checked by the opcode that follows
+    // them (hashes are 32 byte pushes too), ours or not in script order
+    let leaf_keys: Vec<Option<usize>> = decode_script(&leaf)
+        .ok_or(SpendError::InvalidScript("undecodable leaf script".to_string()))?
+        .windows(2)
+        .filter_map(|pair| match pair {
+            [Instruction::Push(data), Instruction::Op(OP_CHECKSIG | OP_CHECKSIGVERIFY | OP_CHECKSIGADD)]
+                if data.len() == 32 =>
+            {
+                Some(our_taproot_key(wallet_state, data))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut tx = Transaction::new(inputs, outputs);
+    for index in 0..tx.inputs.len() {
+        let commitment_hash = get_taproot_commitment_hash(&tx, index, &prevouts, sighash_type, Some(leaf_hash))?;
+        let mut witness = Vec::new();
+        for key_index in leaf_keys.iter().rev() {
+            witness.push(match key_index {
+                Some(key_index) => {
+                    let privkey: [u8; 32] = wallet_state.taproot_keys[*key_index]
+                        .clone()
+                        .try_into()
+                        .expect("private key length is not 32 bytes");
+                    sign_tapscript(&privkey, &commitment_hash, sighash_type)?
+                }
+                None => Vec::new(),
+            });
+        }
+        witness.extend(extra_items.iter().cloned());
+        witness.push(leaf.clone());
+        witness.push(control_block.clone());
+        tx.inputs[index].witness = witness;
+    }
+    verify_transaction(&tx, &prevouts)?;
+    check_policy(&tx, &prevouts, tx.weight() as u64)?;
+    Ok(tx)
+}
@@ -0,0 +1,1320 @@
+// Signing and serialization checked byte for byte: the BIP143 examples, whose
+// signatures predate low-R grinding and so are plain RFC6979, and regtest
+// transactions rebuilt from fixed keys
+use crate::balance::address::{
+    address_to_script, decode_segwit_address, encode_segwit_address, script_to_address, SIGNET_HRP,
+};
+use crate::balance::balance::{derive_wallet_state, get_p2pkh_script, get_p2sh_p2wpkh_script, WalletState};
+use crate::balance::cache::{MempoolExit, WalletCache};
+use crate::spend_funds::builder::{select_payment, sign_wallet_inputs};
+use crate::spend_funds::interpreter::{hash160, verify_input, verify_transaction};
+use crate::spend_funds::message::{sign_message, verify_message};
+use crate::spend_funds::miniscript::{compile_policy, create_miniscript_policy, spend_miniscript};
+use crate::spend_funds::rbf::bump_fee;
+use crate::spend_funds::script::{op_return_script, parse_data_push, push_data, MAX_OP_RETURN_SIZE};
+use crate::spend_funds::sighash::{
+    get_commitment_hash, get_legacy_commitment_hash, get_taproot_commitment_hash, SighashType, SIGHASH_ALL,
+    SIGHASH_ANYONECANPAY, SIGHASH_DEFAULT, SIGHASH_NONE, SIGHASH_SINGLE,
+};
+use crate::spend_funds::spend_p2wpkh::{
+    assemble_transaction, create_multisig_script, get_p2wpkh_scriptcode, get_p2wpkh_witness,
+    get_p2wsh_program, get_p2wsh_witness, get_txid, input_from_utxo, output_from_options, sign,
+};
+use crate::spend_funds::sweep::{consolidate, sweep};
+use crate::spend_funds::taproot::{
+    create_taproot_policy, get_p2tr_program, sign_schnorr, spend_taproot_script, tagged_hash,
+    taproot_policy_tree, TapTree,
+};
+use crate::spend_funds::transaction::{
+    hash256, parse_witness, Outpoint, Transaction, TxIn, Utxo, SEQUENCE_RBF,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hex_literal::hex;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+
+const ALL: SighashType = SighashType(SIGHASH_ALL);
+
+fn pubkey(privkey: &[u8; 32]) -> Vec<u8> {
+    let secret_key = SecretKey::from_slice(privkey).unwrap();
+    PublicKey::from_secret_key(&Secp256k1::new(), &secret_key)
+        .serialize()
+        .to_vec()
+}
+
+fn p2wpkh_program(pubkey: &[u8]) -> Vec<u8> {
+    let mut program = vec![0x00, 0x14];
+    program.extend(hash160(pubkey));
+    program
+}
+
+fn reversed(mut hash: Vec<u8>) -> [u8; 32] {
+    hash.reverse();
+    hash.try_into().unwrap()
+}
+
+// BIP143 "Native P2WPKH": a P2PK input and a P2WPKH input
+const P2WPKH_UNSIGNED_TX: &[u8] = &hex!(
+    "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffff"
+    "ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206"
+    "000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42db"
+    "ee7e4dbe6a21b2d50ce2f0167faa815988ac11000000"
+);
+const P2WPKH_SIGNED_TX: &[u8] = &hex!(
+    "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830"
+    "450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f928"
+    "1a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e81"
+    "5b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f6"
+    "6f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988"
+    "ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c"
+    "4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7aca"
+    "fcdb3566bb0ad253f62fc70f07aeee635711000000"
+);
+const P2PK_PRIVKEY: [u8; 32] =
+    hex!("bbc27228ddcb9209d7fd6f36b02f7dfa6252af40bb2f1cbc7a557da8027ff866");
+const P2PK_SCRIPT: [u8; 35] =
+    hex!("2103c9f4836b9a4f77fc0d81f7bcb01b7f1b35916864b9476c241ce9fc198bd25432ac");
+const P2WPKH_PRIVKEY: [u8; 32] =
+    hex!("619c335025c7f4012e556c2a58b2506e30b8511b53ade95ea316fd8c3286feb9");
+
+fn p2wpkh_example_prevouts() -> [Utxo; 2] {
+    [
+        Utxo {
+            script_pubkey: P2PK_SCRIPT.to_vec(),
+            amount: 625_000_000,
+        },
+        Utxo {
+            script_pubkey: hex!("00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1").to_vec(),
+            amount: 600_000_000,
+        },
+    ]
+}
+
+#[test]
+fn bip143_p2wpkh_commitment_hash() {
+    let tx = Transaction::parse(P2WPKH_UNSIGNED_TX).unwrap();
+    let prevout = &p2wpkh_example_prevouts()[1];
+    let scriptcode = get_p2wpkh_scriptcode(prevout);
+    assert_eq!(
+        scriptcode,
+        hex!("76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac")
+    );
+    assert_eq!(
+        get_commitment_hash(&tx, 1, &scriptcode, prevout.amount, ALL),
+        hex!("c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670")
+    );
+}
+
+#[test]
+fn bip143_p2wpkh_witness_is_deterministic() {
+    let tx = Transaction::parse(P2WPKH_UNSIGNED_TX).unwrap();
+    let prevout = &p2wpkh_example_prevouts()[1];
+    let commitment_hash =
+        get_commitment_hash(&tx, 1, &get_p2wpkh_scriptcode(prevout), prevout.amount, ALL);
+
+    let witness = get_p2wpkh_witness(&P2WPKH_PRIVKEY, commitment_hash.clone(), ALL);
+    assert_eq!(
+        witness,
+        hex!(
+            "0247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c"
+            "4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e"
+            "7acafcdb3566bb0ad253f62fc70f07aeee6357"
+        )
+    );
+    // same key and digest, same nonce
+    assert_eq!(
+        witness,
+        get_p2wpkh_witness(&P2WPKH_PRIVKEY, commitment_hash, ALL)
+    );
+}
+
+#[test]
+fn bip143_p2wpkh_signed_transaction() {
+    let mut tx = Transaction::parse(P2WPKH_UNSIGNED_TX).unwrap();
+    let prevouts = p2wpkh_example_prevouts();
+
+    // the P2PK input is signed over the legacy digest
+    let legacy_hash = get_legacy_commitment_hash(&tx, 0, &P2PK_SCRIPT, ALL);
+    let signature = sign(&P2PK_PRIVKEY, legacy_hash, ALL);
+    assert_eq!(
+        signature,
+        hex!(
+            "30450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194b"
+            "a3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01"
+        )
+    );
+    let mut script_sig = Vec::new();
+    push_data(&mut script_sig, &signature);
+    tx.inputs[0].script_sig = script_sig;
+
+    let commitment_hash = get_commitment_hash(
+        &tx,
+        1,
+        &get_p2wpkh_scriptcode(&prevouts[1]),
+        prevouts[1].amount,
+        ALL,
+    );
+    tx.inputs[1].witness =
+        parse_witness(&get_p2wpkh_witness(&P2WPKH_PRIVKEY, commitment_hash, ALL)).unwrap();
+
+    assert_eq!(tx.serialize(), P2WPKH_SIGNED_TX);
+    verify_transaction(&tx, &prevouts).unwrap();
+
+    // the wtxid commits to the witnesses, the txid doesn't
+    let mut stripped = tx.clone();
+    for input in &mut stripped.inputs {
+        input.witness.clear();
+    }
+    assert_eq!(
+        tx.txid(),
+        hex!("e8151a2af31c368a35053ddd4bdb285a8595c769a3ad83e0fa02314a602d4609")
+    );
+    assert_eq!(tx.txid(), reversed(hash256(&stripped.serialize())));
+    assert_eq!(tx.wtxid(), reversed(hash256(P2WPKH_SIGNED_TX)));
+    assert_ne!(tx.txid(), tx.wtxid());
+    // without witnesses the two are the same
+    assert_eq!(stripped.wtxid(), tx.txid());
+}
+
+// BIP143 "P2SH-P2WPKH"
+const P2SH_P2WPKH_UNSIGNED_TX: &[u8] = &hex!(
+    "0100000001db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a54770100000000feffffff"
+    "02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b59d0d96388ac0008af2f000000001976a9"
+    "14fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac92040000"
+);
+const P2SH_P2WPKH_SIGNED_TX: &[u8] = &hex!(
+    "01000000000101db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a547701000000171600"
+    "1479091972186c449eb1ded22b78e40d009bdf0089feffffff02b8b4eb0b000000001976a914a457b684d7f0d539"
+    "a46a45bbc043f35b59d0d96388ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c"
+    "88ac02473044022047ac8e878352d3ebbde1c94ce3a10d057c24175747116f8288e5d794d12d482f0220217f36a4"
+    "85cae903c713331d877c1f64677e3622ad4010726870540656fe9dcb012103ad1d8e89212f0b92c74d23bb710c00"
+    "662ad1470198ac48c43f7d6f93a2a2687392040000"
+);
+const P2SH_P2WPKH_PRIVKEY: [u8; 32] =
+    hex!("eb696a065ef48a2192da5b28b694f87544b30fae8327c4510137a922f32c6dcf");
+
+#[test]
+fn bip143_p2sh_p2wpkh() {
+    let mut tx = Transaction::parse(P2SH_P2WPKH_UNSIGNED_TX).unwrap();
+    let prevouts = [Utxo {
+        script_pubkey: hex!("a9144733f37cf4db86fbc2efed2500b4f4e49f31202387").to_vec(),
+        amount: 1_000_000_000,
+    }];
+    let redeem_script = p2wpkh_program(&pubkey(&P2SH_P2WPKH_PRIVKEY));
+    let scriptcode = get_p2wpkh_scriptcode(&Utxo {
+        script_pubkey: redeem_script,
+        amount: prevouts[0].amount,
+    });
+    assert_eq!(
+        get_commitment_hash(&tx, 0, &scriptcode, prevouts[0].amount, ALL),
+        hex!("64f3b0f4dd2bb3aa1ce8566d220cc74dda9df97d8490cc81d89d735c92e59fb6")
+    );
+
+    // signed as one of the wallet's BIP49 coins
+    let mut wallet_state = WalletState::default();
+    wallet_state.nested_keys.push(P2SH_P2WPKH_PRIVKEY.to_vec());
+    wallet_state
+        .nested_scripts
+        .push(prevouts[0].script_pubkey.clone());
+    sign_wallet_inputs(&wallet_state, &mut tx, &prevouts).unwrap();
+
+    assert_eq!(tx.serialize(), P2SH_P2WPKH_SIGNED_TX);
+    verify_transaction(&tx, &prevouts).unwrap();
+}
+
+// Regtest: a P2WPKH coin of key 1 paid to a 2-of-3 multisig of keys 1, 2 and 3
+// with a locktime, then the multisig coin spent back with keys 1 and 2
+const KEYS: [[u8; 32]; 3] = [[1; 32], [2; 32], [3; 32]];
+const FUNDING_TXID: [u8; 32] = [0xab; 32];
+const FUNDING_AMOUNT: u64 = 100_000;
+const LOCKTIME: u32 = 150;
+
+const FUNDING_TX: &[u8] = &hex!(
+    "02000000000101abababababababababababababababababababababababababababababababab0100000000fdffffff"
+    "01b88201000000000022002063ae81f48889f3ebcce92fc39fbb9fe63e409780e0855ec83a4fd3fbabdb1d970248"
+    "3045022100da45f00521af6a2043cac54c34e24a6efc997920860f5c702fa51642ad1371ec022019204df9069cd0"
+    "1f38bf417ca761f5c49685adf47257625b42d1e440d5273f550121031b84c5567b126440995d3ed5aaba0565d71e"
+    "1834604819ff9c17f5e9d5dd078f96000000"
+);
+const FUNDING_WTXID: [u8; 32] =
+    hex!("cbf1b066e883db422966466b3aaec3e0efeaac9334e89cde0fc0ff56c0dd34f7");
+const MULTISIG_SPEND_TX: &[u8] = &hex!(
+    "02000000000101f91fe9df6da2e0462da1e1d22d24ce4cf52bd88e48bba8b2f52e2cc55ee492d20000000000fdffffff"
+    "01d07e010000000000160014417d4be90d35363267b8f2afafc9531111c41ae40400483045022100e54d76f6580457"
+    "7a494570e1e74f69d4e75073bcd6882d06159a349c876cbe580220710c723b383774054707c454957c86af125fae2b"
+    "2fe494adf124eb10b2f1e40301483045022100a7557929206eda500894dfb802b4f42b7143ee1725572697c7aca6a3"
+    "886d5767022053965c55819be793f149157df6c2e63d45ebe61cb2830171f6be62ffae43b0f301695221031b84c556"
+    "7b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f21024d4b6cd1361032ca9bd2aeb9d900aa4d45"
+    "d9ead80ac9423374c451a7254d07662102531fe6068134503d2723133227c867ac8fa6c83c537e9a44c3c5bdbdcb1f"
+    "e33753ae00000000"
+);
+
+fn multisig_script() -> Vec<u8> {
+    create_multisig_script(KEYS.iter().map(pubkey).collect(), 2, false).unwrap()
+}
+
+// The funding transaction through the serialized-parts API, with its txid
+fn funding_transaction() -> (Vec<u8>, [u8; 32], Utxo) {
+    let prevout = Utxo {
+        script_pubkey: p2wpkh_program(&pubkey(&KEYS[0])),
+        amount: FUNDING_AMOUNT,
+    };
+    let multisig_output = Utxo {
+        script_pubkey: get_p2wsh_program(&multisig_script(), Some(0)),
+        amount: FUNDING_AMOUNT - 1_000,
+    };
+    let inputs = vec![input_from_utxo(&FUNDING_TXID, 1, SEQUENCE_RBF)];
+    let outputs = vec![output_from_options(
+        &multisig_output.script_pubkey,
+        multisig_output.amount,
+    )];
+
+    let mut unsigned_tx = Transaction::new(
+        vec![TxIn::new(Outpoint {
+            txid: FUNDING_TXID,
+            index: 1,
+        })],
+        vec![multisig_output.clone()],
+    );
+    unsigned_tx.locktime = LOCKTIME;
+    let commitment_hash = get_commitment_hash(
+        &unsigned_tx,
+        0,
+        &get_p2wpkh_scriptcode(&prevout),
+        prevout.amount,
+        ALL,
+    );
+    let witness = get_p2wpkh_witness(&KEYS[0], commitment_hash, ALL);
+
+    let serialized = assemble_transaction(inputs.clone(), outputs.clone(), vec![witness], LOCKTIME);
+    let txid = get_txid(inputs, outputs, LOCKTIME);
+
+    // the parts API and the Transaction type agree
+    let tx = Transaction::parse(&serialized).unwrap();
+    assert_eq!(tx.locktime, LOCKTIME);
+    assert_eq!(tx.txid(), txid);
+    verify_transaction(&tx, &[prevout]).unwrap();
+    (serialized, txid, multisig_output)
+}
+
+#[test]
+fn regtest_funding_transaction() {
+    let (serialized, txid, _) = funding_transaction();
+    assert_eq!(serialized, FUNDING_TX);
+    let tx = Transaction::parse(&serialized).unwrap();
+    assert_eq!(tx.serialize(), serialized);
+    assert_eq!(tx.wtxid(), FUNDING_WTXID);
+    assert_eq!(tx.wtxid(), reversed(hash256(&serialized)));
+    assert_ne!(tx.wtxid(), txid);
+}
+
+#[test]
+fn regtest_multisig_spend() {
+    let (_, funding_txid, multisig_output) = funding_transaction();
+    let script = multisig_script();
+    let mut outpoint_txid = funding_txid;
+    outpoint_txid.reverse();
+
+    let output = Utxo {
+        script_pubkey: p2wpkh_program(&pubkey(&KEYS[2])),
+        amount: multisig_output.amount - 1_000,
+    };
+    let unsigned_tx = Transaction::new(
+        vec![TxIn::new(Outpoint {
+            txid: outpoint_txid,
+            index: 0,
+        })],
+        vec![output.clone()],
+    );
+    let commitment_hash =
+        get_commitment_hash(&unsigned_tx, 0, &script, multisig_output.amount, ALL);
+    // key 3 isn't needed once 1 and 2 have signed
+    let witness = get_p2wsh_witness(KEYS.iter().collect(), commitment_hash, &script, ALL).unwrap();
+    let items = parse_witness(&witness).unwrap();
+    assert_eq!(items.len(), 4);
+    assert!(items[0].is_empty());
+    assert_eq!(items[3], script);
+
+    let serialized = assemble_transaction(
+        vec![input_from_utxo(&outpoint_txid, 0, SEQUENCE_RBF)],
+        vec![output_from_options(&output.script_pubkey, output.amount)],
+        vec![witness],
+        0,
+    );
+    assert_eq!(serialized, MULTISIG_SPEND_TX);
+    let tx = Transaction::parse(&serialized).unwrap();
+    verify_transaction(&tx, &[multisig_output]).unwrap();
+
+    // one signature short
+    assert!(get_p2wsh_witness(vec![&KEYS[0]], vec![0; 32], &script, ALL).is_err());
+}
+
+// BIP143 "Native P2WSH", second example: both inputs sign SINGLE|ANYONECANPAY.
+// The first input's OP_CODESEPARATOR sits in an unexecuted branch, so its
+// scriptCode is the whole witness script.
+const P2WSH_ACP_UNSIGNED_TX: &[u8] = &hex!(
+    "0100000002e9b542c5176808107ff1df906f46bb1f2583b16112b95ee5380665ba7fcfc0010000000000ffffffff"
+    "80e68831516392fcd100d186b3c2c7b95c80b53c77e77c35ba03a66b429a2a1b0000000000ffffffff0280969800"
+    "000000001976a914de4b231626ef508c9a74a8517e6783c0546d6b2888ac80969800000000001976a9146648a8cd"
+    "4531e1ec47f35916de8e259237294d1e88ac00000000"
+);
+
+#[test]
+fn bip143_p2wsh_single_anyonecanpay() {
+    let mut tx = Transaction::parse(P2WSH_ACP_UNSIGNED_TX).unwrap();
+    let amount = 16_777_215;
+    let sighash_type = SighashType(SIGHASH_SINGLE | SIGHASH_ANYONECANPAY);
+    let witness_script =
+        hex!("0063ab68210392972e2eb617b2388771abe27235fd5ac44af8e61693261550447a4c3e39da98ac");
+    assert_eq!(
+        get_p2wsh_program(&witness_script, Some(0)),
+        hex!("0020ba468eea561b26301e4cf69fa34bde4ad60c81e70f059f045ca9a79931004a4d")
+    );
+    let expected = hex!("e9071e75e25b8a1e298a72f0d2e9f4f95a0f5cdf86a533cda597eb402ed13b3a");
+    assert_eq!(
+        get_commitment_hash(&tx, 0, &witness_script, amount, sighash_type),
+        expected
+    );
+
+    // the other input and the other output can change without breaking the signature
+    tx.inputs.truncate(1);
+    tx.outputs.truncate(1);
+    assert_eq!(
+        get_commitment_hash(&tx, 0, &witness_script, amount, sighash_type),
+        expected
+    );
+    // but they are committed to without ANYONECANPAY
+    let single = SighashType(SIGHASH_SINGLE);
+    assert_ne!(
+        get_commitment_hash(&tx, 0, &witness_script, amount, single),
+        get_commitment_hash(
+            &Transaction::parse(P2WSH_ACP_UNSIGNED_TX).unwrap(),
+            0,
+            &witness_script,
+            amount,
+            single
+        )
+    );
+}
+
+// BIP143 "P2SH-P2WSH": a 6-of-6 multisig whose keys sign with each of the six
+// sighash types in turn
+const P2SH_P2WSH_UNSIGNED_TX: &[u8] = &hex!(
+    "010000000136641869ca081e70f394c6948e8af409e18b619df2ed74aa106c1ca29787b96e0100000000ffffffff"
+    "0200e9a435000000001976a914389ffce9cd9ae88dcc0631e88a821ffdbe9bfe2688acc0832f05000000001976a9"
+    "147480a33f950689af511e6e84c138dbbd3c3ee41588ac00000000"
+);
+
+// sighash type, private key, sighash and signature
+type SighashCase = (u32, [u8; 32], [u8; 32], &'static [u8]);
+
+#[test]
+fn bip143_p2sh_p2wsh_sighash_types() {
+    let tx = Transaction::parse(P2SH_P2WSH_UNSIGNED_TX).unwrap();
+    let amount = 987_654_321;
+    let witness_script = hex!(
+        "56210307b8ae49ac90a048e9b53357a2354b3334e9c8bee813ecb98e99a7e07e8c3ba32103b28f0c28bfab5455"
+        "4ae8c658ac5c3e0ce6e79ad336331f78c428dd43eea8449b21034b8113d703413d57761b8b9781957b8c0ac1dfe6"
+        "9f492580ca4195f50376ba4a21033400f6afecb833092a9a21cfdf1ed1376e58c5d1f47de74683123987e967a8f4"
+        "2103a6d48b1131e94ba04d9737d61acdaa1322008af9602b3b14862c07a1789aac162102d8b661b0b3302ee2f162"
+        "b09e07a55ad5dfbe673a9f01d9f0c19617681024306b56ae"
+    );
+    assert_eq!(
+        get_p2wsh_program(&witness_script, Some(0)),
+        hex!("0020a16b5755f7f6f96dbd65f5f0d6ab9418b89af4b1f14a1bb8a09062c35f0dcb54")
+    );
+
+    let cases: [SighashCase; 6] = [
+        (
+            SIGHASH_ALL,
+            hex!("730fff80e1413068a05b57d6a58261f07551163369787f349438ea38ca80fac6"),
+            hex!("185c0be5263dce5b4bb50a047973c1b6272bfbd0103a89444597dc40b248ee7c"),
+            &hex!(
+                "304402206ac44d672dac41f9b00e28f4df20c52eeb087207e8d758d76d92c6fab3b73e2b0220367750dbbe"
+                "19290069cba53d096f44530e4f98acaa594810388cf7409a1870ce01"
+            ),
+        ),
+        (
+            SIGHASH_NONE,
+            hex!("11fa3d25a17cbc22b29c44a484ba552b5a53149d106d3d853e22fdd05a2d8bb3"),
+            hex!("e9733bc60ea13c95c6527066bb975a2ff29a925e80aa14c213f686cbae5d2f36"),
+            &hex!(
+                "3044022068c7946a43232757cbdf9176f009a928e1cd9a1a8c212f15c1e11ac9f2925d9002205b75f937ff"
+                "2f9f3c1246e547e54f62e027f64eefa2695578cc6432cdabce271502"
+            ),
+        ),
+        (
+            SIGHASH_SINGLE,
+            hex!("77bf4141a87d55bdd7f3cd0bdccf6e9e642935fec45f2f30047be7b799120661"),
+            hex!("1e1f1c303dc025bd664acb72e583e933fae4cff9148bf78c157d1e8f78530aea"),
+            &hex!(
+                "3044022059ebf56d98010a932cf8ecfec54c48e6139ed6adb0728c09cbe1e4fa0915302e022007cd986c8f"
+                "a870ff5d2b3a89139c9fe7e499259875357e20fcbb15571c76795403"
+            ),
+        ),
+        (
+            SIGHASH_ALL | SIGHASH_ANYONECANPAY,
+            hex!("14af36970f5025ea3e8b5542c0f8ebe7763e674838d08808896b63c3351ffe49"),
+            hex!("2a67f03e63a6a422125878b40b82da593be8d4efaafe88ee528af6e5a9955c6e"),
+            &hex!(
+                "3045022100fbefd94bd0a488d50b79102b5dad4ab6ced30c4069f1eaa69a4b5a763414067e02203156c6a5"
+                "c9cf88f91265f5a942e96213afae16d83321c8b31bb342142a14d16381"
+            ),
+        ),
+        (
+            SIGHASH_NONE | SIGHASH_ANYONECANPAY,
+            hex!("fe9a95c19eef81dde2b95c1284ef39be497d128e2aa46916fb02d552485e0323"),
+            hex!("781ba15f3779d5542ce8ecb5c18716733a5ee42a6f51488ec96154934e2c890a"),
+            &hex!(
+                "3045022100a5263ea0553ba89221984bd7f0b13613db16e7a70c549a86de0cc0444141a407022005c360ef"
+                "0ae5a5d4f9f2f87a56c1546cc8268cab08c73501d6b3be2e1e1a8a0882"
+            ),
+        ),
+        (
+            SIGHASH_SINGLE | SIGHASH_ANYONECANPAY,
+            hex!("428a7aee9f0c2af0cd19af3cf1c78149951ea528726989b2e83e4778d2c3f890"),
+            hex!("511e8e52ed574121fc1b654970395502128263f62662e076dc6baf05c2e6a99b"),
+            &hex!(
+                "30440220525406a1482936d5a21888260dc165497a90a15669636d8edca6b9fe490d309c022032af0c646a"
+                "34a44d1f4576bf6a4a74b67940f8faa84c7df9abe12a01a11e2b4783"
+            ),
+        ),
+    ];
+    for (sighash_type, privkey, expected_hash, expected_signature) in cases {
+        let sighash_type = SighashType(sighash_type);
+        let commitment_hash = get_commitment_hash(&tx, 0, &witness_script, amount, sighash_type);
+        assert_eq!(commitment_hash, expected_hash, "{}", sighash_type);
+        assert_eq!(
+            sign(&privkey, commitment_hash, sighash_type),
+            expected_signature,
+            "{}",
+            sighash_type
+        );
+    }
+}
+
+#[test]
+fn legacy_single_without_matching_output_signs_one() {
+    let mut tx = Transaction::parse(P2WSH_ACP_UNSIGNED_TX).unwrap();
+    tx.outputs.truncate(1);
+    let mut one = [0u8; 32];
+    one[0] = 1;
+    for sighash_type in [SIGHASH_SINGLE, SIGHASH_SINGLE | SIGHASH_ANYONECANPAY] {
+        // input 1 has no output at its index to commit to
+        assert_eq!(
+            get_legacy_commitment_hash(&tx, 1, &P2PK_SCRIPT, SighashType(sighash_type)),
+            one
+        );
+    }
+    assert_ne!(
+        get_legacy_commitment_hash(&tx, 0, &P2PK_SCRIPT, SighashType(SIGHASH_SINGLE)),
+        one
+    );
+}
+
+// BIP341 wallet-test-vectors.json "scriptPubKey": internal key, leaf scripts,
+// output key and address, and the control block of each leaf
+type ScriptPubKeyCase = (&'static str, &'static [&'static str], &'static str, &'static str, &'static [&'static str]);
+const TAPROOT_SCRIPT_PUBKEYS: [ScriptPubKeyCase; 4] = [
+    (
+        "d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d",
+        &[],
+        "53a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343",
+        "bc1p2wsldez5mud2yam29q22wgfh9439spgduvct83k3pm50fcxa5dps59h4z5",
+        &[],
+    ),
+    (
+        "187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27",
+        &["20d85a959b0290bf19bb89ed43c916be835475d013da4b362117393e25a48229b8ac"],
+        "147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3",
+        "bc1pz37fc4cn9ah8anwm4xqqhvxygjf9rjf2resrw8h8w4tmvcs0863sa2e586",
+        &["c1187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27"],
+    ),
+    (
+        "93478e9488f956df2396be2ce6c5cced75f900dfa18e7dabd2428aae78451820",
+        &["20b617298552a72ade070667e86ca63b8f5789a9fe8731ef91202a91c9f3459007ac"],
+        "e4d810fd50586274face62b8a807eb9719cef49c04177cc6b76a9a4251d5450e",
+        "bc1punvppl2stp38f7kwv2u2spltjuvuaayuqsthe34hd2dyy5w4g58qqfuag5",
+        &["c093478e9488f956df2396be2ce6c5cced75f900dfa18e7dabd2428aae78451820"],
+    ),
+    (
+        "f9f400803e683727b14f463836e1e78e1c64417638aa066919291a225f0e8dd8",
+        &[
+            "2044b178d64c32c4a05cc4f4d1407268f764c940d20ce97abfd44db5c3592b72fdac",
+            "07546170726f6f74",
+        ],
+        "77e30a5522dd9f894c3f8b8bd4c4b2cf82ca7da8a3ea6a239655c39c050ab220",
+        "bc1pwl3s54fzmk0cjnpl3w9af39je7pv5ldg504x5guk2hpecpg2kgsqaqstjq",
+        &[
+            "c1f9f400803e683727b14f463836e1e78e1c64417638aa066919291a225f0e8dd8\
+             2cb2b90daa543b544161530c925f285b06196940d6085ca9474d41dc3822c5cb",
+            "c1f9f400803e683727b14f463836e1e78e1c64417638aa066919291a225f0e8dd8\
+             64512fecdb5afa04f98839b50e6f0cb7b1e539bf6f205f67934083cdcc3c8d89",
+        ],
+    ),
+];
+
+#[test]
+fn bip341_script_pubkeys() {
+    for (internal_key, leaves, output_key, address, control_blocks) in TAPROOT_SCRIPT_PUBKEYS {
+        let internal_key = hex::decode(internal_key).unwrap();
+        let tree = TapTree {
+            leaves: leaves.iter().map(|leaf| hex::decode(leaf).unwrap()).collect(),
+        };
+        let program = get_p2tr_program(&internal_key, tree.merkle_root()).unwrap();
+        assert_eq!(hex::encode(&program[2..]), output_key);
+        assert_eq!(encode_segwit_address("bc", 1, &program[2..]), address);
+        for (index, control_block) in control_blocks.iter().enumerate() {
+            assert_eq!(
+                hex::encode(tree.control_block(&internal_key, index).unwrap()),
+                *control_block
+            );
+        }
+    }
+}
+
+// BIP341 wallet-test-vectors.json "keyPathSpending": nine inputs, seven of
+// them taproot key path spends with different sighash types
+const TAPROOT_UNSIGNED_TX: &[u8] = &hex!(
+    "02000000097de20cbff686da83a54981d2b9bab3586f4ca7e48f57f5b55963115f3b334e9c010000000000000000"
+    "d7b7cab57b1393ace2d064f4d4a2cb8af6def61273e127517d44759b6dafdd990000000000fffffffff8e1f58338"
+    "4333689228c5d28eac13366be082dc57441760d957275419a418420000000000fffffffff0689180aa63b30cb162"
+    "a73c6d2a38b7eeda2a83ece74310fda0843ad604853b0100000000feffffffaa5202bdf6d8ccd2ee0f0202afbbb7"
+    "461d9264a25e5bfd3c5a52ee1239e0ba6c0000000000feffffff956149bdc66faa968eb2be2d2faa29718acbfe39"
+    "41215893a2a3446d32acd050000000000000000000e664b9773b88c09c32cb70a2a3e4da0ced63b7ba3b22f84853"
+    "1bbb1d5d5f4c94010000000000000000e9aa6b8e6c9de67619e6a3924ae25696bb7b694bb677a632a74ef7eadfd4"
+    "eabf0000000000ffffffffa778eb6a263dc090464cd125c466b5a99667720b1c110468831d058aa1b82af1010000"
+    "0000ffffffff0200ca9a3b000000001976a91406afd46bcdfd22ef94ac122aa11f241244a37ecc88ac807840cb00"
+    "00000020ac9a87f5594be208f8532db38cff670c450ed2fea8fcdefcc9a663f78bab962b0065cd1d"
+);
+const TAPROOT_UTXOS_SPENT: [(&str, u64); 9] = [
+    ("512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343", 420_000_000),
+    ("5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3", 462_000_000),
+    ("76a914751e76e8199196d454941c45d1b3a323f1433bd688ac", 294_000_000),
+    ("5120e4d810fd50586274face62b8a807eb9719cef49c04177cc6b76a9a4251d5450e", 504_000_000),
+    ("512091b64d5324723a985170e4dc5a0f84c041804f2cd12660fa5dec09fc21783605", 630_000_000),
+    ("00147dd65592d0ab2fe0d0257d571abf032cd9db93dc", 378_000_000),
+    ("512075169f4001aa68f15bbed28b218df1d0a62cbbcf1188c6665110c293c907b831", 672_000_000),
+    ("5120712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5", 546_000_000),
+    ("512077e30a5522dd9f894c3f8b8bd4c4b2cf82ca7da8a3ea6a239655c39c050ab220", 588_000_000),
+];
+// input index, internal private key, merkle root, sighash type, sighash and
+// the key path witness
+type KeyPathSpend = (usize, &'static str, Option<&'static str>, u32, &'static str, &'static str);
+const TAPROOT_KEY_PATH_SPENDS: [KeyPathSpend; 7] = [
+    (
+        0,
+        "6b973d88838f27366ed61c9ad6367663045cb456e28335c109e30717ae0c6baa",
+        None,
+        0x03,
+        "2514a6272f85cfa0f45eb907fcb0d121b808ed37c6ea160a5a9046ed5526d555",
+        "ed7c1647cb97379e76892be0cacff57ec4a7102aa24296ca39af7541246d8ff1\
+         4d38958d4cc1e2e478e4d4a764bbfd835b16d4e314b72937b29833060b87276c03",
+    ),
+    (
+        1,
+        "1e4da49f6aaf4e5cd175fe08a32bb5cb4863d963921255f33d3bc31e1343907f",
+        Some("5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21"),
+        0x83,
+        "325a644af47e8a5a2591cda0ab0723978537318f10e6a63d4eed783b96a71a4d",
+        "052aedffc554b41f52b521071793a6b88d6dbca9dba94cf34c83696de0c1ec35\
+         ca9c5ed4ab28059bd606a4f3a657eec0bb96661d42921b5f50a95ad33675b54f83",
+    ),
+    (
+        3,
+        "d3c7af07da2d54f7a7735d3d0fc4f0a73164db638b2f2f7c43f711f6d4aa7e64",
+        Some("c525714a7f49c28aedbbba78c005931a81c234b2f6c99a73e4d06082adc8bf2b"),
+        0x01,
+        "bf013ea93474aa67815b1b6cc441d23b64fa310911d991e713cd34c7f5d46669",
+        "ff45f742a876139946a149ab4d9185574b98dc919d2eb6754f8abaa59d18b025\
+         637a3aa043b91817739554f4ed2026cf8022dbd83e351ce1fabc272841d2510a01",
+    ),
+    (
+        4,
+        "f36bb07a11e469ce941d16b63b11b9b9120a84d9d87cff2c84a8d4affb438f4e",
+        Some("ccbd66c6f7e8fdab47b3a486f59d28262be857f30d4773f2d5ea47f7761ce0e2"),
+        0x00,
+        "4f900a0bae3f1446fd48490c2958b5a023228f01661cda3496a11da502a7f7ef",
+        "b4010dd48a617db09926f729e79c33ae0b4e94b79f04a1ae93ede6315eb3669d\
+         e185a17d2b0ac9ee09fd4c64b678a0b61a0a86fa888a273c8511be83bfd6810f",
+    ),
+    (
+        6,
+        "415cfe9c15d9cea27d8104d5517c06e9de48e2f986b695e4f5ffebf230e725d8",
+        Some("2f6b2c5397b6d68ca18e09a3f05161668ffe93a988582d55c6f07bd5b3329def"),
+        0x02,
+        "15f25c298eb5cdc7eb1d638dd2d45c97c4c59dcaec6679cfc16ad84f30876b85",
+        "a3785919a2ce3c4ce26f298c3d51619bc474ae24014bcdd31328cd8cfbab2eff\
+         3395fa0a16fe5f486d12f22a9cedded5ae74feb4bbe5351346508c5405bcfee002",
+    ),
+    (
+        7,
+        "c7b0e81f0a9a0b0499e112279d718cca98e79a12e2f137c72ae5b213aad0d103",
+        Some("6c2dc106ab816b73f9d07e3cd1ef2c8c1256f519748e0813e4edd2405d277bef"),
+        0x82,
+        "cd292de50313804dabe4685e83f923d2969577191a3e1d2882220dca88cbeb10",
+        "ea0c6ba90763c2d3a296ad82ba45881abb4f426b3f87af162dd24d5109edc1cd\
+         d11915095ba47c3a9963dc1e6c432939872bc49212fe34c632cd3ab9fed429c482",
+    ),
+    (
+        8,
+        "77863416be0d0665e517e1c375fd6f75839544eca553675ef7fdf4949518ebaa",
+        Some("ab179431c28d3b68fb798957faf5497d69c883c6fb1e1cd9f81483d87bac90cc"),
+        0x81,
+        "cccb739eca6c13a8a89e6e5cd317ffe55669bbda23f2fd37b0f18755e008edd2",
+        "bbc9584a11074e83bc8c6759ec55401f0ae7b03ef290c3139814f545b58a9f81\
+         27258000874f44bc46db7646322107d4d86aec8e73b8719a61fff761d75b5dd981",
+    ),
+];
+
+#[test]
+fn bip341_key_path_spending() {
+    let mut tx = Transaction::parse(TAPROOT_UNSIGNED_TX).unwrap();
+    let prevouts: Vec<Utxo> = TAPROOT_UTXOS_SPENT
+        .iter()
+        .map(|(script_pubkey, amount)| Utxo {
+            script_pubkey: hex::decode(script_pubkey).unwrap(),
+            amount: *amount,
+        })
+        .collect();
+
+    for (index, privkey, merkle_root, sighash_type, sighash, witness) in TAPROOT_KEY_PATH_SPENDS {
+        let privkey: [u8; 32] = hex::decode(privkey).unwrap().try_into().unwrap();
+        let merkle_root = merkle_root.map(|root| hex::decode(root).unwrap().try_into().unwrap());
+        let sighash_type = SighashType(sighash_type);
+
+        // the tweaked key is the one the output pays to
+        assert_eq!(
+            get_p2tr_program(&pubkey(&privkey), merkle_root).unwrap(),
+            prevouts[index].script_pubkey
+        );
+        let commitment_hash =
+            get_taproot_commitment_hash(&tx, index, &prevouts, sighash_type, None).unwrap();
+        assert_eq!(hex::encode(&commitment_hash), sighash, "input {}", index);
+        let signature = sign_schnorr(&privkey, merkle_root, &commitment_hash, sighash_type).unwrap();
+        assert_eq!(hex::encode(&signature), witness, "input {}", index);
+        tx.inputs[index].witness = vec![signature];
+    }
+
+    // the interpreter accepts every key path spend
+    for (index, ..) in TAPROOT_KEY_PATH_SPENDS {
+        verify_input(&tx, index, &prevouts).unwrap();
+    }
+}
+
+// A hashlock leaf "OP_SHA256 <hash> OP_EQUALVERIFY <key> OP_CHECKSIG" spent
+// through its control block: the hash is a 32 byte push but not a key, so the
+// witness is only the signature and the preimage
+#[test]
+fn taproot_hashlock_leaf_spend() {
+    let mut wallet_state = WalletState {
+        taproot_keys: vec![[0x41; 32].to_vec(), [0x42; 32].to_vec()],
+        ..Default::default()
+    };
+    let preimage = b"taproot hashlock".to_vec();
+    let hash = hex::encode(Sha256::digest(&preimage));
+    let policy = create_taproot_policy(
+        &wallet_state,
+        "hashlock",
+        "@0",
+        &[
+            format!("OP_SHA256 {} OP_EQUALVERIFY @1 OP_CHECKSIG", hash),
+            "@0 OP_CHECKSIG".to_string(),
+        ],
+    )
+    .unwrap();
+    let program = address_to_script(&policy.address).unwrap();
+    wallet_state
+        .watched_utxos
+        .insert(("cd".repeat(32), 0), (program.clone(), 0.001));
+    let destination =
+        encode_segwit_address(SIGNET_HRP, 0, &hash160(&pubkey(&KEYS[0])));
+
+    let sighash_type = SighashType(SIGHASH_DEFAULT);
+    let tx = spend_taproot_script(
+        &wallet_state,
+        &policy,
+        0,
+        &destination,
+        50_000,
+        1_000,
+        sighash_type,
+        std::slice::from_ref(&preimage),
+        None,
+    )
+    .unwrap();
+    let tree = taproot_policy_tree(&policy).unwrap();
+    let witness = &tx.inputs[0].witness;
+    assert_eq!(witness.len(), 4);
+    assert_eq!(witness[0].len(), 64);
+    assert_eq!(witness[1], preimage);
+    assert_eq!(witness[2], tree.leaves[0]);
+    assert_eq!(
+        witness[3],
+        tree.control_block(&hex::decode(&policy.internal_key).unwrap(), 0).unwrap()
+    );
+    // change back to the policy
+    assert_eq!(tx.outputs[1].script_pubkey, program);
+
+    // the wrong preimage fails verification
+    assert!(spend_taproot_script(
+        &wallet_state,
+        &policy,
+        0,
+        &destination,
+        50_000,
+        1_000,
+        sighash_type,
+        &[b"wrong".to_vec()],
+        None,
+    )
+    .is_err());
+}
+
+// Consolidation pays our first unused change address, not a receive one, and
+// the change coin it creates can be spent again
+#[test]
+fn consolidate_to_change_chain() {
+    let receive_key = [0x51; 32];
+    let change_keys = [[0x61; 32], [0x62; 32]];
+    let mut wallet_state = WalletState {
+        private_keys: vec![receive_key.to_vec()],
+        public_keys: vec![pubkey(&receive_key)],
+        witness_programs: vec![p2wpkh_program(&pubkey(&receive_key))],
+        change_keys: change_keys.iter().map(|key| key.to_vec()).collect(),
+        change_programs: change_keys.iter().map(|key| p2wpkh_program(&pubkey(key))).collect(),
+        ..Default::default()
+    };
+    wallet_state.used_programs.insert(wallet_state.change_programs[0].clone());
+    for (index, amount) in [0.0004, 0.0006].into_iter().enumerate() {
+        let program = wallet_state.witness_programs[0].clone();
+        wallet_state.utxos.insert(("ab".repeat(32), index as u32), (program, amount));
+    }
+
+    let (tx, fee) = consolidate(&wallet_state, 50, 2.0, Vec::new(), Vec::new()).unwrap();
+    assert_eq!(tx.outputs.len(), 1);
+    assert_eq!(tx.outputs[0].script_pubkey, wallet_state.change_programs[1]);
+    assert_eq!(tx.outputs[0].amount, 100_000 - fee);
+    assert!(wallet_state.is_ours(&tx.outputs[0].script_pubkey));
+
+    let mut change = WalletState {
+        change_keys: wallet_state.change_keys.clone(),
+        change_programs: wallet_state.change_programs.clone(),
+        ..Default::default()
+    };
+    change.utxos.insert(
+        (hex::encode(tx.txid()), 0),
+        (tx.outputs[0].script_pubkey.clone(), tx.outputs[0].amount as f64 / 100_000_000.0),
+    );
+    let destination = p2wpkh_program(&pubkey(&KEYS[0]));
+    let (sweep_tx, _) = sweep(&change, destination, 2.0, Vec::new(), Vec::new()).unwrap();
+    assert_eq!(sweep_tx.inputs[0].witness[1], pubkey(&change_keys[1]));
+}
+
+// BIP322 test vectors: the key of bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l
+// (WIF L3VFeEujGtevx9w18HD1fhRbCH67Az2dpCymeRE1SoPK6XQtaN2k). Its signet
+// address has the same program, so the same to_sign transaction.
+const BIP322_KEY: [u8; 32] = hex!("bb051cd0dda0246f33c5a9e133ebd8e7bc02a92af6c41adc131ccd7826c5b004");
+const BIP322_VECTORS: [(&str, [u8; 32], &str); 2] = [
+    (
+        "",
+        hex!("c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"),
+        "AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=",
+    ),
+    (
+        "Hello World",
+        hex!("f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"),
+        "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=",
+    ),
+];
+
+#[test]
+fn bip322_simple_signatures() {
+    let (_, program) =
+        decode_segwit_address("bc", "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l").unwrap();
+    assert_eq!(program, hash160(&pubkey(&BIP322_KEY)));
+    let address = encode_segwit_address(SIGNET_HRP, 0, &program);
+    let wallet_state = WalletState {
+        private_keys: vec![BIP322_KEY.to_vec()],
+        public_keys: vec![pubkey(&BIP322_KEY)],
+        witness_programs: vec![p2wpkh_program(&pubkey(&BIP322_KEY))],
+        ..Default::default()
+    };
+
+    for (message, message_hash, signature) in BIP322_VECTORS {
+        assert_eq!(tagged_hash("BIP0322-signed-message", message.as_bytes()), message_hash);
+        verify_message(&address, signature, message).unwrap();
+        // the vectors grind for a low R value, which our signer doesn't, so
+        // check that our (RFC6979) signature verifies instead of comparing bytes
+        let ours = sign_message(&wallet_state, &address, message, true).unwrap();
+        verify_message(&address, &ours, message).unwrap();
+    }
+    // a signature for the other message doesn't verify
+    assert!(verify_message(&address, BIP322_VECTORS[0].2, BIP322_VECTORS[1].0).is_err());
+}
+
+// Legacy (BIP137) signatures for each single key address type, change addresses
+// included, verify with the header of that type, and only for the message
+// they sign
+#[test]
+fn bip137_round_trip() {
+    let key = [0x71; 32];
+    let wallet_state = WalletState {
+        private_keys: vec![key.to_vec()],
+        public_keys: vec![pubkey(&key)],
+        witness_programs: vec![p2wpkh_program(&pubkey(&key))],
+        nested_keys: vec![key.to_vec()],
+        nested_scripts: vec![get_p2sh_p2wpkh_script(&pubkey(&key))],
+        legacy_keys: vec![key.to_vec()],
+        legacy_scripts: vec![get_p2pkh_script(&pubkey(&key))],
+        change_keys: vec![[0x73; 32].to_vec()],
+        change_programs: vec![p2wpkh_program(&pubkey(&[0x73; 32]))],
+        ..Default::default()
+    };
+    let cases = [
+        (&wallet_state.legacy_scripts[0], 31),
+        (&wallet_state.nested_scripts[0], 35),
+        (&wallet_state.witness_programs[0], 39),
+        (&wallet_state.change_programs[0], 39),
+    ];
+    for (script, header) in cases {
+        let address = script_to_address(script).unwrap();
+        let signature = sign_message(&wallet_state, &address, "Hello World", false).unwrap();
+        let data = BASE64.decode(&signature).unwrap();
+        assert_eq!(data.len(), 65);
+        assert!((header..header + 4).contains(&data[0]));
+        verify_message(&address, &signature, "Hello World").unwrap();
+        assert!(verify_message(&address, &signature, "Hello World!").is_err());
+    }
+
+    // the signature of one address doesn't verify for another key's address
+    let other = script_to_address(&p2wpkh_program(&pubkey(&[0x72; 32]))).unwrap();
+    let address = script_to_address(&wallet_state.witness_programs[0]).unwrap();
+    let signature = sign_message(&wallet_state, &address, "Hello World", false).unwrap();
+    assert!(verify_message(&other, &signature, "Hello World").is_err());
+}
+
+// The BIP49 test vector master key, from the "abandon ... about" mnemonic, and
+// the first two addresses of each receive path and of the BIP84 change path
+const ABANDON_TPRV: &str = "tprv8ZgxMBicQKsPe5YMU9gHen4Ez3ApihUfykaqUorj9t6FDqy3nP6eoXiAo2ssvpAjoLroQxHqr3R5nE3a5dU3DHTjTgJDd7zrbniJr6nrCzd";
+const ABANDON_LEGACY: [&str; 2] = ["mkpZhYtJu2r87Js3pDiWJDmPte2NRZ8bJV", "mzpbWabUQm1w8ijuJnAof5eiSTep27deVH"];
+const ABANDON_NESTED: [&str; 2] = ["2Mww8dCYPUpKHofjgcXcBCEGmniw9CoaiD2", "2N55m54k8vr95ggehfUcNkdbUuQvaqG2GxK"];
+const ABANDON_NATIVE: [&str; 2] = [
+    "tb1q6rz28mcfaxtmd6v789l9rrlrusdprr9pqcpvkl",
+    "tb1qd7spv5q28348xl4myc8zmh983w5jx32cjhkn97",
+];
+const ABANDON_CHANGE: [&str; 2] = [
+    "tb1q9u62588spffmq4dzjxsr5l297znf3z6j5p2688",
+    "tb1qkwgskuzmmwwvqajnyr7yp9hgvh5y45kg8wvdmd",
+];
+const ABANDON_TAPROOT: [&str; 2] = [
+    "tb1p8wpt9v4frpf3tkn0srd97pksgsxc5hs52lafxwru9kgeephvs7rqlqt9zj",
+    "tb1p90h6z3p36n9hrzy7580h5l429uwchyg8uc9sz4jwzhdtuhqdl5eqmpwq6n",
+];
+
+#[test]
+fn derived_addresses() {
+    let wallet_state = derive_wallet_state(ABANDON_TPRV, 2).unwrap();
+    assert_eq!(wallet_state.fingerprint, hex!("73c5da0a"));
+    let cases = [
+        (&wallet_state.legacy_scripts, ABANDON_LEGACY),
+        (&wallet_state.nested_scripts, ABANDON_NESTED),
+        (&wallet_state.witness_programs, ABANDON_NATIVE),
+        (&wallet_state.change_programs, ABANDON_CHANGE),
+        (&wallet_state.taproot_programs, ABANDON_TAPROOT),
+    ];
+    for (scripts, addresses) in cases {
+        let derived: Vec<String> = scripts.iter().map(|script| script_to_address(script).unwrap()).collect();
+        assert_eq!(derived, addresses);
+    }
+    // BIP49: the private key of m/49'/1'/0'/0/0
+    assert_eq!(
+        wallet_state.nested_keys[0],
+        hex!("c9bdb49cfbaedca21c4b1f3a7803c34636b1d7dc55a717132443fc3f4c5867e8")
+    );
+}
+
+// P2PKH inputs signed with the keys derived above, over the legacy sighash,
+// checked against transactions signed by an independent RFC6979 implementation
+const P2PKH_SIGNED_TX: &[u8] = &hex!(
+    "0200000002abababababababababababababababababababababababababababababababab000000006b483045022100c3d5"
+    "8122fe076fd21a6c6cdb8e903dff4fb31a76985f91b92cb70338b06f360b022062877219cde2e7368bd8ffdf0287bde53a"
+    "712fa87dc29c42f09b59181759a8c0012102a7451395735369f2ecdfc829c0f774e88ef1303dfe5b2f04dbaab30a535dfd"
+    "d6fdffffffcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd010000006a47304402202cd0"
+    "9b962a0ec64d5760914d74c141662e620a70a2c9ec37c655cae875c8037e022058a7aeeab4588998cc6130ac4e064f0b9b"
+    "359e1bf3bff0f7ddc787069caf3930012103589ae7c835ce76e23cf8feb32f1adf4a7f2ba0ed2ad70801802b0bcd70e99c"
+    "1cfdffffff0130e6020000000000160014d0c4a3ef09e997b6e99e397e518fe3e41a118ca100000000"
+);
+// a P2PKH input next to a P2WPKH one: the legacy input gets an empty witness
+const P2PKH_P2WPKH_SIGNED_TX: &[u8] = &hex!(
+    "02000000000102abababababababababababababababababababababababababababababababab000000006a4730440220"
+    "4ccf26b8c223d1a72f0683dd5f248d591453ea963184addc9e1f8d1c827d0621022015ff43accabd1638fd2875d745772f"
+    "c0343c4edfc9cd05944ce2278e4670ca7d012102a7451395735369f2ecdfc829c0f774e88ef1303dfe5b2f04dbaab30a53"
+    "5dfdd6fdffffffcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd0100000000fdffffff01"
+    "e0220200000000001600143a2d4145a4f098523b3e8127f1da87cfc55b8e790002483045022100819f6bffd1f75b488f02"
+    "66a335a80be7519a43bf104a849db002f63c252ac3c80220706ac7e70c0b772fcd3415d20a9a6ef0ca9499004ac2464a3d"
+    "7a57270b1fde5c012102e7ab2537b5d49e970309aae06e9e49f36ce1c9febbd44ec8e0d1cca0b4f9c31900000000"
+);
+
+#[test]
+fn p2pkh_signed_transactions() {
+    let wallet_state = derive_wallet_state(ABANDON_TPRV, 2).unwrap();
+    let inputs = || {
+        vec![
+            TxIn::new(Outpoint { txid: [0xab; 32], index: 0 }),
+            TxIn::new(Outpoint { txid: [0xcd; 32], index: 1 }),
+        ]
+    };
+
+    let prevouts = [
+        Utxo {
+            script_pubkey: wallet_state.legacy_scripts[0].clone(),
+            amount: 100_000,
+        },
+        Utxo {
+            script_pubkey: wallet_state.legacy_scripts[1].clone(),
+            amount: 100_000,
+        },
+    ];
+    let output = Utxo {
+        script_pubkey: wallet_state.witness_programs[0].clone(),
+        amount: 190_000,
+    };
+    let mut tx = Transaction::new(inputs(), vec![output]);
+    sign_wallet_inputs(&wallet_state, &mut tx, &prevouts).unwrap();
+    assert_eq!(tx.serialize(), P2PKH_SIGNED_TX);
+    verify_transaction(&tx, &prevouts).unwrap();
+
+    let prevouts = [
+        prevouts[0].clone(),
+        Utxo {
+            script_pubkey: wallet_state.witness_programs[0].clone(),
+            amount: 50_000,
+        },
+    ];
+    // paid to the P2WPKH program of the first BIP44 key
+    let legacy_key: [u8; 32] = wallet_state.legacy_keys[0].clone().try_into().unwrap();
+    let output = Utxo {
+        script_pubkey: p2wpkh_program(&pubkey(&legacy_key)),
+        amount: 140_000,
+    };
+    let mut tx = Transaction::new(inputs(), vec![output]);
+    sign_wallet_inputs(&wallet_state, &mut tx, &prevouts).unwrap();
+    assert!(tx.inputs[0].witness.is_empty());
+    assert_eq!(tx.serialize(), P2PKH_P2WPKH_SIGNED_TX);
+    verify_transaction(&tx, &prevouts).unwrap();
+}
+
+// Miniscript outputs, their scripts and descriptor checksums computed
+// independently. KEYS are @0..@2, as BIP84 keys for P2WSH and BIP86 keys
+// (their x-only halves) for P2TR.
+const OR_OLDER_SCRIPT: &[u8] = &hex!(
+    "21031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078fac7364029000b26921024d4b6cd1361032ca9bd2aeb9d900aa4d45d9ead80ac9423374c451a7254d0766ac68"
+);
+const OR_OLDER_DESCRIPTOR: &str = "wsh(or_d(pk(031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f),and_v(v:older(144),pk(024d4b6cd1361032ca9bd2aeb9d900aa4d45d9ead80ac9423374c451a7254d0766))))#7ha4927w";
+const OR_OLDER_TIME_DESCRIPTOR: &str = "wsh(or_d(pk(031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f),and_v(v:older(4194305),pk(024d4b6cd1361032ca9bd2aeb9d900aa4d45d9ead80ac9423374c451a7254d0766))))#rdfl8ed2";
+const MULTI_SCRIPT: &[u8] = &hex!(
+    "5221031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f21024d4b6cd1361032ca9bd2aeb9d900aa4d45d9ead80ac9423374c451a7254d07662102531fe6068134503d2723133227c867ac8fa6c83c537e9a44c3c5bdbdcb1fe33753ae"
+);
+const MULTI_DESCRIPTOR: &str = "wsh(multi(2,031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f,024d4b6cd1361032ca9bd2aeb9d900aa4d45d9ead80ac9423374c451a7254d0766,02531fe6068134503d2723133227c867ac8fa6c83c537e9a44c3c5bdbdcb1fe337))#vyljh2e2";
+const THRESH_OLDER_SCRIPT: &[u8] = &hex!(
+    "21031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078fac6b21024d4b6cd1361032ca9bd2aeb9d900aa4d45d9ead80ac9423374c451a7254d0766ac6c937c630067029000b29268935287"
+);
+const THRESH_OLDER_DESCRIPTOR: &str = "wsh(thresh(2,pk(031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f),a:pk(024d4b6cd1361032ca9bd2aeb9d900aa4d45d9ead80ac9423374c451a7254d0766),sln:older(144)))#pr20lvpl";
+const MULTI_A_LEAF: &[u8] = &hex!(
+    "201b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078fac204d4b6cd1361032ca9bd2aeb9d900aa4d45d9ead80ac9423374c451a7254d0766ba20531fe6068134503d2723133227c867ac8fa6c83c537e9a44c3c5bdbdcb1fe337ba529c"
+);
+const MULTI_A_DESCRIPTOR: &str = "tr(50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0,multi_a(2,1b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f,4d4b6cd1361032ca9bd2aeb9d900aa4d45d9ead80ac9423374c451a7254d0766,531fe6068134503d2723133227c867ac8fa6c83c537e9a44c3c5bdbdcb1fe337))#5mx76nrs";
+const MULTI_A_ADDRESS: &str = "tb1pve2xle3txxee4nn70n7gmth49twg8prx4u2zymxcz9y9nld60hqquzcvnc";
+
+// A wallet holding `keys` of KEYS, watching a 100,000 sat coin of `address`
+fn miniscript_wallet(keys: &[usize], address: Option<&str>) -> WalletState {
+    let mut wallet_state = WalletState {
+        private_keys: keys.iter().map(|&i| KEYS[i].to_vec()).collect(),
+        public_keys: keys.iter().map(|&i| pubkey(&KEYS[i])).collect(),
+        taproot_keys: keys.iter().map(|&i| KEYS[i].to_vec()).collect(),
+        ..Default::default()
+    };
+    if let Some(address) = address {
+        let program = address_to_script(address).unwrap();
+        wallet_state.watched_utxos.insert(("ef".repeat(32), 0), (program, 0.001));
+    }
+    wallet_state
+}
+
+fn miniscript_payment() -> Vec<Utxo> {
+    vec![Utxo {
+        script_pubkey: p2wpkh_program(&pubkey(&KEYS[0])),
+        amount: 50_000,
+    }]
+}
+
+fn miniscript_prevouts(address: &str) -> Vec<Utxo> {
+    vec![Utxo {
+        script_pubkey: address_to_script(address).unwrap(),
+        amount: 100_000,
+    }]
+}
+
+// or(pk(A),and(pk(B),older(N))): A alone, or B once the coin is N blocks (or
+// N - 4194304 512 second units) old
+#[test]
+fn miniscript_or_older() {
+    let wallet_state = miniscript_wallet(&[0, 1, 2], None);
+    let policy = create_miniscript_policy(&wallet_state, "vault", "or(pk(@0),and(pk(@1),older(144)))", false).unwrap();
+    assert_eq!(policy.descriptor, OR_OLDER_DESCRIPTOR);
+    assert_eq!(policy.address, "tb1qk2f9alw304q03muz0deu0hfcdes5f399xml04dj37k7cclxj6fuq20wht4");
+    assert_eq!(compile_policy(&policy).unwrap()[0].encode(), OR_OLDER_SCRIPT);
+    let prevouts = miniscript_prevouts(&policy.address);
+
+    // A signs, B's branch left unused
+    let owner = miniscript_wallet(&[0], Some(&policy.address));
+    let tx = spend_miniscript(&owner, &policy, miniscript_payment(), 1_000, &[], None, None).unwrap();
+    assert_eq!(tx.inputs[0].witness.len(), 2);
+    assert_eq!(tx.inputs[0].sequence, SEQUENCE_RBF);
+    verify_transaction(&tx, &prevouts).unwrap();
+
+    // B needs the timelock: A's part is dissatisfied with an empty signature
+    let heir = miniscript_wallet(&[1], Some(&policy.address));
+    assert!(spend_miniscript(&heir, &policy, miniscript_payment(), 1_000, &[], None, None).is_err());
+    assert!(spend_miniscript(&heir, &policy, miniscript_payment(), 1_000, &[], None, Some(143)).is_err());
+    let tx = spend_miniscript(&heir, &policy, miniscript_payment(), 1_000, &[], None, Some(144)).unwrap();
+    let witness = &tx.inputs[0].witness;
+    assert_eq!(witness.len(), 3);
+    assert!(witness[1].is_empty());
+    assert_eq!(witness[2], OR_OLDER_SCRIPT);
+    assert_eq!(tx.inputs[0].sequence, 144);
+    verify_transaction(&tx, &prevouts).unwrap();
+
+    // a time-based older() takes the sequence with the same type flag
+    let policy =
+        create_miniscript_policy(&wallet_state, "vault", "or(pk(@0),and(pk(@1),older(4194305)))", false).unwrap();
+    assert_eq!(policy.descriptor, OR_OLDER_TIME_DESCRIPTOR);
+    let heir = miniscript_wallet(&[1], Some(&policy.address));
+    assert!(spend_miniscript(&heir, &policy, miniscript_payment(), 1_000, &[], None, Some(144)).is_err());
+    let tx = spend_miniscript(&heir, &policy, miniscript_payment(), 1_000, &[], None, Some(4194305)).unwrap();
+    assert_eq!(tx.inputs[0].sequence, 4194305);
+    verify_transaction(&tx, &miniscript_prevouts(&policy.address)).unwrap();
+}
+
+// thresh() of keys is multi(); with other parts, the parts that can't give 0
+// or 1 on their own are wrapped, older(N) becoming sln:older(N)
+#[test]
+fn miniscript_thresh() {
+    let wallet_state = miniscript_wallet(&[0, 1, 2], None);
+    let policy =
+        create_miniscript_policy(&wallet_state, "multi", "thresh(2,pk(@0),pk(@1),pk(@2))", false).unwrap();
+    assert_eq!(policy.descriptor, MULTI_DESCRIPTOR);
+    assert_eq!(policy.address, "tb1qvwhgrayg38e7hn8f9lpelwuluclyp9uquzz4ajp6flflh27mrktsqkvvsc");
+    assert_eq!(compile_policy(&policy).unwrap()[0].encode(), MULTI_SCRIPT);
+    let signers = miniscript_wallet(&[0, 2], Some(&policy.address));
+    let tx = spend_miniscript(&signers, &policy, miniscript_payment(), 1_000, &[], None, None).unwrap();
+    // CHECKMULTISIG's extra item, two signatures and the script
+    assert_eq!(tx.inputs[0].witness.len(), 4);
+    assert!(tx.inputs[0].witness[0].is_empty());
+    verify_transaction(&tx, &miniscript_prevouts(&policy.address)).unwrap();
+    let one = miniscript_wallet(&[1], Some(&policy.address));
+    assert!(spend_miniscript(&one, &policy, miniscript_payment(), 1_000, &[], None, None).is_err());
+
+    let policy =
+        create_miniscript_policy(&wallet_state, "recovery", "thresh(2,pk(@0),pk(@1),older(144))", false).unwrap();
+    assert_eq!(policy.descriptor, THRESH_OLDER_DESCRIPTOR);
+    assert_eq!(policy.address, "tb1qyy60pm5gcfv2lz6zga0ljqzdn9ddxfrh63rhu9vdu07snzz4ydjqsxgyl7");
+    assert_eq!(compile_policy(&policy).unwrap()[0].encode(), THRESH_OLDER_SCRIPT);
+    let prevouts = miniscript_prevouts(&policy.address);
+    let both = miniscript_wallet(&[0, 1], Some(&policy.address));
+    let tx = spend_miniscript(&both, &policy, miniscript_payment(), 1_000, &[], None, None).unwrap();
+    verify_transaction(&tx, &prevouts).unwrap();
+    let one = miniscript_wallet(&[1], Some(&policy.address));
+    assert!(spend_miniscript(&one, &policy, miniscript_payment(), 1_000, &[], None, None).is_err());
+    let tx = spend_miniscript(&one, &policy, miniscript_payment(), 1_000, &[], None, Some(144)).unwrap();
+    verify_transaction(&tx, &prevouts).unwrap();
+}
+
+// A multi_a() leaf under the unspendable internal key, spent through its
+// control block
+#[test]
+fn miniscript_tapscript_leaf_spend() {
+    let wallet_state = miniscript_wallet(&[0, 1, 2], None);
+    let policy = create_miniscript_policy(&wallet_state, "multi_a", "thresh(2,pk(@0),pk(@1),pk(@2))", true).unwrap();
+    assert_eq!(policy.descriptor, MULTI_A_DESCRIPTOR);
+    assert_eq!(policy.address, MULTI_A_ADDRESS);
+    assert_eq!(compile_policy(&policy).unwrap()[0].encode(), MULTI_A_LEAF);
+
+    let signers = miniscript_wallet(&[1, 2], Some(&policy.address));
+    let tx = spend_miniscript(&signers, &policy, miniscript_payment(), 1_000, &[], None, None).unwrap();
+    // a signature or an empty item per key, last key on top, the leaf and
+    // the control block
+    let witness = &tx.inputs[0].witness;
+    assert_eq!(witness.len(), 5);
+    assert_eq!((witness[0].len(), witness[1].len(), witness[2].len()), (64, 64, 0));
+    assert_eq!(witness[3], MULTI_A_LEAF);
+    assert_eq!(witness[4].len(), 33);
+    verify_transaction(&tx, &miniscript_prevouts(&policy.address)).unwrap();
+    let one = miniscript_wallet(&[0], Some(&policy.address));
+    assert!(spend_miniscript(&one, &policy, miniscript_payment(), 1_000, &[], None, None).is_err());
+}
+
+// Transactions that left the mempool: mined ones are kept, as the chain scan
+// may not reach them, dropped ones are forgotten and free their inputs
+#[test]
+fn prune_left_mempool() {
+    let program = p2wpkh_program(&pubkey(&KEYS[0]));
+    let spend = |index: u8| {
+        let outpoint = Outpoint {
+            txid: [index; 32],
+            index: 0,
+        };
+        Transaction::new(
+            vec![TxIn::new(outpoint)],
+            vec![Utxo {
+                script_pubkey: program.clone(),
+                amount: 10_000,
+            }],
+        )
+    };
+    let txs = [spend(1), spend(2), spend(3), spend(4)];
+    let mut cache = WalletCache::default();
+    for tx in &txs {
+        cache.record_transaction(tx, &[], 500, |_| true);
+    }
+    let txid = |index: usize| hex::encode(txs[index].txid());
+    let exits = [MempoolExit::Confirmed, MempoolExit::Spent, MempoolExit::Dropped];
+    let mempool = [txid(3)].into_iter().collect();
+
+    let gone = cache
+        .prune_transactions(&mempool, |tx| {
+            let index = txs.iter().position(|known| known.txid() == tx.txid()).unwrap();
+            Ok::<_, ()>(exits[index])
+        })
+        .unwrap();
+    assert_eq!(gone, (0..3).map(|index| (txid(index), exits[index])).collect::<Vec<_>>());
+    assert_eq!(cache.transactions.len(), 3);
+    assert!(cache.wallet_transaction(&txid(0)).unwrap().confirmed);
+    assert!(!cache.wallet_transaction(&txid(3)).unwrap().confirmed);
+    // the confirmed and spent ones keep their inputs spent, only the
+    // confirmed one and the one still in the mempool keep their outputs
+    let spent_txids: Vec<String> = cache.spent.iter().map(|outpoint| outpoint[..64].to_string()).collect();
+    assert_eq!(spent_txids, ["01".repeat(32), "02".repeat(32), "04".repeat(32)]);
+    let unconfirmed: Vec<&String> = cache.unconfirmed.iter().map(|output| &output.txid).collect();
+    assert_eq!(unconfirmed, [&txid(0), &txid(3)]);
+
+    // settled transactions aren't checked again
+    let gone = cache.prune_transactions(&mempool, |_| Err(())).unwrap();
+    assert!(gone.is_empty());
+}
+
+// A replacement takes the spent outputs recorded with the original, as the
+// wallet no longer lists its inputs, and lowers the change-chain output
+// rather than a payment to one of our receive addresses
+#[test]
+fn bump_fee_from_cache() {
+    let change_key = [0x61; 32];
+    let wallet_state = WalletState {
+        private_keys: KEYS[..2].iter().map(|key| key.to_vec()).collect(),
+        public_keys: KEYS[..2].iter().map(pubkey).collect(),
+        witness_programs: KEYS[..2].iter().map(|key| p2wpkh_program(&pubkey(key))).collect(),
+        change_keys: vec![change_key.to_vec()],
+        change_programs: vec![p2wpkh_program(&pubkey(&change_key))],
+        ..Default::default()
+    };
+    let prevouts = [Utxo {
+        script_pubkey: wallet_state.witness_programs[0].clone(),
+        amount: 100_000,
+    }];
+    let mut original = Transaction::new(
+        vec![TxIn::new(Outpoint {
+            txid: [0x77; 32],
+            index: 0,
+        })],
+        vec![
+            Utxo {
+                script_pubkey: wallet_state.witness_programs[1].clone(),
+                amount: 30_000,
+            },
+            Utxo {
+                script_pubkey: wallet_state.change_programs[0].clone(),
+                amount: 69_800,
+            },
+        ],
+    );
+    sign_wallet_inputs(&wallet_state, &mut original, &prevouts).unwrap();
+    let mut cache = WalletCache::default();
+    cache.record_transaction(&original, &prevouts, 200, |script| wallet_state.is_ours(script));
+
+    let txid = hex::encode(original.txid());
+    let (tx, fee, original_fee) = bump_fee(&wallet_state, &cache, &txid, 5.0).unwrap();
+    assert_eq!(original_fee, 200);
+    assert_eq!(tx.outputs.len(), 2);
+    assert_eq!(tx.outputs[0].amount, 30_000);
+    assert_eq!(tx.outputs[1].amount, 100_000 - 30_000 - fee);
+    assert!(fee as f64 >= 5.0 * tx.vsize() as f64);
+    verify_transaction(&tx, &prevouts).unwrap();
+}
+
+// The smallest push for each length, on both sides of the PUSHDATA1 and
+// PUSHDATA2 boundaries
+#[test]
+fn push_encodings() {
+    for (len, prefix) in [
+        (0, vec![0x00]),
+        (75, vec![75]),
+        (76, vec![0x4c, 76]),
+        (255, vec![0x4c, 0xff]),
+        (256, vec![0x4d, 0x00, 0x01]),
+        (520, vec![0x4d, 0x08, 0x02]),
+    ] {
+        let data = vec![0xab; len];
+        let mut script = Vec::new();
+        push_data(&mut script, &data);
+        assert_eq!(script, [prefix, data].concat(), "push of {} bytes", len);
+    }
+}
+
+// OP_RETURN outputs: several pushes in one script, up to our 83 byte limit
+#[test]
+fn op_return_scripts() {
+    assert_eq!(parse_data_push("0xdeadbeef").unwrap(), hex!("deadbeef"));
+    assert_eq!(parse_data_push("hello").unwrap(), b"hello");
+    assert!(parse_data_push("0xnothex").is_err());
+
+    let script = op_return_script(&[b"hello".to_vec(), hex!("deadbeef").to_vec()]).unwrap();
+    assert_eq!(script, hex!("6a0568656c6c6f04deadbeef"));
+
+    // 80 bytes need PUSHDATA1 and fill the limit exactly, one more is refused
+    let script = op_return_script(&[vec![0x42; 80]]).unwrap();
+    assert_eq!(script.len(), MAX_OP_RETURN_SIZE);
+    assert_eq!(script[..3], [0x6a, 0x4c, 80]);
+    assert!(op_return_script(&[vec![0x42; 81]]).is_err());
+    // the limit counts every push's length byte
+    assert!(op_return_script(&[vec![0x42; 40], vec![0x42; 40]]).is_ok());
+    assert!(op_return_script(&[vec![0x42; 40], vec![0x42; 41]]).is_err());
+}
+
+// Payments send their change to our first unused change address, never back
+// to a receive address
+#[test]
+fn payment_change_address() {
+    let change_keys = [[0x61; 32], [0x62; 32]];
+    let mut wallet_state = WalletState {
+        private_keys: vec![KEYS[0].to_vec()],
+        public_keys: vec![pubkey(&KEYS[0])],
+        witness_programs: vec![p2wpkh_program(&pubkey(&KEYS[0]))],
+        change_keys: change_keys.iter().map(|key| key.to_vec()).collect(),
+        change_programs: change_keys.iter().map(|key| p2wpkh_program(&pubkey(key))).collect(),
+        ..Default::default()
+    };
+    wallet_state.used_programs.insert(wallet_state.change_programs[0].clone());
+    let program = wallet_state.witness_programs[0].clone();
+    wallet_state.utxos.insert(("ab".repeat(32), 0), (program, 0.001));
+
+    let payment = Utxo {
+        script_pubkey: p2wpkh_program(&pubkey(&KEYS[1])),
+        amount: 30_000,
+    };
+    let (tx, _, fee) = select_payment(&wallet_state, vec![payment], 2.0, Vec::new()).unwrap();
+    assert_eq!(tx.outputs.len(), 2);
+    assert_eq!(tx.outputs[1].script_pubkey, wallet_state.change_programs[1]);
+    assert_eq!(tx.outputs[1].amount, 100_000 - 30_000 - fee);
+}
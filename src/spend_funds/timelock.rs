@@ -0,0 +1,247 @@
+// Timelocked P2WSH outputs: "key A, or key B once a timelock has passed",
+// with the absolute (CLTV, BIP65) or relative (CSV, BIP112) lock checked by
+// the script and met by the spending transaction's nLockTime or nSequence
+use crate::balance::address::{encode_segwit_address, SIGNET_HRP};
+use crate::balance::balance::{btc_to_sats, WalletState};
+use crate::balance::cache::TimelockPolicy;
+use crate::spend_funds::interpreter::verify_transaction;
+use crate::spend_funds::policy::{check_policy, dust_threshold};
+use crate::spend_funds::script::{
+    push_data, push_int, OP_CHECKLOCKTIMEVERIFY, OP_CHECKSEQUENCEVERIFY, OP_CHECKSIG, OP_DROP, OP_ELSE, OP_ENDIF, OP_IF,
+};
+use crate::spend_funds::sighash::{get_commitment_hash, SighashType, SIGHASH_ALL};
+use crate::spend_funds::spend_multisig::parse_cosigner_key;
+use crate::spend_funds::spend_p2wpkh::{get_p2wsh_program, sign, SpendError};
+use crate::spend_funds::transaction::{Outpoint, Transaction, TxIn, Utxo, SEQUENCE_RBF};
+use serde::{Deserialize, Serialize};
+
+// nLockTime values from here on are unix times, not block heights
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+// When a timelocked branch becomes spendable
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Timelock {
+    // from this block height on (OP_CHECKLOCKTIMEVERIFY)
+    Height(u32),
+    // this many blocks after the output confirmed (OP_CHECKSEQUENCEVERIFY)
+    Blocks(u16),
+}
+
+impl Timelock {
+    pub fn new(height: Option<u32>, blocks: Option<u16>) -> Result<Timelock, SpendError> {
+        match (height, blocks) {
+            (Some(height), None) if height < LOCKTIME_THRESHOLD => Ok(Timelock::Height(height)),
+            (Some(height), None) => Err(SpendError::InvalidScript(format!("{} is not a block height", height))),
+            (None, Some(blocks)) => Ok(Timelock::Blocks(blocks)),
+            _ => Err(SpendError::InvalidScript("expected either a block height or a number of blocks".to_string())),
+        }
+    }
+
+    // <n> OP_CHECKLOCKTIMEVERIFY|OP_CHECKSEQUENCEVERIFY OP_DROP
+    pub fn push(self, script: &mut Vec<u8>) {
+        match self {
+            Timelock::Height(height) => {
+                push_int(script, height as i64);
+                script.push(OP_CHECKLOCKTIMEVERIFY);
+            }
+            Timelock::Blocks(blocks) => {
+                push_int(script, blocks as i64);
+                script.push(OP_CHECKSEQUENCEVERIFY);
+            }
+        }
+        script.push(OP_DROP);
+    }
+
+    // nLockTime of the spending transaction and nSequence of its inputs
+    pub fn locktime_and_sequence(self) -> (u32, u32) {
+        match self {
+            // a final sequence would disable nLockTime
+            Timelock::Height(height) => (height, SEQUENCE_RBF),
+            // BIP68: disable flag and type flag clear, a number of blocks
+            Timelock::Blocks(blocks) => (0, blocks as u32),
+        }
+    }
+}
+
+impl std::fmt::Display for Timelock {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Timelock::Height(height) => write!(f, "from block {}", height),
+            Timelock::Blocks(blocks) => write!(f, "{} blocks after confirmation", blocks),
+        }
+    }
+}
+
+// A compressed public key: "@<index>" for our own key, hex, or an extended public key
+pub fn resolve_pubkey(wallet_state: &WalletState, key: &str) -> Result<Vec<u8>, SpendError> {
+    if let Some(index) = key.strip_prefix('@') {
+        return index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| wallet_state.public_keys.get(index))
+            .cloned()
+            .ok_or(SpendError::InvalidScript(format!("no key {}", key)));
+    }
+    let pubkey = parse_cosigner_key(key)?;
+    if pubkey.len() != 33 {
+        return Err(SpendError::InvalidScript(format!("not a compressed public key: {}", key)));
+    }
+    Ok(pubkey)
+}
+
+// OP_IF <primary> OP_ELSE <lock> OP_CHECKLOCKTIMEVERIFY|OP_CHECKSEQUENCEVERIFY OP_DROP <recovery> OP_ENDIF OP_CHECKSIG
+pub fn timelock_script(primary_key: &[u8], recovery_key: &[u8], timelock: Timelock) -> Vec<u8> {
+    let mut script = vec![OP_IF];
+    push_data(&mut script, primary_key);
+    script.push(OP_ELSE);
+    timelock.push(&mut script);
+    push_data(&mut script, recovery_key);
+    script.push(OP_ENDIF);
+    script.push(OP_CHECKSIG);
+    script
+}
+
+pub fn create_timelock_policy(
+    wallet_state: &WalletState,
+    name: &str,
+    primary_key: &str,
+    recovery_key: &str,
+    timelock: Timelock,
+) -> Result<TimelockPolicy, SpendError> {
+    let primary_key = resolve_pubkey(wallet_state, primary_key)?;
+    let recovery_key = resolve_pubkey(wallet_state, recovery_key)?;
+    let script = timelock_script(&primary_key, &recovery_key, timelock);
+    let program = get_p2wsh_program(&script, Some(0));
+
+    Ok(TimelockPolicy {
+        name: name.to_string(),
+        primary_key: hex::encode(primary_key),
+        recovery_key: hex::encode(recovery_key),
+        timelock,
+        witness_script: hex::encode(&script),
+        address: encode_segwit_address(SIGNET_HRP, 0, &program[2..]),
+    })
+}
+
+// Spend the coins of the output `program` to `outputs`, change going back to
+// the same output. The transaction's nLockTime and its inputs' nSequence are
+// set to `locktime` and `sequence`, then `witness` gives the witness of each
+// input, from the transaction and the outputs its inputs spend.
+pub fn spend_script_output(
+    wallet_state: &WalletState,
+    program: &[u8],
+    mut outputs: Vec<Utxo>,
+    fee: u64,
+    locktime: u32,
+    sequence: u32,
+    witness: impl Fn(&Transaction, usize, &[Utxo]) -> Result<Vec<Vec<u8>>, SpendError>,
+) -> Result<Transaction, SpendError> {
+    // largest coins first
+    let mut coins: Vec<(&(String, u32), u64)> = wallet_state
+        .spendable_watched_utxos()
+        .filter(|(_, (script, _))| *script == program)
+        .map(|(outpoint, (_, value))| (outpoint, btc_to_sats(*value)))
+        .collect();
+    coins.sort_by_key(|coin| std::cmp::Reverse(coin.1));
+
+    let required = outputs.iter().map(|output| output.amount).sum::<u64>() + fee;
+    let mut inputs = Vec::new();
+    let mut prevouts = Vec::new();
+    let mut selected = 0;
+    for ((txid, vout), value) in coins {
+        if selected >= required {
+            break;
+        }
+        let outpoint: Outpoint = format!("{}:{}", txid, vout)
+            .parse()
+            .map_err(SpendError::InvalidTransaction)?;
+        let mut input = TxIn::new(outpoint);
+        input.sequence = sequence;
+        inputs.push(input);
+        prevouts.push(Utxo {
+            script_pubkey: program.to_vec(),
+            amount: value,
+        });
+        selected += value;
+    }
+    if selected < required {
+        return Err(SpendError::InsufficientFunds(format!(
+            "the output has {} sats, need {}",
+            selected, required
+        )));
+    }
+
+    // change below the dust threshold goes to the fee
+    if selected >= required + dust_threshold(program) {
+        outputs.push(Utxo {
+            script_pubkey: program.to_vec(),
+            amount: selected - required,
+        });
+    }
+    let mut tx = Transaction::new(inputs, outputs);
+    tx.locktime = locktime;
+
+    for index in 0..prevouts.len() {
+        tx.inputs[index].witness = witness(&tx, index, &prevouts)?;
+    }
+    verify_transaction(&tx, &prevouts)?;
+    check_policy(&tx, &prevouts, tx.weight() as u64)?;
+    Ok(tx)
+}
+
+// Spend the coins of the P2WSH output of `witness_script` to `outputs`, change
+// going back to the same output. Every input is signed (SIGHASH_ALL) with our
+// key `pubkey` and gets the witness `witness(signature)` followed by the script.
+// With a timelock the transaction's nLockTime and nSequence are set to meet it.
+pub fn spend_witness_script(
+    wallet_state: &WalletState,
+    witness_script: &[u8],
+    outputs: Vec<Utxo>,
+    fee: u64,
+    timelock: Option<Timelock>,
+    pubkey: &[u8],
+    witness: impl Fn(Vec<u8>) -> Vec<Vec<u8>>,
+) -> Result<Transaction, SpendError> {
+    let key_index = wallet_state
+        .public_keys
+        .iter()
+        .position(|key| key == pubkey)
+        .ok_or(SpendError::SigningError(format!("{} is not one of our keys", hex::encode(pubkey))))?;
+    let privkey: [u8; 32] = wallet_state.private_keys[key_index]
+        .clone()
+        .try_into()
+        .expect("private key length is not 32 bytes");
+    let program = get_p2wsh_program(witness_script, Some(0));
+    let (locktime, sequence) = timelock.map_or((0, SEQUENCE_RBF), Timelock::locktime_and_sequence);
+
+    let sighash_type = SighashType(SIGHASH_ALL);
+    spend_script_output(wallet_state, &program, outputs, fee, locktime, sequence, |tx, index, prevouts| {
+        let commitment_hash = get_commitment_hash(tx, index, witness_script, prevouts[index].amount, sighash_type);
+        let mut stack = witness(sign(&privkey, commitment_hash, sighash_type));
+        stack.push(witness_script.to_vec());
+        Ok(stack)
+    })
+}
+
+// Spend a timelock policy's coins with the primary key, or with the recovery
+// key once its timelock has passed
+pub fn spend_timelock(
+    wallet_state: &WalletState,
+    policy: &TimelockPolicy,
+    outputs: Vec<Utxo>,
+    fee: u64,
+    recovery: bool,
+) -> Result<Transaction, SpendError> {
+    let script = hex::decode(&policy.witness_script)
+        .map_err(|_| SpendError::InvalidScript(format!("bad witness script for {}", policy.name)))?;
+    let (key, branch, timelock) = if recovery {
+        (&policy.recovery_key, Vec::new(), Some(policy.timelock))
+    } else {
+        (&policy.primary_key, vec![1], None)
+    };
+    let key = hex::decode(key).map_err(|_| SpendError::InvalidScript(format!("bad key in {}", policy.name)))?;
+    spend_witness_script(wallet_state, &script, outputs, fee, timelock, &key, |signature| {
+        vec![signature, branch.clone()]
+    })
+}
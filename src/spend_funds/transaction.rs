@@ -0,0 +1,337 @@
+use crate::spend_funds::spend_p2wpkh::SpendError;
+use sha2::{Digest, Sha256};
+
+// Sequence disabling locktime and relative locktime, no RBF signalling
+pub const SEQUENCE_FINAL: u32 = 0xFFFFFFFF;
+// Default sequence for inputs: opt-in replace-by-fee (BIP125), no relative locktime
+pub const SEQUENCE_RBF: u32 = 0xFFFFFFFD;
+
+#[derive(Clone)]
+pub struct Utxo {
+    pub script_pubkey: Vec<u8>,
+    pub amount: u64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Outpoint {
+    // txid in internal (little-endian) byte order, as serialized on the wire
+    pub txid: [u8; 32],
+    pub index: u32,
+}
+
+#[derive(Clone)]
+pub struct TxIn {
+    pub previous_output: Outpoint,
+    pub script_sig: Vec<u8>,
+    pub sequence: u32,
+    pub witness: Vec<Vec<u8>>,
+}
+
+// A transaction, signed or not
+#[derive(Clone)]
+pub struct Transaction {
+    pub version: u32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<Utxo>,
+    pub locktime: u32,
+}
+
+impl Outpoint {
+    // txid as shown by bitcoin-cli and block explorers
+    pub fn txid_hex(&self) -> String {
+        let mut txid = self.txid;
+        txid.reverse();
+        hex::encode(txid)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(36);
+        data.extend_from_slice(&self.txid);
+        data.extend(self.index.to_le_bytes());
+        data
+    }
+}
+
+// "<txid>:<vout>" with the txid in display order
+impl std::str::FromStr for Outpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (txid, vout) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <txid>:<vout>, got {}", s))?;
+        let mut txid: [u8; 32] = hex::decode(txid)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| format!("invalid txid: {}", txid))?;
+        txid.reverse();
+        let index = vout.parse().map_err(|_| format!("invalid vout: {}", vout))?;
+        Ok(Outpoint { txid, index })
+    }
+}
+
+impl Utxo {
+    // amount || compact size script length || script
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(self.amount.to_le_bytes());
+        data.extend(compact_size(self.script_pubkey.len() as u64));
+        data.extend_from_slice(&self.script_pubkey);
+        data
+    }
+}
+
+impl TxIn {
+    // an input spending `previous_output` with an empty scriptSig and the default sequence
+    pub fn new(previous_output: Outpoint) -> Self {
+        TxIn {
+            previous_output,
+            script_sig: Vec::new(),
+            sequence: SEQUENCE_RBF,
+            witness: Vec::new(),
+        }
+    }
+
+    // BIP125: any input below 0xfffffffe makes the transaction replaceable
+    pub fn signals_rbf(&self) -> bool {
+        self.sequence < 0xFFFFFFFE
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = self.previous_output.serialize();
+        data.extend(compact_size(self.script_sig.len() as u64));
+        data.extend_from_slice(&self.script_sig);
+        data.extend(self.sequence.to_le_bytes());
+        data
+    }
+}
+
+impl Transaction {
+    // version 2 transaction with locktime 0, which is what all our spends use
+    pub fn new(inputs: Vec<TxIn>, outputs: Vec<Utxo>) -> Self {
+        Transaction {
+            version: 2,
+            inputs,
+            outputs,
+            locktime: 0,
+        }
+    }
+
+    fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    // Serialization without marker, flag and witnesses, as hashed for the txid
+    pub fn serialize_without_witness(&self) -> Vec<u8> {
+        self.serialize_with(false)
+    }
+
+    // Network serialization, using the segwit format when any input has a witness
+    pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with(self.has_witness())
+    }
+
+    fn serialize_with(&self, include_witness: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(self.version.to_le_bytes());
+        if include_witness {
+            data.push(0x00); // marker
+            data.push(0x01); // flag
+        }
+        data.extend(compact_size(self.inputs.len() as u64));
+        for input in &self.inputs {
+            data.extend(input.serialize());
+        }
+        data.extend(compact_size(self.outputs.len() as u64));
+        for output in &self.outputs {
+            data.extend(output.serialize());
+        }
+        if include_witness {
+            for input in &self.inputs {
+                data.extend(serialize_witness(&input.witness));
+            }
+        }
+        data.extend(self.locktime.to_le_bytes());
+        data
+    }
+
+    // txid in display (big-endian) byte order
+    pub fn txid(&self) -> [u8; 32] {
+        let mut txid: [u8; 32] = hash256(&self.serialize_without_witness()).try_into().unwrap();
+        txid.reverse();
+        txid
+    }
+
+    // wtxid in display byte order, hashing the witnesses too (equal to the txid without them)
+    pub fn wtxid(&self) -> [u8; 32] {
+        let mut wtxid: [u8; 32] = hash256(&self.serialize()).try_into().unwrap();
+        wtxid.reverse();
+        wtxid
+    }
+
+    // Size in bytes of the network serialization
+    pub fn size(&self) -> usize {
+        self.serialize().len()
+    }
+
+    // BIP141 weight: non-witness bytes count 4 times, witness bytes once
+    pub fn weight(&self) -> usize {
+        self.serialize_without_witness().len() * 3 + self.size()
+    }
+
+    // virtual size, weight / 4 rounded up, which fee rates are quoted in
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(4)
+    }
+
+    // Parse a transaction in either legacy or segwit serialization
+    pub fn parse(bytes: &[u8]) -> Result<Transaction, SpendError> {
+        let mut reader = Reader { bytes, position: 0 };
+        let version = reader.read_u32()?;
+
+        // a zero input count followed by flag 1 marks the segwit format
+        let mut segwit = false;
+        if reader.bytes.get(reader.position) == Some(&0x00)
+            && reader.bytes.get(reader.position + 1) == Some(&0x01)
+        {
+            segwit = true;
+            reader.position += 2;
+        }
+
+        let input_count = reader.read_compact_size()?;
+        let mut inputs = Vec::new();
+        for _ in 0..input_count {
+            let txid = reader.read_bytes(32)?.try_into().unwrap();
+            let index = reader.read_u32()?;
+            let script_len = reader.read_compact_size()?;
+            let script_sig = reader.read_bytes(script_len)?.to_vec();
+            let sequence = reader.read_u32()?;
+            inputs.push(TxIn {
+                previous_output: Outpoint { txid, index },
+                script_sig,
+                sequence,
+                witness: Vec::new(),
+            });
+        }
+
+        let output_count = reader.read_compact_size()?;
+        let mut outputs = Vec::new();
+        for _ in 0..output_count {
+            let amount = u64::from_le_bytes(reader.read_bytes(8)?.try_into().unwrap());
+            let script_len = reader.read_compact_size()?;
+            let script_pubkey = reader.read_bytes(script_len)?.to_vec();
+            outputs.push(Utxo {
+                script_pubkey,
+                amount,
+            });
+        }
+
+        if segwit {
+            for input in inputs.iter_mut() {
+                let item_count = reader.read_compact_size()?;
+                for _ in 0..item_count {
+                    let item_len = reader.read_compact_size()?;
+                    input.witness.push(reader.read_bytes(item_len)?.to_vec());
+                }
+            }
+        }
+
+        let locktime = reader.read_u32()?;
+        if reader.position != bytes.len() {
+            return Err(SpendError::InvalidTransaction(
+                "trailing data after locktime".to_string(),
+            ));
+        }
+
+        Ok(Transaction {
+            version,
+            inputs,
+            outputs,
+            locktime,
+        })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], SpendError> {
+        let end = self.position.checked_add(len).filter(|end| *end <= self.bytes.len());
+        let end = end.ok_or(SpendError::InvalidTransaction(
+            "unexpected end of data".to_string(),
+        ))?;
+        let data = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(data)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, SpendError> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_compact_size(&mut self) -> Result<usize, SpendError> {
+        let n = match self.read_bytes(1)?[0] {
+            0xFD => u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()) as u64,
+            0xFE => u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()) as u64,
+            0xFF => u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()),
+            n => n as u64,
+        };
+        Ok(n as usize)
+    }
+}
+
+// item count followed by each length-prefixed stack item
+pub fn serialize_witness(stack: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = compact_size(stack.len() as u64);
+    for item in stack {
+        data.extend(compact_size(item.len() as u64));
+        data.extend_from_slice(item);
+    }
+    data
+}
+
+// Inverse of serialize_witness, for a witness stack on its own
+pub fn parse_witness(bytes: &[u8]) -> Result<Vec<Vec<u8>>, SpendError> {
+    let mut reader = Reader { bytes, position: 0 };
+    let item_count = reader.read_compact_size()?;
+    let mut stack = Vec::new();
+    for _ in 0..item_count {
+        let item_len = reader.read_compact_size()?;
+        stack.push(reader.read_bytes(item_len)?.to_vec());
+    }
+    if reader.position != bytes.len() {
+        return Err(SpendError::InvalidTransaction("trailing data after witness".to_string()));
+    }
+    Ok(stack)
+}
+
+// Bitcoin's variable length integer used for counts and script lengths
+pub fn compact_size(n: u64) -> Vec<u8> {
+    match n {
+        0..=0xFC => vec![n as u8],
+        0xFD..=0xFFFF => {
+            let mut data = vec![0xFD];
+            data.extend((n as u16).to_le_bytes());
+            data
+        }
+        0x10000..=0xFFFFFFFF => {
+            let mut data = vec![0xFE];
+            data.extend((n as u32).to_le_bytes());
+            data
+        }
+        _ => {
+            let mut data = vec![0xFF];
+            data.extend(n.to_le_bytes());
+            data
+        }
+    }
+}
+
+// this function helps in performing SHA256 double hashing
+pub fn hash256(data: &[u8]) -> Vec<u8> {
+    let first_hash = Sha256::digest(data);
+    Sha256::digest(first_hash).to_vec()
+}
@@ -0,0 +1,159 @@
+// The external signer protocol end to end, against the mock_signer binary:
+// enumerate, getdescriptors and signtx over stdin, HWI style errors, and the
+// signed PSBT combined with another signer's
+use rust::balance::balance::{derive_wallet_state, get_p2wpkh_program, WalletState};
+use rust::balance::cache::WalletCache;
+use rust::psbt::psbt::Psbt;
+use rust::psbt::roles::{create_psbt, extract_transaction, finalize_psbt, sign_psbt, update_psbt};
+use rust::psbt::signer::{ExternalSigner, Signer, SoftwareSigner};
+use rust::spend_funds::transaction::{Outpoint, Utxo};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+const MOCK_SIGNER: &str = env!("CARGO_BIN_EXE_mock_signer");
+// the mock's keys, BIP32 test vector 1
+const MASTER_KEY: &str =
+    "tprv8ZgxMBicQKsPeDgjzdC36fs6bMjGApWDNLR9erAXMs5skhMv36j9MV5ecvfavji5khqjWaWSFhN3YcCUUdiKH6isR4Pwy3U5y5egddBr16m";
+const FINGERPRINT: [u8; 4] = [0x34, 0x42, 0x19, 0x3e];
+
+const MOCK_COIN: Outpoint = Outpoint {
+    txid: [0xaa; 32],
+    index: 0,
+};
+const OTHER_COIN: Outpoint = Outpoint {
+    txid: [0xbb; 32],
+    index: 1,
+};
+const CHANGE_COIN: Outpoint = Outpoint {
+    txid: [0xcc; 32],
+    index: 2,
+};
+
+fn mock_wallet() -> WalletState {
+    let mut wallet_state = derive_wallet_state(MASTER_KEY, 20).unwrap();
+    let program = wallet_state.witness_programs[3].clone();
+    wallet_state
+        .utxos
+        .insert((MOCK_COIN.txid_hex(), MOCK_COIN.index), (program, 0.001));
+    let change = wallet_state.change_programs[5].clone();
+    wallet_state
+        .utxos
+        .insert((CHANGE_COIN.txid_hex(), CHANGE_COIN.index), (change, 0.0002));
+    wallet_state
+}
+
+// A wallet with a single P2WPKH key holding OTHER_COIN
+fn other_wallet() -> WalletState {
+    let privkey = [0x22; 32];
+    let secret_key = SecretKey::from_slice(&privkey).unwrap();
+    let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key).serialize().to_vec();
+    let program = get_p2wpkh_program(&pubkey);
+    let mut wallet_state = WalletState::default();
+    wallet_state
+        .utxos
+        .insert((OTHER_COIN.txid_hex(), OTHER_COIN.index), (program.clone(), 0.0005));
+    wallet_state.private_keys.push(privkey.to_vec());
+    wallet_state.public_keys.push(pubkey);
+    wallet_state.witness_programs.push(program);
+    wallet_state
+}
+
+#[test]
+fn enumerate_and_connect() {
+    let devices = ExternalSigner::enumerate(MOCK_SIGNER).unwrap();
+    assert_eq!(devices, vec![(FINGERPRINT, "mock".to_string())]);
+
+    assert_eq!(ExternalSigner::connect(MOCK_SIGNER, None).unwrap().fingerprint(), FINGERPRINT);
+    assert_eq!(
+        ExternalSigner::connect(MOCK_SIGNER, Some("3442193e")).unwrap().fingerprint(),
+        FINGERPRINT
+    );
+    assert!(ExternalSigner::connect(MOCK_SIGNER, Some("00000000")).is_err());
+    assert!(ExternalSigner::enumerate("/nonexistent/signer").is_err());
+}
+
+#[test]
+fn descriptors() {
+    let signer = ExternalSigner::connect(MOCK_SIGNER, None).unwrap();
+    let descriptors = signer.descriptors(0).unwrap();
+    assert_eq!(
+        descriptors.receive[0],
+        "wpkh([3442193e/84h/1h/0h]tpubDDNRbZGvdA33cgpY5uy2mmphT7sK4uciRjcQScSd64S5KRyZDxHcPuzs24or84Hywugb2JbEEt2jWH8fduiN9cmZzkSj8sSSx6txXkhXyZs/0/*)#0s0dqh6s"
+    );
+    assert_eq!(
+        descriptors.internal[0],
+        "wpkh([3442193e/84h/1h/0h]tpubDDNRbZGvdA33cgpY5uy2mmphT7sK4uciRjcQScSd64S5KRyZDxHcPuzs24or84Hywugb2JbEEt2jWH8fduiN9cmZzkSj8sSSx6txXkhXyZs/1/*)#7y2vaz2g"
+    );
+
+    // the same as the wallet's own signer with the same master key
+    let wallet_state = mock_wallet();
+    let ours = SoftwareSigner::new(&wallet_state).descriptors(0).unwrap();
+    assert_eq!(descriptors.receive, ours.receive);
+    assert_eq!(descriptors.internal, ours.internal);
+
+    // {"error": ...} answers come back as errors
+    let error = signer.descriptors(1).err().unwrap().to_string();
+    assert!(error.contains("only derives account 0"), "{}", error);
+}
+
+#[test]
+fn sign_and_combine() {
+    let mock = mock_wallet();
+    let other = other_wallet();
+    let destination = Utxo {
+        script_pubkey: get_p2wpkh_program(&[0x02; 33]),
+        amount: 149_000,
+    };
+    let psbt = create_psbt(
+        &mock,
+        &WalletCache::default(),
+        &[MOCK_COIN, OTHER_COIN],
+        vec![destination],
+        2,
+    )
+    .unwrap();
+
+    // the mock signs its input through stdin, as the wallet would
+    let signer = ExternalSigner::connect(MOCK_SIGNER, None).unwrap();
+    let mut signed = Psbt::from_base64(&psbt.to_base64()).unwrap();
+    assert_eq!(signer.sign(&mut signed).unwrap(), 1);
+    let mut expected = Psbt::from_base64(&psbt.to_base64()).unwrap();
+    assert_eq!(sign_psbt(&mut expected, &mock).unwrap(), 1);
+    assert_eq!(signed.serialize(), expected.serialize());
+    // signing again adds nothing
+    assert_eq!(signer.sign(&mut signed).unwrap(), 0);
+
+    // the other wallet signs its own copy
+    let mut theirs = Psbt::from_base64(&psbt.to_base64()).unwrap();
+    update_psbt(&mut theirs, &other, &WalletCache::default()).unwrap();
+    assert_eq!(sign_psbt(&mut theirs, &other).unwrap(), 1);
+    // which the mock has no keys for
+    let mut only_theirs = create_psbt(&other, &WalletCache::default(), &[OTHER_COIN], Vec::new(), 2).unwrap();
+    assert_eq!(signer.sign(&mut only_theirs).unwrap(), 0);
+
+    // the mock's half combined with the other signer's
+    signed.combine(theirs).unwrap();
+    assert_eq!(finalize_psbt(&mut signed).unwrap(), 0);
+    let tx = extract_transaction(&signed).unwrap();
+    assert_eq!(tx.inputs[0].witness[1], mock.public_keys[3]);
+    assert_eq!(tx.inputs[1].witness[1], other.public_keys[0]);
+}
+
+// Every descriptor the signer gives can be signed for: a change-chain coin
+// included
+#[test]
+fn sign_change_coin() {
+    let mock = mock_wallet();
+    let signer = ExternalSigner::connect(MOCK_SIGNER, None).unwrap();
+    let descriptors = signer.descriptors(0).unwrap();
+    assert_eq!(descriptors.receive.len(), 1);
+    assert_eq!(descriptors.internal.len(), 1);
+
+    let destination = Utxo {
+        script_pubkey: get_p2wpkh_program(&[0x02; 33]),
+        amount: 19_000,
+    };
+    let mut psbt = create_psbt(&mock, &WalletCache::default(), &[CHANGE_COIN], vec![destination], 2).unwrap();
+    assert_eq!(signer.sign(&mut psbt).unwrap(), 1);
+    assert_eq!(finalize_psbt(&mut psbt).unwrap(), 0);
+    extract_transaction(&psbt).unwrap();
+}